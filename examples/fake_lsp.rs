@@ -0,0 +1,12 @@
+//! The language server `lsp::tests` talk to, on stdin and stdout. Its argument is the
+//! `TextDocumentSyncKind` it asks for, incremental without one.
+
+#[path = "../src/lsp/fake.rs"]
+mod fake;
+#[path = "../src/lsp/rpc.rs"]
+mod rpc;
+
+fn main() {
+    let sync = std::env::args().nth(1).and_then(|arg| arg.parse().ok());
+    fake::serve(sync.unwrap_or(2));
+}
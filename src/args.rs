@@ -0,0 +1,309 @@
+//! The command line. Parsed before the terminal is taken over, so mistakes are reported like in
+//! any other program.
+
+use std::path::{Path, PathBuf};
+
+use crate::session::StateFile;
+
+pub const USAGE: &str = "\
+usage: rim [arguments] [file ...]       edit the files
+   or: rim [arguments] -                read the text from stdin
+
+Arguments:
+   --                   Only file names after this
+   +                    Start at the end of the file
+   +N                   Start at line N
+   +/pattern            Start at the first match of pattern
+   +command, -c command Run command after loading the first file
+   -o                   One window per file, stacked
+   -O                   One window per file, side by side
+   -p                   One tab page per file
+   -d                   Diff mode: side by side, differences highlighted
+   -R                   Read-only
+   -n                   No swap files
+   -r [file]            List swap files, or recover file from its swap file
+   -S session           Restore a session saved by :mksession
+   -u rcfile            Use rcfile instead of ~/.config/rim/rimrc, NONE for none
+   --clean              Don't read any rcfile
+   -h, --help           Show this and exit
+   --version            Show the version and exit
+";
+
+/// How to show several files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Just the first, the rest are a `:next` away
+    #[default]
+    Arglist,
+    Horizontal,
+    Vertical,
+    /// A tab page each
+    Tabs,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    pub files: Vec<String>,
+    pub layout: Layout,
+    /// `-d`, which puts the files side by side unless `-o` says otherwise
+    pub diff: bool,
+    /// `rim -`
+    pub stdin: bool,
+    /// Ex commands from `+...`, `-c` and `-S`, run in order once the files are loaded
+    pub commands: Vec<String>,
+    pub readonly: bool,
+    pub noswapfile: bool,
+    /// `-r`, which lists swap files without `files`
+    pub recover: bool,
+    /// `-u`, `None` for the usual one
+    pub rcfile: Option<String>,
+    pub clean: bool,
+}
+
+impl Args {
+    /// The rcfile to source: the `-u` one, or `~/.config/rim/rimrc` if there is one
+    pub fn rcfile_path(&self) -> Option<PathBuf> {
+        match self.rcfile.as_deref() {
+            _ if self.clean => None,
+            Some("NONE") => None,
+            Some(rcfile) => Some(PathBuf::from(rcfile)),
+            // Only complained about when it's asked for
+            None => std::env::var_os("HOME")
+                .map(|home| Path::new(&home).join(".config/rim/rimrc"))
+                .filter(|rcfile| rcfile.exists()),
+        }
+    }
+
+    /// Where history, registers and marks are kept between runs, none with `--clean`
+    pub fn state_file_path(&self) -> Option<PathBuf> {
+        if self.clean {
+            None
+        } else {
+            StateFile::default_path()
+        }
+    }
+}
+
+/// What to do instead of editing
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    Edit(Args),
+    /// Print this and exit, for `--help` and `--version`
+    Print(String),
+}
+
+/// `args` without the program name. `Err` is a usage error.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Parsed, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => parsed.files.extend(&mut args),
+            "-h" | "--help" => return Ok(Parsed::Print(USAGE.to_string())),
+            "--version" => {
+                return Ok(Parsed::Print(format!(
+                    "rim {}\n",
+                    env!("CARGO_PKG_VERSION")
+                )))
+            }
+            "--clean" => parsed.clean = true,
+            "-" => parsed.stdin = true,
+            "-o" => parsed.layout = Layout::Horizontal,
+            "-O" => parsed.layout = Layout::Vertical,
+            "-p" => parsed.layout = Layout::Tabs,
+            "-d" => parsed.diff = true,
+            "-R" => parsed.readonly = true,
+            "-n" => parsed.noswapfile = true,
+            "-r" => parsed.recover = true,
+            "-c" => parsed.commands.push(value(&mut args, &arg)?),
+            "-S" => parsed
+                .commands
+                .push(format!("source {}", value(&mut args, &arg)?)),
+            "-u" => parsed.rcfile = Some(value(&mut args, &arg)?),
+            "+" => parsed.commands.push("$".to_string()),
+            _ if arg.starts_with('+') => parsed.commands.push(arg[1..].to_string()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option argument: \"{arg}\"")),
+            _ => parsed.files.push(arg),
+        }
+    }
+    if parsed.diff && parsed.layout == Layout::Arglist {
+        parsed.layout = Layout::Vertical;
+    }
+    if parsed.stdin && !parsed.files.is_empty() {
+        return Err("Can't edit stdin and files at the same time".to_string());
+    }
+    Ok(Parsed::Edit(parsed))
+}
+
+/// The argument a flag like `-c` needs
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Argument missing after: \"{flag}\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use std::fs;
+
+    fn parse(args: &[&str]) -> Result<Parsed, String> {
+        super::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn edit(args: &[&str]) -> Args {
+        match parse(args) {
+            Ok(Parsed::Edit(args)) => args,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn files_and_flags() {
+        let args = edit(&[
+            "-O",
+            "a",
+            "+12",
+            "-R",
+            "b",
+            "-c",
+            "set wrap",
+            "+/fn main",
+            "-S",
+            "Session.rim",
+            "--",
+            "-c",
+        ]);
+        assert_eq!(args.files, ["a", "b", "-c"]);
+        assert_eq!(args.layout, Layout::Vertical);
+        assert_eq!(
+            args.commands,
+            ["12", "set wrap", "/fn main", "source Session.rim"]
+        );
+        assert!(args.readonly && !args.noswapfile);
+
+        let args = edit(&["-n", "-u", "NONE", "+", "-"]);
+        assert!(args.noswapfile && args.stdin);
+        assert_eq!(args.rcfile.as_deref(), Some("NONE"));
+        assert_eq!(args.commands, ["$"]);
+        assert_eq!(edit(&["-p", "a", "b"]).layout, Layout::Tabs);
+        let args = edit(&["-d", "a", "b"]);
+        assert!(args.diff);
+        assert_eq!(args.layout, Layout::Vertical);
+        assert_eq!(edit(&["-d", "-o", "a", "b"]).layout, Layout::Horizontal);
+        assert_eq!(edit(&[]), Args::default());
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(
+            parse(&["-x"]),
+            Err("Unknown option argument: \"-x\"".to_string())
+        );
+        assert_eq!(
+            parse(&["a", "-c"]),
+            Err("Argument missing after: \"-c\"".to_string())
+        );
+        assert!(parse(&["-", "a"]).is_err());
+        assert!(matches!(parse(&["a", "--help"]), Ok(Parsed::Print(usage)) if usage == USAGE));
+        assert!(matches!(parse(&["--version"]), Ok(Parsed::Print(v)) if v.starts_with("rim ")));
+    }
+
+    #[test]
+    fn startup_arguments() {
+        let dir = temp_dir("command-line");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "one\ntwo\nthree\n").unwrap();
+        fs::write(&b, "fn main() {}\n").unwrap();
+        let rc = dir.join("rimrc");
+        fs::write(&rc, "\" just options\n\nset so=3\n").unwrap();
+        let start = |h: &mut Harness, args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string());
+            let Ok(Parsed::Edit(args)) = super::parse(args) else {
+                panic!();
+            };
+            // Only the `-u` rcfile, and a state file of the test's own
+            let rcfile = args.rcfile.clone().map(PathBuf::from);
+            h.state()
+                .start(args, None, rcfile, Some(dir.join("state")))
+                .unwrap();
+        };
+
+        let mut h = Harness::with_size("", 10, 60);
+        let (a, b) = (a.display().to_string(), b.display().to_string());
+        start(
+            &mut h,
+            &[
+                "-u",
+                rc.to_str().unwrap(),
+                "-O",
+                &a,
+                &b,
+                "+3",
+                "-c",
+                "set wrap",
+            ],
+        );
+        assert!(h.screen()[0].starts_with("   2 one") && h.screen()[0].contains("1 fn main"));
+        assert_eq!(h.cursor(), (2, 0));
+        h.keys(":set so? wrap?<CR>");
+        assert_eq!(h.message(), "scrolloff=3  wrap");
+
+        h.keys(":args<CR>");
+        assert_eq!(h.message(), format!("[{a}] {b}"));
+        h.keys(":n<CR>:args<CR>");
+        assert_eq!(h.message(), format!("{a} [{b}]"));
+        h.keys(":next<CR>");
+        assert_eq!(h.message(), "Cannot go beyond last file");
+        h.keys("ix<Esc>:prev<CR>");
+        assert_eq!(
+            h.message(),
+            "No write since last change (add ! to override)"
+        );
+        h.keys(":N!<CR>:/t[wh]<CR>");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys(":/^o<CR>");
+        assert_eq!(
+            (h.cursor(), h.message()),
+            ((0, 0), "search hit BOTTOM, continuing at TOP")
+        );
+        h.keys(":$<CR>");
+        assert_eq!(h.cursor(), (2, 0));
+
+        let mut h = Harness::with_size("", 10, 60);
+        start(&mut h, &["-p", &a, &b]);
+        h.finish_background();
+        assert_eq!(h.screen()[0].trim_end(), " a  b");
+        assert_eq!(h.screen()[1], "   1 one");
+        h.keys("gt");
+        assert_eq!(h.screen()[1], "   1 fn main() {}");
+
+        let mut h = Harness::with_size("", 10, 60);
+        start(&mut h, &["-d", &a, &b]);
+        h.finish_background();
+        assert!(h.screen()[1].starts_with("   1 two") && h.screen()[1].contains("~"));
+        let background =
+            |h: &Harness, row| h.backend().borrow().cell(row, 6).style.background_color;
+        let change = h.state().screen().style("DiffChange").background_color;
+        let add = h.state().screen().style("DiffAdd").background_color;
+        assert_eq!((background(&h, 0), background(&h, 1)), (change, add));
+
+        let mut h = Harness::new("");
+        start(&mut h, &["-R", "-n", &a, "+/^t.o"]);
+        assert_eq!(h.cursor(), (1, 0));
+        assert!(h.screen()[22].contains("[RO]"));
+        h.keys(":set swapfile?<CR>");
+        assert_eq!(h.message(), "noswapfile");
+
+        // The recovered file is still the first of the arguments
+        let swap = dir.join(".a.swp");
+        let header = format!("rim swap file\npid: 999999999\nfile name: {a}\n\n");
+        fs::write(&swap, header + "lost work\n").unwrap();
+        let mut h = Harness::new("");
+        start(&mut h, &["-r", &a, &b]);
+        assert_eq!(h.text(), "lost work");
+        assert!(h.message().starts_with("Recovered"));
+        h.keys(":args<CR>");
+        assert_eq!(h.message(), format!("[{a}] {b}"));
+    }
+}
@@ -0,0 +1,163 @@
+use crossterm::{cursor::SetCursorStyle, style::ContentStyle, Result};
+
+use super::Backend;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub style: ContentStyle,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            style: ContentStyle::new(),
+        }
+    }
+}
+
+/// Headless grid for tests. Anything printed past the right edge is dropped instead of wrapping.
+pub struct MemoryBackend {
+    cells: Vec<Vec<Cell>>,
+    cursor: (usize, usize),
+    cursor_visible: bool,
+    raw_mode: bool,
+    /// What `echo` got
+    echoed: Vec<u8>,
+}
+
+impl MemoryBackend {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            cells: vec![vec![Cell::default(); cols]; rows],
+            cursor: (0, 0),
+            cursor_visible: true,
+            raw_mode: false,
+            echoed: Vec::new(),
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cells.first().map_or(0, Vec::len)
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> &Cell {
+        &self.cells[row][col]
+    }
+
+    /// Row `n` with trailing whitespace stripped
+    pub fn line(&self, n: usize) -> String {
+        let line: String = self.cells[n].iter().map(|cell| cell.c).collect();
+        line.trim_end().to_owned()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.rows()).map(|n| self.line(n)).collect()
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn raw_mode(&self) -> bool {
+        self.raw_mode
+    }
+
+    pub fn echoed(&self) -> String {
+        String::from_utf8_lossy(&self.echoed).into_owned()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn size(&self) -> Result<(usize, usize)> {
+        Ok((self.rows(), self.cols()))
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        self.raw_mode = true;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.raw_mode = false;
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        self.raw_mode = false;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.raw_mode = true;
+        Ok(())
+    }
+
+    fn echo(&mut self, bytes: &[u8]) -> Result<()> {
+        self.echoed.extend(bytes);
+        Ok(())
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) -> Result<()> {
+        self.cursor = (row, col);
+        Ok(())
+    }
+
+    fn show_cursor(&mut self) -> Result<()> {
+        self.cursor_visible = true;
+        Ok(())
+    }
+
+    fn hide_cursor(&mut self) -> Result<()> {
+        self.cursor_visible = false;
+        Ok(())
+    }
+
+    fn set_cursor_style(&mut self, _style: SetCursorStyle) -> Result<()> {
+        Ok(())
+    }
+
+    fn print(&mut self, s: &str, style: ContentStyle) -> Result<()> {
+        let (row, mut col) = self.cursor;
+        for c in s.chars() {
+            if let Some(cell) = self.cells.get_mut(row).and_then(|line| line.get_mut(col)) {
+                *cell = Cell { c, style };
+            }
+            col += 1;
+        }
+        self.cursor = (row, col);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::style::{Color, Stylize};
+
+    use super::*;
+
+    #[test]
+    fn print_clips_at_edge() {
+        let mut backend = MemoryBackend::new(2, 4);
+        backend.move_to(1, 2).unwrap();
+        backend
+            .print("abc", ContentStyle::new().with(Color::Red))
+            .unwrap();
+        assert_eq!(backend.lines(), vec!["", "  ab"]);
+        assert_eq!(backend.cell(1, 3).style.foreground_color, Some(Color::Red));
+        assert_eq!(backend.cursor(), (1, 5));
+    }
+}
@@ -0,0 +1,41 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crossterm::{cursor::SetCursorStyle, style::ContentStyle, Result};
+
+#[cfg(test)]
+mod memory;
+mod terminal;
+
+#[cfg(test)]
+pub use memory::MemoryBackend;
+pub use terminal::TerminalBackend;
+
+/// Shared handle so every `Window` can draw without threading the backend through each call
+pub type BackendRef = Rc<RefCell<dyn Backend>>;
+
+/// Everything `Screen` and `Window` need from the terminal. Positions are (row, col) like the
+/// rest of the editor.
+pub trait Backend {
+    /// (rows, cols)
+    fn size(&self) -> Result<(usize, usize)>;
+
+    /// Raw mode, alternate screen, etc.
+    fn setup(&mut self) -> Result<()>;
+    fn finish(&mut self) -> Result<()>;
+
+    /// Hands the terminal over to a `:!` command, out of raw mode and the alternate screen,
+    /// until `resume`
+    fn suspend(&mut self) -> Result<()>;
+    fn resume(&mut self) -> Result<()>;
+    /// The suspended command's output, as it was printed
+    fn echo(&mut self, bytes: &[u8]) -> Result<()>;
+
+    fn move_to(&mut self, row: usize, col: usize) -> Result<()>;
+    fn show_cursor(&mut self) -> Result<()>;
+    fn hide_cursor(&mut self) -> Result<()>;
+    fn set_cursor_style(&mut self, style: SetCursorStyle) -> Result<()>;
+
+    /// Print at the cursor and move it forward
+    fn print(&mut self, s: &str, style: ContentStyle) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+}
@@ -0,0 +1,110 @@
+use std::{
+    fs::OpenOptions,
+    io::{stdout, BufWriter, IsTerminal, Write},
+    panic,
+};
+
+use crossterm::{
+    cursor::{self, SetCursorStyle},
+    queue,
+    style::{ContentStyle, PrintStyledContent},
+    terminal::{self, disable_raw_mode, enable_raw_mode},
+    Result,
+};
+
+use super::Backend;
+
+/// The real terminal, through crossterm
+pub struct TerminalBackend {
+    out: Box<dyn Write>,
+}
+
+impl TerminalBackend {
+    pub fn new() -> Self {
+        Self {
+            out: tty_or_stdout(),
+        }
+    }
+}
+
+/// Stdout, unless that's a pipe (`rim - | sort`), then the terminal itself so the screen doesn't
+/// end up in the pipe
+fn tty_or_stdout() -> Box<dyn Write> {
+    if !stdout().is_terminal() {
+        if let Ok(tty) = OpenOptions::new().write(true).open("/dev/tty") {
+            return Box::new(BufWriter::new(tty));
+        }
+    }
+    Box::new(stdout())
+}
+
+impl Backend for TerminalBackend {
+    fn size(&self) -> Result<(usize, usize)> {
+        let (cols, rows) = terminal::size()?;
+        Ok((rows as usize, cols as usize))
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        enable_raw_mode()?;
+        panic::set_hook(Box::new(|info| {
+            disable_raw_mode().unwrap();
+            crossterm::execute!(tty_or_stdout(), terminal::LeaveAlternateScreen).unwrap();
+            eprintln!("{info}");
+        }));
+
+        queue!(
+            self.out,
+            terminal::EnterAlternateScreen,
+            cursor::MoveTo(0, 0),
+            cursor::SetCursorStyle::SteadyBlock,
+        )?;
+        self.flush()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        disable_raw_mode()?;
+        queue!(self.out, terminal::LeaveAlternateScreen)?;
+        self.flush()
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        queue!(self.out, terminal::LeaveAlternateScreen, cursor::Show)?;
+        self.flush()?;
+        disable_raw_mode()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        enable_raw_mode()?;
+        queue!(self.out, terminal::EnterAlternateScreen)?;
+        self.flush()
+    }
+
+    fn echo(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out.write_all(bytes)?;
+        self.flush()
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) -> Result<()> {
+        queue!(self.out, cursor::MoveTo(col as u16, row as u16))
+    }
+
+    fn show_cursor(&mut self) -> Result<()> {
+        queue!(self.out, cursor::Show)
+    }
+
+    fn hide_cursor(&mut self) -> Result<()> {
+        queue!(self.out, cursor::Hide)
+    }
+
+    fn set_cursor_style(&mut self, style: SetCursorStyle) -> Result<()> {
+        queue!(self.out, style)
+    }
+
+    fn print(&mut self, s: &str, style: ContentStyle) -> Result<()> {
+        queue!(self.out, PrintStyledContent(style.apply(s)))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
@@ -1,58 +1,462 @@
 use std::{
-    fs::File,
-    io::{Read, Seek, Write},
+    cell::RefCell,
+    fs::{self, File, Metadata},
+    io::{self, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::{
+    encoding::{self, Decoded, Encoding, FileFormat},
+    loader::{self, Event, Loading, Tail},
+    lsp::Diagnostic,
+    marks::{Marks, Pos},
+    options::{LocalOptions, Options},
+    save, swap,
+    syntax::{self, Highlighter, Span},
+    undo::{self, Edit, UndoList},
+};
+
+/// Enough about a file on disk to notice someone else writing it
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    mtime: Option<SystemTime>,
+    size: u64,
+    inode: u64,
+}
+
+impl From<&Metadata> for Stat {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            mtime: metadata.modified().ok(),
+            size: metadata.len(),
+            inode: metadata.ino(),
+        }
+    }
+}
+
+/// `removed` lines at `row` replaced by `lines`, as a language server is told about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub row: usize,
+    pub removed: usize,
+    pub lines: Vec<String>,
+}
+
+/// What happened to a buffer's file behind its back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskChange {
+    Changed,
+    Deleted,
+}
+
 pub struct Buffer {
+    /// For telling which buffer a background load or save was for
+    id: usize,
     lines: Vec<String>,
-    handle: Option<File>,
+    /// `None` for `[No Name]`
+    path: Option<PathBuf>,
     filename: String,
     unsaved_changes: bool,
-    terminal_newline: bool,
+    /// `fileformat`, `fileencoding` and friends
+    local: LocalOptions,
+    /// Doesn't exist on disk yet, the first write creates it
+    new_file: bool,
+    readonly: bool,
+    /// Invalid UTF-8 was replaced while reading, so writing it back would lose bytes
+    lossy: bool,
+    /// A listing of a directory rather than a file
+    directory: bool,
+    /// Filled in lazily while drawing, hence the `RefCell`
+    highlighter: RefCell<Option<Highlighter>>,
+    /// Where the swap file goes while there are unsaved changes
+    swap: Option<PathBuf>,
+    /// Bumped on every edit, so the swap file is only rewritten when something happened
+    changes: usize,
+    /// `changes` as of the last swap file write, `None` if there isn't one
+    swapped: Option<usize>,
+    /// The swap file write going on in the background, which has to be done before the swap
+    /// file can be removed
+    swap_writing: Option<JoinHandle<io::Result<()>>>,
+    /// The file as of reading or writing it
+    stat: Option<Stat>,
+    /// The last change the user was told about, so they're told only once
+    noticed: Option<(DiskChange, Option<Stat>)>,
+    /// The rest of a big file, still being read in the background
+    loading: Option<Loading>,
+    /// `changes` as of a write that's still going on in the background
+    saving: Option<usize>,
+    /// Edits a language server hasn't heard about yet, once it's been told about the buffer
+    tracked: Option<Vec<Change>>,
+    /// From the language server, kept on the lines they're about as others come and go
+    diagnostics: Vec<Diagnostic>,
+    marks: Marks,
+    /// What `u` and `<C-r>` go through
+    undo: UndoList,
+    /// Where the undo list is kept between runs, with `undofile`
+    undo_file: Option<PathBuf>,
+    /// Why the undo file couldn't be used, told with the file info until the next write
+    undo_ignored: Option<String>,
 }
 
 impl Buffer {
-    pub fn from_filepath(path: impl ToString) -> Self {
-        let mut file = File::options()
-            .write(true)
-            .read(true)
-            .open(path.to_string())
-            .unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let mut lines: Vec<String> = contents.split('\n').map(String::from).collect();
-        let mut terminal_newline = false;
-        if let Some(end) = lines.last() {
-            if end.is_empty() {
-                lines = lines[..lines.len() - 1].to_vec();
-                terminal_newline = true;
-            }
-        }
+    fn new(lines: Vec<String>, filename: String, path: Option<PathBuf>) -> Self {
         Self {
+            id: loader::next_id(),
             lines,
-            handle: Some(file),
-            filename: path.to_string(),
+            path,
+            filename,
             unsaved_changes: false,
-            terminal_newline,
+            local: LocalOptions::default(),
+            new_file: false,
+            readonly: false,
+            lossy: false,
+            directory: false,
+            highlighter: RefCell::new(None),
+            swap: None,
+            changes: 0,
+            swapped: None,
+            swap_writing: None,
+            stat: None,
+            noticed: None,
+            loading: None,
+            saving: None,
+            tracked: None,
+            diagnostics: Vec::new(),
+            marks: Marks::default(),
+            undo: UndoList::default(),
+            undo_file: None,
+            undo_ignored: None,
         }
     }
 
+    /// Errors are ready for the message line. Files of at least `asyncsize` only have their
+    /// first chunk read here, the rest arrives through `events`.
+    pub fn from_filepath(
+        path: impl ToString,
+        options: &Options,
+        events: &loader::Sender,
+    ) -> Result<Self, String> {
+        let filename = path.to_string();
+        let err = |e: io::Error| format!("\"{filename}\" {e}");
+        let path = PathBuf::from(&filename);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut buffer = Self::new(vec![String::new()], filename.clone(), Some(path));
+                buffer.undo_file = undo::location(Path::new(&buffer.filename), options);
+                buffer.new_file = true;
+                buffer.detect_syntax();
+                return Ok(buffer);
+            }
+            Err(e) => return Err(err(e)),
+        };
+        if metadata.is_dir() {
+            return Self::from_directory(filename, path);
+        }
+
+        let mut buffer = if metadata.len() >= options.asyncsize as u64 {
+            let file = File::open(&path).map_err(err)?;
+            let mut buffer = Self::new(Vec::new(), filename.clone(), Some(path.clone()));
+            let start = loader::start(
+                buffer.id,
+                &path,
+                file,
+                metadata.len(),
+                &options.fileencodings,
+                events,
+            )
+            .map_err(err)?;
+            buffer.lines = start.lines;
+            if buffer.lines.is_empty() {
+                buffer.lines.push(String::new());
+            }
+            buffer.local.fileformat = start.fileformat;
+            buffer.local.bomb = start.bom;
+            buffer.loading = Some(start.loading);
+            buffer
+        } else {
+            let bytes = fs::read(&path).map_err(err)?;
+            let mut buffer = Self::new(Vec::new(), filename, Some(path));
+            buffer.take_decoded(encoding::decode(&bytes, &options.fileencodings));
+            buffer
+        };
+        buffer.stat = Some(Stat::from(&metadata));
+        // Opening for writing doesn't change anything, and unlike the permission bits it knows
+        // about owners and root
+        buffer.readonly = File::options().write(true).open(&buffer.filename).is_err();
+        buffer.detect_syntax();
+        buffer.undo_file = undo::location(Path::new(&buffer.filename), options);
+        if buffer.loading.is_none() {
+            buffer.read_undo();
+        }
+        Ok(buffer)
+    }
+
+    /// Replaces the lines with a whole file's
+    fn take_decoded(&mut self, decoded: Decoded) {
+        let fileformat = FileFormat::detect(&decoded.text);
+        let (lines, endofline) = split_lines(&decoded.text, fileformat);
+        self.lines = lines;
+        self.local = LocalOptions {
+            fileformat,
+            fileencoding: decoded.encoding,
+            endofline,
+            bomb: decoded.bom,
+        };
+        self.lossy = decoded.lossy;
+    }
+
+    /// Directories first, then files, each sorted, with `../` on top
+    fn from_directory(filename: String, path: PathBuf) -> Result<Self, String> {
+        let entries = fs::read_dir(&path).map_err(|e| format!("\"{filename}\" {e}"))?;
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(format!("{name}/"));
+            } else {
+                files.push(name);
+            }
+        }
+        dirs.sort();
+        files.sort();
+        let lines = std::iter::once("../".to_string())
+            .chain(dirs)
+            .chain(files)
+            .collect();
+        let mut buffer = Self::new(lines, filename, Some(path));
+        buffer.directory = true;
+        buffer.readonly = true;
+        Ok(buffer)
+    }
+
+    /// `rim -`, detecting the format and encoding like a file's
+    pub fn from_stdin(bytes: &[u8], options: &Options) -> Self {
+        let mut buffer = Self::new(Vec::new(), String::from("[No Name]"), None);
+        buffer.take_decoded(encoding::decode(bytes, &options.fileencodings));
+        buffer.detect_syntax();
+        buffer
+    }
+
     pub fn from_string(s: String) -> Self {
-        Self {
-            lines: s.split('\n').map(String::from).collect(),
-            handle: None,
-            filename: String::from("[No Name]"),
-            unsaved_changes: false,
-            terminal_newline: false,
+        Self::new(
+            s.split('\n').map(String::from).collect(),
+            String::from("[No Name]"),
+            None,
+        )
+    }
+
+    /// Text that isn't a file, like the `q:` window's history, shown as `name`
+    pub fn scratch(name: &str, lines: Vec<String>) -> Self {
+        Self::new(lines, name.to_owned(), None)
+    }
+
+    fn detect_syntax(&mut self) {
+        let highlighter = syntax::detect(&self.filename, &self.lines)
+            .map(|syntax| Highlighter::new(syntax, self.lines.len()));
+        self.highlighter = RefCell::new(highlighter);
+    }
+
+    /// What to say on the message line after opening, like `"foo.rs" [RO] 12L, 300B`
+    pub fn file_info(&self) -> String {
+        let mut info = format!("\"{}\"", self.filename);
+        if self.directory {
+            info.push_str(" directory");
+            return info;
+        }
+        if self.new_file {
+            info.push_str(" [New]");
+            return info;
+        }
+        if self.loading.is_some() {
+            info.push_str(" [loading]");
+            return info;
+        }
+        if self.readonly {
+            info.push_str(" [RO]");
+        }
+        if self.lossy {
+            info.push_str(" [invalid UTF-8 replaced, :w! to save anyway]");
+        }
+        if let Some(why) = &self.undo_ignored {
+            info.push_str(&format!(" [undo file ignored: {why}]"));
+        }
+        if !self.local.endofline {
+            info.push_str(" [noeol]");
+        }
+        if self.local.fileformat != FileFormat::Unix {
+            info.push_str(&format!(" [{}]", self.local.fileformat.name()));
+        }
+        if self.local.fileencoding != Encoding::Utf8 {
+            info.push_str(&format!(" [{}]", self.local.fileencoding.name()));
+        }
+        // Encoding a huge file just to count it takes a while, and an untouched one is as big as
+        // it was on disk
+        let bytes = match self.stat {
+            Some(stat) if !self.unsaved_changes && !self.lossy => stat.size as usize,
+            _ => self
+                .encoded(self.local.endofline, true)
+                .unwrap_or_default()
+                .len(),
+        };
+        info.push_str(&format!(" {}L, {}B", self.lines.len(), bytes));
+        info
+    }
+
+    /// The file or directory named on line `row` of a directory listing
+    pub fn directory_entry(&self, row: usize) -> Option<PathBuf> {
+        let entry = self.lines.get(row)?.trim_end_matches('/');
+        let dir = self.path.as_deref().filter(|_| self.directory)?;
+        Some(match entry {
+            ".." => dir
+                .canonicalize()
+                .ok()
+                .and_then(|dir| dir.parent().map(Path::to_path_buf))
+                .unwrap_or_else(|| dir.join("..")),
+            _ => dir.join(entry),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_syntax(mut self, name: &str) -> Self {
+        let syntax = syntax::by_name(name).unwrap();
+        self.highlighter = RefCell::new(Some(Highlighter::new(syntax, self.lines.len())));
+        self
+    }
+
+    /// `removed` lines at `row` were replaced by `inserted` ones
+    fn edited(&mut self, row: usize, removed: usize, inserted: usize) {
+        self.changes += 1;
+        if let Some(highlighter) = self.highlighter.get_mut() {
+            highlighter.edit(row, removed, inserted);
+        }
+        if let Some(tracked) = &mut self.tracked {
+            tracked.push(Change {
+                row,
+                removed,
+                lines: self.lines[row..row + inserted].to_vec(),
+            });
+        }
+        // The ones on lines that are gone go with them
+        self.diagnostics.retain_mut(|diagnostic| {
+            if diagnostic.row >= row + removed {
+                diagnostic.row = diagnostic.row + inserted - removed;
+                return true;
+            }
+            diagnostic.row < row + inserted
+        });
+        self.marks.shift(row, removed, inserted);
+    }
+
+    /// An edit by the user made at `start`: `before`, what was at `row`, is now `inserted`
+    /// lines. It can be undone, and goes in the change list for `'.` and `g;`.
+    fn changed(&mut self, row: usize, before: Vec<String>, inserted: usize, start: Pos) {
+        let after = self.lines[row..row + inserted].to_vec();
+        self.undo.record(Edit { row, before, after }, start);
+        let end = self.lines.len() - 1;
+        let last = row + inserted.saturating_sub(1);
+        self.marks
+            .changed((start.0.min(end), start.1), last.min(end));
+        self.unsaved_changes = true;
+    }
+
+    /// Takes the undo list from the undo file, if there's one for the text as it is. One that's
+    /// stale or broken is left alone, and the user told why.
+    fn read_undo(&mut self) {
+        let Some(file) = &self.undo_file else {
+            return;
+        };
+        match UndoList::read(file, &self.lines) {
+            Ok(Some(undo)) => self.undo = undo,
+            Ok(None) => {}
+            Err(e) => self.undo_ignored = Some(e.to_string()),
         }
     }
 
+    /// Edits from here on are a new undo step
+    pub fn seal_undo(&mut self) {
+        self.undo.seal();
+    }
+
+    /// `u`, or `<C-r>` with `redo`. Returns where the cursor goes, `None` when there's nothing
+    /// left to undo or redo.
+    pub fn undo(&mut self, redo: bool) -> Option<Pos> {
+        let step = if redo {
+            self.undo.redo()
+        } else {
+            self.undo.undo()
+        }?
+        .clone();
+        if redo {
+            for edit in &step.edits {
+                self.apply(edit.row, edit.before.len(), edit.after.clone());
+            }
+        } else {
+            for edit in step.edits.iter().rev() {
+                self.apply(edit.row, edit.after.len(), edit.before.clone());
+            }
+        }
+        self.unsaved_changes = !self.undo.at_saved();
+        Some(step.cursor)
+    }
+
+    /// Puts `lines` in place of `removed` lines at `row`, undoing or redoing an edit
+    fn apply(&mut self, row: usize, removed: usize, lines: Vec<String>) {
+        let row = row.min(self.lines.len());
+        let removed = removed.min(self.lines.len() - row);
+        let inserted = lines.len();
+        self.lines.splice(row..row + removed, lines);
+        self.edited(row, removed, inserted);
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
+            self.edited(0, 0, 1);
+        }
+    }
+
+    pub fn marks(&self) -> &Marks {
+        &self.marks
+    }
+
+    pub fn marks_mut(&mut self) -> &mut Marks {
+        &mut self.marks
+    }
+
+    /// Starts keeping edits for `take_changes`
+    pub fn track_changes(&mut self) {
+        self.tracked = Some(Vec::new());
+    }
+
+    /// The edits since the last call, oldest first
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.tracked
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn set_diagnostics(&mut self, mut diagnostics: Vec<Diagnostic>) {
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.row, diagnostic.severity));
+        self.diagnostics = diagnostics;
+    }
+
     pub fn add_char(&mut self, c: char, cursor: (usize, usize)) {
+        let before = vec![self.lines[cursor.0].clone()];
         self.lines[cursor.0].insert(cursor.1, c);
-        self.unsaved_changes = true;
+        self.edited(cursor.0, 1, 1);
+        self.changed(cursor.0, before, 1, cursor);
     }
 
     pub fn add_line_break(&mut self, cursor: (usize, usize)) {
+        let before = vec![self.lines[cursor.0].clone()];
         let line = &mut self.lines[cursor.0];
         let new_line = if cursor.1 == line.len() {
             String::new()
@@ -60,37 +464,102 @@ impl Buffer {
             line.split_off(cursor.1)
         };
         self.lines.insert(cursor.0 + 1, new_line);
-        self.unsaved_changes = true;
+        self.edited(cursor.0, 1, 2);
+        self.changed(cursor.0, before, 2, cursor);
     }
 
     pub fn new_line_below(&mut self, cursor: (usize, usize)) {
         self.lines.insert(cursor.0 + 1, String::new());
+        self.edited(cursor.0 + 1, 0, 1);
+        self.changed(cursor.0 + 1, Vec::new(), 1, (cursor.0 + 1, 0));
     }
 
     pub fn new_line_above(&mut self, cursor: (usize, usize)) {
         self.lines.insert(cursor.0, String::new());
+        self.edited(cursor.0, 0, 1);
+        self.changed(cursor.0, Vec::new(), 1, (cursor.0, 0));
     }
 
     pub fn delete_char(&mut self, cursor: (usize, usize)) {
+        let before = vec![self.lines[cursor.0].clone()];
         self.lines[cursor.0].remove(cursor.1 - 1);
-        self.unsaved_changes = true;
+        self.edited(cursor.0, 1, 1);
+        self.changed(cursor.0, before, 1, (cursor.0, cursor.1 - 1));
     }
 
     pub fn delete_line(&mut self, cursor: (usize, usize)) {
-        self.lines.remove(cursor.0);
+        let before = vec![self.lines.remove(cursor.0)];
+        self.edited(cursor.0, 1, 0);
+        let mut inserted = 0;
         if self.lines.is_empty() {
             self.lines.push(String::new());
+            self.edited(0, 0, 1);
+            inserted = 1;
         }
+        self.changed(cursor.0, before, inserted, (cursor.0, 0));
+    }
+
+    /// Puts `lines` before line `row`
+    pub fn insert_lines(&mut self, row: usize, lines: Vec<String>) {
+        let inserted = lines.len();
+        self.lines.splice(row..row, lines);
+        self.edited(row, 0, inserted);
+        self.changed(row, Vec::new(), inserted, (row, 0));
+    }
+
+    /// Puts `lines` into line `row` at byte `col`, the first carrying on from the text before
+    /// it and the last followed by the rest
+    pub fn insert_text(&mut self, (row, col): (usize, usize), lines: &[String]) {
+        let before = vec![self.lines[row].clone()];
+        let rest = self.lines[row].split_off(col);
+        let mut new = lines.to_vec();
+        let first = new.remove(0);
+        self.lines[row].push_str(&first);
+        match new.last_mut() {
+            Some(last) => last.push_str(&rest),
+            None => self.lines[row].push_str(&rest),
+        }
+        let inserted = new.len() + 1;
+        self.lines.splice(row + 1..row + 1, new);
+        self.edited(row, 1, inserted);
+        self.changed(row, before, inserted, (row, col));
+    }
+
+    /// All new lines, for a `:terminal` whose screen changed. It's not a change to the text.
+    pub fn set_lines(&mut self, lines: Vec<String>) {
+        let removed = self.lines.len();
+        let inserted = lines.len();
+        self.lines = lines;
+        self.edited(0, removed, inserted);
+    }
+
+    /// Puts `lines` in place of lines `first` to `last`, for filters
+    pub fn replace_lines(&mut self, first: usize, last: usize, lines: Vec<String>) {
+        let last = last.min(self.lines.len() - 1);
+        let mut inserted = lines.len();
+        let before = self.lines.splice(first..=last, lines).collect();
+        self.edited(first, last - first + 1, inserted);
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
+            self.edited(0, 0, 1);
+            inserted = 1;
+        }
+        self.changed(first, before, inserted, (first, 0));
     }
 
     pub fn change_line(&mut self, cursor: (usize, usize)) {
-        self.lines[cursor.0].clear();
+        let before = vec![std::mem::take(&mut self.lines[cursor.0])];
+        self.edited(cursor.0, 1, 1);
+        self.changed(cursor.0, before, 1, (cursor.0, 0));
     }
 
     pub fn delete_line_break(&mut self, cursor: (usize, usize)) {
+        let before = self.lines[cursor.0 - 1..=cursor.0].to_vec();
         let old_row = self.lines.remove(cursor.0);
+        let joined = self.lines[cursor.0 - 1].len();
         self.lines[cursor.0 - 1].push_str(&old_row);
-        self.unsaved_changes = true;
+        self.edited(cursor.0 - 1, 2, 1);
+        self.changed(cursor.0 - 1, before, 1, (cursor.0 - 1, joined));
     }
 
     pub fn lines(&self) -> &[String] {
@@ -101,34 +570,442 @@ impl Buffer {
         &self.lines[n]
     }
 
-    pub fn write(&mut self) -> Result<(), String> {
-        if let Some(mut handle) = self.handle.as_ref() {
-            handle.rewind().map_err(|_| "Internal error")?;
-            handle
-                .write_all(self.lines.join("\n").as_bytes())
-                .map_err(|_| "Internal error")?;
-            if self.terminal_newline {
-                handle.write(b"\n").map_err(|_| "Internal error")?;
-            }
-        } else {
+    /// Highlighted parts of line `n`, as byte ranges. Empty without a syntax.
+    pub fn highlights(&self, n: usize) -> Vec<Span> {
+        match self.highlighter.borrow_mut().as_mut() {
+            Some(highlighter) => highlighter.spans(&self.lines, n).to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// As kept in swap files: always `\n`, always UTF-8
+    fn contents(&self) -> String {
+        let mut contents = self.lines.join("\n");
+        if self.local.endofline {
+            contents.push('\n');
+        }
+        contents
+    }
+
+    /// As written to the file. `Err` is a character `fileencoding` can't represent, unless
+    /// `lossy`.
+    fn encoded(&self, endofline: bool, lossy: bool) -> Result<Vec<u8>, char> {
+        let ending = self.local.fileformat.ending();
+        let mut text = self.lines.join(ending);
+        if endofline {
+            text.push_str(ending);
+        }
+        self.local
+            .fileencoding
+            .encode(&text, self.local.bomb, lossy)
+    }
+
+    /// The bytes `:w` writes, with `fixendofline` applied
+    fn bytes_to_write(&mut self, force: bool, options: &Options) -> Result<Vec<u8>, String> {
+        let endofline = self.local.endofline || options.fixendofline;
+        let bytes = self.encoded(endofline, force).map_err(|c| {
+            format!(
+                "Can't convert `{c}` to {} (add ! to override)",
+                self.local.fileencoding.name()
+            )
+        })?;
+        self.local.endofline = endofline;
+        Ok(bytes)
+    }
+
+    /// What `:w` (`:w!` with `force`) writes where, if nothing stands in the way
+    fn prepare_write(
+        &mut self,
+        force: bool,
+        options: &Options,
+    ) -> Result<(PathBuf, Vec<u8>), String> {
+        let Some(path) = self.path.clone() else {
             return Err("No filename".to_string());
+        };
+        if self.directory {
+            return Err(format!("\"{}\" is a directory", self.filename));
         }
-        self.unsaved_changes = false;
+        if !force && self.readonly {
+            return Err("'readonly' option is set (add ! to override)".to_string());
+        }
+        if !force && self.lossy {
+            return Err("Invalid UTF-8 would be lost (add ! to override)".to_string());
+        }
+        if !force && self.disk_change() == Some(DiskChange::Changed) {
+            return Err(
+                "The file has been changed since reading it (add ! to override)".to_string(),
+            );
+        }
+        self.check_idle()?;
+        let bytes = self.bytes_to_write(force, options)?;
+        // `undofile` may have been set since reading the file
+        self.undo_file = undo::location(&path, options);
+        Ok((path, bytes))
+    }
 
+    /// Neither loading nor saving in the background, which writing would get mixed up with
+    fn check_idle(&self) -> Result<(), String> {
+        if self.loading.is_some() {
+            return Err(format!("\"{}\" is still loading", self.filename));
+        }
+        if self.saving.is_some() {
+            return Err(format!("\"{}\" is still being written", self.filename));
+        }
         Ok(())
     }
 
-    pub fn write_to_filename(&mut self, filename: String) -> Result<(), String> {
-        let file = File::options()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(filename.clone())
-            .unwrap();
-        self.terminal_newline = true;
+    /// What `:w` would write, for `:w !cmd`
+    pub fn bytes(&self, options: &Options) -> Vec<u8> {
+        let endofline = self.local.endofline || options.fixendofline;
+        self.encoded(endofline, true).unwrap_or_default()
+    }
+
+    /// `:w -`, which counts as writing a buffer that has nowhere else to go
+    pub fn write_to_stdout(&mut self, force: bool, options: &Options) -> Result<Vec<u8>, String> {
+        self.check_idle()?;
+        let bytes = self.bytes_to_write(force, options)?;
+        if self.path.is_none() {
+            self.unsaved_changes = false;
+        }
+        Ok(bytes)
+    }
+
+    /// `:w`, or `:w!` with `force`
+    pub fn write(&mut self, force: bool, options: &Options) -> Result<(), String> {
+        let (path, bytes) = self.prepare_write(force, options)?;
+        save::save(&path, &bytes, options, force)
+            .map_err(|e| format!("\"{}\" {e}", self.filename))?;
+        self.written(&path, true)
+    }
+
+    /// `:w` that leaves writing `asyncsize` or more bytes to the background, to finish with
+    /// `Event::Saved`. Returns whether it's written already.
+    pub fn write_in_background(
+        &mut self,
+        force: bool,
+        options: &Options,
+        events: &loader::Sender,
+    ) -> Result<bool, String> {
+        let (path, bytes) = self.prepare_write(force, options)?;
+        if bytes.len() < options.asyncsize {
+            save::save(&path, &bytes, options, force)
+                .map_err(|e| format!("\"{}\" {e}", self.filename))?;
+            self.written(&path, true)?;
+            return Ok(true);
+        }
+        self.saving = Some(self.changes);
+        loader::save(self.id, path, bytes, options.clone(), force, events);
+        Ok(false)
+    }
+
+    /// The file at `path` is now what's in the buffer, or was when the write started unless
+    /// `current`. Then the undo file is written too, the only thing that can go wrong.
+    fn written(&mut self, path: &Path, current: bool) -> Result<(), String> {
+        self.stat = fs::metadata(path).ok().as_ref().map(Stat::from);
+        self.new_file = false;
+        self.readonly = false;
+        self.lossy = false;
+        if !current {
+            return Ok(());
+        }
+        self.discard_swap();
+        self.unsaved_changes = false;
+        self.undo.set_saved();
+        self.undo_ignored = None;
+        match &self.undo_file {
+            Some(file) => self
+                .undo
+                .write(file, &self.lines, path)
+                .map_err(|e| format!("Cannot write undo file \"{}\": {e}", file.display())),
+            None => Ok(()),
+        }
+    }
+
+    /// `:w filename`, which won't replace some other existing file without `force`
+    pub fn write_to_filename(
+        &mut self,
+        filename: String,
+        force: bool,
+        options: &Options,
+    ) -> Result<(), String> {
+        let path = PathBuf::from(&filename);
+        if !force && path.exists() && self.path.as_ref() != Some(&path) {
+            return Err("File exists (add ! to override)".to_string());
+        }
+        self.check_idle()?;
+        let bytes = self.bytes_to_write(force, options)?;
+        save::save(&path, &bytes, options, force).map_err(|e| format!("\"{filename}\" {e}"))?;
+        // The old name's swap file goes, the new name's undo file is the one written
+        self.discard_swap();
+        self.swap = swap::location(&path, options);
+        self.undo_file = undo::location(&path, options);
         self.filename = filename;
-        self.handle = Some(file);
-        self.write()
+        self.path = Some(path.clone());
+        self.directory = false;
+        self.written(&path, true)
+    }
+
+    /// On disk, not for `[No Name]` or directory listings
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref().filter(|_| !self.directory)
+    }
+
+    pub fn set_swap(&mut self, swap: Option<PathBuf>) {
+        self.swap = swap;
+    }
+
+    /// A swap file to write, if there are changes it doesn't have yet
+    pub fn swap_job(&mut self) -> Option<swap::Job> {
+        let (swap, path) = (self.swap.as_ref()?, self.path.as_deref()?);
+        // Half a file isn't worth recovering
+        if !self.unsaved_changes || self.swapped == Some(self.changes) || self.loading.is_some() {
+            return None;
+        }
+        let job = swap::Job {
+            swap: swap.clone(),
+            file: path.to_path_buf(),
+            contents: swap::encode(path, &self.contents()),
+        };
+        self.swapped = Some(self.changes);
+        Some(job)
+    }
+
+    /// Brings the swap file up to date off the main thread, so typing never waits on the disk.
+    /// Writes are one at a time, or an older one could finish last.
+    pub fn write_swap(&mut self) {
+        if self.swap_busy() {
+            return;
+        }
+        if let Some(job) = self.swap_job() {
+            self.swap_writing = Some(spawn_blocking(move || job.run()));
+        }
+    }
+
+    /// Whether a swap file write is going on in the background
+    pub fn swap_busy(&self) -> bool {
+        self.swap_writing.as_ref().is_some_and(|w| !w.is_finished())
+    }
+
+    /// Waits for the swap file write going on in the background, if there is one. Only for
+    /// when it's done or rim is exiting, since typing would have to wait too.
+    fn finish_swap(&mut self) -> Option<io::Result<()>> {
+        let writing = self.swap_writing.take()?;
+        Some(futures::executor::block_on(writing).unwrap_or_else(|e| Err(io::Error::other(e))))
+    }
+
+    /// How the last swap file write went, once it's done. When it failed the swap file moves on
+    /// to the next of `directory` that's left, and the next write goes there.
+    pub fn swap_written(&mut self, options: &Options) -> Result<(), String> {
+        if self.swap_busy() {
+            return Ok(());
+        }
+        let Some(Err(e)) = self.finish_swap() else {
+            return Ok(());
+        };
+        let Some(failed) = self.swap.take() else {
+            return Ok(());
+        };
+        self.swapped = None;
+        self.swap = self
+            .path
+            .as_deref()
+            .and_then(|path| swap::fallback(path, options, &failed));
+        let lost = if self.swap.is_none() {
+            ", recovery impossible"
+        } else {
+            ""
+        };
+        Err(format!(
+            "Unable to write swap file \"{}\": {e}{lost}",
+            failed.display()
+        ))
+    }
+
+    /// Removes the swap file, if this buffer wrote one. While a write is still going on that
+    /// happens in the background once it's done, or the write could put it back after it's
+    /// gone, and writes after it wait their turn as usual.
+    pub fn discard_swap(&mut self) {
+        let (Some(swap), Some(_)) = (self.swap.clone(), self.swapped.take()) else {
+            return;
+        };
+        match self.swap_writing.take() {
+            Some(writing) if !writing.is_finished() => {
+                self.swap_writing = Some(spawn_blocking(move || {
+                    let _ = futures::executor::block_on(writing);
+                    let _ = fs::remove_file(swap);
+                    Ok(())
+                }));
+            }
+            _ => {
+                let _ = fs::remove_file(swap);
+            }
+        }
+    }
+
+    /// `discard_swap` for exiting, when there's no later to leave the removal to
+    pub fn discard_swap_now(&mut self) {
+        self.discard_swap();
+        self.finish_swap();
+    }
+
+    /// Whether the file was changed or deleted since it was read or written
+    pub fn disk_change(&self) -> Option<DiskChange> {
+        let (path, stat) = (self.path()?, self.stat?);
+        match fs::metadata(path) {
+            Ok(metadata) if Stat::from(&metadata) != stat => Some(DiskChange::Changed),
+            Ok(_) => None,
+            Err(_) => Some(DiskChange::Deleted),
+        }
+    }
+
+    /// Like `disk_change`, but only once per change
+    pub fn unnoticed_disk_change(&mut self) -> Option<DiskChange> {
+        let change = self.disk_change()?;
+        let now = self
+            .path()
+            .and_then(|path| fs::metadata(path).ok())
+            .map(|metadata| Stat::from(&metadata));
+        if self.noticed == Some((change, now)) {
+            return None;
+        }
+        self.noticed = Some((change, now));
+        Some(change)
+    }
+
+    /// Takes the contents of a swap file instead of what was read from disk
+    pub fn recover(&mut self, contents: &str) {
+        self.loading = None;
+        let (lines, endofline) = split_lines(contents, FileFormat::Unix);
+        let removed = self.lines.len();
+        self.lines = lines;
+        self.local.endofline = endofline;
+        self.edited(0, removed, self.lines.len());
+        // About text that's not there anymore
+        self.undo = UndoList::default();
+        self.unsaved_changes = true;
+        self.new_file = false;
+    }
+
+    /// Opened read-only because someone else has it open, so no swap file either
+    pub fn open_read_only(&mut self) {
+        self.readonly = true;
+        self.swap = None;
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Still reading the file in the background
+    pub fn loading(&self) -> bool {
+        self.loading.is_some()
+    }
+
+    /// How many edits there have been, for noticing there's been another
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    /// Still loading or saving in the background
+    #[cfg(test)]
+    pub fn busy(&self) -> bool {
+        self.loading.is_some() || self.saving.is_some()
+    }
+
+    /// For the statusline, like `[loading 42%]`
+    pub fn progress(&self) -> String {
+        match (&self.loading, self.saving) {
+            (Some(loading), _) => format!("[loading {}%]", loading.progress()),
+            (None, Some(_)) => "[writing]".to_string(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Takes what a background load or save sent. Returns what to say about it once it's done.
+    pub fn background_event(&mut self, event: Event) -> Option<Result<String, String>> {
+        match event {
+            Event::Lines { lines, read, .. } => {
+                self.append_loaded(lines, read);
+                None
+            }
+            Event::Loaded { result, .. } => {
+                self.loading.as_ref()?;
+                Some(self.loaded(result))
+            }
+            Event::Saved { result, .. } => {
+                self.saving?;
+                Some(self.saved(result))
+            }
+        }
+    }
+
+    fn append_loaded(&mut self, lines: Vec<String>, read: u64) {
+        let Some(loading) = &mut self.loading else {
+            return;
+        };
+        let first = loading.received(lines.len(), read);
+        // The empty line that stood in for a file whose first chunk had none, unless it was typed
+        // into
+        if first && self.lines == [""] && !lines.is_empty() {
+            self.lines.clear();
+        }
+        let row = self.lines.len();
+        let inserted = lines.len();
+        self.lines.extend(lines);
+        if let Some(highlighter) = self.highlighter.get_mut() {
+            highlighter.edit(row, 0, inserted);
+        }
+    }
+
+    fn loaded(&mut self, result: Result<Tail, String>) -> Result<String, String> {
+        match result {
+            Ok(Tail::Ended { last }) => {
+                self.local.endofline = last.is_none();
+                if let Some(last) = last {
+                    self.append_loaded(vec![last], 0);
+                }
+            }
+            Ok(Tail::Whole(decoded)) => {
+                self.take_decoded(decoded);
+                self.detect_syntax();
+                self.undo = UndoList::default();
+            }
+            Err(e) => {
+                self.loading = None;
+                // Writing what's there would cut the file short
+                self.readonly = true;
+                return Err(format!("\"{}\" {e}", self.filename));
+            }
+        }
+        self.loading = None;
+        // Unless the user got to edit it meanwhile
+        if self.undo.is_empty() {
+            self.read_undo();
+        }
+        Ok(self.file_info())
+    }
+
+    fn saved(&mut self, result: Result<(), String>) -> Result<String, String> {
+        let changes = self.saving.take();
+        result.map_err(|e| format!("\"{}\" {e}", self.filename))?;
+        if let Some(path) = self.path.clone() {
+            self.written(&path, changes == Some(self.changes))?;
+        }
+        Ok(format!("\"{}\" written", self.filename))
+    }
+
+    /// `<C-c>` while loading: keeps what's there, read-only since it's not the whole file.
+    /// Returns whether there was anything to stop.
+    pub fn cancel_loading(&mut self) -> bool {
+        if self.loading.take().is_none() {
+            return false;
+        }
+        self.readonly = true;
+        true
+    }
+
+    pub fn local_options(&self) -> &LocalOptions {
+        &self.local
     }
 
     pub fn filename(&self) -> &str {
@@ -138,4 +1015,142 @@ impl Buffer {
     pub fn unsaved_changes(&self) -> bool {
         self.unsaved_changes
     }
+
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Name of the syntax in use
+    pub fn filetype(&self) -> Option<&'static str> {
+        self.highlighter
+            .borrow()
+            .as_ref()
+            .map(|highlighter| highlighter.syntax().name())
+    }
+
+    pub fn fileformat(&self) -> &'static str {
+        self.local.fileformat.name()
+    }
+
+    pub fn fileencoding(&self) -> &'static str {
+        self.local.fileencoding.name()
+    }
+
+    /// `:set` for this buffer's own options, which count as a change to it
+    pub fn set_local_options(&mut self, args: &str) -> Result<String, String> {
+        let before = self.local.changed();
+        let shown = self.local.set(args);
+        if self.local.changed() != before {
+            self.unsaved_changes = true;
+        }
+        shown
+    }
+}
+
+/// Lines of a file's contents, and whether it ended in a line ending
+pub fn split_lines(contents: &str, fileformat: FileFormat) -> (Vec<String>, bool) {
+    let ending = fileformat.ending();
+    let mut lines: Vec<String> = contents.split(ending).map(String::from).collect();
+    let mut endofline = contents.is_empty();
+    if lines.len() > 1 && lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+        endofline = true;
+    }
+    (lines, endofline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    #[test]
+    fn readonly_and_existing_files_need_force() {
+        let dir = temp_dir("buffer-force");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "one\n").unwrap();
+        fs::write(&b, "more than what replaces it\n").unwrap();
+
+        let options = Options::default();
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut buffer = Buffer::from_filepath(a.display(), &options, &events).unwrap();
+        assert_eq!(buffer.file_info(), format!("\"{}\" 1L, 4B", a.display()));
+        buffer.readonly = true;
+        assert!(buffer.write(false, &options).is_err());
+        buffer.write(true, &options).unwrap();
+        assert!(!buffer.readonly());
+
+        let b_name = b.display().to_string();
+        assert_eq!(
+            buffer.write_to_filename(b_name.clone(), false, &options),
+            Err("File exists (add ! to override)".to_string())
+        );
+        buffer
+            .write_to_filename(b_name.clone(), true, &options)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&b).unwrap(), "one\n");
+
+        fs::write(&b, "").unwrap();
+        let empty = Buffer::from_filepath(b_name, &options, &events).unwrap();
+        assert_eq!(empty.lines(), [""]);
+    }
+
+    #[test]
+    fn writing_over_a_longer_file() {
+        let dir = temp_dir("buffer-truncate");
+        let path = dir.join("a");
+        fs::write(&path, "more than what replaces it\n").unwrap();
+        let mut buffer = Buffer::from_string("short".to_string());
+        buffer
+            .write_to_filename(path.display().to_string(), true, &Options::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short\n");
+    }
+
+    #[test]
+    fn opening_files() {
+        let dir = temp_dir("open");
+        let new = dir.join("new.txt");
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>", new.display()));
+        assert_eq!(h.message(), format!("\"{}\" [New]", new.display()));
+        assert!(!new.exists());
+        h.keys("ihi<Esc>:w<CR>");
+        assert_eq!(fs::read_to_string(&new).unwrap(), "hi\n");
+
+        fs::write(dir.join("bad.txt"), b"f\xffo\n").unwrap();
+        h.keys(&format!(":e {}<CR>", dir.display()));
+        assert_eq!(h.text(), "../\nbad.txt\nnew.txt");
+        assert_eq!(h.message(), format!("\"{}\" directory", dir.display()));
+        h.keys(":w<CR>");
+        assert!(h.state().screen().message_is_error());
+
+        h.keys("j<CR>");
+        assert_eq!(h.text(), "f\u{ff}o");
+        assert!(h.message().ends_with(" [latin1] 1L, 4B"));
+        h.keys(":w<CR>");
+        assert_eq!(fs::read(dir.join("bad.txt")).unwrap(), b"f\xffo\n");
+
+        h.keys(":set fencs=utf-8<CR>:e!<CR>");
+        assert_eq!(h.text(), "f\u{fffd}o");
+        assert!(h.message().contains("[invalid UTF-8 replaced"));
+        h.keys(":w<CR>");
+        assert_eq!(
+            h.message(),
+            "Invalid UTF-8 would be lost (add ! to override)"
+        );
+        h.keys(":w!<CR>");
+        assert_eq!(
+            fs::read_to_string(dir.join("bad.txt")).unwrap(),
+            "f\u{fffd}o\n"
+        );
+
+        let missing = dir.join("missing/file");
+        h.keys(&format!(":e {}<CR>:w<CR>", missing.display()));
+        assert!(h.message().contains("No such file or directory"));
+        assert!(h.state().screen().message_is_error());
+        assert!(!h.state().should_quit());
+        h.keys(":wq<CR>");
+        assert!(!h.state().should_quit());
+    }
 }
@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+#[cfg(test)]
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+/// Where `State` gets the time from for pending-key timeouts, so tests don't have to sleep
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Only moves when told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock {
+    now: Rc<Cell<Instant>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> Self {
+        Self {
+            now: Rc::new(Cell::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
@@ -19,6 +19,18 @@ impl Commands {
         Self { commands }
     }
 
+    /// Every command name, for completion. The `!` forms are left out, they're the same command.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .commands
+            .keys()
+            .filter(|name| !name.ends_with('!'))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
     pub fn get(&self, key: &str) -> Option<(&CommandFn, Option<String>)> {
         let (cmd, arg) = match key.trim().split_once(' ') {
             Some((cmd, arg)) => (cmd, Some(arg.to_owned())),
@@ -0,0 +1,523 @@
+//! `<Tab>` on the command line: what the word before the cursor could be, going by the command
+//! it's an argument of, and how successive `<Tab>`s go through that as `wildmode` says. Also
+//! insert mode's `<C-n>` and friends, which go through their candidates in a popup menu.
+
+use std::{fs, path::Path};
+
+/// Where the candidates for a word come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Context {
+    Command,
+    File,
+    /// `:b`, files open in a window or in the argument list
+    Buffer,
+    Option,
+    /// After `:set name=`, the option's current value
+    OptionValue(String),
+    Highlight,
+    ColorScheme,
+    /// An argument nothing is known about
+    Nothing,
+}
+
+/// What the word ending at the end of `line` is, and where it starts
+pub fn context(line: &str) -> (Context, usize) {
+    let trimmed = line.trim_start();
+    let Some((cmd, _)) = trimmed.split_once(' ') else {
+        return (Context::Command, line.len() - trimmed.len());
+    };
+    let start = line.rfind(' ').map_or(0, |i| i + 1);
+    let context = match cmd.trim_end_matches('!') {
+        "e" | "w" | "wq" | "vne" | "new" | "r" | "read" | "so" | "source" => Context::File,
+        "b" | "buffer" => Context::Buffer,
+        "set" | "se" => match line[start..].split_once('=') {
+            Some((name, _)) => {
+                let option = name.trim_end_matches(['+', '-']).to_owned();
+                return (Context::OptionValue(option), start + name.len() + 1);
+            }
+            None => Context::Option,
+        },
+        "highlight" | "hi" => Context::Highlight,
+        "colorscheme" | "colo" => Context::ColorScheme,
+        _ => Context::Nothing,
+    };
+    (context, start)
+}
+
+/// The `candidates` that could be `word`, in order: ones starting with it as they come, or with
+/// `fuzzy` anything containing its characters in order, best matches first
+pub fn filter(candidates: Vec<String>, word: &str, fuzzy: bool) -> Vec<String> {
+    let mut matched: Vec<(i32, String)> = Vec::new();
+    for candidate in candidates {
+        let score = if fuzzy {
+            fuzzy_score(&candidate, word)
+        } else {
+            candidate.starts_with(word).then_some(0)
+        };
+        if let Some(score) = score {
+            if !matched.iter().any(|(_, other)| *other == candidate) {
+                matched.push((score, candidate));
+            }
+        }
+    }
+    // Stable, so equally good ones keep their order
+    matched.sort_by_key(|(score, _)| -score);
+    matched
+        .into_iter()
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// How well `candidate` matches `pattern` when its characters only have to appear in order,
+/// ignoring case. Matches at the start of words and runs of matches score higher.
+fn fuzzy_score(candidate: &str, pattern: &str) -> Option<i32> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous: Option<usize> = None;
+    for p in pattern.chars() {
+        let i = next
+            + chars[next..]
+                .iter()
+                .position(|c| c.to_lowercase().eq(p.to_lowercase()))?;
+        if i == 0 || !chars[i - 1].is_alphanumeric() {
+            score += 8;
+        }
+        match previous {
+            Some(previous) if previous + 1 == i => score += 5,
+            Some(previous) => score -= (i - previous - 1).min(5) as i32,
+            None => score -= i.min(5) as i32,
+        }
+        previous = Some(i);
+        next = i + 1;
+    }
+    Some(score)
+}
+
+/// Paths `word` could be the start of, directories with a `/` after them. Hidden files only
+/// come up once a `.` is typed.
+pub fn files(word: &str, fuzzy: bool) -> Vec<String> {
+    let (dir, name) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let home = std::env::var_os("HOME");
+    let read = match (dir.strip_prefix("~/"), &home) {
+        (Some(rest), Some(home)) => Path::new(home).join(rest),
+        _ if dir.is_empty() => Path::new(".").to_path_buf(),
+        _ => Path::new(dir).to_path_buf(),
+    };
+    let Ok(entries) = fs::read_dir(read) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let mut file = entry.file_name().into_string().ok()?;
+            if file.starts_with('.') && !name.starts_with('.') {
+                return None;
+            }
+            if entry.path().is_dir() {
+                file.push('/');
+            }
+            Some(file)
+        })
+        .collect();
+    names.sort();
+    filter(names, name, fuzzy)
+        .into_iter()
+        .map(|file| format!("{dir}{file}"))
+        .collect()
+}
+
+/// The start every one of `candidates` has in common
+pub fn longest_common_prefix(candidates: &[String]) -> String {
+    let Some(first) = candidates.first() else {
+        return String::new();
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, a), _)| i + a.len_utf8())
+            .min(len);
+    }
+    first[..len].to_owned()
+}
+
+/// What one `<Tab>` does, from one comma separated part of `wildmode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage {
+    /// Go to the next candidate
+    pub full: bool,
+    /// Complete as far as all the candidates agree
+    pub longest: bool,
+    /// Show the candidates: `list`, or with `wildmenu` on, `full` and `longest:full`
+    pub menu: bool,
+}
+
+/// The stage for `<Tab>` number `tab`, counting from 0. The last one repeats. Unknown parts
+/// act like `full`.
+pub fn stage(wildmode: &str, wildmenu: bool, tab: usize) -> Stage {
+    let stages: Vec<&str> = wildmode.split(',').filter(|s| !s.is_empty()).collect();
+    let part = stages
+        .get(tab.min(stages.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or("full");
+    match part.split_once(':') {
+        Some(("list", then)) => Stage {
+            full: then == "full",
+            longest: then == "longest",
+            menu: true,
+        },
+        Some(("longest", _)) => Stage {
+            full: false,
+            longest: true,
+            menu: wildmenu,
+        },
+        _ if part == "list" => Stage {
+            full: false,
+            longest: false,
+            menu: true,
+        },
+        _ if part == "longest" => Stage {
+            full: false,
+            longest: true,
+            menu: false,
+        },
+        _ => Stage {
+            full: true,
+            longest: false,
+            menu: wildmenu,
+        },
+    }
+}
+
+/// A `<Tab>` in progress on the command line. Any other key ends it.
+pub struct Completion {
+    /// Where the word starts in the command line, not counting the `:`
+    pub start: usize,
+    /// The word as typed, which cycling comes back around to
+    pub typed: String,
+    pub candidates: Vec<String>,
+    /// The candidate that's in the command line, `None` for `typed`
+    pub selected: Option<usize>,
+    /// How many `<Tab>`s there have been, for `wildmode`
+    pub tabs: usize,
+    pub menu: bool,
+}
+
+impl Completion {
+    /// Moves `step` along the candidates, going through `typed` between the last and first
+    pub fn cycle(&mut self, step: isize) {
+        self.selected = cycle(self.selected, self.candidates.len(), step);
+    }
+
+    /// What the word is now
+    pub fn text(&self) -> &str {
+        self.selected.map_or(&self.typed, |i| &self.candidates[i])
+    }
+}
+
+/// `step` along `len` candidates from `selected`, with `None`, for what was typed, between the
+/// last and the first
+fn cycle(selected: Option<usize>, len: usize, step: isize) -> Option<usize> {
+    let len = len as isize + 1;
+    let current = selected.map_or(len - 1, |i| i as isize);
+    let next = (current + step).rem_euclid(len);
+    (next < len - 1).then_some(next as usize)
+}
+
+/// Whether `c` can be part of a keyword, for `<C-n>` and `<C-p>`
+pub fn is_keyword(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The keywords in `lines`, nearest to the one starting at `from` first: those after it, going
+/// on around the end of the file, or with `forward` false those before it, going back around
+/// the start. The one at `from` itself isn't there. Without `from`, they're in order.
+pub fn keywords(lines: &[String], from: Option<(usize, usize)>, forward: bool) -> Vec<String> {
+    let mut words = Vec::new();
+    for (row, line) in lines.iter().enumerate() {
+        let mut start = None;
+        for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+            match (start, is_keyword(c)) {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    words.push(((row, s), &line[s..i]));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    let Some(from) = from else {
+        return words.into_iter().map(|(_, word)| word.to_owned()).collect();
+    };
+    let split = words.partition_point(|&(at, _)| at < from);
+    let after = words[split..].iter().filter(|&&(at, _)| at != from);
+    let before = words[..split].iter();
+    let ordered: Vec<_> = if forward {
+        after.chain(before).collect()
+    } else {
+        before.rev().chain(after.rev()).collect()
+    };
+    ordered
+        .into_iter()
+        .map(|(_, word)| word.to_string())
+        .collect()
+}
+
+/// Insert mode completion: the candidates for what's from byte `start` of the cursor's line
+/// up to the cursor, in a menu by it until a key that isn't for the menu
+pub struct Popup {
+    pub start: usize,
+    /// What was there before the menu came up, which cycling comes back around to
+    pub typed: String,
+    pub candidates: Vec<String>,
+    /// `None` for `typed`
+    pub selected: Option<usize>,
+    /// Whether the selected candidate goes in the buffer straight away, not just on `<C-y>`
+    pub insert: bool,
+    /// Whether the menu is drawn, or completion just goes through the candidates in the buffer
+    pub menu: bool,
+}
+
+impl Popup {
+    pub fn cycle(&mut self, step: isize) {
+        self.selected = cycle(self.selected, self.candidates.len(), step);
+    }
+
+    /// What's in the buffer now
+    pub fn text(&self) -> &str {
+        match self.selected {
+            Some(i) if self.insert => &self.candidates[i],
+            _ => &self.typed,
+        }
+    }
+}
+
+/// How `completeopt` says insert mode completion goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompleteOpt {
+    /// `menu`, or `menuone` to show it even for one candidate
+    pub menu: bool,
+    pub menuone: bool,
+    /// Only as far as all the candidates agree goes in to start with
+    pub longest: bool,
+    /// Nothing goes in until `<C-y>`
+    pub noinsert: bool,
+    /// No candidate is selected to start with
+    pub noselect: bool,
+}
+
+impl CompleteOpt {
+    pub fn parse(completeopt: &str) -> Self {
+        let has = |name| completeopt.split(',').any(|part| part == name);
+        Self {
+            menu: has("menu") || has("menuone"),
+            menuone: has("menuone"),
+            longest: has("longest"),
+            noinsert: has("noinsert"),
+            noselect: has("noselect"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    fn strings(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn contexts() {
+        assert_eq!(context("se"), (Context::Command, 0));
+        assert_eq!(context("  e foo/b"), (Context::File, 4));
+        assert_eq!(context("w! a"), (Context::File, 3));
+        assert_eq!(
+            context("set wrap ff=u"),
+            (Context::OptionValue("ff".into()), 12)
+        );
+        assert_eq!(context("set so+="), (Context::OptionValue("so".into()), 8));
+        assert_eq!(context("set sc"), (Context::Option, 4));
+        assert_eq!(context("hi link Wild"), (Context::Highlight, 8));
+        assert_eq!(context("colo "), (Context::ColorScheme, 5));
+        assert_eq!(context("q x"), (Context::Nothing, 2));
+    }
+
+    #[test]
+    fn matching() {
+        let options = strings(&["sidescrolloff", "scrolloff", "scroll", "shell"]);
+        assert_eq!(
+            filter(options.clone(), "scr", false),
+            ["scrolloff", "scroll"]
+        );
+        assert_eq!(filter(options, "sof", true), ["scrolloff", "sidescrolloff"]);
+        assert_eq!(
+            longest_common_prefix(&strings(&["backup", "backupdir", "backupcopy"])),
+            "backup"
+        );
+        assert_eq!(longest_common_prefix(&strings(&["ab", "cd"])), "");
+    }
+
+    #[test]
+    fn wildmode_stages() {
+        let full = stage("full", true, 3);
+        assert!(full.full && full.menu && !full.longest);
+        let longest = stage("longest:full,full", true, 0);
+        assert!(longest.longest && longest.menu && !longest.full);
+        assert_eq!(stage("longest:full,full", true, 5), full);
+        let list = stage("list:longest", false, 0);
+        assert!(list.longest && list.menu && !list.full);
+
+        let mut completion = Completion {
+            start: 0,
+            typed: "wr".into(),
+            candidates: strings(&["wrap", "writebackup"]),
+            selected: None,
+            tabs: 0,
+            menu: false,
+        };
+        completion.cycle(1);
+        assert_eq!(completion.text(), "wrap");
+        completion.cycle(1);
+        completion.cycle(1);
+        assert_eq!(completion.text(), "wr");
+        completion.cycle(-1);
+        assert_eq!(completion.text(), "writebackup");
+    }
+
+    #[test]
+    fn keyword_order() {
+        let lines = strings(&["alpha beta", "gamma alpha", "", "delta_1 é"]);
+        assert_eq!(
+            keywords(&lines, Some((1, 6)), true),
+            ["delta_1", "é", "alpha", "beta", "gamma"]
+        );
+        assert_eq!(
+            keywords(&lines, Some((1, 6)), false),
+            ["gamma", "beta", "alpha", "é", "delta_1"]
+        );
+        assert_eq!(keywords(&lines[..1], None, false), ["alpha", "beta"]);
+        assert_eq!(
+            CompleteOpt::parse("menuone,noselect"),
+            CompleteOpt {
+                menu: true,
+                menuone: true,
+                longest: false,
+                noinsert: false,
+                noselect: true,
+            }
+        );
+    }
+
+    #[test]
+    fn tab_completion() {
+        let mut h = Harness::new("text");
+        h.keys(":checkt<Tab>");
+        assert_eq!(h.message(), ":checktime");
+        h.keys("<Esc>:set wr<Tab>");
+        assert_eq!(h.message(), ":set wrap");
+        assert_eq!(h.screen()[22], "wrap  writebackup");
+        h.keys("<Tab>");
+        assert_eq!(h.message(), ":set writebackup");
+        h.keys("<Tab>");
+        assert_eq!(h.message(), ":set wr");
+        h.keys("<S-Tab>");
+        assert_eq!(h.message(), ":set writebackup");
+        h.keys("<BS>");
+        assert_eq!(h.message(), ":set writebacku");
+        assert!(h.screen()[22].starts_with("[No Name]"));
+
+        h.keys("<C-u>set ff=<Tab>");
+        assert_eq!(h.message(), ":set ff=unix");
+        h.keys("<C-u>hi Wild<Tab>");
+        assert_eq!(h.message(), ":hi WildMenu");
+        h.keys("<C-u>colo li<Tab>");
+        assert_eq!(h.message(), ":colo light");
+
+        h.keys("<C-u>set wildmode=longest:full,full<CR>:set bac<Tab>");
+        assert_eq!(h.message(), ":set backup");
+        assert_eq!(h.screen()[22], "backup  backupcopy  backupdir");
+        h.keys("<Tab><Tab>");
+        assert_eq!(h.message(), ":set backupcopy");
+
+        h.keys("<Esc>:set wop=fuzzy wim=full<CR>:set sof<Tab>");
+        assert_eq!(h.message(), ":set scrolloff");
+
+        let dir = temp_dir("tab_completion");
+        fs::write(dir.join("alpha.txt"), "").unwrap();
+        fs::create_dir(dir.join("alps")).unwrap();
+        let dir = dir.display();
+        h.keys(&format!("<Esc>:set wop=<CR>:e {dir}/al<Tab>"));
+        assert_eq!(h.message(), format!(":e {dir}/alpha.txt"));
+        h.keys("<Tab>");
+        assert_eq!(h.message(), format!(":e {dir}/alps/"));
+        h.keys("<C-u>e<Tab>");
+        assert_eq!(h.message(), ":e");
+        h.keys(&format!("<C-u>vne {dir}/alpha.txt<CR>:b <Tab>"));
+        assert_eq!(h.message(), format!(":b {dir}/alpha.txt"));
+    }
+
+    #[test]
+    fn insert_completion() {
+        let mut h = Harness::new("counter count\ncontinue");
+        h.keys("Goco<C-n>");
+        assert_eq!(h.buffer().nth_line(2), "counter");
+        assert_eq!(
+            &h.screen()[3..6],
+            ["~    counter", "~    count", "~    continue"]
+        );
+        let selected = h.state().screen().style("PmenuSel");
+        assert_eq!(h.backend().borrow().cell(3, 6).style, selected);
+        h.keys("<C-n>");
+        assert_eq!(h.buffer().nth_line(2), "count");
+        assert_eq!(h.backend().borrow().cell(4, 6).style, selected);
+        h.keys("<C-p><C-p>");
+        assert_eq!(h.buffer().nth_line(2), "co");
+        h.keys("<C-p><C-e>");
+        assert_eq!(h.buffer().nth_line(2), "co");
+        h.keys("<C-p>");
+        assert_eq!(h.buffer().nth_line(2), "continue");
+        h.keys("<C-y>");
+        assert_eq!(h.screen()[3], "~");
+        h.keys("<CR>  coun<C-x><C-l>");
+        assert_eq!(h.buffer().nth_line(3), "  counter count");
+        h.keys("<C-n>x");
+        assert_eq!(h.buffer().nth_line(3), "  counx");
+        h.keys("<C-x><C-l>");
+        assert_eq!(h.message(), "Pattern not found");
+
+        let dir = temp_dir("insert_completion");
+        fs::write(dir.join("alpha.txt"), "zebra zeal\n").unwrap();
+        fs::create_dir(dir.join("alps")).unwrap();
+        let dir = dir.display();
+        h.keys(&format!("<CR>{dir}/al<C-x><C-f>"));
+        assert_eq!(h.buffer().nth_line(4), format!("{dir}/alpha.txt"));
+        h.keys("<C-n> <Esc>");
+        assert_eq!(h.buffer().nth_line(4), format!("{dir}/alps/ "));
+
+        h.keys(":set cot=menuone,noinsert<CR>ocont<C-n>");
+        assert_eq!(h.buffer().nth_line(5), "cont");
+        assert!(h.screen()[6].contains(" continue"));
+        h.keys("<C-y>");
+        assert_eq!(h.buffer().nth_line(5), "continue");
+
+        h.keys(&format!(
+            "<Esc>:set cot=longest<CR>:vne {dir}/alpha.txt<CR>"
+        ));
+        h.keys("oc<C-n>");
+        assert_eq!(h.buffer().nth_line(1), "co");
+        h.keys("<C-n>");
+        assert_eq!(h.buffer().nth_line(1), "counter");
+        h.keys("<Esc>oz<C-p>");
+        assert_eq!(h.buffer().nth_line(2), "ze");
+    }
+}
@@ -0,0 +1,222 @@
+//! Diff mode, `rim -d`: which lines of two texts differ. Where one text has lines the other
+//! doesn't, they're added; where both have different lines between the same unchanged ones,
+//! as many as both have are changed and the rest of the longer run are added.
+
+use std::ops::Range;
+
+/// Past this many lines added or removed, finding the fewest would take too long and too much
+/// memory, so everything between what's the same at the start and the end is one hunk
+const MAX_EDITS: usize = 2000;
+
+/// Lines of each text that stand where the other has something else, or nothing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub a: Range<usize>,
+    pub b: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    Same,
+    Added,
+    Changed,
+}
+
+/// What a window in diff mode shows about how its text differs from the one it's compared with
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Its own lines are `a`
+    pub hunks: Vec<Hunk>,
+    pub lines: Vec<Line>,
+    /// Buffer id, edits and whether it's still loading, for both texts as of when `hunks` were
+    /// worked out
+    pub compared: Option<[(usize, usize, bool); 2]>,
+}
+
+impl Diff {
+    pub fn new(hunks: Vec<Hunk>, len: usize, compared: [(usize, usize, bool); 2]) -> Self {
+        let mut lines = vec![Line::Same; len];
+        for hunk in &hunks {
+            for (n, row) in hunk.a.clone().enumerate() {
+                lines[row] = if n < hunk.b.len() {
+                    Line::Changed
+                } else {
+                    Line::Added
+                };
+            }
+        }
+        Self {
+            hunks,
+            lines,
+            compared: Some(compared),
+        }
+    }
+}
+
+/// The fewest hunks that make `a` into `b`, in order
+pub fn hunks(a: &[String], b: &[String]) -> Vec<Hunk> {
+    // What's the same at either end doesn't need looking at
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a_rest, b_rest) = (&a[prefix..], &b[prefix..]);
+    let suffix = a_rest
+        .iter()
+        .rev()
+        .zip(b_rest.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = &a_rest[..a_rest.len() - suffix];
+    let b_mid = &b_rest[..b_rest.len() - suffix];
+    let same = common(a_mid, b_mid).unwrap_or_default();
+    let mut hunks = Vec::new();
+    let (mut x, mut y) = (0, 0);
+    for (i, j) in same.into_iter().chain([(a_mid.len(), b_mid.len())]) {
+        if i > x || j > y {
+            hunks.push(Hunk {
+                a: prefix + x..prefix + i,
+                b: prefix + y..prefix + j,
+            });
+        }
+        (x, y) = (i + 1, j + 1);
+    }
+    hunks
+}
+
+/// The pairs of lines, one from each, that the fewest insertions and deletions leave alone,
+/// found with Myers' algorithm. `None` past `MAX_EDITS`.
+fn common(a: &[String], b: &[String]) -> Option<Vec<(usize, usize)>> {
+    if a.is_empty() || b.is_empty() {
+        return Some(Vec::new());
+    }
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (a.len() + b.len()).min(MAX_EDITS) as isize;
+    let offset = max + 1;
+    // How far along `a` each diagonal `k` (x - y) got
+    let mut v = vec![0; 2 * max as usize + 3];
+    // `v` around the diagonals each round started from, to find the way back
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: {
+        for d in 0..=max {
+            trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+            let at = |k: isize| (k + offset) as usize;
+            for k in (-d..=d).step_by(2) {
+                let mut x = if down(v[at(k - 1)], v[at(k + 1)], k, d) {
+                    v[at(k + 1)]
+                } else {
+                    v[at(k - 1)] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                v[at(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+        return None;
+    }
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| (k + d + 1) as usize;
+        let k = x - y;
+        let prev_k = if down(v[at(k - 1)], v[at(k + 1)], k, d) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    pairs.reverse();
+    Some(pairs)
+}
+
+/// Whether diagonal `k` is reached by a step down from diagonal `k + 1`, taking a line of `b`,
+/// rather than by one to the right from `k - 1`, taking a line of `a`. `left` and `right` are
+/// how far along `a` those two diagonals got.
+fn down(left: isize, right: isize, k: isize, d: isize) -> bool {
+    k == -d || (k != d && left < right)
+}
+
+/// The line of the other text that line `row` lines up with, going from `b` to `a` with `back`
+pub fn line_across(hunks: &[Hunk], row: usize, back: bool) -> usize {
+    let mut shift = 0;
+    for hunk in hunks {
+        let (from, to) = if back {
+            (&hunk.b, &hunk.a)
+        } else {
+            (&hunk.a, &hunk.b)
+        };
+        if row < from.start {
+            break;
+        }
+        if row < from.end {
+            return to.start + (row - from.start).min(to.len().saturating_sub(1));
+        }
+        shift = to.end as isize - from.end as isize;
+    }
+    row.saturating_add_signed(shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.chars().map(String::from).collect()
+    }
+
+    fn hunks(a: &str, b: &str) -> Vec<(Range<usize>, Range<usize>)> {
+        super::hunks(&lines(a), &lines(b))
+            .into_iter()
+            .map(|hunk| (hunk.a, hunk.b))
+            .collect()
+    }
+
+    #[test]
+    fn fewest_hunks() {
+        assert_eq!(hunks("abc", "abc"), []);
+        assert_eq!(hunks("", "ab"), [(0..0, 0..2)]);
+        assert_eq!(hunks("abc", "aXc"), [(1..2, 1..2)]);
+        assert_eq!(hunks("abc", "abXc"), [(2..2, 2..3)]);
+        assert_eq!(hunks("abcd", "ad"), [(1..3, 1..1)]);
+        assert_eq!(
+            hunks("abcabba", "cbabac"),
+            [(0..2, 0..0), (3..3, 1..2), (5..6, 4..4), (7..7, 5..6)]
+        );
+        assert_eq!(hunks("xaby", "zabw"), [(0..1, 0..1), (3..4, 3..4)]);
+    }
+
+    #[test]
+    fn lines_and_lining_up() {
+        let hunks = super::hunks(&lines("abcdef"), &lines("aXYbdeZf"));
+        let diff = Diff::new(hunks, 6, [(0, 0, false); 2]);
+        use Line::*;
+        assert_eq!(diff.lines, [Same, Same, Added, Same, Same, Same]);
+        let back = Diff::new(
+            super::hunks(&lines("aXYbdeZf"), &lines("abcdef")),
+            8,
+            [(0, 0, false); 2],
+        );
+        assert_eq!(
+            back.lines,
+            [Same, Added, Added, Same, Same, Same, Added, Same]
+        );
+        let across: Vec<usize> = (0..6)
+            .map(|row| line_across(&diff.hunks, row, false))
+            .collect();
+        assert_eq!(across, [0, 3, 4, 4, 5, 7]);
+        assert_eq!(line_across(&diff.hunks, 7, true), 5);
+        assert_eq!(line_across(&diff.hunks, 2, true), 1);
+    }
+}
@@ -0,0 +1,270 @@
+//! Line endings (`fileformat`) and character encodings (`fileencoding`) of files on disk. In
+//! memory everything is UTF-8 lines; these turn bytes into that and back.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// `\n`
+    Unix,
+    /// `\r\n`
+    Dos,
+    /// `\r`
+    Mac,
+}
+
+impl FileFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileFormat::Unix => "unix",
+            FileFormat::Dos => "dos",
+            FileFormat::Mac => "mac",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "unix" => FileFormat::Unix,
+            "dos" => FileFormat::Dos,
+            "mac" => FileFormat::Mac,
+            _ => return None,
+        })
+    }
+
+    pub fn ending(self) -> &'static str {
+        match self {
+            FileFormat::Unix => "\n",
+            FileFormat::Dos => "\r\n",
+            FileFormat::Mac => "\r",
+        }
+    }
+
+    /// Dos only when every line ends in `\r\n`, so a stray `\r` in a unix file stays visible
+    pub fn detect(text: &str) -> Self {
+        let newlines = text.matches('\n').count();
+        if newlines == 0 {
+            if text.contains('\r') {
+                FileFormat::Mac
+            } else {
+                FileFormat::Unix
+            }
+        } else if text.matches("\r\n").count() == newlines {
+            FileFormat::Dos
+        } else {
+            FileFormat::Unix
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    /// Empty means the default, UTF-8
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "" | "utf-8" | "utf8" => Encoding::Utf8,
+            "utf-16le" => Encoding::Utf16Le,
+            "utf-16" | "utf-16be" => Encoding::Utf16Be,
+            "latin1" | "iso-8859-1" => Encoding::Latin1,
+            _ => return None,
+        })
+    }
+
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xef\xbb\xbf",
+            Encoding::Utf16Le => b"\xff\xfe",
+            Encoding::Utf16Be => b"\xfe\xff",
+            Encoding::Latin1 => b"",
+        }
+    }
+
+    /// `None` if `bytes` aren't valid in this encoding
+    fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) {
+                    return None;
+                }
+                let units = bytes.chunks_exact(2).map(|pair| match self {
+                    Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                });
+                char::decode_utf16(units).collect::<Result<_, _>>().ok()
+            }
+            Encoding::Latin1 => Some(bytes.iter().map(|&b| char::from(b)).collect()),
+        }
+    }
+
+    /// Characters that don't exist in the encoding become `?` if `lossy`, and are an error
+    /// otherwise
+    pub fn encode(self, text: &str, bom: bool, lossy: bool) -> Result<Vec<u8>, char> {
+        let mut bytes = Vec::with_capacity(text.len());
+        if bom {
+            bytes.extend_from_slice(self.bom());
+        }
+        match self {
+            Encoding::Utf8 => bytes.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+            Encoding::Utf16Be => bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    match u8::try_from(c) {
+                        Ok(b) => bytes.push(b),
+                        Err(_) if lossy => bytes.push(b'?'),
+                        Err(_) => return Err(c),
+                    }
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// How a file was read
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub text: String,
+    pub encoding: Encoding,
+    pub bom: bool,
+    /// Nothing in `fileencodings` fit, so invalid UTF-8 was replaced
+    pub lossy: bool,
+}
+
+/// Tries the encodings in `fileencodings` in order, `ucs-bom` meaning whatever a byte order
+/// mark says
+pub fn decode(bytes: &[u8], fileencodings: &str) -> Decoded {
+    for name in fileencodings.split(',') {
+        if name == "ucs-bom" {
+            let boms = [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be];
+            let found = boms.into_iter().find_map(|encoding| {
+                let rest = bytes.strip_prefix(encoding.bom())?;
+                Some((encoding, encoding.decode(rest)?))
+            });
+            if let Some((encoding, text)) = found {
+                return Decoded {
+                    text,
+                    encoding,
+                    bom: true,
+                    lossy: false,
+                };
+            }
+        } else if let Some(encoding) = Encoding::from_name(name) {
+            if let Some(text) = encoding.decode(bytes) {
+                return Decoded {
+                    text,
+                    encoding,
+                    bom: false,
+                    lossy: false,
+                };
+            }
+        }
+    }
+    Decoded {
+        text: String::from_utf8_lossy(bytes).into_owned(),
+        encoding: Encoding::Utf8,
+        bom: false,
+        lossy: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use std::fs;
+
+    const FENCS: &str = "ucs-bom,utf-8,latin1";
+
+    #[test]
+    fn formats() {
+        assert_eq!(FileFormat::detect("a\r\nb\r\n"), FileFormat::Dos);
+        assert_eq!(FileFormat::detect("a\r\nb\n"), FileFormat::Unix);
+        assert_eq!(FileFormat::detect("a\rb\r"), FileFormat::Mac);
+        assert_eq!(FileFormat::detect("ab"), FileFormat::Unix);
+    }
+
+    #[test]
+    fn encodings() {
+        let decoded = decode(b"\xef\xbb\xbfhi", FENCS);
+        assert_eq!((decoded.text.as_str(), decoded.bom), ("hi", true));
+
+        let utf16 = Encoding::Utf16Le
+            .encode("h\u{e9}\u{1f600}", true, false)
+            .unwrap();
+        assert_eq!(&utf16[..4], b"\xff\xfeh\x00");
+        let decoded = decode(&utf16, FENCS);
+        assert_eq!(decoded.text, "h\u{e9}\u{1f600}");
+        assert_eq!(decoded.encoding, Encoding::Utf16Le);
+
+        let decoded = decode(b"caf\xe9", FENCS);
+        assert_eq!(
+            (decoded.text.as_str(), decoded.encoding),
+            ("caf\u{e9}", Encoding::Latin1)
+        );
+        assert_eq!(
+            Encoding::Latin1.encode("caf\u{e9}", false, false),
+            Ok(b"caf\xe9".to_vec())
+        );
+        assert_eq!(
+            Encoding::Latin1.encode("\u{263a}", false, false),
+            Err('\u{263a}')
+        );
+        assert_eq!(
+            Encoding::Latin1.encode("\u{263a}", false, true),
+            Ok(b"?".to_vec())
+        );
+
+        assert!(decode(b"caf\xe9", "ucs-bom,utf-8").lossy);
+    }
+
+    #[test]
+    fn fileformats_and_encodings() {
+        let dir = temp_dir("fileformat");
+        let file = dir.join("crlf.txt");
+        fs::write(&file, "one\r\ntwo\r\n").unwrap();
+
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>", file.display()));
+        assert_eq!(h.text(), "one\ntwo");
+        assert!(h.message().ends_with(" [dos] 2L, 10B"));
+        h.keys("A!<Esc>:w<CR>");
+        assert_eq!(fs::read(&file).unwrap(), b"one!\r\ntwo\r\n");
+
+        h.keys(":set ff? fenc=utf-16le bomb<CR>");
+        assert_eq!(h.message(), "fileformat=dos");
+        h.keys(":set ff=unix<CR>:w<CR>");
+        assert_eq!(
+            fs::read(&file).unwrap(),
+            Encoding::Utf16Le
+                .encode("one!\ntwo\n", true, false)
+                .unwrap()
+        );
+        h.keys(&format!(":e {}<CR>", file.display()));
+        assert_eq!(h.text(), "one!\ntwo");
+        assert!(h.message().ends_with(" [utf-16le] 2L, 20B"));
+
+        let noeol = dir.join("noeol");
+        fs::write(&noeol, "last").unwrap();
+        h.keys(&format!(":e {}<CR>", noeol.display()));
+        assert!(h.message().contains(" [noeol] "));
+        h.keys(":set nofixeol<CR>:w<CR>");
+        assert_eq!(fs::read_to_string(&noeol).unwrap(), "last");
+        h.keys(":set fixeol<CR>:w<CR>");
+        assert_eq!(fs::read_to_string(&noeol).unwrap(), "last\n");
+    }
+}
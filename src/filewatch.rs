@@ -0,0 +1,161 @@
+//! Notices other programs writing the files that are open, so `Screen::check_time` runs right
+//! away instead of only on `:checktime`.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use futures::StreamExt;
+use inotify::{EventStream, Inotify, WatchDescriptor, WatchMask};
+
+pub struct FileWatcher {
+    /// `None` if inotify isn't available, then nothing is ever noticed
+    events: Option<EventStream<[u8; 4096]>>,
+    /// Directories rather than the files themselves: a file that's replaced by renaming over it
+    /// (like rim's own saves do) would otherwise be lost track of
+    dirs: HashMap<PathBuf, WatchDescriptor>,
+    names: HashSet<OsString>,
+}
+
+impl FileWatcher {
+    pub fn new() -> Self {
+        Self {
+            events: Inotify::init()
+                .and_then(|inotify| inotify.into_event_stream([0; 4096]))
+                .ok(),
+            dirs: HashMap::new(),
+            names: HashSet::new(),
+        }
+    }
+
+    /// Watches `files`, and stops watching anything else
+    pub fn watch<'a>(&mut self, files: impl IntoIterator<Item = &'a Path>) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        let mut watches = events.watches();
+        let mut dirs = HashSet::new();
+        self.names.clear();
+        for file in files {
+            let Some(name) = file.file_name() else {
+                continue;
+            };
+            self.names.insert(name.to_owned());
+            dirs.insert(match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            });
+        }
+
+        self.dirs.retain(|dir, wd| {
+            let keep = dirs.contains(dir);
+            if !keep {
+                let _ = watches.remove(wd.clone());
+            }
+            keep
+        });
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::CREATE
+            | WatchMask::DELETE;
+        for dir in dirs {
+            if let Entry::Vacant(entry) = self.dirs.entry(dir) {
+                if let Ok(wd) = watches.add(entry.key(), mask) {
+                    entry.insert(wd);
+                }
+            }
+        }
+    }
+
+    /// Waits until something happens to one of the watched files
+    pub async fn changed(&mut self) {
+        if let Some(events) = &mut self.events {
+            while let Some(Ok(event)) = events.next().await {
+                if event.name.is_some_and(|name| self.names.contains(&name)) {
+                    return;
+                }
+            }
+        }
+        // Nothing more is coming
+        futures::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    #[tokio::test]
+    async fn notices_writes() {
+        let dir = temp_dir("filewatch");
+        let file = dir.join("watched");
+        fs::write(&file, "old").unwrap();
+        let mut watcher = FileWatcher::new();
+        watcher.watch([file.as_path()]);
+
+        fs::write(dir.join("other"), "ignored").unwrap();
+        fs::write(&file, "new").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn external_changes() {
+        let dir = temp_dir("external");
+        let file = dir.join("generated.rs");
+        fs::write(&file, "one\ntwo\n").unwrap();
+
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>jA!<Esc>", file.display()));
+        h.keys(":checktime<CR>");
+        assert!(!h.state().screen().prompting());
+
+        fs::write(&file, "one\ntwo\nthree\n").unwrap();
+        h.keys(":w<CR>");
+        assert_eq!(
+            h.message(),
+            "The file has been changed since reading it (add ! to override)"
+        );
+        h.keys(":checktime<CR>");
+        assert!(h
+            .message()
+            .contains("has changed on disk since reading it!"));
+        h.keys("k");
+        assert_eq!(h.text(), "one\ntwo!");
+        // Only asked once per change
+        h.keys(":checktime<CR>");
+        assert!(!h.state().screen().prompting());
+
+        h.keys(":e<CR>");
+        assert_eq!(
+            h.message(),
+            "No write since last change (add ! to override)"
+        );
+        h.keys(":e!<CR>");
+        assert_eq!(h.text(), "one\ntwo\nthree");
+        assert_eq!(h.cursor(), (1, 3));
+
+        h.keys(":set autoread<CR>");
+        fs::write(&file, "four\n").unwrap();
+        h.keys(":checktime<CR>");
+        assert_eq!(h.text(), "four");
+        assert!(!h.state().screen().prompting());
+
+        fs::write(&file, "five\n").unwrap();
+        h.keys("ix<Esc>:checktime<CR>l");
+        assert_eq!(h.text(), "five");
+        h.keys("0ix<Esc>:w<CR>");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "xfive\n");
+
+        fs::remove_file(&file).unwrap();
+        h.keys(":checktime<CR>");
+        assert!(h.message().contains("no longer exists on disk"));
+    }
+}
@@ -0,0 +1,251 @@
+//! Drives a `State` headlessly: feed it keys in `str_to_keys` notation and look at what came out.
+
+use std::{cell::RefCell, fs, path::PathBuf, rc::Rc, time::Duration};
+
+use crossterm::event::KeyEvent;
+
+use crate::{
+    backend::MemoryBackend,
+    buffer::Buffer,
+    clock::FakeClock,
+    job,
+    keys::keyhandler::{handle_key_event, run_due_commands, str_to_keys},
+    loader,
+    state::{Mode, State},
+};
+
+/// A fresh, empty directory for one test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rim-test-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub struct Harness {
+    state: State,
+    backend: Rc<RefCell<MemoryBackend>>,
+    clock: FakeClock,
+    background: loader::Receiver,
+    /// For `:!` commands, which only make progress in `finish_job`
+    runtime: tokio::runtime::Runtime,
+    jobs: job::Receiver,
+}
+
+impl Harness {
+    pub fn new(contents: &str) -> Self {
+        Self::with_size(contents, 24, 80)
+    }
+
+    pub fn with_size(contents: &str, rows: usize, cols: usize) -> Self {
+        let backend = Rc::new(RefCell::new(MemoryBackend::new(rows, cols)));
+        let clock = FakeClock::new();
+        let mut state = State::new(backend.clone(), Rc::new(clock.clone())).unwrap();
+        state
+            .screen_mut()
+            .active_window_mut()
+            .set_buffer(Buffer::from_string(contents.to_owned()))
+            .unwrap();
+        let background = state.screen_mut().take_events();
+        let jobs = state.screen_mut().take_job_events();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        Self {
+            state,
+            backend,
+            clock,
+            background,
+            runtime,
+            jobs,
+        }
+    }
+
+    /// Same path as a real keypress. Pending keys only time out through `advance`.
+    pub fn keys(&mut self, keys: &str) -> &mut Self {
+        let _runtime = self.runtime.enter();
+        for key in str_to_keys(keys) {
+            handle_key_event(KeyEvent::new(key.code, key.modifiers), &mut self.state).unwrap();
+            self.state.screen_mut().lsp_sync().unwrap();
+            self.state.screen_mut().diff_sync().unwrap();
+        }
+        self
+    }
+
+    pub fn advance(&mut self, duration: Duration) -> &mut Self {
+        self.clock.advance(duration);
+        run_due_commands(&mut self.state).unwrap();
+        self
+    }
+
+    /// What the event loop does every `updatetime`, but right away
+    pub fn start_swaps(&mut self) -> &mut Self {
+        let _runtime = self.runtime.enter();
+        self.state.screen_mut().write_swaps().unwrap();
+        self
+    }
+
+    /// `start_swaps` twice, waiting for the writes each time: the second hears how the first
+    /// went, and tries elsewhere if that's what it takes
+    pub fn write_swaps(&mut self) -> &mut Self {
+        for _ in 0..2 {
+            self.start_swaps();
+            while self.state.screen().swaps_busy() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        self
+    }
+
+    /// Waits for every background load and save, handling what they send like the event loop
+    pub fn finish_background(&mut self) -> &mut Self {
+        while self.state.screen().busy() {
+            let event = self.background.blocking_recv().unwrap();
+            self.state.screen_mut().background_event(event).unwrap();
+        }
+        self.state.screen_mut().diff_sync().unwrap();
+        self
+    }
+
+    /// Runs the `:!` command until it's done, handling its output like the event loop
+    pub fn finish_job(&mut self) -> &mut Self {
+        let _runtime = self.runtime.enter();
+        while self.state.screen().job_running() {
+            let event = self.runtime.block_on(self.jobs.recv()).unwrap();
+            self.state.job_event(event).unwrap();
+            self.state.screen_mut().lsp_sync().unwrap();
+        }
+        self
+    }
+
+    /// Handles output from `:terminal` programs and language servers like the event loop, until
+    /// `done` says so
+    pub fn wait_for(&mut self, done: impl Fn(&mut Self) -> bool) -> &mut Self {
+        let runtime = self.runtime.handle().clone();
+        let _runtime = runtime.enter();
+        while !done(self) {
+            let event = self.runtime.block_on(async {
+                tokio::time::timeout(Duration::from_secs(10), self.jobs.recv()).await
+            });
+            let event = event.expect("timed out waiting").unwrap();
+            self.state.job_event(event).unwrap();
+            self.state.screen_mut().lsp_sync().unwrap();
+        }
+        self
+    }
+
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    pub fn text(&self) -> String {
+        self.buffer().lines().join("\n")
+    }
+
+    /// (row, col) in the buffer
+    pub fn cursor(&self) -> (usize, usize) {
+        self.state.screen().active_window().adjusetd_cursor()
+    }
+
+    /// What register `name` holds, lines ending in `\n`
+    pub fn register(&self, name: char) -> Option<String> {
+        self.state.screen().register_text(name)
+    }
+
+    pub fn mode(&self) -> &Mode {
+        self.state.mode()
+    }
+
+    pub fn message(&self) -> &str {
+        self.state.screen().message()
+    }
+
+    /// Every row of the rendered screen, trailing whitespace stripped
+    pub fn screen(&self) -> Vec<String> {
+        self.backend.borrow().lines()
+    }
+
+    pub fn backend(&self) -> Rc<RefCell<MemoryBackend>> {
+        self.backend.clone()
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        self.state.screen().active_window().buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_renders_to_backend() {
+        let mut h = Harness::with_size("", 5, 20);
+        assert!(h.backend().borrow().raw_mode());
+        h.keys("ihi<Esc>");
+        assert_eq!(
+            h.screen(),
+            vec!["   1 hi", "~", "~", "[No Name] [+]    1:2", ""]
+        );
+        assert_eq!(h.backend().borrow().cursor(), (0, 6));
+        assert!(h.backend().borrow().cursor_visible());
+    }
+
+    #[test]
+    fn edit_sequence() {
+        let mut h = Harness::new("one\ntwo\nthree");
+        h.keys("jddA foo<Esc>");
+        assert_eq!(h.text(), "one\nthree foo");
+        assert_eq!(h.cursor(), (1, 8));
+        assert_eq!(h.mode(), &Mode::Normal);
+        h.keys("ggO<Esc>");
+        assert_eq!(h.text(), "\none\nthree foo");
+    }
+
+    #[test]
+    fn pending_keys_time_out() {
+        let mut h = Harness::new("");
+        h.keys("ijk");
+        assert_eq!(h.mode(), &Mode::Normal);
+        assert_eq!(h.text(), "");
+
+        h.keys("ij").advance(Duration::from_secs(1)).keys("k");
+        assert_eq!(h.mode(), &Mode::Insert);
+        assert_eq!(h.text(), "jk");
+    }
+
+    #[test]
+    fn completed_keymaps_take_back_only_typed_keys() {
+        // `<C-x>` went nowhere while `<C-x><C-l>` was pending, so there's nothing of it to
+        // delete once that's what it turns out to be
+        let mut h = Harness::new("one");
+        h.keys("Aqq<C-x><C-l>");
+        assert_eq!(h.text(), "oneqq");
+        h.keys("<Esc>Ajk");
+        assert_eq!((h.text(), h.mode()), ("oneqq".to_string(), &Mode::Normal));
+    }
+
+    #[test]
+    fn command_line() {
+        let mut h = Harness::new("text");
+        h.keys(":w<CR>");
+        assert_eq!(h.mode(), &Mode::Normal);
+        assert_eq!(h.message(), "No filename");
+        assert!(h.state().screen().message_is_error());
+        assert_eq!(h.screen()[23], "No filename");
+
+        h.keys("ZQ");
+        assert!(h.state().should_quit());
+    }
+
+    #[test]
+    fn registers() {
+        let mut h = Harness::new("one\ntwo");
+        assert_eq!(h.register('"'), None);
+        h.keys("\"ayyjdd/ne<CR>");
+        assert_eq!(h.register('a'), Some("one\n".to_owned()));
+        assert_eq!(h.register('"'), Some("two\n".to_owned()));
+        assert_eq!(h.register('/'), Some("ne".to_owned()));
+    }
+}
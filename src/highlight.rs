@@ -0,0 +1,567 @@
+//! Named highlight groups (`LineNr`, `StatusLine`, `Comment`, ...) and the color schemes that
+//! fill them in.
+//!
+//! Color schemes are lists of `:highlight` arguments, one per line, with `"` starting a comment:
+//!
+//! ```text
+//! " comment
+//! Normal fg=#d0d0d0
+//! Comment fg=#808080 attr=italic
+//! link Keyword Statement
+//! ```
+
+use std::{cell::RefCell, collections::HashMap, fs, path::Path, rc::Rc};
+
+use crossterm::style::{Attribute, Attributes, Color, ContentStyle};
+
+/// Shared between `Screen` and every `Window`, like the options
+pub type HighlightsRef = Rc<RefCell<Highlights>>;
+
+const BUNDLED: &[(&str, &str)] = &[
+    ("dark", include_str!("../runtime/colors/dark.rim")),
+    ("light", include_str!("../runtime/colors/light.rim")),
+];
+
+pub const DEFAULT_SCHEME: &str = "dark";
+
+/// Groups that look like another group unless a scheme says otherwise
+const DEFAULT_LINKS: &[(&str, &str)] = &[
+    ("Character", "String"),
+    ("Number", "Constant"),
+    ("Boolean", "Constant"),
+    ("Float", "Number"),
+    ("Function", "Identifier"),
+    ("Conditional", "Statement"),
+    ("Repeat", "Statement"),
+    ("Label", "Statement"),
+    ("Operator", "Statement"),
+    ("Keyword", "Statement"),
+    ("Exception", "Statement"),
+    ("Include", "PreProc"),
+    ("Define", "PreProc"),
+    ("Macro", "PreProc"),
+    ("StorageClass", "Type"),
+    ("Structure", "Type"),
+    ("Typedef", "Type"),
+    ("SpecialChar", "Special"),
+    ("Delimiter", "Special"),
+    ("CursorLineNr", "LineNr"),
+    ("IncSearch", "Search"),
+    ("WildMenu", "PmenuSel"),
+];
+
+/// The 16 basic colors: name, color, and roughly what xterm shows for them
+const BASIC_COLORS: [(&str, Color, (u8, u8, u8)); 16] = [
+    ("Black", Color::Black, (0, 0, 0)),
+    ("DarkRed", Color::DarkRed, (205, 0, 0)),
+    ("DarkGreen", Color::DarkGreen, (0, 205, 0)),
+    ("DarkYellow", Color::DarkYellow, (205, 205, 0)),
+    ("DarkBlue", Color::DarkBlue, (0, 0, 238)),
+    ("DarkMagenta", Color::DarkMagenta, (205, 0, 205)),
+    ("DarkCyan", Color::DarkCyan, (0, 205, 205)),
+    ("Grey", Color::Grey, (229, 229, 229)),
+    ("DarkGrey", Color::DarkGrey, (127, 127, 127)),
+    ("Red", Color::Red, (255, 0, 0)),
+    ("Green", Color::Green, (0, 255, 0)),
+    ("Yellow", Color::Yellow, (255, 255, 0)),
+    ("Blue", Color::Blue, (92, 92, 255)),
+    ("Magenta", Color::Magenta, (255, 0, 255)),
+    ("Cyan", Color::Cyan, (0, 255, 255)),
+    ("White", Color::White, (255, 255, 255)),
+];
+
+const ATTRIBUTES: &[(&str, Attribute)] = &[
+    ("bold", Attribute::Bold),
+    ("dim", Attribute::Dim),
+    ("italic", Attribute::Italic),
+    ("underline", Attribute::Underlined),
+    ("undercurl", Attribute::Undercurled),
+    ("reverse", Attribute::Reverse),
+    ("inverse", Attribute::Reverse),
+    ("strikethrough", Attribute::CrossedOut),
+];
+
+/// How many colors the terminal can show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorDepth {
+    /// `COLORTERM=truecolor` is the only reliable sign of 24-bit support
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        if matches!(var("COLORTERM").as_str(), "truecolor" | "24bit") {
+            Self::TrueColor
+        } else if var("TERM").contains("256") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    /// The closest color the terminal can actually show
+    fn adapt(self, color: Color) -> Color {
+        match (self, color) {
+            (Self::TrueColor, _) => color,
+            (Self::Ansi256, Color::Rgb { r, g, b }) => Color::AnsiValue(rgb_to_ansi256(r, g, b)),
+            (Self::Ansi16, Color::Rgb { .. } | Color::AnsiValue(_)) => {
+                let rgb = color_to_rgb(color);
+                BASIC_COLORS
+                    .iter()
+                    .min_by_key(|(_, _, basic)| distance(rgb, *basic))
+                    .unwrap()
+                    .1
+            }
+            _ => color,
+        }
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+/// Channel values of the 6x6x6 cube in the 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| {
+        (0..6)
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - c as i32).abs())
+            .unwrap()
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (16 + 36 * ri + 6 * gi + bi) as u8;
+    // The grey ramp is finer than the cube's diagonal
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let grey_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let grey = 232 + grey_index;
+    let (cube_rgb, grey_rgb) = (ansi256_to_rgb(cube), ansi256_to_rgb(grey));
+    if distance((r, g, b), grey_rgb) < distance((r, g, b), cube_rgb) {
+        grey
+    } else {
+        cube
+    }
+}
+
+fn ansi256_to_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => BASIC_COLORS[n as usize].2,
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + 10 * (n - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn color_to_rgb(color: Color) -> (u8, u8, u8) {
+    match color {
+        Color::Rgb { r, g, b } => (r, g, b),
+        Color::AnsiValue(n) => ansi256_to_rgb(n),
+        _ => BASIC_COLORS
+            .iter()
+            .find(|(_, basic, _)| *basic == color)
+            .map_or((0, 0, 0), |(_, _, rgb)| *rgb),
+    }
+}
+
+/// `#rrggbb`, `0`-`255`, a basic color name, or `NONE`
+fn parse_color(s: &str) -> Result<Option<Color>, String> {
+    let err = || format!("Invalid color: `{s}`");
+    if s.eq_ignore_ascii_case("NONE") {
+        return Ok(None);
+    }
+    if let Some(hex) = s.strip_prefix('#') {
+        // Slicing by bytes is only safe when they're all ASCII
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(err());
+        }
+        let channel = |i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| err());
+        return Ok(Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        }));
+    }
+    if let Ok(n) = s.parse::<u8>() {
+        return Ok(Some(match n {
+            0..=15 => BASIC_COLORS[n as usize].1,
+            _ => Color::AnsiValue(n),
+        }));
+    }
+    let name = match s.to_ascii_lowercase().as_str() {
+        "gray" => "grey".to_owned(),
+        "darkgray" => "darkgrey".to_owned(),
+        other => other.to_owned(),
+    };
+    BASIC_COLORS
+        .iter()
+        .find(|(basic, _, _)| basic.eq_ignore_ascii_case(&name))
+        .map(|(_, color, _)| Some(*color))
+        .ok_or_else(err)
+}
+
+fn show_color(color: Color) -> String {
+    match color {
+        Color::Rgb { r, g, b } => format!("#{r:02x}{g:02x}{b:02x}"),
+        Color::AnsiValue(n) => n.to_string(),
+        _ => BASIC_COLORS
+            .iter()
+            .find(|(_, basic, _)| *basic == color)
+            .map_or_else(|| format!("{color:?}"), |(name, _, _)| name.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Group {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    attributes: Attributes,
+    /// Takes over entirely while set, like vim
+    link: Option<String>,
+}
+
+impl Group {
+    fn linked(to: &str) -> Self {
+        Self {
+            link: Some(to.to_owned()),
+            ..Self::default()
+        }
+    }
+
+    fn show(&self, name: &str) -> String {
+        if let Some(link) = &self.link {
+            return format!("{name} links to {link}");
+        }
+        let mut parts = vec![name.to_owned()];
+        if let Some(fg) = self.fg {
+            parts.push(format!("fg={}", show_color(fg)));
+        }
+        if let Some(bg) = self.bg {
+            parts.push(format!("bg={}", show_color(bg)));
+        }
+        let attributes: Vec<&str> = ATTRIBUTES
+            .iter()
+            .filter(|(name, attribute)| *name != "inverse" && self.attributes.has(*attribute))
+            .map(|(name, _)| *name)
+            .collect();
+        if !attributes.is_empty() {
+            parts.push(format!("attr={}", attributes.join(",")));
+        }
+        if parts.len() == 1 {
+            parts.push("cleared".to_owned());
+        }
+        parts.join(" ")
+    }
+}
+
+pub struct Highlights {
+    groups: HashMap<String, Group>,
+    depth: ColorDepth,
+    scheme: String,
+}
+
+impl Highlights {
+    pub fn new(depth: ColorDepth) -> Self {
+        let mut highlights = Self {
+            groups: HashMap::new(),
+            depth,
+            scheme: String::new(),
+        };
+        highlights
+            .load_scheme(DEFAULT_SCHEME)
+            .expect("bundled scheme is valid");
+        highlights
+    }
+
+    fn defaults() -> HashMap<String, Group> {
+        DEFAULT_LINKS
+            .iter()
+            .map(|(from, to)| (from.to_string(), Group::linked(to)))
+            .collect()
+    }
+
+    /// How to draw text in group `name`. Whatever the group leaves unset comes from `Normal`.
+    pub fn style(&self, name: &str) -> ContentStyle {
+        let mut group = self.groups.get(name);
+        // Links can form cycles through `:highlight link`
+        for _ in 0..16 {
+            match group.and_then(|g| g.link.as_ref()) {
+                Some(link) => group = self.groups.get(link),
+                None => break,
+            }
+        }
+        let normal = self.groups.get("Normal").cloned().unwrap_or_default();
+        let group = group.cloned().unwrap_or_default();
+        let mut style = ContentStyle::new();
+        style.foreground_color = group.fg.or(normal.fg).map(|c| self.depth.adapt(c));
+        style.background_color = group.bg.or(normal.bg).map(|c| self.depth.adapt(c));
+        style.attributes = group.attributes;
+        style
+    }
+
+    /// Every group there is, for completion
+    pub fn group_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.groups.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn scheme_name(&self) -> &str {
+        &self.scheme
+    }
+
+    /// The schemes `:colorscheme` can load, bundled and the user's
+    pub fn scheme_names() -> Vec<String> {
+        let mut names: Vec<String> = BUNDLED.iter().map(|(name, _)| name.to_string()).collect();
+        let user = std::env::var_os("HOME")
+            .and_then(|home| fs::read_dir(Path::new(&home).join(".config/rim/colors")).ok());
+        for entry in user.into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "rim") {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// `:colorscheme name`. User schemes in `~/.config/rim/colors` win over bundled ones.
+    pub fn load_scheme(&mut self, name: &str) -> Result<(), String> {
+        let user = std::env::var_os("HOME").and_then(|home| {
+            fs::read_to_string(Path::new(&home).join(format!(".config/rim/colors/{name}.rim"))).ok()
+        });
+        let source = match user {
+            Some(source) => source,
+            None => BUNDLED
+                .iter()
+                .find(|(bundled, _)| *bundled == name)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| format!("Cannot find color scheme `{name}`"))?,
+        };
+
+        let old = std::mem::replace(&mut self.groups, Self::defaults());
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('"') {
+                continue;
+            }
+            if let Err(e) = self.command(line) {
+                self.groups = old;
+                return Err(format!("{name}, line {}: {e}", i + 1));
+            }
+        }
+        self.scheme = name.to_owned();
+        Ok(())
+    }
+
+    /// `:highlight` with its arguments, returning anything to show on the message line:
+    /// - nothing lists every group
+    /// - `Group` shows one group
+    /// - `Group fg=#ff0000 bg=NONE attr=bold,italic` changes a group
+    /// - `link From To` makes `From` look like `To`
+    /// - `clear` goes back to the color scheme, `clear Group` empties one group
+    pub fn command(&mut self, args: &str) -> Result<String, String> {
+        let mut words = args.split_whitespace().peekable();
+        let Some(name) = words.next() else {
+            let mut names: Vec<&String> = self.groups.keys().collect();
+            names.sort();
+            return Ok(names
+                .into_iter()
+                .map(|name| self.groups[name].show(name))
+                .collect::<Vec<_>>()
+                .join("  "));
+        };
+        match name {
+            "clear" => {
+                match words.next() {
+                    Some(group) => {
+                        self.groups.insert(group.to_owned(), Group::default());
+                    }
+                    None => {
+                        let scheme = self.scheme.clone();
+                        self.load_scheme(&scheme)?;
+                    }
+                }
+                return Ok(String::new());
+            }
+            "link" => {
+                let (Some(from), Some(to), None) = (words.next(), words.next(), words.next())
+                else {
+                    return Err("Usage: `highlight link From To`".to_string());
+                };
+                self.groups.insert(from.to_owned(), Group::linked(to));
+                return Ok(String::new());
+            }
+            _ => {}
+        }
+        if words.peek().is_none() {
+            return self
+                .groups
+                .get(name)
+                .map(|group| group.show(name))
+                .ok_or_else(|| format!("Highlight group not found: `{name}`"));
+        }
+
+        let mut group = self.groups.get(name).cloned().unwrap_or_default();
+        group.link = None;
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("Missing equal sign: `{word}`"))?;
+            match key {
+                "fg" | "guifg" | "ctermfg" => group.fg = parse_color(value)?,
+                "bg" | "guibg" | "ctermbg" => group.bg = parse_color(value)?,
+                "attr" | "gui" | "cterm" => {
+                    group.attributes = Attributes::default();
+                    for attribute in value.split(',') {
+                        if attribute.eq_ignore_ascii_case("NONE") {
+                            continue;
+                        }
+                        let (_, attribute) = ATTRIBUTES
+                            .iter()
+                            .find(|(known, _)| *known == attribute)
+                            .ok_or_else(|| format!("Illegal attribute: `{attribute}`"))?;
+                        group.attributes.set(*attribute);
+                    }
+                }
+                _ => return Err(format!("Illegal argument: `{word}`")),
+            }
+        }
+        self.groups.insert(name.to_owned(), group);
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[test]
+    fn set_link_and_show() {
+        let mut h = Highlights::new(ColorDepth::TrueColor);
+        h.command("Normal fg=NONE bg=NONE").unwrap();
+        h.command("Foo fg=#ff8000 attr=bold,underline").unwrap();
+        assert_eq!(
+            h.command("Foo"),
+            Ok("Foo fg=#ff8000 attr=bold,underline".to_string())
+        );
+        let style = h.style("Foo");
+        assert_eq!(
+            style.foreground_color,
+            Some(Color::Rgb {
+                r: 255,
+                g: 128,
+                b: 0
+            })
+        );
+        assert!(style.attributes.has(Attribute::Bold));
+        for bad in ["#aébcd", "#+1+2+3", "#12345"] {
+            assert_eq!(
+                h.command(&format!("Foo fg={bad}")),
+                Err(format!("Invalid color: `{bad}`"))
+            );
+        }
+
+        h.command("link Bar Foo").unwrap();
+        assert_eq!(h.style("Bar"), style);
+        assert_eq!(h.command("Bar"), Ok("Bar links to Foo".to_string()));
+        h.command("Bar bg=Blue").unwrap();
+        assert_eq!(h.style("Bar").foreground_color, None);
+        assert_eq!(h.style("Bar").background_color, Some(Color::Blue));
+
+        // Cycles don't hang
+        h.command("link A B").unwrap();
+        h.command("link B A").unwrap();
+        h.style("A");
+
+        assert!(h.command("Foo fg=#12").is_err());
+        assert!(h.command("Foo attr=sparkly").is_err());
+        assert!(h.command("Foo fg").is_err());
+        assert!(h.command("Missing").is_err());
+    }
+
+    #[test]
+    fn normal_fills_in() {
+        let mut h = Highlights::new(ColorDepth::TrueColor);
+        h.command("Normal fg=White bg=Black").unwrap();
+        h.command("Foo fg=Red").unwrap();
+        assert_eq!(h.style("Foo").foreground_color, Some(Color::Red));
+        assert_eq!(h.style("Foo").background_color, Some(Color::Black));
+        assert_eq!(h.style("Unknown").foreground_color, Some(Color::White));
+    }
+
+    #[test]
+    fn color_depth_fallback() {
+        let orange = Color::Rgb {
+            r: 255,
+            g: 135,
+            b: 0,
+        };
+        assert_eq!(ColorDepth::TrueColor.adapt(orange), orange);
+        assert_eq!(ColorDepth::Ansi256.adapt(orange), Color::AnsiValue(208));
+        assert_eq!(ColorDepth::Ansi16.adapt(orange), Color::DarkYellow);
+        let grey = Color::Rgb {
+            r: 128,
+            g: 128,
+            b: 128,
+        };
+        assert_eq!(ColorDepth::Ansi256.adapt(grey), Color::AnsiValue(244));
+        assert_eq!(ColorDepth::Ansi16.adapt(grey), Color::DarkGrey);
+        assert_eq!(ColorDepth::Ansi16.adapt(Color::Red), Color::Red);
+    }
+
+    #[test]
+    fn schemes() {
+        let mut h = Highlights::new(ColorDepth::TrueColor);
+        assert_eq!(h.scheme_name(), "dark");
+        let dark = h.style("Normal");
+        h.load_scheme("light").unwrap();
+        assert_eq!(h.scheme_name(), "light");
+        assert_ne!(h.style("Normal"), dark);
+        h.command("Comment fg=Red").unwrap();
+        h.command("clear").unwrap();
+        assert_ne!(h.style("Comment").foreground_color, Some(Color::Red));
+        assert!(h.load_scheme("nope").is_err());
+        assert_eq!(h.scheme_name(), "light");
+    }
+
+    #[test]
+    fn highlight_groups() {
+        let mut h = Harness::with_size("a\nb", 5, 20);
+        let style = |h: &Harness, row, col| h.backend().borrow().cell(row, col).style;
+        h.keys(":hi LineNr fg=Red bg=Blue attr=bold<CR>");
+        assert_eq!(style(&h, 1, 3).foreground_color, Some(Color::Red));
+        assert_eq!(style(&h, 1, 3).background_color, Some(Color::Blue));
+        h.keys(":hi LineNr<CR>");
+        assert_eq!(h.message(), "LineNr fg=Red bg=Blue attr=bold");
+        h.keys(":hi StatusLine bg=Green<CR>");
+        assert_eq!(style(&h, 3, 0).background_color, Some(Color::Green));
+
+        h.keys(":hi ErrorMsg fg=Magenta<CR>:hi Nope<CR>");
+        assert!(h.state().screen().message_is_error());
+        assert_eq!(style(&h, 4, 0).foreground_color, Some(Color::Magenta));
+
+        h.keys(":colo light<CR>:colo<CR>");
+        assert_eq!(h.message(), "light");
+        assert_ne!(style(&h, 1, 3).foreground_color, Some(Color::Red));
+        h.keys(":colorscheme missing<CR>");
+        assert_eq!(h.message(), "Cannot find color scheme `missing`");
+    }
+}
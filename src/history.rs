@@ -0,0 +1,185 @@
+//! What was typed on the command line, `:` commands and `/` searches apart, kept between runs
+//! in the state file with one entry per line after the character saying which list it's from.
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct History {
+    commands: Vec<String>,
+    searches: Vec<String>,
+}
+
+impl History {
+    /// Oldest first. `kind` is `:` or `/`.
+    pub fn list(&self, kind: char) -> &[String] {
+        match kind {
+            '/' => &self.searches,
+            _ => &self.commands,
+        }
+    }
+
+    fn list_mut(&mut self, kind: char) -> &mut Vec<String> {
+        match kind {
+            '/' => &mut self.searches,
+            _ => &mut self.commands,
+        }
+    }
+
+    /// Makes `entry` the newest, without repeating it, keeping no more than `max`
+    pub fn add(&mut self, kind: char, entry: &str, max: usize) {
+        if entry.trim().is_empty() {
+            return;
+        }
+        let list = self.list_mut(kind);
+        list.retain(|old| old != entry);
+        list.push(entry.to_owned());
+        let extra = list.len().saturating_sub(max);
+        list.drain(..extra);
+    }
+
+    /// The newest entry before `from` that starts with `prefix`, for `<Up>`
+    pub fn older(&self, kind: char, prefix: &str, from: usize) -> Option<usize> {
+        let list = self.list(kind);
+        (0..from.min(list.len()))
+            .rev()
+            .find(|&i| list[i].starts_with(prefix))
+    }
+
+    /// The oldest entry after `from` that starts with `prefix`, for `<Down>`
+    pub fn newer(&self, kind: char, prefix: &str, from: usize) -> Option<usize> {
+        let list = self.list(kind);
+        (from + 1..list.len()).find(|&i| list[i].starts_with(prefix))
+    }
+
+    /// Puts the older entries from another run before these, as long as they aren't here
+    /// already, keeping no more than `max`
+    pub fn merge(&mut self, older: &History, max: usize) {
+        for kind in [':', '/'] {
+            let list = self.list_mut(kind);
+            let mut merged: Vec<String> = older
+                .list(kind)
+                .iter()
+                .filter(|entry| !list.contains(entry))
+                .cloned()
+                .collect();
+            merged.append(list);
+            let extra = merged.len().saturating_sub(max);
+            merged.drain(..extra);
+            *list = merged;
+        }
+    }
+
+    /// A line of the state file, which is an entry if it starts with `:` or `/`. Returns
+    /// whether it was one.
+    pub fn read_line(&mut self, line: &str) -> bool {
+        let mut chars = line.chars();
+        match chars.next() {
+            Some(kind @ (':' | '/')) => {
+                self.list_mut(kind).push(chars.as_str().to_owned());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// One line per entry, for the state file
+    pub fn write_lines(&self, contents: &mut String) {
+        for (kind, list) in [(':', &self.commands), ('/', &self.searches)] {
+            for entry in list {
+                contents.push(kind);
+                contents.push_str(entry);
+                contents.push('\n');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+    use crate::state::Mode;
+
+    #[test]
+    fn adds_and_filters() {
+        let mut history = History::default();
+        for entry in ["set wrap", "w", "set nowrap", "w", ""] {
+            history.add(':', entry, 3);
+        }
+        history.add('/', "fn", 3);
+        assert_eq!(history.list(':'), ["set wrap", "set nowrap", "w"]);
+        assert_eq!(history.list('/'), ["fn"]);
+        assert_eq!(history.older(':', "set", 3), Some(1));
+        assert_eq!(history.older(':', "set", 1), Some(0));
+        assert_eq!(history.older(':', "set", 0), None);
+        assert_eq!(history.newer(':', "set", 0), Some(1));
+        assert_eq!(history.newer(':', "set", 1), None);
+
+        history.add(':', "e", 3);
+        assert_eq!(history.list(':'), ["set nowrap", "w", "e"]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut history = History::default();
+        history.add(':', "s/a:b/", 50);
+        history.add('/', "^fn ", 50);
+        let mut contents = String::new();
+        history.write_lines(&mut contents);
+        assert_eq!(contents, ":s/a:b/\n/^fn \n");
+        let mut read = History::default();
+        assert!(!read.read_line("'A 1 0 /a"));
+        for line in contents.lines() {
+            assert!(read.read_line(line));
+        }
+        assert_eq!(read, history);
+
+        let mut newer = History::default();
+        newer.add(':', "w", 2);
+        newer.add(':', "s/a:b/", 2);
+        newer.merge(&history, 2);
+        assert_eq!(newer.list(':'), ["w", "s/a:b/"]);
+        assert_eq!(newer.list('/'), ["^fn "]);
+    }
+
+    #[test]
+    fn command_line_editing() {
+        let mut h = Harness::new("one two\nthree");
+        h.keys(":st<Left>e<End> wrap<CR>:2<CR>");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys(":<Up>");
+        assert_eq!(h.message(), ":2");
+        h.keys("<Up><Up>");
+        assert_eq!(h.message(), ":set wrap");
+        h.keys("<Down><Down>");
+        assert_eq!(h.message(), ":");
+        h.keys("<Esc>:s<Up>");
+        assert_eq!(h.message(), ":set wrap");
+        h.keys("<C-w>");
+        assert_eq!(h.message(), ":set ");
+        h.keys("<C-w>abc def<Left><Left><C-u>");
+        assert_eq!(h.message(), ":ef");
+        h.keys("<BS><End><BS><BS>");
+        assert_eq!((h.message(), h.mode()), (":", &Mode::Command));
+        h.keys("<BS>");
+        assert_eq!((h.message(), h.mode()), ("", &Mode::Normal));
+
+        h.keys("gg:<C-r><C-w> <C-r>:<C-r>x");
+        assert_eq!(h.message(), ":one 2");
+        h.keys("<Esc>/thr<CR>");
+        assert_eq!((h.cursor(), h.message()), ((1, 0), "/thr"));
+        h.keys("gg/<Up><CR>");
+        assert_eq!(h.cursor(), (1, 0));
+
+        h.keys("q:");
+        assert_eq!(h.text(), "set wrap\n2\n");
+        assert_eq!(h.cursor(), (2, 0));
+        assert!(h.screen().iter().any(|row| row.contains("[Command Line]")));
+        h.keys("k<CR>");
+        assert_eq!((h.text().as_str(), h.cursor()), ("one two\nthree", (1, 0)));
+        assert!(!h.screen().iter().any(|row| row.contains("[Command Line]")));
+        h.keys("q/:q<CR>");
+        assert_eq!(h.text(), "one two\nthree");
+        assert!(!h.state().should_quit());
+        // Back to the full height
+        assert!(h.screen()[22].starts_with("[No Name]"));
+    }
+}
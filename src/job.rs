@@ -0,0 +1,249 @@
+//! External commands on the tokio runtime, for `:!cmd`, filters like `:%!sort` and `:r !cmd`.
+//! Their output comes back through a channel as it's printed, so the event loop keeps going and
+//! `<C-c>` can stop them.
+
+use std::process::Stdio;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, Command},
+    select,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::loader;
+
+pub type Sender = mpsc::UnboundedSender<Event>;
+pub type Receiver = mpsc::UnboundedReceiver<Event>;
+
+#[derive(Debug)]
+pub enum Event {
+    /// Some of the command's stdout, or with `stderr` its stderr
+    Output {
+        id: usize,
+        bytes: Vec<u8>,
+        stderr: bool,
+    },
+    /// After all of its output. `Ok` has the exit code, `None` when a signal ended it.
+    Exited {
+        id: usize,
+        result: Result<Option<i32>, String>,
+    },
+}
+
+impl Event {
+    pub fn id(&self) -> usize {
+        match self {
+            Event::Output { id, .. } | Event::Exited { id, .. } => *id,
+        }
+    }
+}
+
+/// A command that's still running. Dropping it kills the command, and nothing more is heard
+/// from it.
+pub struct Job {
+    pub id: usize,
+    _kill: oneshot::Sender<()>,
+}
+
+/// Starts `cmd` with `shell -c`, with `input` on its stdin. Without input an `interactive`
+/// command gets the terminal's stdin, and `<C-c>` there stops it. Has to be called on the
+/// runtime.
+pub fn spawn(
+    shell: &str,
+    cmd: &str,
+    input: Option<Vec<u8>>,
+    interactive: bool,
+    events: &Sender,
+) -> Result<Job, String> {
+    let stdin = match (&input, interactive) {
+        (Some(_), _) => Stdio::piped(),
+        (None, true) => Stdio::inherit(),
+        (None, false) => Stdio::null(),
+    };
+    let mut child = Command::new(shell)
+        .arg("-c")
+        .arg(cmd)
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Cannot execute {shell}: {e}"))?;
+    let id = loader::next_id();
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        tokio::spawn(async move {
+            // A command that doesn't read all of it is fine
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    let stdout = forward(id, child.stdout.take(), false, events.clone());
+    let stderr = forward(id, child.stderr.take(), true, events.clone());
+    Ok(watch(id, child, vec![stdout, stderr], interactive, events))
+}
+
+/// Waits for `child` to exit, then for `outputs` to be done forwarding what it printed, then
+/// sends `Exited`. The returned `Job` kills it when dropped.
+pub fn watch(
+    id: usize,
+    mut child: Child,
+    outputs: Vec<JoinHandle<()>>,
+    interactive: bool,
+    events: &Sender,
+) -> Job {
+    let (kill, mut killed) = oneshot::channel();
+    let events = events.clone();
+    tokio::spawn(async move {
+        let status = select! {
+            status = child.wait() => status,
+            _ = &mut killed => {
+                let _ = child.kill().await;
+                return;
+            }
+            _ = interrupted(interactive) => {
+                let _ = child.kill().await;
+                child.wait().await
+            }
+        };
+        for output in outputs {
+            let _ = output.await;
+        }
+        let result = status
+            .map(|status| status.code())
+            .map_err(|e| e.to_string());
+        let _ = events.send(Event::Exited { id, result });
+    });
+    Job { id, _kill: kill }
+}
+
+/// `<C-c>` while the terminal is handed over, which is a signal rather than a key then
+async fn interrupted(interactive: bool) {
+    if interactive && tokio::signal::ctrl_c().await.is_ok() {
+        return;
+    }
+    std::future::pending().await
+}
+
+/// Sends everything read from `pipe` as `Output`, until it's closed
+pub fn forward(
+    id: usize,
+    pipe: Option<impl AsyncRead + Unpin + Send + 'static>,
+    stderr: bool,
+    events: Sender,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut buf = vec![0; 8192];
+        while let Ok(n @ 1..) = pipe.read(&mut buf).await {
+            let bytes = buf[..n].to_vec();
+            if events.send(Event::Output { id, bytes, stderr }).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    /// stdout, stderr and the exit code
+    async fn run(cmd: &str, input: Option<&str>) -> (String, String, Option<i32>) {
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        let input = input.map(|input| input.as_bytes().to_vec());
+        let job = spawn("sh", cmd, input, false, &events).unwrap();
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        loop {
+            match receiver.recv().await.unwrap() {
+                Event::Output {
+                    id,
+                    bytes,
+                    stderr: err,
+                } => {
+                    assert_eq!(id, job.id);
+                    let out = if err { &mut stderr } else { &mut stdout };
+                    out.push_str(&String::from_utf8(bytes).unwrap());
+                }
+                Event::Exited { result, .. } => return (stdout, stderr, result.unwrap()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_commands() {
+        assert_eq!(
+            run("sort", Some("b\na\n")).await,
+            ("a\nb\n".into(), "".into(), Some(0))
+        );
+        assert_eq!(
+            run("echo out; echo oops >&2; exit 3", None).await,
+            ("out\n".into(), "oops\n".into(), Some(3))
+        );
+        let (events, _receiver) = mpsc::unbounded_channel();
+        assert!(spawn("/nonexistent", "true", None, false, &events).is_err());
+    }
+
+    #[test]
+    fn shell_commands() {
+        let mut h = Harness::new("c\nb\na\nd");
+        h.keys(":%!sort<CR>");
+        assert_eq!(h.message(), "Running `sort`, <C-c> to stop it");
+        h.keys("x").finish_job();
+        assert_eq!(h.text(), "a\nb\nc\nd");
+        assert_eq!(h.message(), "4 lines filtered through `sort`");
+
+        h.keys("j!j");
+        assert_eq!(h.message(), ":.,.+1!");
+        h.keys("tr a-z A-Z<CR>").finish_job();
+        assert_eq!(h.text(), "a\nB\nC\nd");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys("!!exit 1<CR>").finish_job();
+        assert_eq!(h.message(), "shell returned 1");
+        assert_eq!(h.text(), "a\nB\nC\nd");
+        h.keys(":2,3!grep -v C<CR>").finish_job();
+        assert_eq!(h.text(), "a\nB\nd");
+
+        h.keys(":!echo %<CR>");
+        assert_eq!(h.message(), "Empty file name for '%'");
+        let dir = temp_dir("shell_commands");
+        let file = dir.join("file.txt");
+        h.keys(&format!(":w {}<CR>", file.display()));
+        h.keys(":!echo %; echo \\%<CR>");
+        assert!(!h.backend().borrow().raw_mode());
+        h.finish_job();
+        assert!(h.backend().borrow().raw_mode());
+        let echoed = h.backend().borrow().echoed();
+        let name = file.display().to_string();
+        assert_eq!(echoed, format!(":!echo {name}; echo %\n{name}\n%\n"));
+        assert_eq!(
+            h.screen()[21..],
+            [
+                name.as_str(),
+                "%",
+                "Press ENTER or type command to continue"
+            ]
+        );
+        h.keys(":");
+        assert_eq!(h.message(), ":");
+        h.keys("<Esc>:!seq 30<CR>").finish_job();
+        assert_eq!(h.screen()[0], "1");
+        assert_eq!(h.screen()[23], "-- More --");
+        h.keys(" ");
+        assert_eq!(h.screen()[0], "8");
+        assert_eq!(h.screen()[22], "30");
+        h.keys("q");
+        assert!(!h.state().screen().paging());
+
+        h.keys(":!sleep 10<CR>");
+        assert!(h.state().screen().job_running());
+        h.keys("dd<C-c>");
+        assert!(!h.state().screen().job_running());
+        assert!(h.backend().borrow().raw_mode());
+        assert_eq!(h.message(), "Interrupted");
+        assert_eq!(h.text(), "a\nB\nd");
+    }
+}
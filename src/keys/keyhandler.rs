@@ -1,13 +1,19 @@
-use std::{future::poll_fn, task::Poll, time::Duration};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
     Result,
 };
 use futures::StreamExt;
-use tokio::select;
+use tokio::{select, time::sleep_until};
 
-use crate::state::{Command, Mode, State};
+use crate::{
+    filewatch::FileWatcher,
+    state::{Command, Mode, State},
+};
 
 use super::trie::{FetchResult, Trie};
 
@@ -58,6 +64,26 @@ pub fn str_to_keys(s: &str) -> Vec<Key> {
                     code: KeyCode::Esc,
                     modifiers: KeyModifiers::empty(),
                 },
+                "Tab" => Key {
+                    code: KeyCode::Tab,
+                    modifiers: KeyModifiers::empty(),
+                },
+                "S-Tab" => Key {
+                    code: KeyCode::BackTab,
+                    modifiers: KeyModifiers::SHIFT,
+                },
+                "Up" | "Down" | "Left" | "Right" | "Home" | "End" => Key {
+                    code: match substr {
+                        "Up" => KeyCode::Up,
+                        "Down" => KeyCode::Down,
+                        "Left" => KeyCode::Left,
+                        "Right" => KeyCode::Right,
+                        "Home" => KeyCode::Home,
+                        _ => KeyCode::End,
+                    },
+                    modifiers: KeyModifiers::empty(),
+                },
+                "lt" => Key::char('<'),
                 _ => {
                     assert!(substr.starts_with("C-"));
                     Key {
@@ -84,44 +110,181 @@ pub fn new_keymap_trie(maps: Vec<(&str, KeymapFn)>) -> KeymapTrie {
     trie
 }
 
-fn async_read_event(_cx: &mut std::task::Context<'_>) -> Poll<Result<Event>> {
-    // TODO: this is weird?? why do we have to block??
-    match event::poll(Duration::from_secs(1)) {
-        Ok(true) => Poll::Ready(event::read()),
-        _ => Poll::Pending,
-    }
-}
-
 pub async fn watch(state: &mut State) -> Result<()> {
-    let mut read_event = poll_fn(async_read_event);
-    loop {
+    let mut events = None;
+    let mut next_swap = Instant::now() + state.screen().updatetime();
+    let mut watcher = FileWatcher::new();
+    let mut background = state.screen_mut().take_events();
+    let mut jobs = state.screen_mut().take_job_events();
+    while !state.should_quit() {
+        // Not even read while a `:!` command has the terminal, the keys are for it
+        let suspended = state.screen().suspended();
+        if suspended {
+            events = None;
+        } else if events.is_none() {
+            events = Some(EventStream::new());
+        }
+        watcher.watch(state.screen().files().iter().map(PathBuf::as_path));
+        state.screen_mut().lsp_sync()?;
+        state.screen_mut().diff_sync()?;
+        // Nothing queued: wake up eventually anyway, it's cheap
+        let deadline = state
+            .next_deadline()
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(60));
         select! {
-            event = &mut read_event => {
+            event = next_event(&mut events) => {
                 // TODO: other events like screen resize
-                if let Ok(Event::Key(key_event)) = event {
+                if let Some(Ok(Event::Key(key_event))) = event {
                     handle_key_event(key_event, state)?;
                 }
             }
-            cmd = state.queue().next() => {
-                if let Some(Ok(cmd)) = cmd {
-                    dispatch_cmd(state, cmd)?;
-                }
+            _ = sleep_until(deadline.into()) => {
+                run_due_commands(state)?;
+            }
+            Some(event) = background.recv(), if !suspended => {
+                state.screen_mut().background_event(event)?;
             }
+            Some(event) = jobs.recv() => {
+                state.job_event(event)?;
+            }
+            _ = watcher.changed(), if !suspended => {
+                state.screen_mut().check_time()?;
+            }
+            _ = sleep_until(next_swap.into()) => {
+                state.screen_mut().write_swaps()?;
+                next_swap = Instant::now() + state.screen().updatetime();
+            }
+        }
+    }
+    state.screen_mut().finish()?;
+    // Language servers get a moment to exit by themselves before they're killed
+    state.screen_mut().lsp_shutdown();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while state.screen().lsp_running() {
+        select! {
+            Some(event) = jobs.recv() => state.screen_mut().lsp_exit_event(event),
+            _ = sleep_until(deadline.into()) => break,
         }
     }
+    Ok(())
+}
+
+/// Every key goes to the program, unless it's part of a terminal mode keymap
+fn terminal_key(key_event: KeyEvent, state: &mut State) -> Result<()> {
+    let mut key = Key {
+        code: key_event.code,
+        modifiers: key_event.modifiers.difference(KeyModifiers::SHIFT),
+    };
+    // Some terminals send `<C-\>` as `<C-4>`
+    if key.code == KeyCode::Char('4') && key.modifiers == KeyModifiers::CONTROL {
+        key.code = KeyCode::Char('\\');
+    }
+    state.append_current_key_event(key);
+    let keymaps = state.keymaps();
+    match keymaps[&Mode::Terminal].fetch(state.current_key_event().into()) {
+        FetchResult::Some(f) => {
+            state.clear_current_key_event();
+            f(state)
+        }
+        FetchResult::MaybeIncomplete => Ok(()),
+        FetchResult::None => {
+            let keys: Vec<KeyEvent> = state
+                .current_key_event()
+                .iter()
+                .map(|key| KeyEvent::new(key.code, key.modifiers))
+                .collect();
+            state.clear_current_key_event();
+            state.screen_mut().terminal_keys(&keys)
+        }
+    }
+}
+
+async fn next_event(events: &mut Option<EventStream>) -> Option<Result<Event>> {
+    match events {
+        Some(events) => events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) fn run_due_commands(state: &mut State) -> Result<()> {
+    for cmd in state.take_due_commands() {
+        dispatch_cmd(state, cmd)?;
+    }
+    Ok(())
 }
 
 fn dispatch_cmd(state: &mut State, cmd: Command) -> Result<()> {
     match cmd {
-        // TODO: something is not right here but i dont know what :\
         Command::ClearCurrentKeyEvent => state.clear_current_key_event(),
     }
 
     Ok(())
 }
 
-fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
-    if key_event.modifiers.intersects(KeyModifiers::CONTROL) {
+pub(crate) fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
+    state.screen_mut().close_float()?;
+    if !key_event.modifiers.intersects(KeyModifiers::CONTROL) {
+        // Typing on keeps whatever completion put in
+        state.screen_mut().end_popup()?;
+    }
+    if state.screen().prompting() {
+        if let KeyCode::Char(c) = key_event.code {
+            if state.screen_mut().answer_prompt(c)? {
+                state.finish()?;
+            }
+        }
+        return Ok(());
+    }
+    // Control keys are only ever keymaps, never typed
+    let ctrl = key_event.modifiers.intersects(KeyModifiers::CONTROL);
+    if state.screen().job_running() {
+        if let (true, KeyCode::Char('c')) = (ctrl, key_event.code) {
+            state.screen_mut().interrupt()?;
+        }
+        return Ok(());
+    }
+    if state.screen().paging() {
+        let key = match key_event.code {
+            KeyCode::Enter => '\r',
+            KeyCode::Char(c) => c,
+            _ => '\x1b',
+        };
+        if state.screen_mut().page(key)? {
+            state.enter_command_mode(':')?;
+        }
+        return Ok(());
+    }
+    if let Mode::Terminal = state.mode() {
+        return terminal_key(key_event, state);
+    }
+    if let Mode::Normal = state.mode() {
+        // Whatever came before, typed or from a filter that finished since, is one undo step
+        state.screen_mut().seal_undo();
+    }
+    if state.screen().register_pending() {
+        // The register name after `<C-r>`
+        let register = match key_event.code {
+            KeyCode::Char('w') if ctrl => None,
+            KeyCode::Char(c) if !ctrl => Some(c),
+            _ => Some('\0'),
+        };
+        return state.screen_mut().command_insert_register(register);
+    }
+    if state.screen().mark_pending() {
+        // The mark name after `m`, `'` or `` ` ``
+        let name = match key_event.code {
+            KeyCode::Char(c) if !ctrl => Some(c),
+            _ => None,
+        };
+        return state.screen_mut().mark_key(name);
+    }
+    if state.screen().register_name_pending() {
+        // The register name after `"`
+        let name = match key_event.code {
+            KeyCode::Char(c) if !ctrl => Some(c),
+            _ => None,
+        };
+        state.screen_mut().register_name_key(name);
         return Ok(());
     }
     match key_event.code {
@@ -145,6 +308,7 @@ fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
         }
         KeyCode::Up => {
             if let Mode::Command = state.mode() {
+                state.screen_mut().command_history(-1)?;
             } else {
                 state.screen_mut().active_window_mut().move_cursor_row(-1)?;
             }
@@ -152,23 +316,35 @@ fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
         }
         KeyCode::Down => {
             if let Mode::Command = state.mode() {
+                state.screen_mut().command_history(1)?;
             } else {
                 state.screen_mut().active_window_mut().move_cursor_row(1)?;
             }
             return Ok(());
         }
+        KeyCode::Home | KeyCode::End => {
+            if let Mode::Command = state.mode() {
+                state
+                    .screen_mut()
+                    .command_move_cursor_to_end(key_event.code == KeyCode::End)?;
+            }
+            return Ok(());
+        }
+        KeyCode::BackTab => {
+            if let Mode::Command = state.mode() {
+                state.complete(-1)?;
+            }
+            return Ok(());
+        }
         KeyCode::Tab => {}
         KeyCode::Char(_) => {}
         KeyCode::Esc => {}
 
         // I don't think I care about any of these
-        KeyCode::Home
-        | KeyCode::BackTab
-        | KeyCode::Delete
+        KeyCode::Delete
         | KeyCode::Insert
         | KeyCode::F(_)
         | KeyCode::Null
-        | KeyCode::End
         | KeyCode::PageUp
         | KeyCode::PageDown
         | KeyCode::CapsLock
@@ -183,7 +359,7 @@ fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
             return Ok(());
         }
     };
-    if let Mode::Insert = state.mode() {
+    if let (false, Mode::Insert) = (ctrl, state.mode()) {
         match key_event.code {
             KeyCode::Tab => {
                 for _ in 0..4 {
@@ -195,11 +371,20 @@ fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
             KeyCode::Char(c) => state.screen_mut().active_window_mut().type_char(c)?,
             _ => {}
         }
-    } else if let Mode::Command = state.mode() {
+    } else if let (false, Mode::Command) = (ctrl, state.mode()) {
         match key_event.code {
-            KeyCode::Tab => {}
+            KeyCode::Tab => state.complete(1)?,
+            KeyCode::Backspace if state.screen().get_curr_command().is_empty() => {
+                // Nothing left to take back, so the command line goes too
+                state.clear_current_key_event();
+                return state.leave_command_mode();
+            }
             KeyCode::Backspace => state.screen_mut().command_delete_char()?,
-            KeyCode::Enter => state.enter_command()?,
+            KeyCode::Enter => {
+                // Don't let the Enter go on to whatever mode the command left us in
+                state.clear_current_key_event();
+                return state.enter_command();
+            }
             KeyCode::Char(c) => state.screen_mut().command_type_char(c)?,
             _ => {}
         }
@@ -221,11 +406,15 @@ fn handle_key_event(key_event: KeyEvent, state: &mut State) -> Result<()> {
                 //       after you clear it
                 //     - just like a timeout but I'm worried about race conditions
                 //     - and display the char differently so it's clear it's pending completion
-                let len = state.current_key_event().len();
-                state
-                    .screen_mut()
-                    .active_window_mut()
-                    .delete_chars(len - i)?;
+                // Only keys that were typed into the buffer need to be taken back out
+                let typed = state.current_key_event()[i..]
+                    .iter()
+                    .filter(|key| {
+                        matches!(key.code, KeyCode::Char(_))
+                            && !key.modifiers.intersects(KeyModifiers::CONTROL)
+                    })
+                    .count();
+                state.screen_mut().active_window_mut().delete_chars(typed)?;
             }
             f(state)?;
             state.clear_current_key_event();
@@ -0,0 +1,362 @@
+//! Reads and writes big files (`asyncsize` and up) in the background, so that opening or saving
+//! a few gigabytes doesn't freeze the editor. The first chunk of a file is read right away so
+//! there's something to look at, and the rest streams in as `Event`s the event loop hands to
+//! the buffer with the matching id. A buffer that's gone by then just never hears about it.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{
+    encoding::{self, Decoded, Encoding, FileFormat},
+    options::Options,
+    save,
+};
+
+/// How much is read at a time
+const CHUNK: usize = 1 << 20;
+
+pub type Sender = mpsc::UnboundedSender<Event>;
+pub type Receiver = mpsc::UnboundedReceiver<Event>;
+
+pub enum Event {
+    /// More complete lines of a file being loaded, `read` bytes into it
+    Lines {
+        id: usize,
+        lines: Vec<String>,
+        read: u64,
+    },
+    Loaded {
+        id: usize,
+        result: Result<Tail, String>,
+    },
+    Saved {
+        id: usize,
+        result: Result<(), String>,
+    },
+}
+
+impl Event {
+    /// The buffer it's for
+    pub fn id(&self) -> usize {
+        match self {
+            Event::Lines { id, .. } | Event::Loaded { id, .. } | Event::Saved { id, .. } => *id,
+        }
+    }
+}
+
+/// How a file finished loading
+pub enum Tail {
+    /// Every complete line was sent. `last` is the rest of a file that didn't end in a line
+    /// ending.
+    Ended { last: Option<String> },
+    /// It wasn't UTF-8 after all, so it was read again as a whole. Replaces any lines sent so
+    /// far.
+    Whole(Decoded),
+}
+
+/// Tells buffers apart in `Event`s
+pub fn next_id() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A file that's still streaming in. Dropping it stops the reading.
+pub struct Loading {
+    cancel: Arc<AtomicBool>,
+    read: u64,
+    total: u64,
+    /// Lines received since the first chunk
+    received: usize,
+}
+
+impl Loading {
+    /// How far along it is, in percent
+    pub fn progress(&self) -> u64 {
+        (self.read * 100 / self.total.max(1)).min(100)
+    }
+
+    /// Notes the arrival of `lines` more lines, returning whether they're the first
+    pub fn received(&mut self, lines: usize, read: u64) -> bool {
+        let first = self.received == 0;
+        self.received += lines;
+        self.read = read;
+        first
+    }
+}
+
+impl Drop for Loading {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// What the first chunk of a file said about it
+pub struct Start {
+    /// Can be empty when not even one line fit, or it needs decoding as a whole
+    pub lines: Vec<String>,
+    pub fileformat: FileFormat,
+    pub bom: bool,
+    pub loading: Loading,
+}
+
+/// Reads the first chunk of `file` now and leaves the rest to the background. Only UTF-8 can be
+/// streamed; anything else `fileencodings` asks for is decoded in one go once it's all read.
+pub fn start(
+    id: usize,
+    path: &Path,
+    mut file: File,
+    total: u64,
+    fileencodings: &str,
+    events: &Sender,
+) -> io::Result<Start> {
+    let mut first = Vec::with_capacity(CHUNK);
+    (&mut file).take(CHUNK as u64).read_to_end(&mut first)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut loading = Loading {
+        cancel: cancel.clone(),
+        read: first.len() as u64,
+        total,
+        received: 0,
+    };
+    let (path, fileencodings, events) =
+        (path.to_path_buf(), fileencodings.to_owned(), events.clone());
+
+    let bom = first.starts_with(Encoding::Utf8.bom());
+    let body = &first[if bom { Encoding::Utf8.bom().len() } else { 0 }..];
+    let ucs_bom = fileencodings.split(',').any(|name| name == "ucs-bom");
+    let utf8_first = fileencodings
+        .split(',')
+        .find(|&name| name != "ucs-bom")
+        .and_then(Encoding::from_name)
+        == Some(Encoding::Utf8);
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => Some(text),
+        // Cut off in the middle of a character, which the next chunk finishes
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&body[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    };
+    let text = text.filter(|_| if bom { ucs_bom } else { utf8_first });
+    let Some(text) = text else {
+        loading.read = 0;
+        spawn(move || {
+            let result = whole(&path, &fileencodings);
+            let _ = events.send(Event::Loaded { id, result });
+        });
+        return Ok(Start {
+            lines: Vec::new(),
+            fileformat: FileFormat::Unix,
+            bom: false,
+            loading,
+        });
+    };
+
+    let mut lines = Lines {
+        fileformat: FileFormat::detect(text),
+        pending: Vec::new(),
+    };
+    let fileformat = lines.fileformat;
+    let first_lines = lines.split(body).unwrap_or_default();
+    spawn(move || stream(id, file, lines, &path, &fileencodings, &cancel, &events));
+    Ok(Start {
+        lines: first_lines,
+        fileformat,
+        bom,
+        loading,
+    })
+}
+
+/// Splits a stream of bytes into lines
+struct Lines {
+    fileformat: FileFormat,
+    /// An incomplete line, kept until the rest of it is read
+    pending: Vec<u8>,
+}
+
+impl Lines {
+    /// The lines `bytes` completes, `None` if they aren't UTF-8
+    fn split(&mut self, bytes: &[u8]) -> Option<Vec<String>> {
+        self.pending.extend_from_slice(bytes);
+        let terminator = match self.fileformat {
+            FileFormat::Mac => '\r',
+            FileFormat::Unix | FileFormat::Dos => '\n',
+        };
+        let Some(end) = self.pending.iter().rposition(|&b| b == terminator as u8) else {
+            return Some(Vec::new());
+        };
+        let rest = self.pending.split_off(end + 1);
+        let complete = String::from_utf8(std::mem::replace(&mut self.pending, rest)).ok()?;
+        Some(
+            complete
+                .split_terminator(terminator)
+                .map(|line| match self.fileformat {
+                    FileFormat::Dos => line.strip_suffix('\r').unwrap_or(line).to_owned(),
+                    _ => line.to_owned(),
+                })
+                .collect(),
+        )
+    }
+
+    /// What's left at the end of the file
+    fn finish(self) -> Option<Tail> {
+        let last = if self.pending.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8(self.pending).ok()?)
+        };
+        Some(Tail::Ended { last })
+    }
+}
+
+/// Reads the rest of `file`, a chunk at a time, until it's done or `cancel` is set
+fn stream(
+    id: usize,
+    mut file: File,
+    mut lines: Lines,
+    path: &Path,
+    fileencodings: &str,
+    cancel: &AtomicBool,
+    events: &Sender,
+) {
+    let mut chunk = vec![0; CHUNK];
+    let result = loop {
+        if cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        let n = match file.read(&mut chunk) {
+            Ok(0) => {
+                break lines
+                    .finish()
+                    .map_or_else(|| whole(path, fileencodings), Ok)
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e.to_string()),
+        };
+        let read = file.stream_position().unwrap_or_default();
+        match lines.split(&chunk[..n]) {
+            Some(lines) => {
+                if events.send(Event::Lines { id, lines, read }).is_err() {
+                    return;
+                }
+            }
+            None => break whole(path, fileencodings),
+        }
+    };
+    let _ = events.send(Event::Loaded { id, result });
+}
+
+/// The fallback when streaming doesn't work out
+fn whole(path: &Path, fileencodings: &str) -> Result<Tail, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    Ok(Tail::Whole(encoding::decode(&bytes, fileencodings)))
+}
+
+/// Writes `contents` to `path` in the background, see `save::save`
+pub fn save(
+    id: usize,
+    path: PathBuf,
+    contents: Vec<u8>,
+    options: Options,
+    force: bool,
+    events: &Sender,
+) {
+    let events = events.clone();
+    spawn(move || {
+        let result = save::save(&path, &contents, &options, force).map_err(|e| e.to_string());
+        let _ = events.send(Event::Saved { id, result });
+    });
+}
+
+/// On tokio's blocking pool, or a thread of its own without a runtime (in tests)
+fn spawn(f: impl FnOnce() + Send + 'static) {
+    match Handle::try_current() {
+        Ok(handle) => drop(handle.spawn_blocking(f)),
+        Err(_) => drop(thread::spawn(f)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    #[test]
+    fn splits_across_chunks() {
+        let mut lines = Lines {
+            fileformat: FileFormat::Dos,
+            pending: Vec::new(),
+        };
+        assert_eq!(lines.split(b"one\r\ntw").unwrap(), ["one"]);
+        assert_eq!(lines.split(b"o\r").unwrap(), Vec::<String>::new());
+        // `é` split in two
+        assert_eq!(lines.split(b"\n\r\n\xc3").unwrap(), ["two", ""]);
+        assert_eq!(lines.split(b"\xa9").unwrap(), Vec::<String>::new());
+        assert!(matches!(
+            lines.finish(),
+            Some(Tail::Ended { last: Some(last) }) if last == "\u{e9}"
+        ));
+
+        let mut lines = Lines {
+            fileformat: FileFormat::Unix,
+            pending: Vec::new(),
+        };
+        assert_eq!(lines.split(b"a\nb\n").unwrap(), ["a", "b"]);
+        assert!(lines.pending.is_empty());
+        assert!(lines.split(b"caf\xe9\n").is_none());
+    }
+
+    #[test]
+    fn background_loads_and_saves() {
+        let dir = temp_dir("background");
+        let big = dir.join("big.txt");
+        // A few chunks' worth
+        let text: String = (0..200_000).map(|i| format!("line {i}\n")).collect();
+        fs::write(&big, &text).unwrap();
+        let mut h = Harness::new("");
+        h.keys(&format!(":set asyncsize=1000<CR>:e {}<CR>", big.display()));
+        let first = h.text().lines().count();
+        assert!(first > 1 && first < 200_000);
+        assert!(h.message().ends_with(" [loading]"));
+        assert!(h.screen()[22].contains("[loading "));
+        h.finish_background();
+        assert_eq!(h.text() + "\n", text);
+        assert!(h.message().ends_with(&format!(" 200000L, {}B", text.len())));
+        assert!(!h.screen()[22].contains("[loading"));
+
+        h.keys("ggIx<Esc>:w<CR>");
+        assert!(h.message().ends_with(" writing..."));
+        assert!(h.screen()[22].contains("[writing]"));
+        h.finish_background();
+        assert_eq!(h.message(), format!("\"{}\" written", big.display()));
+        assert!(!h.screen()[22].contains("[+]"));
+        assert!(fs::read_to_string(&big)
+            .unwrap()
+            .starts_with("xline 0\nline 1\n"));
+
+        // <C-c> keeps what's there so far
+        h.keys(&format!(":e {}<CR><C-c>", big.display()));
+        assert!(h.message().contains(" [Interrupted] "));
+        assert!(h.text().lines().count() < 200_000);
+        assert!(h.screen()[22].contains("[RO]"));
+        h.keys(":w<CR>");
+        assert!(h.message().contains("'readonly' option is set"));
+
+        // Not UTF-8, so it's decoded once it's all there
+        let latin1 = dir.join("latin1.txt");
+        fs::write(&latin1, b"caf\xe9\n").unwrap();
+        h.keys(&format!(":e {}<CR>", latin1.display()));
+        h.finish_background();
+        assert_eq!(h.text(), "caf\u{e9}");
+        assert!(h.message().ends_with(" [latin1] 1L, 5B"));
+    }
+}
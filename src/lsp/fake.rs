@@ -0,0 +1,375 @@
+//! A language server just smart enough for tests, built as the `fake_lsp` example for the
+//! tests in `lsp` to start. It keeps each document's text up to date, says every `bad` is an
+//! error, and knows words: where `fn word` defines one, where else it's used, how to rename it
+//! and which ones start with what's typed. Positions are in UTF-16, like most servers'. It
+//! wants edits the way its `TextDocumentSyncKind` says, and only that way.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use serde_json::{json, Value};
+
+use super::rpc::{self, Decoder};
+
+pub fn serve(sync: u64) {
+    let mut server = Server {
+        sync,
+        ..Server::default()
+    };
+    let mut decoder = Decoder::default();
+    let mut stdin = io::stdin().lock();
+    let mut buf = vec![0; 8192];
+    while let Ok(n @ 1..) = stdin.read(&mut buf) {
+        for message in decoder.feed(&buf[..n]) {
+            if !server.handle(&message) {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    sync: u64,
+    documents: HashMap<String, Vec<String>>,
+    next_request: i64,
+}
+
+fn send(message: Value) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(&rpc::encode(&message));
+    let _ = stdout.flush();
+}
+
+fn position(row: usize, col: usize) -> Value {
+    json!({"line": row, "character": col})
+}
+
+fn range(row: usize, start: usize, end: usize) -> Value {
+    json!({"start": position(row, start), "end": position(row, end)})
+}
+
+fn text_edit(row: usize, start: usize, end: usize, text: &str) -> Value {
+    json!({"range": range(row, start, end), "newText": text})
+}
+
+fn utf16(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/// The byte offset in `line` of UTF-16 offset `col`
+fn byte(line: &str, col: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= col {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Every whole `word` in `lines`: row and UTF-16 start
+fn occurrences(lines: &[String], word: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    for (row, line) in lines.iter().enumerate() {
+        for (i, _) in line.match_indices(word) {
+            let before = line[..i].chars().next_back().is_some_and(is_word);
+            let after = line[i + word.len()..].chars().next().is_some_and(is_word);
+            if !before && !after {
+                found.push((row, utf16(&line[..i])));
+            }
+        }
+    }
+    found
+}
+
+impl Server {
+    /// Returns whether to keep going
+    fn handle(&mut self, message: &Value) -> bool {
+        let method = message.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_owned();
+        let Some(id) = message.get("id").cloned() else {
+            match method {
+                "textDocument/didOpen" => {
+                    let text = params.get("textDocument").and_then(|d| d.get("text"));
+                    let text = text.and_then(Value::as_str).unwrap_or("");
+                    self.documents.insert(uri.clone(), split(text));
+                    self.publish(&uri);
+                }
+                "textDocument/didChange" if self.sync == 0 => {
+                    send(json!({
+                        "jsonrpc": "2.0",
+                        "method": "window/showMessage",
+                        "params": {"type": 1, "message": "Unwanted didChange"},
+                    }));
+                }
+                "textDocument/didChange" => {
+                    let changes = params.get("contentChanges").and_then(Value::as_array);
+                    for change in changes.into_iter().flatten() {
+                        self.change(&uri, change);
+                    }
+                    self.publish(&uri);
+                }
+                "exit" => return false,
+                _ => {}
+            }
+            return true;
+        };
+        if method.is_empty() {
+            // What `workspace/applyEdit` got back
+            return true;
+        }
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {"positionEncoding": "utf-16", "textDocumentSync": self.sync},
+            }),
+            "textDocument/definition" => self.definition(&uri, &params),
+            "textDocument/references" => self.references(&uri, &params),
+            "textDocument/hover" => match self.word_at(&uri, &params) {
+                Some((word, ..)) => json!({
+                    "contents": {"kind": "markdown", "value": format!("`{word}`\n\nA word.")},
+                }),
+                None => Value::Null,
+            },
+            "textDocument/rename" => {
+                let name = params.get("newName").and_then(Value::as_str).unwrap_or("");
+                self.rename(&uri, &params, name)
+            }
+            "textDocument/codeAction" => self.code_actions(&uri, &params),
+            "workspace/executeCommand" => {
+                self.execute(&params);
+                Value::Null
+            }
+            "textDocument/formatting" => {
+                let lines = self.documents.get(&uri).cloned().unwrap_or_default();
+                let edits: Vec<Value> = lines
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, line)| line.ends_with(' '))
+                    .map(|(row, line)| {
+                        let trimmed = utf16(line.trim_end());
+                        text_edit(row, trimmed, utf16(line), "")
+                    })
+                    .collect();
+                edits.into()
+            }
+            "textDocument/completion" => self.completion(&uri, &params),
+            _ => Value::Null,
+        };
+        send(json!({"jsonrpc": "2.0", "id": id, "result": result}));
+        true
+    }
+
+    fn change(&mut self, uri: &str, change: &Value) {
+        let Some(lines) = self.documents.get_mut(uri) else {
+            return;
+        };
+        let text = change.get("text").and_then(Value::as_str).unwrap_or("");
+        let Some(range) = change.get("range") else {
+            *lines = split(text);
+            return;
+        };
+        if self.sync != 2 {
+            return;
+        }
+        let at = |name: &str, lines: &[String]| {
+            let position = range.get(name)?;
+            let row = position.get("line")?.as_u64()? as usize;
+            let col = position.get("character")?.as_u64()? as usize;
+            let offset: usize = lines[..row.min(lines.len())]
+                .iter()
+                .map(|line| line.len() + 1)
+                .sum();
+            Some(offset + lines.get(row).map_or(0, |line| byte(line, col)))
+        };
+        let (Some(start), Some(end)) = (at("start", lines), at("end", lines)) else {
+            return;
+        };
+        let mut whole: String = lines.iter().flat_map(|l| [l.as_str(), "\n"]).collect();
+        let end = end.min(whole.len());
+        whole.replace_range(start.min(end)..end, text);
+        *lines = split(&whole);
+    }
+
+    fn publish(&self, uri: &str) {
+        let lines = self.documents.get(uri).cloned().unwrap_or_default();
+        let diagnostics: Vec<Value> = occurrences(&lines, "bad")
+            .into_iter()
+            .map(|(row, col)| {
+                json!({
+                    "range": range(row, col, col + 3),
+                    "severity": 1,
+                    "message": "bad word",
+                })
+            })
+            .collect();
+        send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        }));
+    }
+
+    /// The word at the position in `params`: the word, its row and where it starts in UTF-16
+    fn word_at(&self, uri: &str, params: &Value) -> Option<(String, usize, usize)> {
+        let position = params.get("position")?;
+        let row = position.get("line")?.as_u64()? as usize;
+        let line = self.documents.get(uri)?.get(row)?;
+        let at = byte(line, position.get("character")?.as_u64()? as usize);
+        let start = line[..at]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_word(c))
+            .last()
+            .map_or(at, |(i, _)| i);
+        let end = line[at..]
+            .find(|c| !is_word(c))
+            .map_or(line.len(), |i| at + i);
+        (start < end).then(|| (line[start..end].to_owned(), row, utf16(&line[..start])))
+    }
+
+    fn location(uri: &str, row: usize, col: usize, len: usize) -> Value {
+        json!({"uri": uri, "range": range(row, col, col + len)})
+    }
+
+    fn definition(&self, uri: &str, params: &Value) -> Value {
+        let Some((word, ..)) = self.word_at(uri, params) else {
+            return Value::Null;
+        };
+        let lines = &self.documents[uri];
+        let definition = lines.iter().enumerate().find_map(|(row, line)| {
+            let i = line.find(&format!("fn {word}"))?;
+            Some((row, utf16(&line[..i + 3])))
+        });
+        match definition {
+            Some((row, col)) => Self::location(uri, row, col, utf16(&word)),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, uri: &str, params: &Value) -> Value {
+        let Some((word, ..)) = self.word_at(uri, params) else {
+            return Value::Null;
+        };
+        occurrences(&self.documents[uri], &word)
+            .into_iter()
+            .map(|(row, col)| Self::location(uri, row, col, utf16(&word)))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// A `WorkspaceEdit` putting `name` in place of the word and everywhere else it's used
+    fn rename(&self, uri: &str, params: &Value, name: &str) -> Value {
+        let Some((word, ..)) = self.word_at(uri, params) else {
+            return Value::Null;
+        };
+        let edits: Vec<Value> = occurrences(&self.documents[uri], &word)
+            .into_iter()
+            .map(|(row, col)| text_edit(row, col, col + utf16(&word), name))
+            .collect();
+        json!({"changes": {uri: edits}})
+    }
+
+    /// Capitalizing the word, as an edit, and shouting its line, as a command that comes back
+    /// with `workspace/applyEdit`
+    fn code_actions(&self, uri: &str, params: &Value) -> Value {
+        let mut position = params.get("range").and_then(|r| r.get("start")).cloned();
+        let params = json!({
+            "textDocument": {"uri": uri},
+            "position": position.take().unwrap_or(Value::Null),
+        });
+        let Some((word, row, col)) = self.word_at(uri, &params) else {
+            return json!([]);
+        };
+        let capitalized: String = word
+            .chars()
+            .take(1)
+            .flat_map(char::to_uppercase)
+            .chain(word.chars().skip(1))
+            .collect();
+        let edit = text_edit(row, col, col + utf16(&word), &capitalized);
+        json!([
+            {
+                "title": format!("Capitalize `{word}`"),
+                "kind": "quickfix",
+                "edit": {"changes": {uri: [edit]}},
+            },
+            {
+                "title": "Shout",
+                "command": {"title": "Shout", "command": "shout", "arguments": [uri, row]},
+            },
+        ])
+    }
+
+    fn execute(&mut self, params: &Value) {
+        let arguments = params.get("arguments").and_then(Value::as_array);
+        let (Some(uri), Some(row)) = (
+            arguments.and_then(|a| a.first()).and_then(Value::as_str),
+            arguments.and_then(|a| a.get(1)).and_then(Value::as_u64),
+        ) else {
+            return;
+        };
+        let row = row as usize;
+        let Some(line) = self.documents.get(uri).and_then(|lines| lines.get(row)) else {
+            return;
+        };
+        let edit = text_edit(row, 0, utf16(line), &line.to_uppercase());
+        self.next_request += 1;
+        send(json!({
+            "jsonrpc": "2.0",
+            "id": self.next_request,
+            "method": "workspace/applyEdit",
+            "params": {"edit": {"changes": {uri: [edit]}}},
+        }));
+    }
+
+    /// Words of the document that start with what's before the cursor
+    fn completion(&self, uri: &str, params: &Value) -> Value {
+        let Some(position) = params.get("position") else {
+            return Value::Null;
+        };
+        let row = position.get("line").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let col = position.get("character").and_then(Value::as_u64);
+        let lines = self.documents.get(uri).cloned().unwrap_or_default();
+        let line = lines.get(row).map_or("", String::as_str);
+        let before = &line[..byte(line, col.unwrap_or(0) as usize)];
+        let prefix_start = before
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_word(c))
+            .last()
+            .map_or(before.len(), |(i, _)| i);
+        let prefix = &before[prefix_start..];
+        let mut words: Vec<&str> = lines
+            .iter()
+            .flat_map(|line| line.split(|c| !is_word(c)))
+            .filter(|word| word.starts_with(prefix) && *word != prefix)
+            .collect();
+        words.sort();
+        words.dedup();
+        let items: Vec<Value> = words
+            .into_iter()
+            .map(|word| json!({"label": word}))
+            .collect();
+        json!({"isIncomplete": false, "items": items})
+    }
+}
+
+/// A document's lines: a final line ending doesn't start another one
+fn split(text: &str) -> Vec<String> {
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    text.split('\n').map(String::from).collect()
+}
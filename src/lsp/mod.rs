@@ -0,0 +1,929 @@
+//! Language servers: for each filetype with a command in `lspservers`, a server started on the
+//! runtime like any other job and spoken to in JSON-RPC over its stdin and stdout. Buffers are
+//! kept in sync with it as they're edited, and what it says comes back to `Screen` as
+//! `Message`s.
+
+mod rpc;
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, process::Command, sync::mpsc};
+
+use crate::{
+    buffer::Change,
+    job::{self, Job},
+    loader,
+};
+
+/// The command for `filetype` in `lspservers`, which looks like
+/// `rust:rust-analyzer,python:pylsp` with `\,` for a comma in a command
+pub fn server(lspservers: &str, filetype: &str) -> Option<String> {
+    let mut entries = vec![String::new()];
+    let mut chars = lspservers.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => entries.last_mut()?.extend(chars.next()),
+            ',' => entries.push(String::new()),
+            c => entries.last_mut()?.push(c),
+        }
+    }
+    entries.into_iter().find_map(|entry| {
+        let (name, cmd) = entry.split_once(':')?;
+        (name.trim() == filetype).then(|| cmd.trim().to_owned())
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl Severity {
+    fn from_lsp(value: Option<&Value>) -> Self {
+        match value.and_then(Value::as_u64) {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _ => Severity::Error,
+        }
+    }
+
+    fn to_lsp(self) -> usize {
+        self as usize + 1
+    }
+
+    /// What the sign column shows on its line
+    pub fn sign(self) -> &'static str {
+        match self {
+            Severity::Error => "E>",
+            Severity::Warning => "W>",
+            Severity::Information => "I>",
+            Severity::Hint => "H>",
+        }
+    }
+
+    /// The highlight group for its sign and message
+    pub fn group(self) -> &'static str {
+        match self {
+            Severity::Error => "DiagnosticError",
+            Severity::Warning => "DiagnosticWarn",
+            Severity::Information => "DiagnosticInfo",
+            Severity::Hint => "DiagnosticHint",
+        }
+    }
+}
+
+/// How a server wants to hear about edits: its `TextDocumentSyncKind`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sync {
+    None,
+    /// The whole text every time
+    Full,
+    /// Just the lines that changed
+    Incremental,
+}
+
+impl Sync {
+    /// From `textDocumentSync`, which is a kind or has one as its `change`. Without one the
+    /// server hears nothing.
+    fn from_lsp(value: Option<&Value>) -> Self {
+        let kind = value.and_then(|value| value.get("change").unwrap_or(value).as_u64());
+        match kind {
+            Some(1) => Sync::Full,
+            Some(2) => Sync::Incremental,
+            _ => Sync::None,
+        }
+    }
+}
+
+/// What a server had to say about a place in a buffer, which moves with the lines around it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub row: usize,
+    /// A byte offset into the line
+    pub col: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// A `TextEdit`, with its positions made into (row, byte offset)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub text: String,
+}
+
+/// `edits` made to `lines`, all of them as of before any: lines `first` to `last` and what
+/// takes their place. `None` when that changes nothing.
+pub fn apply(lines: &[String], mut edits: Vec<Edit>) -> Option<(usize, usize, Vec<String>)> {
+    let first = edits.iter().map(|edit| edit.start.0).min()?;
+    let first = first.min(lines.len() - 1);
+    let last = edits.iter().map(|edit| edit.end.0).max()?;
+    let last = last.clamp(first, lines.len() - 1);
+    // Every line with its line ending, as the server sees the text
+    let mut text: String = lines[first..=last]
+        .iter()
+        .flat_map(|line| [line.as_str(), "\n"])
+        .collect();
+    let offset = |(row, col): (usize, usize)| {
+        if row > last {
+            return text.len();
+        }
+        let start: usize = lines[first..row.max(first)]
+            .iter()
+            .map(|line| line.len() + 1)
+            .sum();
+        start + col.min(lines[row.max(first)].len())
+    };
+    let mut spliced: Vec<(usize, usize, String)> = edits
+        .drain(..)
+        .map(|edit| (offset(edit.start), offset(edit.end), edit.text))
+        .collect();
+    // From the back, so every offset still means what it did
+    spliced.sort_by_key(|&(start, end, _)| (start, end));
+    for (start, end, new) in spliced.into_iter().rev() {
+        let end = end.max(start);
+        if text.is_char_boundary(start) && text.is_char_boundary(end) {
+            text.replace_range(start..end, &new);
+        }
+    }
+    if text.ends_with('\n') {
+        text.pop();
+    }
+    let new: Vec<String> = text.split('\n').map(String::from).collect();
+    (new != lines[first..=last]).then_some((first, last, new))
+}
+
+/// The `file://` URI of `path`, made absolute
+pub fn uri(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    });
+    let mut uri = String::from("file://");
+    for &byte in path.to_string_lossy().as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
+
+/// The path of a `file://` URI
+pub fn path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (encoded[i], hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+/// What an answer from the server was to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Initialize,
+    Shutdown,
+    Definition,
+    References,
+    Hover,
+    Rename,
+    /// With `Some(i)`, action `i` is taken rather than all of them shown
+    CodeActions {
+        apply: Option<usize>,
+    },
+    ExecuteCommand,
+    /// For the buffer with the id
+    Formatting {
+        buffer: usize,
+    },
+    /// For the buffer with the id, to replace what's from byte `start` of line `row` up to the
+    /// cursor
+    Completion {
+        buffer: usize,
+        row: usize,
+        start: usize,
+    },
+}
+
+/// Something from the server for `Screen` to deal with
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Response {
+        request: Request,
+        result: Result<Value, String>,
+    },
+    /// `textDocument/publishDiagnostics`, as they came
+    Diagnostics {
+        uri: String,
+        diagnostics: Vec<Value>,
+    },
+    /// A `WorkspaceEdit` the server asked for with `workspace/applyEdit`, and was told was done
+    ApplyEdit(Value),
+    /// `window/showMessage`, and whether it's an error
+    Show(String, bool),
+}
+
+/// A buffer the server has been told about with `didOpen`
+struct Document {
+    buffer: usize,
+    uri: String,
+    version: i64,
+}
+
+pub struct Client {
+    pub filetype: String,
+    id: usize,
+    _job: Job,
+    /// To the task that writes the server's stdin
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+    decoder: rpc::Decoder,
+    next_request: i64,
+    pending: HashMap<i64, Request>,
+    /// Messages held back until the server has answered `initialize`
+    queued: Option<Vec<Value>>,
+    /// Whether positions count bytes, rather than UTF-16 code units like the protocol has it
+    /// unless both sides agree otherwise
+    utf8: bool,
+    sync: Sync,
+    documents: Vec<Document>,
+}
+
+impl Client {
+    /// Starts `cmd` with `shell -c` and asks it to `initialize`. Has to be called on the
+    /// runtime.
+    pub fn spawn(
+        shell: &str,
+        cmd: &str,
+        filetype: &str,
+        events: &job::Sender,
+    ) -> Result<Self, String> {
+        let mut child = Command::new(shell)
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Where servers log, which there's nowhere to show
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Cannot execute {shell}: {e}"))?;
+        let id = loader::next_id();
+        let (writer, mut written) = mpsc::unbounded_channel::<Vec<u8>>();
+        let stdin = child.stdin.take();
+        tokio::spawn(async move {
+            let Some(mut stdin) = stdin else {
+                return;
+            };
+            while let Some(bytes) = written.recv().await {
+                // A server that's gone is heard about as exited
+                if stdin.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let output = job::forward(id, child.stdout.take(), false, events.clone());
+        let mut client = Self {
+            filetype: filetype.to_owned(),
+            id,
+            _job: job::watch(id, child, vec![output], false, events),
+            writer,
+            decoder: rpc::Decoder::default(),
+            next_request: 1,
+            pending: HashMap::new(),
+            queued: None,
+            utf8: false,
+            sync: Sync::None,
+            documents: Vec::new(),
+        };
+        let root = env::current_dir().map_or(Value::Null, |dir| uri(&dir).into());
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": root,
+            "capabilities": capabilities(),
+        });
+        client.request("initialize", params, Request::Initialize);
+        client.queued = Some(Vec::new());
+        Ok(client)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn write(&mut self, message: Value) {
+        match &mut self.queued {
+            Some(queued) => queued.push(message),
+            // A server that's gone is heard about as exited
+            None => {
+                let _ = self.writer.send(rpc::encode(&message));
+            }
+        }
+    }
+
+    pub fn request(&mut self, method: &str, params: Value, request: Request) {
+        let id = self.next_request;
+        self.next_request += 1;
+        self.pending.insert(id, request);
+        let mut message = json!({"jsonrpc": "2.0", "id": id, "method": method});
+        // `Value::Null` is no params at all, like `shutdown` and `exit` have
+        if !params.is_null() {
+            message["params"] = params;
+        }
+        self.write(message);
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        let mut message = json!({"jsonrpc": "2.0", "method": method});
+        if !params.is_null() {
+            message["params"] = params;
+        }
+        self.write(message);
+    }
+
+    /// The answer to a request from the server, which doesn't wait on anything
+    fn respond(&mut self, id: Value, result: Result<Value, (i64, &str)>) {
+        let message = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        let _ = self.writer.send(rpc::encode(&message));
+    }
+
+    /// What the server printed, which it answers itself where it can
+    pub fn output(&mut self, bytes: &[u8]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for message in self.decoder.feed(bytes) {
+            let method = message.get("method").and_then(Value::as_str);
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            match (method, message.get("id").cloned()) {
+                (Some(method), Some(id)) => {
+                    let result = match method {
+                        "workspace/applyEdit" => {
+                            messages.extend(params.get("edit").cloned().map(Message::ApplyEdit));
+                            Ok(json!({"applied": true}))
+                        }
+                        "workspace/configuration" => {
+                            let items = params.get("items").and_then(Value::as_array);
+                            Ok(Value::Array(vec![Value::Null; items.map_or(0, Vec::len)]))
+                        }
+                        "window/workDoneProgress/create"
+                        | "client/registerCapability"
+                        | "client/unregisterCapability" => Ok(Value::Null),
+                        _ => Err((-32601, "Method not found")),
+                    };
+                    self.respond(id, result);
+                }
+                (Some("textDocument/publishDiagnostics"), None) => {
+                    let uri = params.get("uri").and_then(Value::as_str);
+                    let diagnostics = params.get("diagnostics").and_then(Value::as_array);
+                    if let (Some(uri), Some(diagnostics)) = (uri, diagnostics) {
+                        messages.push(Message::Diagnostics {
+                            uri: uri.to_owned(),
+                            diagnostics: diagnostics.clone(),
+                        });
+                    }
+                }
+                (Some("window/showMessage"), None) => {
+                    let text = params.get("message").and_then(Value::as_str);
+                    let error = params.get("type").and_then(Value::as_u64) == Some(1);
+                    messages.extend(text.map(|text| Message::Show(text.to_owned(), error)));
+                }
+                (Some(_), None) => {}
+                (None, Some(id)) => {
+                    let Some(request) = id.as_i64().and_then(|id| self.pending.remove(&id)) else {
+                        continue;
+                    };
+                    let result = match message.get("error") {
+                        Some(error) => Err(error
+                            .get("message")
+                            .and_then(Value::as_str)
+                            .unwrap_or("Request failed")
+                            .to_owned()),
+                        None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+                    };
+                    match request {
+                        Request::Initialize => self.initialized(result.unwrap_or(Value::Null)),
+                        Request::Shutdown => self.notify("exit", Value::Null),
+                        _ => messages.push(Message::Response { request, result }),
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        messages
+    }
+
+    /// The server answered `initialize`: everything held back can go
+    fn initialized(&mut self, result: Value) {
+        let capabilities = result.get("capabilities");
+        let encoding = capabilities.and_then(|capabilities| capabilities.get("positionEncoding"));
+        self.utf8 = encoding.and_then(Value::as_str) == Some("utf-8");
+        self.sync = Sync::from_lsp(capabilities.and_then(|c| c.get("textDocumentSync")));
+        let queued = self.queued.take().unwrap_or_default();
+        self.notify("initialized", json!({}));
+        for message in queued {
+            self.write(message);
+        }
+    }
+
+    /// Characters as the server counts them, up to byte `col` of `line`
+    fn character(&self, line: &str, col: usize) -> usize {
+        let mut col = col.min(line.len());
+        while !line.is_char_boundary(col) {
+            col -= 1;
+        }
+        if self.utf8 {
+            return col;
+        }
+        line[..col].encode_utf16().count()
+    }
+
+    /// The byte offset into `line` of what the server counts as `character`
+    fn col(&self, line: &str, character: usize) -> usize {
+        if self.utf8 {
+            let mut col = character.min(line.len());
+            while !line.is_char_boundary(col) {
+                col -= 1;
+            }
+            return col;
+        }
+        let mut units = 0;
+        for (i, c) in line.char_indices() {
+            units += c.len_utf16();
+            // Inside a surrogate pair counts as the character it splits
+            if units > character {
+                return i;
+            }
+        }
+        line.len()
+    }
+
+    /// A `Position` for byte `col` of line `row`
+    pub fn position(&self, lines: &[String], (row, col): (usize, usize)) -> Value {
+        let line = lines.get(row).map_or("", String::as_str);
+        json!({"line": row, "character": self.character(line, col)})
+    }
+
+    /// Where a `Position` is in `lines`, as (row, byte offset)
+    pub fn location(&self, lines: &[String], position: &Value) -> Option<(usize, usize)> {
+        let row = position.get("line")?.as_u64()? as usize;
+        let character = position.get("character")?.as_u64()? as usize;
+        Some(match lines.get(row) {
+            Some(line) => (row, self.col(line, character)),
+            // Past the end is the end
+            None => (row, 0),
+        })
+    }
+
+    /// A `TextEdit` made into an `Edit` on `lines`
+    pub fn edit(&self, lines: &[String], edit: &Value) -> Option<Edit> {
+        let range = edit.get("range")?;
+        Some(Edit {
+            start: self.location(lines, range.get("start")?)?,
+            end: self.location(lines, range.get("end")?)?,
+            text: edit.get("newText")?.as_str()?.to_owned(),
+        })
+    }
+
+    /// A `Diagnostic` made into one on `lines`
+    pub fn diagnostic(&self, lines: &[String], diagnostic: &Value) -> Option<Diagnostic> {
+        let start = diagnostic.get("range")?.get("start")?;
+        let (row, col) = self.location(lines, start)?;
+        Some(Diagnostic {
+            row: row.min(lines.len().saturating_sub(1)),
+            col,
+            severity: Severity::from_lsp(diagnostic.get("severity")),
+            message: diagnostic.get("message")?.as_str()?.to_owned(),
+        })
+    }
+
+    /// `diagnostic` back as the server had it, for `codeAction`'s context
+    pub fn lsp_diagnostic(&self, lines: &[String], diagnostic: &Diagnostic) -> Value {
+        let position = self.position(lines, (diagnostic.row, diagnostic.col));
+        json!({
+            "range": {"start": position, "end": position},
+            "severity": diagnostic.severity.to_lsp(),
+            "message": diagnostic.message,
+        })
+    }
+
+    /// Whether the buffer with id `buffer` is open in the server
+    pub fn has_document(&self, buffer: usize) -> bool {
+        self.documents
+            .iter()
+            .any(|document| document.buffer == buffer)
+    }
+
+    /// `textDocument/didOpen` for the buffer with id `buffer`. Returns whether it was, which it
+    /// isn't when another buffer has the same file open already.
+    pub fn open(&mut self, buffer: usize, path: &Path, lines: &[String]) -> bool {
+        let uri = uri(path);
+        if self.documents.iter().any(|document| document.uri == uri) {
+            return false;
+        }
+        let item = json!({
+            "uri": uri,
+            "languageId": self.filetype,
+            "version": 0,
+            "text": text(lines),
+        });
+        self.notify("textDocument/didOpen", json!({"textDocument": item}));
+        self.documents.push(Document {
+            buffer,
+            uri,
+            version: 0,
+        });
+        true
+    }
+
+    /// `textDocument/didChange` with the buffer's edits since the last one, `lines` being its
+    /// text now. They go as whole lines, or as all of `lines` when that's what the server
+    /// wants.
+    pub fn change(&mut self, buffer: usize, changes: Vec<Change>, lines: &[String]) {
+        let Some(document) = self.documents.iter_mut().find(|d| d.buffer == buffer) else {
+            return;
+        };
+        if changes.is_empty() {
+            return;
+        }
+        if let Some(queued) = &mut self.queued {
+            // It's not known yet how the server wants edits, but it hasn't seen the document
+            // either, so it might as well open with the text as it is now
+            let open = queued.iter_mut().find(|message| {
+                message["method"] == "textDocument/didOpen"
+                    && message["params"]["textDocument"]["uri"] == document.uri.as_str()
+            });
+            if let Some(open) = open {
+                open["params"]["textDocument"]["text"] = text(lines).into();
+            }
+            return;
+        }
+        let changes: Vec<Value> = match self.sync {
+            Sync::None => return,
+            Sync::Full => vec![json!({"text": text(lines)})],
+            Sync::Incremental => {
+                let line = |row: usize| json!({"line": row, "character": 0});
+                changes
+                    .into_iter()
+                    .map(|change| {
+                        json!({
+                            "range": {
+                                "start": line(change.row),
+                                "end": line(change.row + change.removed),
+                            },
+                            "text": text(&change.lines),
+                        })
+                    })
+                    .collect()
+            }
+        };
+        document.version += 1;
+        let identifier = json!({"uri": document.uri, "version": document.version});
+        let params = json!({"textDocument": identifier, "contentChanges": changes});
+        self.notify("textDocument/didChange", params);
+    }
+
+    /// `shutdown`, and `exit` once the server has answered it. Until it's gone by itself,
+    /// dropping the client kills it.
+    pub fn shutdown(&mut self) {
+        self.request("shutdown", Value::Null, Request::Shutdown);
+    }
+
+    /// `textDocument/didSave`
+    pub fn save(&mut self, buffer: usize) {
+        if let Some(identifier) = self.text_document(buffer) {
+            let params = json!({"textDocument": identifier});
+            self.notify("textDocument/didSave", params);
+        }
+    }
+
+    /// `textDocument/didClose` for every open buffer that isn't one of `buffers`
+    pub fn close_all_but(&mut self, buffers: &[usize]) {
+        let (kept, closed) = std::mem::take(&mut self.documents)
+            .into_iter()
+            .partition(|document| buffers.contains(&document.buffer));
+        self.documents = kept;
+        for document in closed {
+            let identifier = json!({"uri": document.uri});
+            let params = json!({"textDocument": identifier});
+            self.notify("textDocument/didClose", params);
+        }
+    }
+
+    /// A `TextDocumentIdentifier` for the buffer with id `buffer`, if it's open
+    pub fn text_document(&self, buffer: usize) -> Option<Value> {
+        let document = self.documents.iter().find(|d| d.buffer == buffer)?;
+        Some(json!({"uri": document.uri}))
+    }
+
+    /// `TextDocumentPositionParams` for byte `cursor` of the buffer with id `buffer`
+    pub fn position_params(
+        &self,
+        buffer: usize,
+        lines: &[String],
+        cursor: (usize, usize),
+    ) -> Option<Value> {
+        Some(json!({
+            "textDocument": self.text_document(buffer)?,
+            "position": self.position(lines, cursor),
+        }))
+    }
+}
+
+/// A buffer's text as the server has it: every line with a line ending
+fn text(lines: &[String]) -> String {
+    lines
+        .iter()
+        .flat_map(|line| [line.as_str(), "\n"])
+        .collect()
+}
+
+/// What we can do, for `initialize`
+fn capabilities() -> Value {
+    json!({
+        "general": {"positionEncodings": ["utf-8", "utf-16"]},
+        "textDocument": {
+            "synchronization": {"didSave": true},
+            "publishDiagnostics": {},
+            "hover": {"contentFormat": ["plaintext", "markdown"]},
+            "definition": {},
+            "references": {},
+            "rename": {},
+            "formatting": {},
+            "completion": {"completionItem": {"snippetSupport": false}},
+            "codeAction": {
+                "codeActionLiteralSupport": {
+                    "codeActionKind": {"valueSet": ["quickfix", "refactor", "source"]},
+                },
+            },
+        },
+        "workspace": {
+            "applyEdit": true,
+            "workspaceEdit": {"documentChanges": true},
+        },
+    })
+}
+
+/// The text of a hover's `contents`, which can be a `MarkupContent`, a `MarkedString` or a
+/// list of those
+pub fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(hover_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        _ => contents
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+    }
+}
+
+/// The `TextEdit`s of a `WorkspaceEdit` for each URI, from `changes` or `documentChanges`.
+/// Creating, renaming and deleting files isn't done.
+pub fn workspace_edits(edit: &Value) -> Vec<(String, Vec<Value>)> {
+    let mut edits = Vec::new();
+    for (uri, changes) in edit
+        .get("changes")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        edits.push((uri.clone(), changes.as_array().cloned().unwrap_or_default()));
+    }
+    for change in edit
+        .get("documentChanges")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let uri = change
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str);
+        let changes = change.get("edits").and_then(Value::as_array);
+        if let (Some(uri), Some(changes)) = (uri, changes) {
+            edits.push((uri.to_owned(), changes.clone()));
+        }
+    }
+    edits
+}
+
+/// The `Location`s in an answer to `definition` or `references`: one, a list of them, or a
+/// list of `LocationLink`s. Each is a URI and a `Range`.
+pub fn locations(result: &Value) -> Vec<(String, Value)> {
+    let one = |location: &Value| {
+        let uri = location
+            .get("uri")
+            .or_else(|| location.get("targetUri"))?
+            .as_str()?;
+        let range = location
+            .get("range")
+            .or_else(|| location.get("targetSelectionRange"))?;
+        Some((uri.to_owned(), range.clone()))
+    };
+    match result {
+        Value::Array(locations) => locations.iter().filter_map(one).collect(),
+        location => one(location).into_iter().collect(),
+    }
+}
+
+/// The text a completion item puts in
+pub fn completion_word(item: &Value) -> Option<&str> {
+    item.get("textEdit")
+        .and_then(|edit| edit.get("newText"))
+        .or_else(|| item.get("insertText"))
+        .or_else(|| item.get("label"))?
+        .as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    #[test]
+    fn edits() {
+        let lines: Vec<String> = ["fn main() {", "    let x = 1;", "}"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let edit = |start, end, text: &str| Edit {
+            start,
+            end,
+            text: text.to_owned(),
+        };
+        assert_eq!(
+            apply(
+                &lines,
+                vec![edit((1, 8), (1, 9), "count"), edit((1, 12), (1, 13), "2")]
+            ),
+            Some((1, 1, vec!["    let count = 2;".to_string()]))
+        );
+        assert_eq!(
+            apply(&lines, vec![edit((0, 11), (2, 0), " ")]),
+            Some((0, 2, vec!["fn main() { }".to_string()]))
+        );
+        assert_eq!(
+            apply(&lines, vec![edit((0, 0), (3, 0), "x\n\ny\n")]),
+            Some((0, 2, vec!["x".to_string(), String::new(), "y".to_string()]))
+        );
+        assert_eq!(apply(&lines, vec![edit((2, 0), (2, 1), "}")]), None);
+    }
+
+    #[test]
+    fn positions_and_uris() {
+        let (events, _) = mpsc::unbounded_channel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _runtime = runtime.enter();
+        let mut client = Client::spawn("sh", "cat >/dev/null", "rust", &events).unwrap();
+        let line = "é😀x".to_string();
+        assert_eq!(client.character(&line, 6), 3);
+        assert_eq!(client.col(&line, 3), 6);
+        assert_eq!(client.col(&line, 2), 2);
+        client.utf8 = true;
+        assert_eq!(client.character(&line, 6), 6);
+
+        let path = Path::new("/tmp/a b/ü.rs");
+        assert_eq!(uri(path), "file:///tmp/a%20b/%C3%BC.rs");
+        assert_eq!(self::path(&uri(path)).as_deref(), Some(path));
+        assert_eq!(
+            server(r"rust:rust-analyzer,python: a\,b ", "python").as_deref(),
+            Some("a,b")
+        );
+        assert_eq!(server("rust:ra", "c"), None);
+    }
+
+    /// `lspservers` for the fake server, asking for edits the `sync` way
+    fn fake_server(sync: u64) -> String {
+        // `cargo test` builds examples next to the test binary's `deps`
+        let exe = std::env::current_exe().unwrap();
+        let server = exe.parent().unwrap().with_file_name("examples/fake_lsp");
+        let server = format!("{} {sync}", server.display());
+        format!("rust:{}", server.replace(' ', "\\ "))
+    }
+
+    #[test]
+    fn language_server() {
+        let dir = temp_dir("lsp");
+        let main = dir.join("main.rs");
+        let text = "fn helper() {}\nfn main() {\n    helper();  \n    let bad = 1;\n}\n";
+        fs::write(&main, text).unwrap();
+        let mut h = Harness::new("");
+        h.keys(&format!(":set lsps={}<CR>", fake_server(2)));
+        h.keys(&format!(":e {}<CR>", main.display()));
+        h.wait_for(|h| !h.buffer().diagnostics().is_empty());
+        assert!(h.screen()[3].starts_with("E>   3     let bad = 1;"));
+        assert!(h.screen()[3].ends_with("  bad word"));
+        // The server hears about edits, and says what it thinks of them
+        h.keys("jjjccgood<Esc>");
+        h.wait_for(|h| h.buffer().diagnostics().is_empty());
+        assert!(h.screen()[3].starts_with("   4 good"));
+        h.keys("cc    let bad = 1;<Esc>");
+        h.wait_for(|h| !h.buffer().diagnostics().is_empty());
+
+        h.keys("gg0jjllllgd");
+        h.wait_for(|h| h.cursor() == (0, 3));
+        h.keys("gr");
+        h.wait_for(|h| h.message() == "(1 of 2): fn helper() {}");
+        h.keys(":cn<CR>");
+        assert_eq!(h.cursor(), (2, 4));
+        h.keys("K");
+        h.wait_for(|h| h.screen()[5].contains(" A word."));
+        assert!(h.screen()[3].contains(" `helper`"));
+        h.keys("h");
+        assert!(!h.screen()[5].contains(" A word."));
+
+        h.keys("l:LspRename assist<CR>");
+        h.wait_for(|h| h.text().starts_with("fn assist() {}"));
+        assert_eq!(h.buffer().nth_line(2), "    assist();  ");
+        assert_eq!(h.cursor(), (2, 4));
+        h.keys(":LspFormat<CR>");
+        h.wait_for(|h| h.buffer().nth_line(2) == "    assist();");
+
+        h.keys(":LspCodeAction<CR>");
+        h.wait_for(|h| h.state().screen().paging());
+        assert!(h.screen().iter().any(|row| row == "1: Capitalize `assist`"));
+        h.keys("q:LspCodeAction 1<CR>");
+        h.wait_for(|h| h.buffer().nth_line(2) == "    Assist();");
+        h.keys(":LspCodeAction 2<CR>");
+        h.wait_for(|h| h.buffer().nth_line(2) == "    ASSIST();");
+
+        h.keys("ggA as<C-x><C-o>");
+        h.wait_for(|h| h.buffer().nth_line(0) == "fn assist() {} assist");
+        h.keys("<Esc>:w<CR>");
+        assert_eq!(
+            fs::read_to_string(&main).unwrap(),
+            "fn assist() {} assist\nfn main() {\n    ASSIST();\n    let bad = 1;\n}\n"
+        );
+
+        // Asked to, it goes by itself
+        h.state().screen_mut().lsp_shutdown();
+        h.wait_for(|h| !h.state().screen().lsp_running());
+    }
+
+    #[test]
+    fn document_sync() {
+        let dir = temp_dir("lsp-sync");
+        let main = dir.join("main.rs");
+        fs::write(&main, "let bad = 1;\nfn word() {}\n").unwrap();
+
+        // Edits that come with a range are lost on it
+        let mut h = Harness::new("");
+        h.keys(&format!(":set lsps={}<CR>", fake_server(1)));
+        h.keys(&format!(":e {}<CR>", main.display()));
+        h.wait_for(|h| !h.buffer().diagnostics().is_empty());
+        h.keys("ccgood<Esc>");
+        h.wait_for(|h| h.buffer().diagnostics().is_empty());
+        h.keys("ccbad<Esc>");
+        h.wait_for(|h| !h.buffer().diagnostics().is_empty());
+
+        // Doesn't want to hear about edits at all
+        let mut h = Harness::new("");
+        h.keys(&format!(":set lsps={}<CR>", fake_server(0)));
+        h.keys(&format!(":e {}<CR>", main.display()));
+        h.wait_for(|h| !h.buffer().diagnostics().is_empty());
+        h.keys("ccgood<Esc>jK");
+        // Answered after whatever it made of the edit
+        h.wait_for(|h| h.screen()[4].contains(" A word."));
+        assert!(!h.state().screen().message_is_error());
+    }
+}
@@ -0,0 +1,85 @@
+//! JSON-RPC as language servers speak it: each message a JSON body after a `Content-Length`
+//! header and a blank line.
+
+use serde_json::Value;
+
+pub fn encode(message: &Value) -> Vec<u8> {
+    let body = message.to_string();
+    let mut bytes = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+    bytes.extend(body.as_bytes());
+    bytes
+}
+
+/// Collects what's read until whole messages come out
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    /// Every message completed by `bytes`. Anything that isn't one, like a server printing to
+    /// stdout before it gets going, is skipped.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Value> {
+        self.buf.extend(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = self.next() {
+            messages.extend(message);
+        }
+        messages
+    }
+
+    /// `None` when more has to be read first, `Some(None)` for a body that isn't JSON
+    fn next(&mut self) -> Option<Option<Value>> {
+        const LENGTH: &[u8] = b"Content-Length:";
+        let start = find(&self.buf, LENGTH)?;
+        let headers_end = start + find(&self.buf[start..], b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&self.buf[start..headers_end]);
+        let length = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length:"))
+            .and_then(|length| length.trim().parse::<usize>().ok());
+        let body_start = headers_end + 4;
+        let Some(length) = length else {
+            self.buf.drain(..body_start);
+            return Some(None);
+        };
+        if self.buf.len() < body_start + length {
+            return None;
+        }
+        let body: Vec<u8> = self
+            .buf
+            .drain(..body_start + length)
+            .skip(body_start)
+            .collect();
+        Some(serde_json::from_slice(&body).ok())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn frames() {
+        let message = json!({"id": 1, "method": "é"});
+        let bytes = encode(&message);
+        assert!(bytes.starts_with(b"Content-Length: 22\r\n\r\n{"));
+
+        let mut decoder = Decoder::default();
+        let mut stream = b"\nrunning 1 test\n".to_vec();
+        stream.extend(&bytes);
+        stream.extend(b"Content-Length: 3\r\n\r\nnah");
+        stream.extend(&bytes);
+        let (first, rest) = stream.split_at(30);
+        assert!(decoder.feed(first).is_empty());
+        assert_eq!(decoder.feed(rest), [message.clone(), message]);
+        assert!(decoder.feed(b"").is_empty());
+    }
+}
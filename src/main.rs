@@ -1,25 +1,80 @@
-use crossterm::Result;
+use std::io::{self, Write};
 
+use args::Parsed;
+use crossterm::Result;
 use keys::keyhandler;
+use options::Options;
 use state::State;
 
+mod args;
+mod backend;
 mod buffer;
+mod clock;
 mod command;
+mod complete;
+mod diff;
+mod encoding;
+mod filewatch;
+#[cfg(test)]
+mod harness;
+mod highlight;
+mod history;
+mod job;
 mod keys;
+mod loader;
+mod lsp;
+mod marks;
+mod options;
+mod quickfix;
+mod range;
+mod registers;
+mod save;
 mod screen;
+mod session;
+mod shell;
 mod state;
+mod statusline;
+mod swap;
+mod syntax;
+mod term;
+mod undo;
+mod vt;
 mod window;
+mod wrap;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut state = State::init()?;
-
-    let mut args = std::env::args();
-    let _ = args.next().unwrap();
-    if let Some(filename) = args.next() {
-        state.screen_mut().active_window_mut().load_file(filename)?;
+    let args = match args::parse(std::env::args().skip(1)) {
+        Ok(Parsed::Edit(args)) => args,
+        Ok(Parsed::Print(text)) => {
+            print!("{text}");
+            return Ok(());
+        }
+        Err(e) => {
+            // Before the terminal is taken over, so it reads like any other program's
+            eprintln!("rim: {e}\nMore info with: rim --help");
+            std::process::exit(2);
+        }
+    };
+    if args.recover && args.files.is_empty() {
+        print!("{}", swap::list(&Options::default().directory));
+        return Ok(());
     }
+    // All of it before the terminal is taken over, since it comes first in a pipeline
+    let stdin = if args.stdin {
+        Some(shell::read_stdin()?)
+    } else {
+        None
+    };
+
+    let mut state = State::init()?;
+    let (rcfile, state_file) = (args.rcfile_path(), args.state_file_path());
+    state.start(args, stdin, rcfile, state_file)?;
 
     // Loops until quit
-    keyhandler::watch(&mut state).await
+    keyhandler::watch(&mut state).await?;
+    if let Some(bytes) = state.screen_mut().take_stdout() {
+        io::stdout().write_all(&bytes)?;
+    }
+    Ok(())
 }
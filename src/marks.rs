@@ -0,0 +1,306 @@
+//! Marks: places in a buffer that stay on their lines as lines above come and go, and the jump
+//! and change lists that `<C-o>` and `g;` go back through.
+
+use std::collections::BTreeMap;
+
+/// (row, col) in a buffer, col being a byte offset
+pub type Pos = (usize, usize);
+
+/// How many jumps or changes are remembered
+pub const LIST_LEN: usize = 100;
+
+/// A buffer's marks: `a`-`z`, the special ones like `'.`, and the global `A`-`Z` ones that are
+/// in it
+#[derive(Debug, Default)]
+pub struct Marks {
+    marks: BTreeMap<char, Pos>,
+    /// Where changes were made, oldest first
+    changes: Vec<Pos>,
+    /// Where `g;` and `g,` are in `changes`, its length when they haven't been used since the
+    /// last change
+    change_index: usize,
+}
+
+impl Marks {
+    /// `` ` `` is another name for `'`, the place before the last jump
+    pub fn get(&self, name: char) -> Option<Pos> {
+        let name = if name == '`' { '\'' } else { name };
+        self.marks.get(&name).copied()
+    }
+
+    pub fn set(&mut self, name: char, pos: Pos) {
+        let name = if name == '`' { '\'' } else { name };
+        self.marks.insert(name, pos);
+    }
+
+    pub fn remove(&mut self, name: char) {
+        self.marks.remove(&name);
+    }
+
+    /// In order of their names
+    pub fn iter(&self) -> impl Iterator<Item = (char, Pos)> + '_ {
+        self.marks.iter().map(|(&name, &pos)| (name, pos))
+    }
+
+    /// `removed` lines at `row` were replaced by `inserted` ones. Marks below move with their
+    /// lines. Ones on lines that went without a replacement move up to the last line that's
+    /// left of the change, or go too if nothing is.
+    pub fn shift(&mut self, row: usize, removed: usize, inserted: usize) {
+        let moved = |pos: Pos| -> Option<Pos> {
+            if pos.0 >= row + removed {
+                Some((pos.0 + inserted - removed, pos.1))
+            } else if pos.0 < row + inserted {
+                Some(pos)
+            } else if inserted > 0 {
+                Some((row + inserted - 1, pos.1))
+            } else {
+                None
+            }
+        };
+        self.marks = std::mem::take(&mut self.marks)
+            .into_iter()
+            .filter_map(|(name, pos)| Some((name, moved(pos)?)))
+            .collect();
+        self.changes = std::mem::take(&mut self.changes)
+            .into_iter()
+            .filter_map(moved)
+            .collect();
+        self.change_index = self.change_index.min(self.changes.len());
+    }
+
+    /// A change from `start` to line `last`: `'.` goes there, `'[` and `']` around it, and
+    /// it's the newest in the change list. More changes on the same line are one entry.
+    pub fn changed(&mut self, start: Pos, last: usize) {
+        self.marks.insert('.', start);
+        self.marks.insert('[', (start.0, 0));
+        self.marks.insert(']', (last, 0));
+        if self.changes.last().is_some_and(|pos| pos.0 == start.0) {
+            self.changes.pop();
+        }
+        self.changes.push(start);
+        if self.changes.len() > LIST_LEN {
+            self.changes.remove(0);
+        }
+        self.change_index = self.changes.len();
+    }
+
+    /// `g;` (`step` -1) and `g,` (1): an older or newer place in the change list
+    pub fn step_change(&mut self, step: isize) -> Result<Pos, &'static str> {
+        if self.changes.is_empty() {
+            return Err("changelist is empty");
+        }
+        let index = self.change_index as isize + step;
+        if index < 0 {
+            return Err("At start of changelist");
+        }
+        if index as usize >= self.changes.len() {
+            return Err("At end of changelist");
+        }
+        self.change_index = index as usize;
+        Ok(self.changes[self.change_index])
+    }
+}
+
+/// A place in some buffer, like a global mark's or a jump list entry: the buffer, and its file
+/// for when the buffer is gone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Place {
+    pub buffer: usize,
+    pub file: Option<String>,
+    pub pos: Pos,
+}
+
+impl Place {
+    /// A place in `file` from an earlier run, which no buffer has yet
+    pub fn in_file(file: String, pos: Pos) -> Self {
+        Self {
+            buffer: usize::MAX,
+            file: Some(file),
+            pos,
+        }
+    }
+
+    pub fn same_line(&self, other: &Place) -> bool {
+        let same_buffer = match (&self.file, &other.file) {
+            (Some(file), Some(other)) => file == other,
+            _ => self.buffer == other.buffer,
+        };
+        same_buffer && self.pos.0 == other.pos.0
+    }
+}
+
+/// A window's jump list: where `G`, searches, mark jumps and the like went from, oldest first
+#[derive(Debug, Default)]
+pub struct JumpList {
+    jumps: Vec<Place>,
+    /// Where `<C-o>` and `<C-i>` are, the length when they haven't been used since the last
+    /// jump
+    index: usize,
+}
+
+impl JumpList {
+    /// A jump from `from`. The same line from before goes, so each line is in there once.
+    pub fn push(&mut self, from: Place) {
+        self.jumps.retain(|jump| !jump.same_line(&from));
+        self.jumps.push(from);
+        if self.jumps.len() > LIST_LEN {
+            self.jumps.remove(0);
+        }
+        self.index = self.jumps.len();
+    }
+
+    /// `<C-o>` from `current`, which is remembered for `<C-i>` to come back to
+    pub fn back(&mut self, current: Place) -> Option<Place> {
+        if self.jumps.is_empty() {
+            return None;
+        }
+        if self.index == self.jumps.len() {
+            self.push(current);
+            self.index = self.jumps.len() - 1;
+        }
+        self.index = self.index.checked_sub(1)?;
+        Some(self.jumps[self.index].clone())
+    }
+
+    /// Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Place> {
+        self.jumps.iter()
+    }
+
+    /// `<C-i>`
+    pub fn forward(&mut self) -> Option<Place> {
+        if self.index + 1 >= self.jumps.len() {
+            return None;
+        }
+        self.index += 1;
+        Some(self.jumps[self.index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use std::fs;
+
+    #[test]
+    fn marks_follow_lines() {
+        let mut marks = Marks::default();
+        marks.set('a', (2, 3));
+        marks.set('b', (5, 0));
+        marks.set('`', (9, 1));
+        marks.shift(0, 0, 2);
+        assert_eq!(marks.get('a'), Some((4, 3)));
+        marks.shift(4, 1, 0);
+        assert_eq!(marks.get('a'), None);
+        assert_eq!(marks.get('b'), Some((6, 0)));
+        // Joining lines 5 and 6
+        marks.shift(5, 2, 1);
+        assert_eq!(marks.get('b'), Some((5, 0)));
+        assert_eq!(marks.get('\''), Some((9, 1)));
+
+        marks.changed((1, 2), 1);
+        marks.changed((1, 4), 1);
+        marks.changed((3, 0), 4);
+        assert_eq!(marks.get('.'), Some((3, 0)));
+        assert_eq!(marks.get(']'), Some((4, 0)));
+        assert_eq!(marks.step_change(1), Err("At end of changelist"));
+        assert_eq!(marks.step_change(-1), Ok((3, 0)));
+        assert_eq!(marks.step_change(-1), Ok((1, 4)));
+        assert_eq!(marks.step_change(-1), Err("At start of changelist"));
+        marks.shift(0, 1, 0);
+        assert_eq!(marks.step_change(1), Ok((2, 0)));
+    }
+
+    #[test]
+    fn jump_list() {
+        let jump = |row| Place {
+            buffer: 1,
+            file: None,
+            pos: (row, 0),
+        };
+        let mut jumps = JumpList::default();
+        assert_eq!(jumps.back(jump(0)), None);
+        jumps.push(jump(1));
+        jumps.push(jump(5));
+        jumps.push(jump(1));
+        assert_eq!(jumps.back(jump(9)), Some(jump(1)));
+        assert_eq!(jumps.back(jump(1)), Some(jump(5)));
+        assert_eq!(jumps.back(jump(5)), None);
+        assert_eq!(jumps.forward(), Some(jump(1)));
+        assert_eq!(jumps.forward(), Some(jump(9)));
+        assert_eq!(jumps.forward(), None);
+    }
+
+    #[test]
+    fn marks_and_jumps() {
+        let mut h = Harness::new("one\ntwo\n  three\nfour\nfive");
+        h.keys("jjmaG");
+        assert_eq!(h.cursor(), (4, 0));
+        h.keys("''");
+        assert_eq!(h.cursor(), (2, 2));
+        h.keys("''");
+        assert_eq!(h.cursor(), (4, 0));
+        h.keys("`a");
+        assert_eq!(h.cursor(), (2, 0));
+        h.keys("ggOnew<Esc>'a");
+        assert_eq!(h.cursor(), (3, 2));
+        h.keys("'.");
+        assert_eq!(h.cursor(), (0, 0));
+        h.keys("ggdd`a");
+        assert_eq!(h.cursor(), (2, 0));
+        h.keys("'z");
+        assert_eq!(h.message(), "Mark not set");
+
+        h.keys(":1<CR>G<C-o>");
+        assert_eq!(h.cursor().0, 0);
+        h.keys("<C-o>");
+        assert_eq!(h.cursor().0, 2);
+        h.keys("<Tab><C-i>");
+        assert_eq!(h.cursor().0, 4);
+
+        h.keys("ggAx<Esc>GAy<Esc>ggg;");
+        assert_eq!(h.cursor(), (4, 4));
+        h.keys("g;");
+        assert_eq!(h.cursor(), (0, 3));
+        h.keys("g;");
+        assert_eq!(h.message(), "At start of changelist");
+        h.keys("g,g,g,");
+        assert_eq!(h.message(), "At end of changelist");
+        h.keys("`^");
+        assert_eq!(h.cursor(), (4, 5));
+
+        h.keys(":marks a.<CR>");
+        assert_eq!(
+            &h.screen()[20..23],
+            [
+                "mark line  col file/text",
+                " a      3    0 three",
+                " .      5    4 fivey"
+            ]
+        );
+        h.keys("<CR>:delm a-c<CR>:marks a<CR>");
+        assert_eq!(h.message(), "No marks set");
+        h.keys(":delm ?<CR>");
+        assert_eq!(h.message(), "Invalid argument: `?`");
+
+        let dir = temp_dir("marks_and_jumps");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "alpha\nbeta\n").unwrap();
+        fs::write(&b, "gamma\n").unwrap();
+        let mut h = Harness::new("");
+        h.keys(&format!(
+            ":e {}<CR>jmA:e {}<CR>'A",
+            a.display(),
+            b.display()
+        ));
+        assert_eq!(h.text(), "alpha\nbeta");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys("<C-o>");
+        assert_eq!(h.text(), "gamma");
+        h.keys("<C-i>");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys(":marks<CR>");
+        assert!(h.screen().contains(&" A      2    0 beta".to_string()));
+    }
+}
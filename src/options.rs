@@ -0,0 +1,420 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::encoding::{Encoding, FileFormat};
+
+/// Shared between `Screen` and every `Window`, like the backend
+pub type OptionsRef = Rc<RefCell<Options>>;
+
+/// A type an option can have. Booleans are special cased by `:set` (`wrap`, `nowrap`, `invwrap`).
+pub trait OptionValue {
+    fn as_bool_mut(&mut self) -> Option<&mut bool> {
+        None
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        None
+    }
+
+    fn assign(&mut self, value: &str) -> Result<(), String>;
+
+    /// `:set opt+=value`
+    fn add(&mut self, value: &str) -> Result<(), String>;
+
+    /// `:set opt-=value`
+    fn subtract(&mut self, value: &str) -> Result<(), String>;
+
+    fn show(&self) -> String;
+}
+
+impl OptionValue for bool {
+    fn as_bool_mut(&mut self) -> Option<&mut bool> {
+        Some(self)
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        Some(*self)
+    }
+
+    fn assign(&mut self, _value: &str) -> Result<(), String> {
+        Err("Invalid argument".to_string())
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn subtract(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl OptionValue for usize {
+    fn assign(&mut self, value: &str) -> Result<(), String> {
+        *self = value
+            .parse()
+            .map_err(|_| format!("Number required: `{value}`"))?;
+        Ok(())
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), String> {
+        let mut n = 0;
+        n.assign(value)?;
+        *self += n;
+        Ok(())
+    }
+
+    fn subtract(&mut self, value: &str) -> Result<(), String> {
+        let mut n = 0;
+        n.assign(value)?;
+        *self = self.saturating_sub(n);
+        Ok(())
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl OptionValue for String {
+    fn assign(&mut self, value: &str) -> Result<(), String> {
+        *self = value.to_owned();
+        Ok(())
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), String> {
+        self.push_str(value);
+        Ok(())
+    }
+
+    fn subtract(&mut self, value: &str) -> Result<(), String> {
+        *self = self.replacen(value, "", 1);
+        Ok(())
+    }
+
+    fn show(&self) -> String {
+        self.clone()
+    }
+}
+
+impl OptionValue for FileFormat {
+    fn assign(&mut self, value: &str) -> Result<(), String> {
+        *self =
+            FileFormat::from_name(value).ok_or_else(|| format!("Invalid argument: `{value}`"))?;
+        Ok(())
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn subtract(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn show(&self) -> String {
+        self.name().to_owned()
+    }
+}
+
+impl OptionValue for Encoding {
+    fn assign(&mut self, value: &str) -> Result<(), String> {
+        *self = Encoding::from_name(value).ok_or_else(|| format!("Invalid argument: `{value}`"))?;
+        Ok(())
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn subtract(&mut self, value: &str) -> Result<(), String> {
+        self.assign(value)
+    }
+
+    fn show(&self) -> String {
+        self.name().to_owned()
+    }
+}
+
+/// The arguments of `:set`, split on whitespace except where it's escaped, as in
+/// `makeprg=cargo\ build`
+pub fn words(args: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut escaped = false;
+    for (i, c) in args.char_indices() {
+        if c.is_whitespace() && !escaped {
+            if let Some(start) = start.take() {
+                words.push(&args[start..i]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    words.extend(start.map(|start| &args[start..]));
+    words
+}
+
+macro_rules! options {
+    (
+        $(#[$attr:meta])*
+        pub struct $struct:ident {
+            $( $name:ident $(, $short:literal)? : $ty:ty = $default:expr ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone)]
+        pub struct $struct {
+            $( pub $name: $ty, )*
+        }
+
+        impl Default for $struct {
+            fn default() -> Self {
+                Self {
+                    $( $name: $default, )*
+                }
+            }
+        }
+
+        impl $struct {
+            /// Full names of every option, for `:set` listing
+            pub const NAMES: &'static [&'static str] = &[ $( stringify!($name) ),* ];
+
+            /// Resolves abbreviations (`so` -> `scrolloff`)
+            fn full_name(name: &str) -> Option<&'static str> {
+                match name {
+                    $( stringify!($name) $( | $short )? => Some(stringify!($name)), )*
+                    _ => None,
+                }
+            }
+
+            fn field(&self, name: &str) -> &dyn OptionValue {
+                match name {
+                    $( stringify!($name) => &self.$name, )*
+                    _ => unreachable!("resolved by full_name"),
+                }
+            }
+
+            fn field_mut(&mut self, name: &str) -> &mut dyn OptionValue {
+                match name {
+                    $( stringify!($name) => &mut self.$name, )*
+                    _ => unreachable!("resolved by full_name"),
+                }
+            }
+
+            fn reset(&mut self, name: &str) {
+                match name {
+                    $( stringify!($name) => self.$name = $default, )*
+                    _ => unreachable!("resolved by full_name"),
+                }
+            }
+
+            /// Whether `arg` to `:set` is about one of these options
+            #[allow(dead_code)]
+            pub fn knows(arg: &str) -> bool {
+                let name = arg.split(['=', '?', '&', '!']).next().unwrap_or_default();
+                let name = name.trim_end_matches(['+', '-']);
+                [Some(name), name.strip_prefix("no"), name.strip_prefix("inv")]
+                    .into_iter()
+                    .flatten()
+                    .any(|name| Self::full_name(name).is_some())
+            }
+            /// Applies the arguments of a `:set` command, returning anything that should be shown on the
+            /// message line (for `:set opt?` and friends)
+            pub fn set(&mut self, args: &str) -> Result<String, String> {
+                let mut shown = Vec::new();
+                for arg in words(args) {
+                    if let Some(msg) = self.set_one(arg)? {
+                        shown.push(msg);
+                    }
+                }
+                Ok(shown.join("  "))
+            }
+
+            /// `:set` with no arguments: everything that isn't the default
+            pub fn changed(&self) -> String {
+                let default = Self::default();
+                Self::NAMES
+                    .iter()
+                    .filter(|name| self.field(name).show() != default.field(name).show())
+                    .map(|name| self.describe(name))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            }
+
+            fn describe(&self, name: &str) -> String {
+                let field = self.field(name);
+                match field.as_bool() {
+                    Some(true) => name.to_owned(),
+                    Some(false) => format!("no{name}"),
+                    None => format!("{name}={}", field.show()),
+                }
+            }
+
+            /// What `:set name=<Tab>` starts from, `None` for booleans and unknown options
+            #[allow(dead_code)]
+            pub fn value(&self, name: &str) -> Option<String> {
+                let field = self.field(Self::full_name(name)?);
+                field.as_bool().is_none().then(|| field.show())
+            }
+
+            fn resolve(name: &str) -> Result<&'static str, String> {
+                Self::full_name(name).ok_or_else(|| format!("Unknown option: `{name}`"))
+            }
+
+            fn set_one(&mut self, arg: &str) -> Result<Option<String>, String> {
+                if let Some((name, value)) = arg.split_once('=') {
+                    let (name, op) = match name.char_indices().last() {
+                        Some((i, c @ ('+' | '-'))) => (&name[..i], Some(c)),
+                        _ => (name, None),
+                    };
+                    let name = Self::resolve(name)?;
+                    let value = &value.replace("\\ ", " ");
+                    let field = self.field_mut(name);
+                    match op {
+                        Some('+') => field.add(value)?,
+                        Some(_) => field.subtract(value)?,
+                        None => field.assign(value)?,
+                    }
+                    return Ok(None);
+                }
+                if let Some(name) = arg.strip_suffix('?') {
+                    return Ok(Some(self.describe(Self::resolve(name)?)));
+                }
+                if let Some(name) = arg.strip_suffix('&') {
+                    self.reset(Self::resolve(name)?);
+                    return Ok(None);
+                }
+
+                let (name, toggle) = match (arg.strip_prefix("inv"), arg.strip_suffix('!')) {
+                    (Some(name), _) | (_, Some(name)) => (name, true),
+                    _ => (arg, false),
+                };
+                if let Some(name) = Self::full_name(name) {
+                    return match self.field_mut(name).as_bool_mut() {
+                        Some(value) => {
+                            *value = !*value || !toggle;
+                            Ok(None)
+                        }
+                        // `:set scrolloff` shows the value like `:set scrolloff?`
+                        None if !toggle => Ok(Some(self.describe(name))),
+                        None => Err(format!("Invalid argument: `{arg}`")),
+                    };
+                }
+                if let Some(name) = arg.strip_prefix("no") {
+                    if let Some(value) = self.field_mut(Self::resolve(name)?).as_bool_mut() {
+                        *value = false;
+                        return Ok(None);
+                    }
+                }
+                Err(format!("Unknown option: `{arg}`"))
+            }
+        }
+    };
+}
+
+options! {
+    pub struct Options {
+        wrap: bool = false,
+        linebreak, "lbr": bool = false,
+        showbreak, "sbr": String = String::new(),
+        breakindent, "bri": bool = false,
+        scrolloff, "so": usize = 0,
+        sidescrolloff, "siso": usize = 0,
+        scroll, "scr": usize = 0,
+        statusline, "stl": String = String::new(),
+        laststatus, "ls": usize = 2,
+        backup, "bk": bool = false,
+        writebackup, "wb": bool = true,
+        backupdir, "bdir": String = ".,~/tmp,~/".to_string(),
+        backupcopy, "bkc": String = "auto".to_string(),
+        swapfile, "swf": bool = true,
+        directory, "dir": String = ".,~/tmp,/var/tmp,/tmp".to_string(),
+        undofile, "udf": bool = false,
+        undodir, "udir": String = ".".to_string(),
+        updatetime, "ut": usize = 4000,
+        autoread, "ar": bool = false,
+        fixendofline, "fixeol": bool = true,
+        fileencodings, "fencs": String = "ucs-bom,utf-8,latin1".to_string(),
+        asyncsize, "asz": usize = 4 * 1024 * 1024,
+        shell, "sh": String = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string()),
+        history, "hi": usize = 50,
+        cmdwinheight, "cwh": usize = 7,
+        wildmenu, "wmnu": bool = true,
+        wildmode, "wim": String = "full".to_string(),
+        wildoptions, "wop": String = String::new(),
+        completeopt, "cot": String = "menu".to_string(),
+        termwinscroll, "twsl": usize = 10000,
+        makeprg, "mp": String = "make".to_string(),
+        grepprg, "gp": String = "grep -n $* /dev/null".to_string(),
+        errorformat, "efm": String = "%f:%l:%c: %m,%f:%l: %m".to_string(),
+        grepformat, "gfm": String = "%f:%l:%c:%m,%f:%l:%m".to_string(),
+        lspservers, "lsps": String = String::new(),
+    }
+}
+
+options! {
+    /// The options each buffer has its own copy of, set from the file when it's read
+    pub struct LocalOptions {
+        fileformat, "ff": FileFormat = FileFormat::Unix,
+        fileencoding, "fenc": Encoding = Encoding::Utf8,
+        endofline, "eol": bool = true,
+        bomb: bool = false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_bools() {
+        let mut options = Options::default();
+        options.set("wrap lbr").unwrap();
+        assert!(options.wrap && options.linebreak);
+        options.set("nowrap invlbr").unwrap();
+        assert!(!options.wrap && !options.linebreak);
+        options.set("wrap!").unwrap();
+        assert!(options.wrap);
+        assert_eq!(
+            options.set("wrap? lbr?"),
+            Ok("wrap  nolinebreak".to_string())
+        );
+        assert!(options.set("nosbr").is_err());
+        assert!(options.set("bogus").is_err());
+    }
+
+    #[test]
+    fn set_values() {
+        let mut options = Options::default();
+        options.set("sbr=>>").unwrap();
+        options.set("showbreak+=\\").unwrap();
+        assert_eq!(options.showbreak, ">>\\");
+        assert_eq!(options.changed(), "showbreak=>>\\");
+        options.set("sbr&").unwrap();
+        assert_eq!(options.changed(), "");
+        options.set(r"mp=cargo\ build\ -q  gp=rg").unwrap();
+        assert_eq!(
+            (&*options.makeprg, &*options.grepprg),
+            ("cargo build -q", "rg")
+        );
+    }
+
+    #[test]
+    fn local_options() {
+        let mut local = LocalOptions::default();
+        local.set("ff=dos noeol").unwrap();
+        assert_eq!(
+            (local.fileformat, local.endofline),
+            (FileFormat::Dos, false)
+        );
+        assert!(local.set("fenc=klingon").is_err());
+        assert_eq!(local.set("fenc?"), Ok("fileencoding=utf-8".to_string()));
+        assert!(LocalOptions::knows("noeol") && LocalOptions::knows("ff=mac"));
+        assert!(!LocalOptions::knows("wrap") && Options::knows("invwrap"));
+    }
+}
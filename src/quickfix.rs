@@ -0,0 +1,386 @@
+//! Quickfix and location lists: places in files, from the output of `:make` or `:grep`, to go
+//! through one by one.
+
+use regex::Regex;
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub file: String,
+    /// From 1, like the output it came from
+    pub line: usize,
+    /// From 1, 0 when there wasn't one
+    pub col: usize,
+    /// `e` for an error, `w` for a warning and so on
+    pub kind: Option<char>,
+    pub text: String,
+}
+
+impl Entry {
+    /// Its line in the list window: `file|3 col 5 error| text`
+    pub fn describe(&self) -> String {
+        let mut place = self.line.to_string();
+        if self.col > 0 {
+            place.push_str(&format!(" col {}", self.col));
+        }
+        if let Some(kind) = self.kind {
+            place.push(' ');
+            place.push_str(match kind {
+                'e' => "error",
+                'w' => "warning",
+                'n' => "note",
+                'i' => "info",
+                _ => "",
+            });
+        }
+        format!("{}|{}| {}", self.file, place.trim_end(), self.text)
+    }
+}
+
+/// Which entry `:cc`, `:cnext` and the like go to
+pub enum Pick {
+    /// From 0, or the last one if there aren't that many
+    Nth(usize),
+    Step(isize),
+}
+
+#[derive(Default)]
+pub struct List {
+    /// What made it, like `:make`
+    pub title: String,
+    entries: Vec<Entry>,
+    /// The one last jumped to
+    current: usize,
+}
+
+impl List {
+    pub fn new(title: String, entries: Vec<Entry>) -> Self {
+        Self {
+            title,
+            entries,
+            current: 0,
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Makes the entry `pick` says the current one
+    pub fn pick(&mut self, pick: Pick) -> Result<&Entry, String> {
+        match pick {
+            Pick::Nth(i) => self.select(i),
+            Pick::Step(step) => self.step(step),
+        }
+    }
+
+    /// Makes entry `i` the current one
+    fn select(&mut self, i: usize) -> Result<&Entry, String> {
+        if self.entries.is_empty() {
+            return Err("No Errors".to_string());
+        }
+        self.current = i.min(self.entries.len() - 1);
+        Ok(&self.entries[self.current])
+    }
+
+    fn step(&mut self, step: isize) -> Result<&Entry, String> {
+        if self.entries.is_empty() {
+            return Err("No Errors".to_string());
+        }
+        match self.current.checked_add_signed(step) {
+            Some(i) if i < self.entries.len() => self.select(i),
+            _ => Err("No more items".to_string()),
+        }
+    }
+
+    /// Where `:cfdo` goes: the first entry in each file
+    pub fn first_per_file(&self) -> Vec<usize> {
+        let mut seen = Vec::new();
+        let mut firsts = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !seen.contains(&&entry.file) {
+                seen.push(&entry.file);
+                firsts.push(i);
+            }
+        }
+        firsts
+    }
+
+    /// What the message line says after a jump: `(2 of 5): text`
+    pub fn position(&self) -> String {
+        let entry = &self.entries[self.current];
+        format!(
+            "({} of {}): {}",
+            self.current + 1,
+            self.entries.len(),
+            entry.text
+        )
+    }
+}
+
+/// An `errorformat`: comma separated patterns where `%f` is the file, `%l` the line, `%c` the
+/// column, `%t` the kind of message, `%m` the message and `%%` a percent sign. `\,` is a comma
+/// in a pattern.
+pub struct Format {
+    patterns: Vec<(Regex, Vec<char>)>,
+}
+
+impl Format {
+    pub fn new(errorformat: &str) -> Result<Self, String> {
+        let mut patterns = Vec::new();
+        let mut pattern = String::new();
+        let mut items = Vec::new();
+        let mut chars = errorformat.chars();
+        let mut finish = |pattern: &mut String, items: &mut Vec<char>| -> Result<(), String> {
+            if !pattern.is_empty() {
+                let regex = Regex::new(&format!("^{pattern}$")).map_err(|e| e.to_string())?;
+                patterns.push((regex, std::mem::take(items)));
+                pattern.clear();
+            }
+            Ok(())
+        };
+        while let Some(c) = chars.next() {
+            match c {
+                ',' => finish(&mut pattern, &mut items)?,
+                '\\' => match chars.next() {
+                    Some(c) => pattern.push_str(&regex::escape(&c.to_string())),
+                    None => pattern.push_str(r"\\"),
+                },
+                '%' => {
+                    let item = chars.next().unwrap_or('%');
+                    pattern.push_str(match item {
+                        'f' => r"(.+?)",
+                        'l' | 'c' => r"(\d+)",
+                        't' => r"(\w)",
+                        'm' => r"(.*)",
+                        '%' => "%",
+                        _ => return Err(format!("Invalid %{item} in errorformat")),
+                    });
+                    if item != '%' {
+                        items.push(item);
+                    }
+                }
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        finish(&mut pattern, &mut items)?;
+        Ok(Self { patterns })
+    }
+
+    /// The entries in a program's output. Lines of rustc or cargo JSON (`--message-format=json`)
+    /// are understood as they are, the rest have to match one of the patterns.
+    pub fn parse(&self, output: &str) -> Vec<Entry> {
+        output
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(value) => compiler_message(&value),
+                Err(_) => self.parse_line(line),
+            })
+            .collect()
+    }
+
+    fn parse_line(&self, line: &str) -> Option<Entry> {
+        self.patterns.iter().find_map(|(regex, items)| {
+            let captures = regex.captures(line)?;
+            let mut entry = Entry {
+                file: String::new(),
+                line: 0,
+                col: 0,
+                kind: None,
+                text: String::new(),
+            };
+            for (item, capture) in items.iter().zip(captures.iter().skip(1)) {
+                let text = capture.map_or("", |m| m.as_str());
+                match item {
+                    'f' => entry.file = text.to_owned(),
+                    'l' => entry.line = text.parse().ok()?,
+                    'c' => entry.col = text.parse().ok()?,
+                    't' => entry.kind = text.chars().next().map(|c| c.to_ascii_lowercase()),
+                    _ => entry.text = text.to_owned(),
+                }
+            }
+            (!entry.file.is_empty()).then_some(entry)
+        })
+    }
+}
+
+/// A diagnostic from rustc, or from cargo wrapping one, at its primary span. Anything else in
+/// the stream, like cargo's `compiler-artifact` lines, isn't one.
+fn compiler_message(value: &Value) -> Option<Entry> {
+    let message = match value.get("reason").and_then(Value::as_str) {
+        Some("compiler-message") => value.get("message")?,
+        Some(_) => return None,
+        None => value,
+    };
+    let span = message
+        .get("spans")?
+        .as_array()?
+        .iter()
+        .find(|span| span.get("is_primary") == Some(&Value::Bool(true)))?;
+    Some(Entry {
+        file: span.get("file_name")?.as_str()?.to_owned(),
+        line: span.get("line_start")?.as_u64()? as usize,
+        col: span.get("column_start")?.as_u64()? as usize,
+        kind: message
+            .get("level")
+            .and_then(Value::as_str)
+            .and_then(|level| level.chars().next()),
+        text: message.get("message")?.as_str()?.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use std::fs;
+
+    #[test]
+    fn errorformat() {
+        let error = Format::new("%f:%l:%c: %t%*: %m,%f:%l: %m").err();
+        assert_eq!(error.as_deref(), Some("Invalid %* in errorformat"));
+        let format = Format::new(r"%f:%l:%c: %m,%f(%l): %m,%f:%l\,%c %%%m").unwrap();
+        let output = "src/main.rs:3:5: expected `;`\n\
+                      make: *** [all] Error 1\n\
+                      lib.c(12): missing semicolon\n\
+                      a.c:7,2 %odd";
+        let entries = format.parse(output);
+        assert_eq!(
+            entries.iter().map(Entry::describe).collect::<Vec<_>>(),
+            [
+                "src/main.rs|3 col 5| expected `;`",
+                "lib.c|12| missing semicolon",
+                "a.c|7 col 2| odd",
+            ]
+        );
+    }
+
+    #[test]
+    fn cargo_json() {
+        let format = Format::new("%f:%l: %m").unwrap();
+        let output = concat!(
+            r#"{"reason":"compiler-artifact","target":{"name":"dep"}}"#,
+            "\n",
+            r#"{"reason":"compiler-message","message":{"message":"unused variable: `x`","#,
+            r#""level":"warning","spans":[{"file_name":"src/lib.rs","line_start":4,"#,
+            r#""column_start":9,"is_primary":true}]}}"#,
+            "\n",
+            r#"{"message":"mismatched types","level":"error","spans":[{"file_name":"a.rs","#,
+            r#""line_start":2,"column_start":1,"is_primary":false},{"file_name":"b.rs","#,
+            r#""line_start":8,"column_start":3,"is_primary":true}]}"#,
+            "\n",
+            r#"{"message":"aborting due to 1 previous error","level":"error","spans":[]}"#,
+        );
+        let entries = format.parse(output);
+        assert_eq!(
+            entries.iter().map(Entry::describe).collect::<Vec<_>>(),
+            [
+                "src/lib.rs|4 col 9 warning| unused variable: `x`",
+                "b.rs|8 col 3 error| mismatched types",
+            ]
+        );
+    }
+
+    #[test]
+    fn moving_through() {
+        let entry = |file: &str, line| Entry {
+            file: file.to_owned(),
+            line,
+            col: 0,
+            kind: None,
+            text: format!("at {line}"),
+        };
+        let mut list = List::new(
+            ":make".to_string(),
+            vec![entry("a", 1), entry("a", 5), entry("b", 2)],
+        );
+        assert_eq!(list.step(-1), Err("No more items".to_string()));
+        assert_eq!(list.step(1).map(|entry| entry.line), Ok(5));
+        assert_eq!(list.position(), "(2 of 3): at 5");
+        assert_eq!(list.select(9).map(|entry| entry.line), Ok(2));
+        assert_eq!(list.step(1), Err("No more items".to_string()));
+        assert_eq!(list.first_per_file(), [0, 2]);
+        assert_eq!(List::default().step(1), Err("No Errors".to_string()));
+    }
+
+    #[test]
+    fn quickfix() {
+        let dir = temp_dir("quickfix");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "one\ntwo\nthree\n").unwrap();
+        fs::write(&b, "x\ntwo\n").unwrap();
+        let (a, b) = (a.display().to_string(), b.display().to_string());
+        let mut h = Harness::new("");
+        h.keys(&format!(":grep two {a} {b}<CR>")).finish_job();
+        assert_eq!(h.message(), "(1 of 2): two");
+        assert_eq!((h.buffer().filename(), h.cursor()), (a.as_str(), (1, 0)));
+        h.keys(":cnext<CR>");
+        assert_eq!((h.buffer().filename(), h.cursor()), (b.as_str(), (1, 0)));
+        h.keys(":cn<CR>");
+        assert_eq!(h.message(), "No more items");
+        h.keys("Ax<Esc>:cfirst<CR>");
+        assert_eq!(
+            h.message(),
+            "No write since last change (add ! to override)"
+        );
+        h.keys(":e!<CR>:cfirst<CR>");
+        assert_eq!(h.buffer().filename(), a);
+        h.keys(":grep nowhere %<CR>").finish_job();
+        assert_eq!(h.message(), "shell returned 1");
+
+        let errors = dir.join("errors");
+        let json = concat!(
+            r#"{"reason":"compiler-message","message":{"message":"mismatched types","#,
+            r#""level":"error","spans":[{"file_name":"B","line_start":2,"#,
+            r#""column_start":1,"is_primary":true}]}}"#
+        );
+        let output = format!(
+            "{a}:3:2: bad thing\nmake: *** Error 1\n{}\n",
+            json.replace('B', &b)
+        );
+        fs::write(&errors, output).unwrap();
+        h.keys(&format!(
+            ":set makeprg=cat\\ {}\\ &&\\ exit\\ 2<CR>",
+            errors.display()
+        ));
+        h.keys(":make<CR>").finish_job();
+        assert_eq!(h.message(), "(1 of 2): bad thing");
+        assert_eq!(h.cursor(), (2, 1));
+
+        h.keys(":copen<CR>");
+        let listed = |h: &mut Harness, line: &str| h.screen().iter().any(|row| row.ends_with(line));
+        assert!(listed(&mut h, &format!("{a}|3 col 2| bad thing")));
+        assert!(listed(
+            &mut h,
+            &format!("{b}|2 col 1 error| mismatched types")
+        ));
+        h.keys("j<CR>");
+        assert_eq!(h.message(), "(2 of 2): mismatched types");
+        assert_eq!((h.buffer().filename(), h.cursor()), (b.as_str(), (1, 0)));
+        h.keys("<space>j:q<CR>");
+        assert!(!h.state().should_quit());
+        assert!(!listed(&mut h, "bad thing"));
+
+        let extra = dir.join("extra");
+        fs::write(&extra, "added\n").unwrap();
+        h.keys(&format!(":cfdo r {} | w<CR>", extra.display()));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "one\ntwo\nthree\nadded\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "x\ntwo\nadded\n");
+
+        h.keys(&format!(":lgrep! three {a}<CR>")).finish_job();
+        assert_eq!(
+            h.message(),
+            format!(":grep -n three {a} /dev/null: 1 found")
+        );
+        h.keys(":ll<CR>");
+        assert_eq!((h.buffer().filename(), h.cursor()), (a.as_str(), (2, 0)));
+        h.keys(":lnext<CR>");
+        assert_eq!(h.message(), "No more items");
+        h.keys(":clast<CR>");
+        assert_eq!((h.buffer().filename(), h.cursor()), (b.as_str(), (1, 0)));
+    }
+}
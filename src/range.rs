@@ -0,0 +1,100 @@
+//! Line ranges in front of ex commands: `:%!sort`, `:.,+3!jq .`, `:$`.
+//!
+//! An address is `.`, `$` or a line number, followed by any number of `+N`/`-N` (a bare `+` or
+//! `-` is one). Leaving out the line number means `.`, so `:+2` is two lines down. Two
+//! addresses are separated by `,`, and `%` is the whole buffer.
+
+/// Lines `first` to `last`, both included, counted from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub first: usize,
+    pub last: usize,
+}
+
+/// Splits the range off the front of `cmd`, `None` when it doesn't have one. `cursor` is the
+/// cursor's line and `last` the buffer's last one. Lines past either end are the end; a
+/// backwards range is turned around.
+pub fn parse(cmd: &str, cursor: usize, last: usize) -> (Option<Range>, &str) {
+    if let Some(rest) = cmd.strip_prefix('%') {
+        return (Some(Range { first: 0, last }), rest);
+    }
+    let (first, rest) = address(cmd, cursor, last);
+    let (second, rest) = match rest.strip_prefix(',') {
+        Some(after) => {
+            let (second, after) = address(after, cursor, last);
+            (Some(second.unwrap_or(cursor)), after)
+        }
+        None if first.is_none() => return (None, cmd),
+        None => (None, rest),
+    };
+    let first = first.unwrap_or(cursor);
+    let second = second.unwrap_or(first);
+    let range = Range {
+        first: first.min(second),
+        last: first.max(second),
+    };
+    (Some(range), rest)
+}
+
+/// One address, clamped to the buffer
+fn address(s: &str, cursor: usize, last: usize) -> (Option<usize>, &str) {
+    let (base, mut rest) = if let Some(rest) = s.strip_prefix('.') {
+        (Some(cursor as isize), rest)
+    } else if let Some(rest) = s.strip_prefix('$') {
+        (Some(last as isize), rest)
+    } else {
+        match number(s) {
+            // Line numbers count from 1, `:0` is the first line too
+            (Some(n), rest) => (Some(n.max(1) - 1), rest),
+            (None, rest) => (None, rest),
+        }
+    };
+    let mut line = base;
+    while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+        let (n, after) = number(&rest[1..]);
+        let n = n.unwrap_or(1);
+        let from = line.unwrap_or(cursor as isize);
+        line = Some(if sign == '+' {
+            from.saturating_add(n)
+        } else {
+            from.saturating_sub(n)
+        });
+        rest = after;
+    }
+    (line.map(|line| line.clamp(0, last as isize) as usize), rest)
+}
+
+fn number(s: &str) -> (Option<isize>, &str) {
+    let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match s[..digits].parse() {
+        Ok(n) => (Some(n), &s[digits..]),
+        Err(_) => (None, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(cmd: &str) -> (Option<(usize, usize)>, &str) {
+        let (range, rest) = parse(cmd, 4, 9);
+        (range.map(|range| (range.first, range.last)), rest)
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(range("%!sort"), (Some((0, 9)), "!sort"));
+        assert_eq!(range(".,+3!jq ."), (Some((4, 7)), "!jq ."));
+        assert_eq!(range("$"), (Some((9, 9)), ""));
+        assert_eq!(range("12"), (Some((9, 9)), ""));
+        assert_eq!(range("0"), (Some((0, 0)), ""));
+        assert_eq!(range("2,.-"), (Some((1, 3)), ""));
+        assert_eq!(range("+2"), (Some((6, 6)), ""));
+        assert_eq!(range("6,2d"), (Some((1, 5)), "d"));
+        assert_eq!(range(",$"), (Some((4, 9)), ""));
+        assert_eq!(range("+9223372036854775807!sort"), (Some((9, 9)), "!sort"));
+        assert_eq!(range("$-9223372036854775807-9"), (Some((0, 0)), ""));
+        assert_eq!(range("!ls"), (None, "!ls"));
+        assert_eq!(range("set wrap"), (None, "set wrap"));
+    }
+}
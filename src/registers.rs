@@ -0,0 +1,191 @@
+//! Where yanked and deleted text goes, for `p` and `<C-r>` to get back. `"` is the last of
+//! either, `0` the last yank, `1`-`9` the last deletes, newest first, and `a`-`z` only what's
+//! put there by name, `A`-`Z` adding to them. `:`, `/` and `%` are read only and not kept here.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub lines: Vec<String>,
+    /// Whole lines, which `p` puts below the cursor's, rather than text that goes after it
+    pub linewise: bool,
+}
+
+impl Register {
+    pub fn new(lines: Vec<String>, linewise: bool) -> Register {
+        Register { lines, linewise }
+    }
+
+    /// The text as one string, linewise registers ending in a line break
+    #[cfg(test)]
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if self.linewise {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Registers(BTreeMap<char, Register>);
+
+impl Registers {
+    /// Whether `"{name}` can go before a yank or a delete
+    pub fn writable(name: char) -> bool {
+        matches!(name, '"' | '_' | '0'..='9' | 'a'..='z' | 'A'..='Z')
+    }
+
+    pub fn get(&self, name: char) -> Option<&Register> {
+        self.0.get(&name.to_ascii_lowercase())
+    }
+
+    /// Everything that's set, for the state file
+    pub fn iter(&self) -> impl Iterator<Item = (char, &Register)> {
+        self.0.iter().map(|(&name, register)| (name, register))
+    }
+
+    /// Sets one register and nothing else, as the state file does
+    pub fn set(&mut self, name: char, register: Register) {
+        self.0.insert(name, register);
+    }
+
+    /// Takes the ones from `other` that aren't set here
+    pub fn fill(&mut self, other: Registers) {
+        for (name, register) in other.0 {
+            self.0.entry(name).or_insert(register);
+        }
+    }
+
+    /// `y` into `name`, `None` being no `"{name}` before it, which is `0`
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        match name {
+            None | Some('"') => {
+                self.0.insert('0', register.clone());
+                self.0.insert('"', register);
+            }
+            Some(name) => self.store(name, register),
+        }
+    }
+
+    /// `d` or `c` into `name`, `None` being no `"{name}` before it, which shifts `1`-`9` down
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        match name {
+            None | Some('"') => {
+                for n in (b'1'..b'9').rev() {
+                    if let Some(older) = self.0.remove(&(n as char)) {
+                        self.0.insert((n + 1) as char, older);
+                    }
+                }
+                self.0.insert('1', register.clone());
+                self.0.insert('"', register);
+            }
+            Some(name) => self.store(name, register),
+        }
+    }
+
+    /// A register named with `"{name}`, which `"` follows. `_` throws it away, `A`-`Z` add to
+    /// `a`-`z`.
+    fn store(&mut self, name: char, register: Register) {
+        let register = match name {
+            '_' => return,
+            'A'..='Z' => {
+                let lower = name.to_ascii_lowercase();
+                let mut old = self.0.remove(&lower).unwrap_or_default();
+                let mut lines = register.lines.into_iter();
+                // Text after text carries on the same line
+                if !old.linewise && !register.linewise {
+                    if let (Some(last), Some(first)) = (old.lines.last_mut(), lines.next()) {
+                        last.push_str(&first);
+                    }
+                }
+                old.lines.extend(lines);
+                old.linewise |= register.linewise;
+                self.0.insert(lower, old.clone());
+                old
+            }
+            _ => {
+                self.0.insert(name, register.clone());
+                register
+            }
+        };
+        self.0.insert('"', register);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    fn lines(lines: &[&str], linewise: bool) -> Register {
+        Register::new(
+            lines.iter().map(|&line| line.to_owned()).collect(),
+            linewise,
+        )
+    }
+
+    #[test]
+    fn numbered_and_named() {
+        let mut registers = Registers::default();
+        registers.yank(None, lines(&["one"], true));
+        registers.delete(None, lines(&["two"], true));
+        registers.delete(None, lines(&["three"], true));
+        assert_eq!(registers.get('0'), Some(&lines(&["one"], true)));
+        assert_eq!(registers.get('1'), Some(&lines(&["three"], true)));
+        assert_eq!(registers.get('2'), Some(&lines(&["two"], true)));
+        assert_eq!(registers.get('"'), Some(&lines(&["three"], true)));
+
+        registers.yank(Some('a'), lines(&["x"], false));
+        registers.yank(Some('A'), lines(&["y", "z"], false));
+        assert_eq!(registers.get('a'), Some(&lines(&["xy", "z"], false)));
+        assert_eq!(registers.get('A'), registers.get('a'));
+        registers.yank(Some('A'), lines(&["w"], true));
+        assert_eq!(registers.get('a'), Some(&lines(&["xy", "z", "w"], true)));
+        assert_eq!(registers.get('"'), registers.get('a'));
+        // Named ones leave the numbered ones alone
+        registers.delete(Some('b'), lines(&["four"], true));
+        assert_eq!(registers.get('1'), Some(&lines(&["three"], true)));
+
+        registers.delete(Some('_'), lines(&["gone"], true));
+        assert_eq!(registers.get('"'), Some(&lines(&["four"], true)));
+        assert_eq!(registers.get('_'), None);
+    }
+
+    #[test]
+    fn yank_delete_and_put() {
+        let mut h = Harness::new("one\n  two\nthree");
+        h.keys("yyjp");
+        assert_eq!(h.text(), "one\n  two\none\nthree");
+        assert_eq!(h.cursor(), (2, 0));
+        h.keys("jddggP");
+        assert_eq!(h.text(), "three\none\n  two\none");
+        h.keys("j\"add\"byyjj\"aP");
+        assert_eq!(h.text(), "three\n  two\none\none");
+        assert_eq!(h.cursor(), (2, 0));
+        // `"` has the last of them, `1` the last delete and `0` the last yank
+        h.keys("\"bp");
+        assert_eq!(h.text(), "three\n  two\none\n  two\none");
+        assert_eq!(h.cursor(), (3, 2));
+        h.keys("gg\"1p\"0p");
+        assert_eq!(h.text(), "three\nthree\none\n  two\none\n  two\none");
+        h.keys("\"cp");
+        assert_eq!(h.message(), "Nothing in register c");
+        h.keys("\":yy");
+        assert_eq!(h.message(), "Invalid register name: ':'");
+
+        // Text that isn't lines goes after the cursor, which ends up on its last char
+        let mut h = Harness::new("ab");
+        h.keys("/b<CR>0\"/p");
+        assert_eq!(h.text(), "abb");
+        assert_eq!(h.cursor(), (0, 1));
+        h.keys("0\"/P");
+        assert_eq!(h.text(), "babb");
+        assert_eq!(h.cursor(), (0, 0));
+
+        // `cc` keeps the line before it's emptied, `<C-r>` inserts any register
+        let mut h = Harness::new("first\nsecond");
+        h.keys("\"zcclast<Esc>j\"ayy:<C-r>z <C-r>a <C-r>\"");
+        assert_eq!(h.message(), ":first second second");
+    }
+}
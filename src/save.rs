@@ -0,0 +1,285 @@
+//! Writing files so that a crash or a full disk never leaves a half-written file behind.
+//!
+//! Normally the new contents go to a temporary file next to the original, which is synced and
+//! then renamed over it. That would break hard links and symlinks, and can't keep the owner when
+//! someone else's file is writable to us, so then (or with `backupcopy=yes`) the file is
+//! overwritten in place instead.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{chown, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::options::Options;
+
+/// Where the copy of the old file goes. Directories come from `backupdir`, `.` meaning the
+/// file's own.
+fn backup_path(path: &Path, backupdir: &str) -> Option<PathBuf> {
+    let name = format!("{}~", path.file_name()?.to_string_lossy());
+    backupdir.split(',').find_map(|dir| {
+        let dir = match dir {
+            "." => path
+                .parent()
+                .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+            _ => match dir.strip_prefix("~/") {
+                Some(rest) => PathBuf::from(std::env::var_os("HOME")?).join(rest),
+                None => PathBuf::from(dir),
+            },
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir
+        };
+        dir.is_dir().then(|| dir.join(&name))
+    })
+}
+
+/// Copies the old file to `backup`. A backup that's only kept while writing must not clobber
+/// one that's already there, so as Vim does its last character goes from `~` to `z`, `y` and
+/// on down until the name is free.
+fn make_backup(path: &Path, backup: PathBuf, keep: bool) -> io::Result<PathBuf> {
+    if keep {
+        fs::copy(path, &backup)?;
+        return Ok(backup);
+    }
+    let name = backup.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.strip_suffix('~').unwrap_or(&name);
+    for last in std::iter::once('~').chain(('a'..='z').rev()) {
+        let free = backup.with_file_name(format!("{stem}{last}"));
+        match OpenOptions::new().write(true).create_new(true).open(&free) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = fs::copy(path, &free) {
+            let _ = fs::remove_file(&free);
+            return Err(e);
+        }
+        return Ok(free);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Cannot create backup file (add ! to override)",
+    ))
+}
+
+/// Whether the file has to be overwritten where it is rather than replaced
+fn in_place(path: &Path, options: &Options) -> io::Result<bool> {
+    match options.backupcopy.as_str() {
+        "yes" => return Ok(true),
+        "no" => return Ok(false),
+        _ => {}
+    }
+    let Ok(link) = fs::symlink_metadata(path) else {
+        return Ok(false);
+    };
+    Ok(link.file_type().is_symlink() || link.nlink() > 1)
+}
+
+fn write_in_place(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// A new file next to `path` that nothing else is using
+fn create_temp(path: &Path) -> io::Result<(File, PathBuf)> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    for i in 0.. {
+        let temp = dir.join(format!(".{name}.{}.{i}.rim-tmp", std::process::id()));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Gives the temporary file the original's owner and permissions, then the new contents.
+/// `Ok(false)` if the owner can't be kept.
+fn fill_temp(file: &mut File, temp: &Path, path: &Path, contents: &[u8]) -> io::Result<bool> {
+    if let Ok(original) = fs::metadata(path) {
+        if chown(temp, Some(original.uid()), Some(original.gid())).is_err() {
+            return Ok(false);
+        }
+        file.set_permissions(original.permissions())?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+/// Writes a temporary file and renames it over `path`. `Ok(false)` means the result couldn't
+/// look like the original and it should be written in place after all.
+fn write_and_rename(path: &Path, contents: &[u8]) -> io::Result<bool> {
+    // Probably a directory we can't write to, though the file itself may be writable
+    let Ok((mut file, temp)) = create_temp(path) else {
+        return Ok(false);
+    };
+    let filled = fill_temp(&mut file, &temp, path, contents);
+    if !matches!(filled, Ok(true)) {
+        let _ = fs::remove_file(&temp);
+        return filled;
+    }
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    // The rename itself only survives a crash once the directory is synced too
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+    Ok(true)
+}
+
+/// Writes `path` with what's in `original` in it, like a swap or undo file does, so it's only
+/// readable by whoever can read `original`: it gets `original`'s permissions, or only the
+/// owner's when there's no such file yet
+pub fn write_copy(path: &Path, contents: &[u8], original: &Path) -> io::Result<()> {
+    let mode = fs::metadata(original).map_or(0o600, |original| original.mode() & 0o777);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    // `mode` only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.write_all(contents)
+}
+
+/// Replaces the contents of `path` as safely as `options` allow. With `force`, going without a
+/// backup is fine.
+pub fn save(path: &Path, contents: &[u8], options: &Options, force: bool) -> io::Result<()> {
+    let backup = match backup_path(path, &options.backupdir) {
+        _ if !(options.backup || options.writebackup) || !path.is_file() => None,
+        Some(backup) => match make_backup(path, backup, options.backup) {
+            Ok(backup) => Some(backup),
+            Err(_) if force => None,
+            Err(e) => return Err(e),
+        },
+        None if force => None,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Cannot create backup file (add ! to override)",
+            ))
+        }
+    };
+
+    if in_place(path, options)? || !write_and_rename(path, contents)? {
+        write_in_place(path, contents)?;
+    }
+
+    if let (Some(backup), false) = (backup, options.backup) {
+        // Only needed while writing, and made fresh for it
+        let _ = fs::remove_file(backup);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::harness::temp_dir;
+
+    #[test]
+    fn replaces_contents_and_keeps_permissions() {
+        let dir = temp_dir("save-replace");
+        let path = dir.join("file");
+        fs::write(&path, "a much longer original\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        save(&path, b"short\n", &Options::default(), false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short\n");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        // No temporary or backup files left over
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn links_survive() {
+        let dir = temp_dir("save-links");
+        let (path, hard, soft) = (dir.join("file"), dir.join("hard"), dir.join("soft"));
+        fs::write(&path, "old\n").unwrap();
+        fs::hard_link(&path, &hard).unwrap();
+        symlink(&path, &soft).unwrap();
+
+        save(&hard, b"via hard link\n", &Options::default(), false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "via hard link\n");
+        // Written in place, so nothing of the longer old contents may be left after the new
+        save(&hard, b"short\n", &Options::default(), false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short\n");
+        save(&soft, b"via symlink\n", &Options::default(), false).unwrap();
+        assert!(fs::symlink_metadata(&soft)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "via symlink\n");
+    }
+
+    #[test]
+    fn backups() {
+        let dir = temp_dir("save-backups");
+        let backups = dir.join("backups");
+        fs::create_dir(&backups).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, "one\n").unwrap();
+        let mut options = Options {
+            backup: true,
+            backupdir: format!("/nonexistent,{}", backups.display()),
+            ..Options::default()
+        };
+        save(&path, b"two\n", &options, false).unwrap();
+        assert_eq!(fs::read_to_string(backups.join("file~")).unwrap(), "one\n");
+
+        // The backup kept from before isn't this write's to remove
+        options.backup = false;
+        save(&path, b"three\n", &options, false).unwrap();
+        assert_eq!(fs::read_to_string(backups.join("file~")).unwrap(), "one\n");
+        assert_eq!(fs::read_dir(&backups).unwrap().count(), 1);
+
+        options.backupdir = "/nonexistent".to_string();
+        assert!(save(&path, b"four\n", &options, false).is_err());
+        save(&path, b"four\n", &options, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+    }
+
+    #[test]
+    fn write_backup_leaves_existing_backups_alone() {
+        let dir = temp_dir("save-write-backup");
+        let path = dir.join("foo");
+        fs::write(&path, "old\n").unwrap();
+        fs::write(dir.join("foo~"), "mine\n").unwrap();
+        fs::write(dir.join("fooz"), "also mine\n").unwrap();
+        let options = Options {
+            backupdir: ".".to_string(),
+            ..Options::default()
+        };
+        save(&path, b"new\n", &options, false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(fs::read_to_string(dir.join("foo~")).unwrap(), "mine\n");
+        assert_eq!(fs::read_to_string(dir.join("fooz")).unwrap(), "also mine\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        let backup = make_backup(&path, dir.join("foo~"), false).unwrap();
+        assert_eq!(backup, dir.join("fooy"));
+        assert_eq!(fs::read_to_string(backup).unwrap(), "new\n");
+    }
+}
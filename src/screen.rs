@@ -1,57 +1,255 @@
-use std::{io::stdout, panic};
-
-use crossterm::{
-    cursor::{self, SetCursorStyle},
-    execute,
-    style::{self, Color},
-    terminal::{self, disable_raw_mode, enable_raw_mode},
-    Result,
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
 };
 
-use crate::window::Window;
+use crossterm::{cursor::SetCursorStyle, event::KeyEvent, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::{
+    args::Layout,
+    backend::BackendRef,
+    buffer::{self, Buffer, DiskChange},
+    complete::{self, CompleteOpt, Completion, Context, Popup},
+    diff::{self, Diff},
+    encoding::{self, FileFormat},
+    highlight::{ColorDepth, Highlights, HighlightsRef},
+    history::History,
+    job::{self, Job},
+    loader::{self, Event},
+    lsp::{self, Client, Message, Request},
+    marks::Place,
+    options::{self, LocalOptions, Options, OptionsRef},
+    quickfix::{Entry, Format, List, Pick},
+    range::Range,
+    registers::{Register, Registers},
+    session::{self, Positions, Rect, SessionWindow, StateFile},
+    shell,
+    state::Mode,
+    swap::{self, Swapped},
+    term::Terminal,
+    window::Window,
+};
+
+/// A file that's been read but waits on the user to say what to do about its swap file
+struct SwapPrompt {
+    window: usize,
+    buffer: Buffer,
+    swap: PathBuf,
+    swapped: Swapped,
+}
+
+/// What a running command's output is for
+enum JobKind {
+    /// `:!cmd` and `:w !cmd`, shown once it's done. An interactive one has the terminal to
+    /// itself meanwhile.
+    Show { interactive: bool },
+    /// `:{range}!cmd`: replaces lines `range` of the buffer with the id
+    Filter { buffer: usize, range: Range },
+    /// `:r !cmd`: goes below the cursor of the buffer with the id
+    Read { buffer: usize },
+    /// `:make` and `:grep`: a new quickfix or location list
+    List(ListJob),
+}
+
+struct ListJob {
+    title: String,
+    format: Format,
+    location: bool,
+    jump: bool,
+}
+
+/// A list from `:make` or `:grep`, until `State` puts it with `set_list`
+pub struct FinishedList {
+    list: List,
+    location: bool,
+    jump: bool,
+}
+
+/// The window `:copen` or `:lopen` opened
+struct ListWindow {
+    window: usize,
+    /// Where `<CR>` jumps, and whose location list it shows
+    origin: usize,
+    location: bool,
+}
+
+struct RunningJob {
+    job: Job,
+    cmd: String,
+    kind: JobKind,
+    output: Vec<u8>,
+    errors: Vec<u8>,
+}
+
+/// Output too long for the message line, over the bottom of the windows until a key is pressed
+struct Pager {
+    lines: Vec<String>,
+    /// The first one shown
+    top: usize,
+}
+
+/// A question every key goes to until it's answered
+enum Prompt {
+    Swap(Box<SwapPrompt>),
+    /// The file in window `i` changed on disk
+    Reload(usize),
+}
 
 pub struct Screen {
+    backend: BackendRef,
+    options: OptionsRef,
+    highlights: HighlightsRef,
+    /// Mirrors `State`'s, for statuslines
+    mode: Rc<Cell<Mode>>,
+    /// Every tab page's windows. Those on tab pages that aren't shown are hidden.
     windows: Vec<Window>,
     cur_window: usize,
+    /// The tab page shown, and how many there are
+    cur_tab: usize,
+    tab_count: usize,
 
+    /// Byte offset into `message`, which starts with the `:` or `/` of the command line
     command_mode_cursor: Option<usize>,
+    history: History,
+    /// Where `history` and the rest of `StateFile` are kept between runs, once `load_state`
+    /// says so
+    state_file: Option<PathBuf>,
+    /// Where the cursor was in files that were left, by their absolute names
+    positions: Positions,
+    /// `<Up>` and `<Down>` going through `history`: where they are, and what was typed before
+    /// the first one, which entries have to start with
+    browsing: Option<(usize, String)>,
+    /// `<C-r>` was typed, the next key says which register to insert
+    register_pending: bool,
+    registers: Registers,
+    /// `"` was typed, the next key names the register for the next yank, delete or put
+    register_name_pending: bool,
+    /// What `"{name}` named, until a yank, delete or put uses it
+    register_name: Option<char>,
+    /// `m`, `'` or `` ` `` was typed, the next key names the mark
+    mark_pending: Option<char>,
+    /// `A`-`Z`, as of when they were set. While their buffer is open it keeps them up to date.
+    global_marks: BTreeMap<char, Place>,
+    /// The `q:` window: which one it is and whether it's `:` or `/` history
+    command_window: Option<(usize, char)>,
+    /// `<Tab>` on the command line, until another key comes along
+    completion: Option<Completion>,
+    list_window: Option<ListWindow>,
 
     message: String,
     message_is_error: bool,
+
+    prompt: Option<Prompt>,
+
+    /// For buffers to hear back from their background loads and saves
+    events: loader::Sender,
+    /// Until the event loop takes it
+    receiver: Option<loader::Receiver>,
+
+    /// The one external command that can run at a time
+    job: Option<RunningJob>,
+    jobs: job::Sender,
+    /// Until the event loop takes it
+    job_receiver: Option<job::Receiver>,
+    pager: Option<Pager>,
+    finished_list: Option<FinishedList>,
+
+    /// A language server for each filetype that's needed one
+    lsp: Vec<Client>,
+    /// Filetypes whose server wouldn't start or has exited, so it isn't started again and again
+    lsp_failed: Vec<String>,
+    /// Lines over the windows by the cursor, like `K`'s, until the next key
+    float: Option<Vec<String>>,
+    /// Insert mode completion, until a key that isn't for its menu
+    popup: Option<Popup>,
+
+    /// What `:w -` wrote, for stdout once the terminal is given back
+    stdout: Option<Vec<u8>>,
+
+    /// The files from the command line, for `:next` and `:prev`
+    arglist: Vec<String>,
+    arg_index: usize,
 }
 
 impl Screen {
-    fn setup() -> Result<()> {
-        enable_raw_mode()?;
-        panic::set_hook(Box::new(|info| {
-            Self::finish().unwrap();
-            eprintln!("{info}");
-        }));
-
-        execute!(
-            stdout(),
-            terminal::EnterAlternateScreen,
-            cursor::MoveTo(0, 0),
-            cursor::SetCursorStyle::SteadyBlock,
-        )
-    }
-
-    pub fn finish() -> Result<()> {
-        disable_raw_mode()?;
-        execute!(stdout(), terminal::LeaveAlternateScreen)
+    pub fn finish(&mut self) -> Result<()> {
+        for window in &mut self.windows {
+            window.discard_swap_now();
+        }
+        if let Some(path) = self.state_file.clone() {
+            // Nowhere left to complain about it
+            let _ = self.write_state(&path);
+        }
+        self.backend.borrow_mut().finish()
     }
 
-    pub fn new() -> Result<Self> {
-        Self::setup()?;
+    pub fn new(backend: BackendRef) -> Result<Self> {
+        backend.borrow_mut().setup()?;
+        let (rows, cols) = backend.borrow().size()?;
+        let options = Rc::new(RefCell::new(Options::default()));
+        let highlights = Rc::new(RefCell::new(Highlights::new(ColorDepth::from_env())));
+        let mode = Rc::new(Cell::new(Mode::Normal));
+        let (events, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (jobs, job_receiver) = tokio::sync::mpsc::unbounded_channel();
 
-        let screen = Self {
-            windows: vec![Window::new(Screen::usable_rows(), Screen::cols(), (0, 0))],
+        let mut screen = Self {
+            // Status bar and messages
+            windows: vec![Window::new(
+                rows - 2,
+                cols,
+                (0, 0),
+                backend.clone(),
+                options.clone(),
+                highlights.clone(),
+                mode.clone(),
+            )],
+            backend,
+            options,
+            highlights,
+            mode,
             cur_window: 0,
+            cur_tab: 0,
+            tab_count: 1,
             command_mode_cursor: None,
+            history: History::default(),
+            state_file: None,
+            positions: Positions::default(),
+            browsing: None,
+            register_pending: false,
+            registers: Registers::default(),
+            register_name_pending: false,
+            register_name: None,
+            mark_pending: None,
+            global_marks: BTreeMap::new(),
+            command_window: None,
+            completion: None,
+            list_window: None,
             message: String::new(),
             message_is_error: false,
+            prompt: None,
+            events,
+            receiver: Some(receiver),
+            job: None,
+            jobs,
+            job_receiver: Some(job_receiver),
+            pager: None,
+            finished_list: None,
+            lsp: Vec::new(),
+            lsp_failed: Vec::new(),
+            float: None,
+            popup: None,
+            stdout: None,
+            arglist: Vec::new(),
+            arg_index: 0,
         };
 
+        screen.layout_statuslines()?;
         screen.draw()?;
         Ok(screen)
     }
@@ -66,262 +264,3368 @@ impl Screen {
     }
 
     pub fn new_vertical_split(&mut self, filename: Option<String>) -> Result<()> {
+        self.active_window_mut().set_statusline(true)?;
         let half_width = self.active_window().width() / 2;
-        let (width_a, width_b) = if self.active_window().width() % 2 == 0 {
+        let (width_a, width_b) = if self.active_window().width().is_multiple_of(2) {
             (half_width, half_width)
         } else {
             (half_width, half_width + 1)
         };
         self.active_window_mut().set_width(width_a);
-        let mut new_window = Window::new(
-            self.active_window().height(),
-            width_b,
-            (
-                self.active_window().loc().0,
-                self.active_window().loc().1 + width_a,
-            ),
-        );
+        let (row, col) = self.active_window().loc();
+        self.push_window(self.active_window().height(), width_b, (row, col + width_a));
         if let Some(filename) = filename {
-            new_window.load_file(filename)?;
+            self.load_into(self.windows.len() - 1, filename)?;
         }
-        self.windows.push(new_window);
+        self.layout_statuslines()?;
         self.draw()?;
         self.active_window().print_divider()?;
-        self.cur_window = self.windows.len() - 1;
-        Ok(())
+        self.focus(self.windows.len() - 1)
     }
 
     pub fn new_horizontal_split(&mut self, filename: Option<String>) -> Result<()> {
-        let half_height = self.active_window().height() / 2;
-        let (height_a, height_b) = if self.active_window().height() % 2 == 0 {
-            (half_height, half_height)
-        } else {
-            (half_height, half_height + 1)
+        self.active_window_mut().set_statusline(true)?;
+        let height = self.active_window().height();
+        self.split_below(height - height / 2)?;
+        if let Some(filename) = filename {
+            self.load_into(self.cur_window, filename)?;
+        }
+        Ok(())
+    }
+
+    /// `:terminal [cmd]`: runs `cmd`, or else a shell, in a new window below
+    pub fn terminal(&mut self, cmd: Option<String>) -> Result<()> {
+        let cmd = match cmd.map(|cmd| self.expand(&cmd)).transpose() {
+            Ok(cmd) => cmd,
+            Err(e) => return self.set_error_message(e),
+        };
+        self.active_window_mut().set_statusline(true)?;
+        let height = self.active_window().height();
+        if height < 3 {
+            return self.set_error_message("Not enough room");
+        }
+        self.split_below(height - height / 2)?;
+        let (shell, scrollback) = {
+            let options = self.options.borrow();
+            (options.shell.clone(), options.termwinscroll)
         };
-        self.active_window_mut().set_height(height_a);
-        let mut new_window = Window::new(
-            height_b - 1,
+        let size = self.active_window().terminal_size();
+        match Terminal::spawn(&shell, cmd.as_deref(), size, scrollback, &self.jobs) {
+            Ok(terminal) => {
+                self.active_window_mut().start_terminal(terminal)?;
+                self.reprint_cursor()
+            }
+            Err(e) => {
+                self.close_window(self.cur_window)?;
+                self.set_error_message(e)
+            }
+        }
+    }
+
+    /// Keys typed in terminal-job mode, for the program in the active window
+    pub fn terminal_keys(&mut self, keys: &[KeyEvent]) -> Result<()> {
+        if let Some(terminal) = self.active_window_mut().terminal_mut() {
+            for &key in keys {
+                terminal.key(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts the cursor where the active window's `:terminal` program has it
+    pub fn follow_terminal(&mut self) -> Result<()> {
+        self.active_window_mut().terminal_changed()?;
+        self.reprint_cursor()
+    }
+
+    /// Gives the bottom `rows` of the active window, statusline included, to a new window and
+    /// makes that the active one
+    fn split_below(&mut self, rows: usize) -> Result<()> {
+        self.active_window_mut().set_statusline(true)?;
+        let height = self.active_window().height() - rows;
+        self.active_window_mut().set_height(height);
+        let (row, col) = self.active_window().loc();
+        self.push_window(
+            rows - 1,
             self.active_window().width(),
-            (
-                self.active_window().loc().0 + height_a + 1,
-                self.active_window().loc().1,
-            ),
+            (row + height + 1, col),
         );
-        if let Some(filename) = filename {
-            new_window.load_file(filename)?;
+        self.layout_statuslines()?;
+        self.focus(self.windows.len() - 1)?;
+        self.draw()
+    }
+
+    /// A new last window on the shown tab page
+    fn push_window(&mut self, height: usize, width: usize, loc: (usize, usize)) {
+        let mut window = Window::new(
+            height,
+            width,
+            loc,
+            self.backend.clone(),
+            self.options.clone(),
+            self.highlights.clone(),
+            self.mode.clone(),
+        );
+        window.set_tab(self.cur_tab);
+        self.windows.push(window);
+    }
+
+    /// Window `i` goes away and the window it was split off from takes its rows back
+    fn close_window(&mut self, i: usize) -> Result<()> {
+        let mut closed = self.windows.remove(i);
+        closed.discard_swap();
+        let (row, col) = closed.loc();
+        let above = self.windows.iter().position(|window| {
+            window.tab() == closed.tab()
+                && window.bottom() == row
+                && window.loc().1 == col
+                && window.width() == closed.width()
+        });
+        if let Some(j) = above {
+            let height = self.windows[j].height() + closed.bottom() - row;
+            self.windows[j].set_height(height);
         }
-        self.windows.push(new_window);
-        self.cur_window = self.windows.len() - 1;
+        // The windows after it move up one
+        let shift = |j: usize| if j > i { j - 1 } else { j };
+        if let Some((window, _)) = &mut self.command_window {
+            *window = shift(*window);
+        }
+        self.list_window = self.list_window.take().and_then(|list| {
+            (list.window != i).then(|| ListWindow {
+                window: shift(list.window),
+                origin: if list.origin == i {
+                    above.unwrap_or(0)
+                } else {
+                    shift(list.origin)
+                },
+                location: list.location,
+            })
+        });
+        // A window on another tab page closing leaves the cursor where it is
+        let next = if closed.tab() == self.cur_tab {
+            above.unwrap_or_else(|| self.first_window(self.cur_tab))
+        } else {
+            shift(self.cur_window)
+        };
+        self.layout_statuslines()?;
+        self.focus(next)?;
         self.draw()
     }
 
-    pub fn load_file(&mut self, filename: Option<String>) -> Result<()> {
-        if let Some(filename) = filename {
-            self.active_window_mut().load_file(filename)?;
+    /// Makes window `i` the active one, showing its tab page if it's on another
+    fn focus(&mut self, i: usize) -> Result<()> {
+        let tab = self.windows[i].tab();
+        for (j, window) in self.windows.iter_mut().enumerate() {
+            if window.tab() == tab {
+                window.set_active(i == j)?;
+            }
         }
-        Ok(())
+        self.cur_window = i;
+        if tab != self.cur_tab {
+            self.show_tab(tab)?;
+        }
+        self.print_tabline()?;
+        self.reprint_cursor()
     }
 
-    // TODO: lots of redundant logic here
-    pub fn move_to_left_window(&mut self) -> Result<()> {
-        let active_loc = self.active_window().loc();
-        if let Some((i, _)) = self
-            .windows
+    /// The first window on tab page `tab`
+    fn first_window(&self, tab: usize) -> usize {
+        self.windows
             .iter()
-            .enumerate()
-            .filter(|(_, window)| window.loc().1 + window.width() == active_loc.1)
-            .min_by_key(|(_, window)| window.loc().0.abs_diff(active_loc.0))
-        {
-            self.cur_window = i;
-            self.reprint_cursor()?;
+            .position(|window| window.tab() == tab)
+            .unwrap_or(0)
+    }
+
+    /// Windows along the bottom only get a statusline as `laststatus` says. The others always
+    /// have one, it's what separates them from the window below.
+    fn layout_statuslines(&mut self) -> Result<()> {
+        let bottom = self.messageline_row();
+        let laststatus = self.options.borrow().laststatus;
+        for tab in 0..self.tab_count {
+            let windows = self.windows.iter().filter(|w| w.tab() == tab).count();
+            let shown = match laststatus {
+                0 => false,
+                1 => windows > 1,
+                _ => true,
+            };
+            for window in &mut self.windows {
+                if window.tab() == tab && window.bottom() == bottom {
+                    window.set_statusline(shown)?;
+                }
+            }
         }
         Ok(())
     }
 
-    pub fn move_to_right_window(&mut self) -> Result<()> {
-        let active_loc = self.active_window().loc();
-        let active_width = self.active_window().width();
-        if let Some((i, _)) = self
-            .windows
-            .iter()
-            .enumerate()
-            .filter(|(_, window)| window.loc().1 == active_loc.1 + active_width)
-            .min_by_key(|(_, window)| window.loc().0.abs_diff(active_loc.0))
-        {
-            self.cur_window = i;
-            self.reprint_cursor()?;
+    /// `:tabnew [file]` and `:tabedit [file]`: a tab page after the shown one, with a single
+    /// window showing `file` or else nothing
+    pub fn new_tab(&mut self, filename: Option<String>) -> Result<()> {
+        if self.command_window.is_some() {
+            return self.set_error_message("Invalid in command-line window");
+        }
+        if self.tab_count == 1 {
+            if self
+                .windows
+                .iter()
+                .any(|w| w.loc().0 == 0 && w.height() < 2)
+            {
+                return self.set_error_message("Not enough room");
+            }
+            self.move_windows(true)?;
+        }
+        let tab = self.cur_tab + 1;
+        for window in &mut self.windows {
+            if window.tab() >= tab {
+                window.set_tab(window.tab() + 1);
+            }
+        }
+        self.tab_count += 1;
+        let (rows, cols) = self.backend.borrow().size()?;
+        let top = self.top();
+        self.push_window(rows - 2 - top, cols, (top, 0));
+        let i = self.windows.len() - 1;
+        self.windows[i].set_tab(tab);
+        self.layout_statuslines()?;
+        self.focus(i)?;
+        match filename {
+            Some(filename) => self.load_into(i, filename),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    pub fn move_to_up_window(&mut self) -> Result<()> {
-        let active_loc = self.active_window().loc();
-        if let Some((i, _)) = self
+    /// `:tabnext [n]`: tab page `n`, counting from 1, or else the next one
+    pub fn tab_next(&mut self, n: Option<String>) -> Result<()> {
+        match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(n @ 1..)) if n <= self.tab_count => self.goto_tab(n - 1),
+            Some(_) => self.set_error_message("Invalid argument"),
+            None => self.step_tab(1),
+        }
+    }
+
+    /// `gt` and `gT`: the tab page `step` along, going around at either end
+    pub fn step_tab(&mut self, step: isize) -> Result<()> {
+        let count = self.tab_count as isize;
+        let tab = (self.cur_tab as isize + step).rem_euclid(count) as usize;
+        self.goto_tab(tab)
+    }
+
+    /// Tab page `tab`, at the window that was active there
+    fn goto_tab(&mut self, tab: usize) -> Result<()> {
+        if self.command_window.is_some() {
+            return self.set_error_message("Invalid in command-line window");
+        }
+        let active = self
             .windows
             .iter()
-            .enumerate()
-            .filter(|(_, window)| window.loc().0 + window.height() + 1 == active_loc.0)
-            .min_by_key(|(_, window)| window.loc().1.abs_diff(active_loc.1))
-        {
-            self.cur_window = i;
-            self.reprint_cursor()?;
-        }
-        Ok(())
+            .position(|window| window.tab() == tab && window.active());
+        self.focus(active.unwrap_or_else(|| self.first_window(tab)))
     }
 
-    pub fn move_to_down_window(&mut self) -> Result<()> {
-        let active_loc = self.active_window().loc();
-        let active_height = self.active_window().height() + 1;
-        if let Some((i, _)) = self
+    /// `:tabclose[!]`: the shown tab page and all its windows, unless one of them has changes
+    /// that weren't written. The tab page after it is shown instead.
+    pub fn close_tab(&mut self, force: bool) -> Result<()> {
+        if self.command_window.is_some() {
+            return self.set_error_message("Invalid in command-line window");
+        }
+        if self.tab_count == 1 {
+            return self.set_error_message("Cannot close last tab page");
+        }
+        let tab = self.cur_tab;
+        let windows = self.windows.iter().filter(|window| window.tab() == tab);
+        if !force && windows.clone().any(Window::unsaved_changes) {
+            return self.set_error_message("No write since last change (add ! to override)");
+        }
+        if self
+            .list_window
+            .as_ref()
+            .is_some_and(|list| self.windows[list.window].tab() == tab)
+        {
+            self.list_window = None;
+        }
+        for i in (0..self.windows.len()).rev() {
+            if self.windows[i].tab() != tab {
+                continue;
+            }
+            self.remember_position(i);
+            self.windows.remove(i).discard_swap();
+            if let Some(list) = &mut self.list_window {
+                let shift = |j: usize| if j > i { j - 1 } else { j };
+                list.window = shift(list.window);
+                list.origin = shift(list.origin);
+            }
+        }
+        for window in &mut self.windows {
+            if window.tab() > tab {
+                window.set_tab(window.tab() - 1);
+            }
+        }
+        self.tab_count -= 1;
+        if self.tab_count == 1 {
+            self.move_windows(false)?;
+        }
+        let next = tab.min(self.tab_count - 1);
+        let active = self
             .windows
             .iter()
-            .enumerate()
-            .filter(|(_, window)| window.loc().0 == active_loc.0 + active_height)
-            .min_by_key(|(_, window)| window.loc().1.abs_diff(active_loc.1))
+            .position(|window| window.tab() == next && window.active());
+        self.cur_window = active.unwrap_or_else(|| self.first_window(next));
+        self.show_tab(next)?;
+        self.focus(self.cur_window)
+    }
+
+    /// Draws tab page `tab`'s windows, the others being hidden from now on
+    fn show_tab(&mut self, tab: usize) -> Result<()> {
+        self.cur_tab = tab;
+        for window in &mut self.windows {
+            window.set_hidden(window.tab() != tab);
+        }
+        // Windows don't draw their last column, so what the other tab page had there stays
+        let (rows, cols) = (self.messageline_row(), self.cols());
         {
-            self.cur_window = i;
-            self.reprint_cursor()?;
+            let style = self.highlights.borrow().style("Normal");
+            let mut backend = self.backend.borrow_mut();
+            for row in 0..rows {
+                backend.move_to(row, 0)?;
+                backend.print(&" ".repeat(cols), style)?;
+            }
+        }
+        // Dividers aren't part of `draw`
+        for window in &self.windows {
+            if window.loc().1 + window.width() < cols {
+                window.print_divider()?;
+            }
+        }
+        self.draw()
+    }
+
+    /// The row below the tabline, which is only there with more than one tab page
+    fn top(&self) -> usize {
+        usize::from(self.tab_count > 1)
+    }
+
+    /// Makes room for the tabline above every tab page's windows, or gives its row back
+    fn move_windows(&mut self, down: bool) -> Result<()> {
+        for window in &mut self.windows {
+            let (row, col) = window.loc();
+            match (down, row) {
+                (true, 0) => window.set_height(window.height() - 1),
+                (false, 1) => window.set_height(window.height() + 1),
+                _ => {}
+            }
+            window.set_loc(if down { (row + 1, col) } else { (row - 1, col) });
+            // Keeps the cursor in view
+            window.options_changed()?;
         }
         Ok(())
     }
 
-    pub fn set_cursor_shape(&mut self, shape: SetCursorStyle) -> Result<()> {
-        execute!(stdout(), shape)
+    /// `rim -d` and `:diffthis`: the active window, or with `all` every window on the tab page,
+    /// in diff mode. The first window in it is compared with the second, and the rest with the
+    /// first.
+    pub fn diff_this(&mut self, all: bool) -> Result<()> {
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            let wanted = if all {
+                window.tab() == self.cur_tab
+            } else {
+                i == self.cur_window
+            };
+            if wanted && window.diff().is_none() {
+                window.set_diff(Some(Diff::default()))?;
+            }
+        }
+        self.diff_sync()
     }
 
-    fn draw(&self) -> Result<()> {
-        for window in &self.windows {
-            window.draw()?;
-            let loc = window.loc();
-            if loc.1 + window.width() < Screen::cols() {}
+    /// `:diffoff`: every window on the tab page out of diff mode
+    pub fn diff_off(&mut self) -> Result<()> {
+        for window in &mut self.windows {
+            if window.tab() == self.cur_tab && window.diff().is_some() {
+                window.set_diff(None)?;
+            }
         }
-        self.print_messageline()?;
         self.reprint_cursor()
     }
 
-    fn reprint_cursor(&self) -> Result<()> {
-        if let Some(col) = self.command_mode_cursor {
-            execute!(
-                stdout(),
-                cursor::MoveTo(col as u16, Screen::rows() as u16),
-                cursor::Show
-            )
-        } else {
-            self.active_window().reprint_cursor()
+    /// After every key: in diff mode, the differences are worked out again once a text has
+    /// changed, and the other windows scroll to the lines that go with the active one's
+    pub fn diff_sync(&mut self) -> Result<()> {
+        let windows: Vec<usize> = (0..self.windows.len())
+            .filter(|&i| self.windows[i].tab() == self.cur_tab && self.windows[i].diff().is_some())
+            .collect();
+        let [first, second, ..] = windows[..] else {
+            return Ok(());
+        };
+        let version = |window: &Window| {
+            let buffer = window.buffer();
+            (buffer.id(), buffer.changes(), buffer.loading())
+        };
+        for &i in &windows {
+            let other = if i == first { second } else { first };
+            let compared = [version(&self.windows[i]), version(&self.windows[other])];
+            if self.windows[i]
+                .diff()
+                .is_some_and(|diff| diff.compared == Some(compared))
+            {
+                continue;
+            }
+            let lines = self.windows[i].buffer().lines();
+            let hunks = diff::hunks(lines, self.windows[other].buffer().lines());
+            let diff = Diff::new(hunks, lines.len(), compared);
+            self.windows[i].set_diff(Some(diff))?;
+        }
+        let active = &self.windows[self.cur_window];
+        let Some(hunks) = active.diff().map(|diff| &diff.hunks) else {
+            return self.reprint_cursor();
+        };
+        // By way of the first window, which the others are compared with
+        let to_first = |row| match self.cur_window == first {
+            true => row,
+            false => diff::line_across(hunks, row, false),
+        };
+        let (top, row) = (
+            to_first(active.top_line()),
+            to_first(active.adjusetd_cursor().0),
+        );
+        let mut scrolls = Vec::new();
+        for &i in &windows {
+            if i == self.cur_window {
+                continue;
+            }
+            let across = |row| match (i == first, self.windows[i].diff()) {
+                (false, Some(diff)) => diff::line_across(&diff.hunks, row, true),
+                _ => row,
+            };
+            scrolls.push((i, across(top), across(row)));
         }
+        for (i, top, row) in scrolls {
+            self.windows[i].scroll_to(top, row)?;
+        }
+        self.reprint_cursor()
     }
 
-    /// For use in `draw`
-    fn print_messageline(&self) -> Result<()> {
-        execute!(stdout(), cursor::MoveTo(0, Screen::rows() as u16))?;
-        let formatted_message = if self.message.len() > Screen::cols() {
-            self.message[..Screen::cols()].to_owned()
-        } else {
-            let padding = " ".repeat(Screen::cols() - self.message.len());
-            format!("{}{}", self.message, padding)
+    /// `]c` and `[c`: to the first line of the next or previous difference in diff mode
+    pub fn step_diff(&mut self, step: isize) -> Result<()> {
+        let window = self.active_window();
+        let Some(diff) = window.diff() else {
+            return Ok(());
         };
-        if self.message_is_error {
-            execute!(
-                stdout(),
-                style::ResetColor,
-                style::SetForegroundColor(Color::Red),
-                style::Print(formatted_message)
-            )
+        let row = window.adjusetd_cursor().0;
+        let last = window.buffer().lines().len() - 1;
+        let mut starts = diff.hunks.iter().map(|hunk| hunk.a.start.min(last));
+        let next = if step > 0 {
+            starts.find(|&start| start > row)
         } else {
-            execute!(stdout(), style::ResetColor, style::Print(formatted_message))
+            starts.rev().find(|&start| start < row)
+        };
+        match next {
+            Some(next) => self.active_window_mut().goto(next, 0),
+            None => Ok(()),
         }
     }
 
-    /// Usable on its own
-    fn reprint_messageline(&self) -> Result<()> {
-        self.print_messageline()?;
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode.set(mode);
+    }
+
+    /// `:set`
+    pub fn set_options(&mut self, args: Option<String>) -> Result<()> {
+        let result = match args {
+            Some(args) => {
+                // The active buffer's own options like `fileformat` go to it, the rest apply
+                // everywhere
+                let (local, global): (Vec<&str>, Vec<&str>) = options::words(&args)
+                    .into_iter()
+                    .partition(|arg| LocalOptions::knows(arg));
+                self.active_window_mut()
+                    .set_local_options(&local.join(" "))
+                    .and_then(|shown| {
+                        let more = self.options.borrow_mut().set(&global.join(" "))?;
+                        Ok([shown, more].join("  ").trim().to_owned())
+                    })
+            }
+            None => Ok(self.options.borrow().changed()),
+        };
+        self.layout_statuslines()?;
+        for window in &mut self.windows {
+            window.options_changed()?;
+        }
+        // Maybe a server to try again
+        self.lsp_failed.clear();
+        match result {
+            Ok(msg) => self.set_message(msg),
+            Err(e) => self.set_error_message(e),
+        }
+    }
+
+    /// `:highlight`
+    pub fn highlight(&mut self, args: Option<String>) -> Result<()> {
+        let result = self
+            .highlights
+            .borrow_mut()
+            .command(args.as_deref().unwrap_or(""));
+        self.highlights_changed(result)
+    }
+
+    /// `:colorscheme`, which shows the current one without an argument
+    pub fn colorscheme(&mut self, name: Option<String>) -> Result<()> {
+        let result = match name {
+            Some(name) => self
+                .highlights
+                .borrow_mut()
+                .load_scheme(&name)
+                .map(|_| String::new()),
+            None => Ok(self.highlights.borrow().scheme_name().to_owned()),
+        };
+        self.highlights_changed(result)
+    }
+
+    fn highlights_changed(&mut self, result: std::result::Result<String, String>) -> Result<()> {
+        match result {
+            Ok(msg) => self.set_message(msg)?,
+            Err(e) => self.set_error_message(e)?,
+        }
+        // Dividers aren't part of `draw`, so they need repainting separately
+        for window in &self.windows {
+            if window.loc().1 + window.width() < self.cols() {
+                window.print_divider()?;
+            }
+        }
         self.reprint_cursor()
     }
 
-    fn cols() -> usize {
-        terminal::size().unwrap().0 as usize
+    pub fn load_file(&mut self, filename: Option<String>) -> Result<()> {
+        match filename {
+            Some(filename) => self.load_into(self.cur_window, filename),
+            None => Ok(()),
+        }
     }
 
-    fn rows() -> usize {
-        terminal::size().unwrap().1 as usize
+    /// The files from the command line: all of them go in the argument list, and the first or
+    /// all of them on screen, as `layout` says. With `recover` the first comes from its swap
+    /// file, for `rim -r file`.
+    pub fn open_files(&mut self, files: Vec<String>, layout: Layout, recover: bool) -> Result<()> {
+        self.arglist = files.clone();
+        self.arg_index = 0;
+        let mut files = files.into_iter();
+        let first = files.next();
+        self.load_file(first.clone())?;
+        if let (true, Some(first)) = (recover, first) {
+            // Before another file's swap file can be asked about
+            self.recover_first(&first)?;
+        }
+        for filename in files {
+            match layout {
+                Layout::Horizontal => self.new_horizontal_split(Some(filename))?,
+                Layout::Vertical => self.new_vertical_split(Some(filename))?,
+                Layout::Tabs => self.new_tab(Some(filename))?,
+                Layout::Arglist => break,
+            }
+        }
+        if self.windows.len() > 1 {
+            self.focus(0)?;
+        }
+        Ok(())
     }
 
-    fn usable_rows() -> usize {
-        // Status bar and messages
-        Self::rows() - 2
+    /// `rim -R`
+    pub fn set_read_only(&mut self) -> Result<()> {
+        for window in &mut self.windows {
+            window.open_read_only()?;
+        }
+        self.reprint_cursor()
     }
 
-    pub fn write(&mut self) -> Result<()> {
-        match self.active_window_mut().write() {
-            Ok(msg) => self.set_message(msg),
-            Err(e) => self.set_error_message(e),
+    /// `:next` and `:prev`: the file `step` files along the argument list
+    pub fn step_arglist(&mut self, step: isize, force: bool) -> Result<()> {
+        let i = self.arg_index as isize + step;
+        if i < 0 {
+            return self.set_error_message("Cannot go before first file");
+        }
+        let Some(filename) = self.arglist.get(i as usize).cloned() else {
+            return self.set_error_message("Cannot go beyond last file");
+        };
+        if !force && self.active_window().unsaved_changes() {
+            return self.set_error_message("No write since last change (add ! to override)");
         }
+        self.arg_index = i as usize;
+        self.load_into(self.cur_window, filename)
     }
 
-    pub fn write_to_filename(&mut self, filename: String) -> Result<()> {
-        match self.active_window_mut().write_to_filename(filename) {
-            Ok(msg) => self.set_message(msg),
-            Err(e) => self.set_error_message(e),
+    /// `:b name`: the window that shows `name`, or else `name` in this one if it's in the
+    /// argument list
+    pub fn buffer(&mut self, name: Option<String>) -> Result<()> {
+        let Some(name) = name else {
+            return self.set_error_message("Argument required");
+        };
+        if let Some(i) = self
+            .windows
+            .iter()
+            .position(|window| window.buffer().filename() == name)
+        {
+            return self.focus(i);
+        }
+        let Some(i) = self.arglist.iter().position(|filename| *filename == name) else {
+            return self.set_error_message(format!("No matching buffer for {name}"));
+        };
+        if self.active_window().unsaved_changes() {
+            return self.set_error_message("No write since last change (add ! to override)");
         }
+        self.arg_index = i;
+        self.load_into(self.cur_window, name)
     }
 
-    pub fn set_message(&mut self, message: impl ToString) -> Result<()> {
-        self.message = message.to_string();
-        self.message_is_error = false;
-        self.draw()
+    /// `:args`, with the current one in brackets
+    pub fn show_arglist(&mut self) -> Result<()> {
+        let message = self
+            .arglist
+            .iter()
+            .enumerate()
+            .map(|(i, filename)| {
+                if i == self.arg_index {
+                    format!("[{filename}]")
+                } else {
+                    filename.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.set_message(message)
     }
 
-    pub fn set_error_message(&mut self, message: impl ToString) -> Result<()> {
-        self.message = message.to_string();
-        self.message_is_error = true;
-        self.draw()
+    /// `:/pattern`
+    pub fn search(&mut self, pattern: &str) -> Result<()> {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => return self.set_error_message(format!("Invalid pattern: {e}")),
+        };
+        let from = self.active_window().place();
+        let found = self.active_window_mut().search_forward(&regex)?;
+        if found.is_some() {
+            self.active_window_mut().remember_jump(from);
+        }
+        match found {
+            Some(true) => self.set_error_message("search hit BOTTOM, continuing at TOP"),
+            Some(false) => self.set_message(format!("/{pattern}")),
+            None => self.set_error_message(format!("Pattern not found: {pattern}")),
+        }
     }
 
-    pub fn enter_command_mode(&mut self) -> Result<()> {
-        self.command_mode_cursor = Some(1);
-        self.message = ":".into();
-        self.message_is_error = false;
-        self.draw()
+    /// `rim -`
+    pub fn read_stdin(&mut self, bytes: &[u8]) -> Result<()> {
+        let buffer = Buffer::from_stdin(bytes, &self.options.borrow());
+        let message = format!("\"-\" {}L, {}B", buffer.lines().len(), bytes.len());
+        self.active_window_mut().set_buffer(buffer)?;
+        self.set_message(message)
     }
 
-    pub fn leave_command_mode(&mut self) -> Result<()> {
-        self.command_mode_cursor = None;
-        if self.message.starts_with(':') {
-            self.message = "".into();
+    /// Loads `filename` into window `i`, saying how that went on the message line. If someone
+    /// else's swap file is in the way, the user gets asked about it first.
+    fn load_into(&mut self, i: usize, filename: String) -> Result<()> {
+        let result = Buffer::from_filepath(filename, &self.options.borrow(), &self.events);
+        let mut buffer = match result {
+            Ok(buffer) => buffer,
+            Err(e) => return self.set_error_message(e),
+        };
+        let Some(path) = buffer.path().map(PathBuf::from) else {
+            return self.show_buffer(i, buffer);
+        };
+        let options = self.options.borrow().clone();
+        match swap::existing(&path, &options.directory).map(|swap| (swap::read(&swap), swap)) {
+            Some((Ok(swapped), swap)) if swapped.pid != std::process::id() => {
+                return self.ask_about_swap(SwapPrompt {
+                    window: i,
+                    buffer,
+                    swap,
+                    swapped,
+                });
+            }
+            // Another window of ours already has it
+            Some((Ok(_), swap)) => buffer.set_swap(Some(swap)),
+            _ => buffer.set_swap(swap::location(&path, &options)),
         }
-        self.draw()
+        self.show_buffer(i, buffer)
     }
 
-    pub fn command_move_cursor(&mut self, rl: isize) -> Result<()> {
-        let old_col = self.command_mode_cursor.expect("is in command mode");
-        let new_col = old_col as isize + rl;
-        if new_col < 1 {
-            self.command_mode_cursor = Some(1);
-        } else if new_col as usize > self.message.len() {
-            self.command_mode_cursor = Some(self.message.len());
+    /// Window `i`'s cursor goes where it was when `buffer`'s file was last left, which is
+    /// also mark `'"`
+    fn show_buffer(&mut self, i: usize, mut buffer: Buffer) -> Result<()> {
+        self.remember_position(i);
+        let info = buffer.file_info();
+        let last = buffer
+            .path()
+            .and_then(|_| self.positions.get(&session::absolute(buffer.filename())));
+        if let Some(pos) = last {
+            buffer.marks_mut().set('"', pos);
+        }
+        self.windows[i].set_buffer(buffer)?;
+        if let Some((row, col)) = last {
+            self.windows[i].goto(row, col)?;
+        }
+        self.print_tabline()?;
+        self.set_message(info)
+    }
+
+    fn ask_about_swap(&mut self, prompt: SwapPrompt) -> Result<()> {
+        let running = if prompt.swapped.running() {
+            format!(", process {} is still editing it", prompt.swapped.pid)
         } else {
-            self.command_mode_cursor = Some(new_col as usize);
+            String::new()
+        };
+        let message = format!(
+            "Swap file \"{}\" already exists{running}! [O]pen read-only, (E)dit anyway, \
+             (R)ecover, (D)elete it, (Q)uit, (A)bort",
+            prompt.swap.display()
+        );
+        self.prompt = Some(Prompt::Swap(Box::new(prompt)));
+        self.set_error_message(message)
+    }
+
+    /// Whether keys should go to `answer_prompt`
+    pub fn prompting(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    /// Returns whether the user chose to quit. Keys that aren't an answer are ignored.
+    pub fn answer_prompt(&mut self, answer: char) -> Result<bool> {
+        match self.prompt.take() {
+            Some(Prompt::Swap(prompt)) => self.answer_swap_prompt(*prompt, answer),
+            Some(Prompt::Reload(i)) => match answer.to_ascii_lowercase() {
+                'l' => {
+                    self.reload(i)?;
+                    let info = self.windows[i].buffer().file_info();
+                    self.set_message(info).map(|_| false)
+                }
+                'k' => self.set_message("").map(|_| false),
+                _ => {
+                    self.prompt = Some(Prompt::Reload(i));
+                    Ok(false)
+                }
+            },
+            None => Ok(false),
         }
-        self.reprint_cursor()
     }
 
-    pub fn command_type_char(&mut self, c: char) -> Result<()> {
-        self.message.push(c);
-        self.command_move_cursor(1)?;
-        self.reprint_messageline()
+    fn answer_swap_prompt(&mut self, mut prompt: SwapPrompt, answer: char) -> Result<bool> {
+        let location = prompt
+            .buffer
+            .path()
+            .and_then(|path| swap::location(path, &self.options.borrow()));
+        match answer.to_ascii_lowercase() {
+            'o' => prompt.buffer.open_read_only(),
+            'e' => prompt.buffer.set_swap(location),
+            'r' => {
+                prompt.buffer.recover(&prompt.swapped.contents);
+                // Ours now, and kept until the recovered text is written somewhere
+                prompt.buffer.set_swap(Some(prompt.swap.clone()));
+                if let Some(job) = prompt.buffer.swap_job() {
+                    let _ = job.run();
+                }
+                let message = format!(
+                    "Recovered \"{}\" from \"{}\", :w to keep it",
+                    prompt.buffer.filename(),
+                    prompt.swap.display()
+                );
+                self.windows[prompt.window].set_buffer(prompt.buffer)?;
+                return self.set_message(message).map(|_| false);
+            }
+            'd' => {
+                let _ = fs::remove_file(&prompt.swap);
+                prompt.buffer.set_swap(Some(prompt.swap));
+            }
+            'q' => return Ok(true),
+            'a' => return self.set_message("").map(|_| false),
+            _ => {
+                self.prompt = Some(Prompt::Swap(Box::new(prompt)));
+                return Ok(false);
+            }
+        }
+        self.show_buffer(prompt.window, prompt.buffer)?;
+        Ok(false)
     }
 
-    pub fn command_delete_char(&mut self) -> Result<()> {
-        if self.message.len() == 1 {
-            // TODO: leave command mode (how to change state)
+    /// `rim -r file`, with `filename` just loaded and its swap file asked about if it has one
+    fn recover_first(&mut self, filename: &str) -> Result<()> {
+        if self.prompting() {
+            self.answer_prompt('r')?;
+            Ok(())
         } else {
-            self.message.remove(self.message.len() - 1);
-            self.command_move_cursor(-1)?;
+            self.set_error_message(format!("No swap file found for \"{filename}\""))
         }
-        self.reprint_messageline()
     }
 
-    pub fn get_curr_command(&self) -> &str {
-        &self.message[1..]
+    /// `:e`, which reads the current file again when there's no `filename`
+    pub fn edit(&mut self, filename: Option<String>, force: bool) -> Result<()> {
+        if let Some(filename) = filename {
+            return self.load_into(self.cur_window, filename);
+        }
+        if !force && self.active_window().unsaved_changes() {
+            return self.set_error_message("No write since last change (add ! to override)");
+        }
+        if self.active_window().buffer().path().is_none() {
+            return self.set_error_message("No file name");
+        }
+        self.reload(self.cur_window)?;
+        let info = self.active_window().buffer().file_info();
+        self.set_message(info)
+    }
+
+    /// Reads window `i`'s file again, throwing away any changes
+    fn reload(&mut self, i: usize) -> Result<()> {
+        let filename = self.windows[i].buffer().filename().to_owned();
+        let result = Buffer::from_filepath(filename, &self.options.borrow(), &self.events);
+        let mut buffer = match result {
+            Ok(buffer) => buffer,
+            Err(e) => return self.set_error_message(e),
+        };
+        // Before picking a new one, so the old one isn't in the way
+        self.windows[i].discard_swap();
+        if let Some(path) = buffer.path().map(PathBuf::from) {
+            buffer.set_swap(swap::location(&path, &self.options.borrow()));
+        }
+        self.windows[i].reload(buffer)
     }
+
+    /// `:checktime`, and whenever the file watcher sees something: tells the user about files
+    /// that changed behind their back, or with `autoread` just reloads unmodified ones
+    pub fn check_time(&mut self) -> Result<()> {
+        if self.prompting() {
+            return Ok(());
+        }
+        for i in 0..self.windows.len() {
+            let Some(change) = self.windows[i].unnoticed_disk_change() else {
+                continue;
+            };
+            let filename = self.windows[i].buffer().filename().to_owned();
+            let autoread = self.options.borrow().autoread;
+            match change {
+                DiskChange::Deleted => {
+                    return self
+                        .set_error_message(format!("\"{filename}\" no longer exists on disk"))
+                }
+                DiskChange::Changed if autoread && !self.windows[i].unsaved_changes() => {
+                    self.reload(i)?
+                }
+                DiskChange::Changed => {
+                    self.prompt = Some(Prompt::Reload(i));
+                    return self.set_error_message(format!(
+                        "\"{filename}\" has changed on disk since reading it! [K]eep your version, \
+                         (L)oad file"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where background loads and saves report back, for the event loop
+    pub fn take_events(&mut self) -> loader::Receiver {
+        self.receiver.take().expect("only taken once")
+    }
+
+    /// Hands `event` to the buffer it's for, if that's still around
+    pub fn background_event(&mut self, event: Event) -> Result<()> {
+        let id = event.id();
+        if let Some(Prompt::Swap(prompt)) = &mut self.prompt {
+            if prompt.buffer.id() == id {
+                // Nothing to say until the question is answered
+                prompt.buffer.background_event(event);
+                return Ok(());
+            }
+        }
+        let Some(window) = self
+            .windows
+            .iter_mut()
+            .find(|window| window.buffer().id() == id)
+        else {
+            return Ok(());
+        };
+        let result = window.background_event(event)?;
+        // Don't clobber a command being typed or a question being asked
+        if self.command_mode_cursor.is_some() || self.prompting() {
+            return self.reprint_cursor();
+        }
+        match result {
+            Some(Ok(message)) => self.set_message(message),
+            Some(Err(e)) => self.set_error_message(e),
+            None => self.reprint_cursor(),
+        }
+    }
+
+    /// Whether anything is still being loaded or saved in the background
+    #[cfg(test)]
+    pub fn busy(&self) -> bool {
+        let prompted = match &self.prompt {
+            Some(Prompt::Swap(prompt)) => prompt.buffer.busy(),
+            _ => false,
+        };
+        prompted || self.windows.iter().any(|window| window.buffer().busy())
+    }
+
+    /// `<C-c>`: stops loading the active window's file where it is
+    pub fn cancel_loading(&mut self) -> Result<()> {
+        if self.active_window_mut().cancel_loading()? {
+            let buffer = self.active_window().buffer();
+            let message = format!(
+                "\"{}\" [Interrupted] {}L, the rest wasn't read",
+                buffer.filename(),
+                buffer.lines().len()
+            );
+            self.set_error_message(message)?;
+        }
+        Ok(())
+    }
+
+    /// Files open in any window, for the file watcher
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.windows
+            .iter()
+            .filter_map(|window| window.buffer().path().map(PathBuf::from))
+            .collect()
+    }
+
+    /// Starts writing the swap files that are out of date, after telling the user about the
+    /// last writes that failed
+    pub fn write_swaps(&mut self) -> Result<()> {
+        let mut failed = None;
+        for window in &mut self.windows {
+            if let Err(e) = window.swap_written() {
+                failed = Some(e);
+            }
+            window.write_swap();
+        }
+        match failed {
+            Some(e) => self.set_error_message(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether any swap file is still being written
+    #[cfg(test)]
+    pub fn swaps_busy(&self) -> bool {
+        self.windows.iter().any(Window::swap_busy)
+    }
+
+    /// How often swap files are brought up to date
+    pub fn updatetime(&self) -> Duration {
+        Duration::from_millis(self.options.borrow().updatetime.max(1) as u64)
+    }
+
+    /// Reads the state file at `path`, which is also where it's written on exit: the history,
+    /// global marks and jump list it has come after any from this run, and its registers are
+    /// the ones this run hasn't set
+    pub fn load_state(&mut self, path: PathBuf) -> Result<()> {
+        let result = StateFile::read(&path);
+        self.state_file = Some(path);
+        let state = match result {
+            Ok(state) => state,
+            Err(e) => return self.set_error_message(format!("Cannot read state file: {e}")),
+        };
+        let max = self.options.borrow().history;
+        self.history.merge(&state.history, max);
+        self.registers.fill(state.registers);
+        let in_file = |place: Place| {
+            let file = session::relative(place.file.as_deref().unwrap_or_default());
+            Place::in_file(file, place.pos)
+        };
+        for (name, place) in state.marks {
+            self.global_marks
+                .entry(name)
+                .or_insert_with(|| in_file(place));
+        }
+        let jumps = self.active_window_mut().jumps_mut();
+        let newer: Vec<Place> = jumps.iter().cloned().collect();
+        for jump in state.jumps.into_iter().map(in_file).chain(newer) {
+            jumps.push(jump);
+        }
+        self.positions = state.positions;
+        Ok(())
+    }
+
+    /// Everything `load_state` reads, merged with what other runs wrote since
+    fn write_state(&mut self, path: &Path) -> std::io::Result<()> {
+        for i in 0..self.windows.len() {
+            self.remember_position(i);
+        }
+        let in_file = |place: Place| {
+            let file = session::absolute(place.file.as_deref()?);
+            Some(Place::in_file(file, place.pos))
+        };
+        let marks = self
+            .global_marks
+            .keys()
+            .filter_map(|&name| Some((name, in_file(self.mark(name)?)?)))
+            .collect();
+        let jumps = self.active_window().jumps().iter().cloned();
+        let mut state = StateFile {
+            history: self.history.clone(),
+            registers: self.registers.clone(),
+            marks,
+            jumps: jumps.filter_map(in_file).collect(),
+            positions: self.positions.clone(),
+        };
+        let older = StateFile::read(path).unwrap_or_default();
+        state.merge(older, self.options.borrow().history);
+        state.write(path)
+    }
+
+    /// Where the cursor is in window `i`'s file, for when it's opened again
+    fn remember_position(&mut self, i: usize) {
+        let window = &self.windows[i];
+        if window.buffer().path().is_some() {
+            let file = session::absolute(window.buffer().filename());
+            self.positions.set(file, window.adjusetd_cursor());
+        }
+    }
+
+    /// `:mksession [file]`: the windows, their files and cursors, and the working directory, as
+    /// ex commands for `:source` or `rim -S`. The file is `Session.rim` unless it's named, and
+    /// is only overwritten with `!`.
+    pub fn make_session(&mut self, file: Option<String>, force: bool) -> Result<()> {
+        let file = file.unwrap_or_else(|| "Session.rim".to_string());
+        if !force && Path::new(&file).exists() {
+            return self.set_error_message(format!("\"{file}\" exists (add ! to override)"));
+        }
+        let cwd = match env::current_dir() {
+            Ok(cwd) => cwd,
+            Err(e) => return self.set_error_message(format!("No working directory: {e}")),
+        };
+        // Just the tab page that's shown, as if the tabline weren't there
+        let tabline = self.top();
+        let shown = self.windows.iter().filter(|w| w.tab() == self.cur_tab);
+        let windows: Vec<SessionWindow> = shown
+            .map(|window| {
+                let (top, left) = window.loc();
+                let buffer = window.buffer();
+                SessionWindow {
+                    rect: Rect {
+                        top: top - tabline,
+                        left,
+                        bottom: window.bottom() - tabline,
+                        right: left + window.width(),
+                    },
+                    file: buffer.path().map(|_| buffer.filename().to_owned()),
+                    byte: window.byte_offset(),
+                }
+            })
+            .collect();
+        let active = self.windows[..self.cur_window]
+            .iter()
+            .filter(|w| w.tab() == self.cur_tab)
+            .count();
+        match fs::write(&file, session::script(&cwd, &windows, active)) {
+            Ok(()) => self.set_message(format!("\"{file}\" written")),
+            Err(e) => self.set_error_message(format!("Cannot write \"{file}\": {e}")),
+        }
+    }
+
+    /// `:cd [dir]`, home without one. Files that are open keep the names they were opened by.
+    pub fn change_dir(&mut self, dir: Option<String>) -> Result<()> {
+        let Some(dir) = dir.or_else(|| env::var("HOME").ok()) else {
+            return self.set_error_message("Argument required");
+        };
+        match env::set_current_dir(&dir) {
+            Ok(()) => Ok(()),
+            Err(e) => self.set_error_message(format!("Can't change to \"{dir}\": {e}")),
+        }
+    }
+
+    /// `:goto [n]`: byte `n` of the buffer, the first without one
+    pub fn goto_byte(&mut self, n: Option<String>) -> Result<()> {
+        let n = match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => n,
+            Some(Err(_)) => return self.set_error_message("Number required"),
+            None => 1,
+        };
+        let window = self.active_window_mut();
+        window.push_jump();
+        window.goto_byte(n)
+    }
+
+    /// `:wincmd {c}`: `h`, `j`, `k` and `l` like `<space>h` and the rest, `w` to the next window
+    /// and `t` to the first
+    pub fn wincmd(&mut self, c: Option<String>) -> Result<()> {
+        match c.as_deref() {
+            Some("h") => self.move_to_left_window(),
+            Some("j") => self.move_to_down_window(),
+            Some("k") => self.move_to_up_window(),
+            Some("l") => self.move_to_right_window(),
+            Some("w") => {
+                let count = self.windows.len();
+                let next = (1..=count)
+                    .map(|step| (self.cur_window + step) % count)
+                    .find(|&i| self.windows[i].tab() == self.cur_tab);
+                self.focus(next.unwrap_or(self.cur_window))
+            }
+            Some("t") => self.focus(self.first_window(self.cur_tab)),
+            Some(c) => self.set_error_message(format!("Invalid argument: `{c}`")),
+            None => self.set_error_message("Argument required"),
+        }
+    }
+
+    /// `"`: the next key names a register
+    pub fn start_register_name(&mut self) {
+        self.register_name_pending = true;
+    }
+
+    pub fn register_name_pending(&self) -> bool {
+        self.register_name_pending
+    }
+
+    /// The rest of `"`. `None` is a key that can't name a register, which just ends it.
+    pub fn register_name_key(&mut self, name: Option<char>) {
+        self.register_name_pending = false;
+        self.register_name =
+            name.filter(|&name| Registers::writable(name) || matches!(name, ':' | '/' | '%'));
+    }
+
+    /// Register `name`: the ones kept in `registers`, and `:`, `/` and `%`, which are the last
+    /// command line, the last search and the file name
+    fn register(&self, name: char) -> Option<Register> {
+        let text = match name {
+            ':' | '/' => self.history.list(name).last().cloned(),
+            '%' => {
+                let buffer = self.active_window().buffer();
+                buffer.path().map(|_| buffer.filename().to_owned())
+            }
+            _ => return self.registers.get(name).cloned(),
+        };
+        text.map(|text| Register::new(vec![text], false))
+    }
+
+    #[cfg(test)]
+    pub fn register_text(&self, name: char) -> Option<String> {
+        self.register(name).map(|register| register.text())
+    }
+
+    /// Takes the register `"{name}` named, which has to be one that can be written to
+    fn take_register_name(&mut self) -> std::result::Result<Option<char>, String> {
+        match self.register_name.take() {
+            Some(name) if !Registers::writable(name) => {
+                Err(format!("Invalid register name: '{name}'"))
+            }
+            name => Ok(name),
+        }
+    }
+
+    /// `yy` and `Y`
+    pub fn yank_line(&mut self) -> Result<()> {
+        match self.take_register_name() {
+            Ok(name) => {
+                let line = self.active_window().cursor_line();
+                self.registers.yank(name, line);
+                Ok(())
+            }
+            Err(e) => self.set_error_message(e),
+        }
+    }
+
+    /// `dd`, and `cc` before insert mode, with the line going in a register
+    pub fn delete_line(&mut self, change: bool) -> Result<()> {
+        let name = match self.take_register_name() {
+            Ok(name) => name,
+            Err(e) => return self.set_error_message(e),
+        };
+        let line = self.active_window().cursor_line();
+        self.registers.delete(name, line);
+        let window = self.active_window_mut();
+        if change {
+            window.change_line()
+        } else {
+            window.delete_line()
+        }
+    }
+
+    /// `p` (`after`) and `P`
+    pub fn put(&mut self, after: bool) -> Result<()> {
+        let name = self.register_name.take().unwrap_or('"');
+        match self.register(name) {
+            Some(register) => self.active_window_mut().put(&register, after),
+            None => self.set_error_message(format!("Nothing in register {name}")),
+        }
+    }
+
+    /// A command line that was run, `kind` being `:` or `/`
+    pub fn add_history(&mut self, kind: char, line: &str) {
+        let max = self.options.borrow().history;
+        self.history.add(kind, line, max);
+    }
+
+    /// `q:` and `q/`: the history in a window of its own, where it can be edited like any text
+    /// and `<CR>` runs the line under the cursor
+    pub fn open_command_window(&mut self, kind: char) -> Result<()> {
+        if self.command_window.is_some() {
+            return self.set_error_message("The command-line window is already open");
+        }
+        let height = self.active_window().height();
+        if height < 3 {
+            return self.set_error_message("Not enough room");
+        }
+        let rows = (self.options.borrow().cmdwinheight + 1).clamp(2, height / 2 + 1);
+        let mut lines = self.history.list(kind).to_vec();
+        lines.push(String::new());
+        self.split_below(rows)?;
+        self.active_window_mut()
+            .set_buffer(Buffer::scratch("[Command Line]", lines))?;
+        self.active_window_mut().maximize_cursor_row()?;
+        self.command_window = Some((self.cur_window, kind));
+        Ok(())
+    }
+
+    /// `:q` in the `q:` window or a `:copen` one closes just that. Returns whether it did.
+    pub fn close_command_window(&mut self) -> Result<bool> {
+        match (&self.command_window, &self.list_window) {
+            (Some((i, _)), _) if *i == self.cur_window => {
+                self.command_window = None;
+                self.close_window(self.cur_window)?;
+                Ok(true)
+            }
+            (_, Some(list)) if list.window == self.cur_window => {
+                self.close_window(self.cur_window)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// `<CR>` in the `q:` window: closes it and hands back the line under the cursor, along
+    /// with whether it's a `:` command or a `/` search
+    pub fn take_command_window_line(&mut self) -> Result<Option<(char, String)>> {
+        let Some((_, kind)) = self.command_window else {
+            return Ok(None);
+        };
+        let window = self.active_window();
+        let line = window
+            .buffer()
+            .nth_line(window.adjusetd_cursor().0)
+            .to_owned();
+        if !self.close_command_window()? {
+            return Ok(None);
+        }
+        Ok(Some((kind, line)))
+    }
+
+    /// `<CR>` in normal mode: opens the entry under the cursor in a directory listing, and
+    /// otherwise just goes down a line
+    pub fn open_or_move_down(&mut self) -> Result<()> {
+        match self.active_window().directory_entry() {
+            Some(path) => self.load_into(self.cur_window, path.display().to_string()),
+            None => self.active_window_mut().move_cursor_row(1),
+        }
+    }
+
+    // TODO: lots of redundant logic here
+    pub fn move_to_left_window(&mut self) -> Result<()> {
+        let active_loc = self.active_window().loc();
+        if let Some((i, _)) = self
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, window)| window.tab() == self.cur_tab)
+            .filter(|(_, window)| window.loc().1 + window.width() == active_loc.1)
+            .min_by_key(|(_, window)| window.loc().0.abs_diff(active_loc.0))
+        {
+            self.focus(i)?;
+        }
+        Ok(())
+    }
+
+    pub fn move_to_right_window(&mut self) -> Result<()> {
+        let active_loc = self.active_window().loc();
+        let active_width = self.active_window().width();
+        if let Some((i, _)) = self
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, window)| window.tab() == self.cur_tab)
+            .filter(|(_, window)| window.loc().1 == active_loc.1 + active_width)
+            .min_by_key(|(_, window)| window.loc().0.abs_diff(active_loc.0))
+        {
+            self.focus(i)?;
+        }
+        Ok(())
+    }
+
+    pub fn move_to_up_window(&mut self) -> Result<()> {
+        let active_loc = self.active_window().loc();
+        if let Some((i, _)) = self
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, window)| window.tab() == self.cur_tab)
+            .filter(|(_, window)| window.loc().0 + window.height() + 1 == active_loc.0)
+            .min_by_key(|(_, window)| window.loc().1.abs_diff(active_loc.1))
+        {
+            self.focus(i)?;
+        }
+        Ok(())
+    }
+
+    pub fn move_to_down_window(&mut self) -> Result<()> {
+        let active_loc = self.active_window().loc();
+        let active_height = self.active_window().height() + 1;
+        if let Some((i, _)) = self
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, window)| window.tab() == self.cur_tab)
+            .filter(|(_, window)| window.loc().0 == active_loc.0 + active_height)
+            .min_by_key(|(_, window)| window.loc().1.abs_diff(active_loc.1))
+        {
+            self.focus(i)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: SetCursorStyle) -> Result<()> {
+        let mut backend = self.backend.borrow_mut();
+        backend.set_cursor_style(shape)?;
+        backend.flush()
+    }
+
+    fn draw(&self) -> Result<()> {
+        for window in &self.windows {
+            window.draw()?;
+            let loc = window.loc();
+            if loc.1 + window.width() < self.cols() {}
+        }
+        self.print_tabline()?;
+        self.print_wildmenu()?;
+        self.print_float()?;
+        self.print_popup()?;
+        if self.pager.is_some() {
+            return self.print_pager();
+        }
+        self.print_messageline()?;
+        self.reprint_cursor()
+    }
+
+    /// For use in `draw`: along the top, a label for each tab page, once there's more than one
+    fn print_tabline(&self) -> Result<()> {
+        if self.tab_count < 2 {
+            return Ok(());
+        }
+        let cols = self.cols();
+        let highlights = self.highlights.borrow();
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(0, 0)?;
+        let mut width = 0;
+        for tab in 0..self.tab_count {
+            let label: String = self.tab_label(tab).chars().take(cols - width).collect();
+            width += label.chars().count();
+            let group = if tab == self.cur_tab {
+                "TabLineSel"
+            } else {
+                "TabLine"
+            };
+            backend.print(&label, highlights.style(group))?;
+        }
+        backend.print(&" ".repeat(cols - width), highlights.style("TabLineFill"))
+    }
+
+    /// ` 2 main.rs `: how many windows tab page `tab` has, when it's more than one, and the
+    /// file in the one that's active there
+    fn tab_label(&self, tab: usize) -> String {
+        let windows: Vec<&Window> = self.windows.iter().filter(|w| w.tab() == tab).collect();
+        let window = windows.iter().find(|w| w.active()).unwrap_or(&windows[0]);
+        let filename = window.buffer().filename();
+        let name = filename.rsplit('/').next().unwrap_or(filename);
+        match windows.len() {
+            1 => format!(" {name} "),
+            n => format!(" {n} {name} "),
+        }
+    }
+
+    fn reprint_cursor(&self) -> Result<()> {
+        if self.pager.is_some() {
+            return Ok(());
+        }
+        if let Some(cursor) = self.command_mode_cursor {
+            let col = self.message[..cursor].chars().count();
+            let row = self.messageline_row();
+            let mut backend = self.backend.borrow_mut();
+            backend.move_to(row, col)?;
+            backend.show_cursor()?;
+            backend.flush()
+        } else {
+            self.active_window().reprint_cursor()
+        }
+    }
+
+    /// For use in `draw`
+    fn print_messageline(&self) -> Result<()> {
+        let formatted_message = if self.message.len() > self.cols() {
+            self.message[..self.cols()].to_owned()
+        } else {
+            let padding = " ".repeat(self.cols() - self.message.len());
+            format!("{}{}", self.message, padding)
+        };
+        let style = self.highlights.borrow().style(if self.message_is_error {
+            "ErrorMsg"
+        } else {
+            "Normal"
+        });
+        let row = self.messageline_row();
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(row, 0)?;
+        backend.print(&formatted_message, style)
+    }
+
+    /// The candidates of a `<Tab>` over the row above the message line, the one in the command
+    /// line highlighted. Scrolled just far enough for that one to fit. For use in `draw`.
+    fn print_wildmenu(&self) -> Result<()> {
+        let Some(completion) = self.completion.as_ref().filter(|c| c.menu) else {
+            return Ok(());
+        };
+        let cols = self.cols();
+        let highlights = self.highlights.borrow();
+        let (normal, selected) = (highlights.style("StatusLine"), highlights.style("WildMenu"));
+        let widths: Vec<usize> = completion
+            .candidates
+            .iter()
+            .map(|candidate| candidate.chars().count() + 2)
+            .collect();
+        let room = cols.saturating_sub(4);
+        let shown = completion.selected.unwrap_or(0);
+        let mut first = 0;
+        while first < shown && widths[first..=shown].iter().sum::<usize>() > room {
+            first += 1;
+        }
+
+        let mut spans = Vec::new();
+        let mut used = 0;
+        if first > 0 {
+            spans.push(("< ".to_owned(), normal));
+            used += 2;
+        }
+        for (i, candidate) in completion.candidates.iter().enumerate().skip(first) {
+            if used + widths[i] > cols.saturating_sub(2) {
+                spans.push((">".to_owned(), normal));
+                used += 1;
+                break;
+            }
+            let style = if completion.selected == Some(i) {
+                selected
+            } else {
+                normal
+            };
+            spans.push((candidate.clone(), style));
+            spans.push(("  ".to_owned(), normal));
+            used += widths[i];
+        }
+        spans.push((" ".repeat(cols.saturating_sub(used)), normal));
+
+        let row = self.messageline_row() - 1;
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(row, 0)?;
+        for (text, style) in spans {
+            backend.print(&text, style)?;
+        }
+        Ok(())
+    }
+
+    /// As many lines as fit above a prompt on the last row, for use in `draw`
+    fn print_pager(&self) -> Result<()> {
+        let Some(pager) = &self.pager else {
+            return Ok(());
+        };
+        let (rows, cols) = (self.messageline_row() + 1, self.cols());
+        let shown = (pager.lines.len() - pager.top).min(rows - 1);
+        let highlights = self.highlights.borrow();
+        let (prompt, group) = if pager.top + shown < pager.lines.len() {
+            ("-- More --", "MoreMsg")
+        } else {
+            ("Press ENTER or type command to continue", "Question")
+        };
+        let first = self.messageline_row() - shown;
+        let mut backend = self.backend.borrow_mut();
+        let rows = pager.lines[pager.top..pager.top + shown]
+            .iter()
+            .map(|line| (line.as_str(), highlights.style("Normal")))
+            .chain([(prompt, highlights.style(group))]);
+        for (i, (line, style)) in rows.enumerate() {
+            let line: String = line.chars().take(cols).collect();
+            let padding = cols - line.chars().count();
+            backend.move_to(first + i, 0)?;
+            backend.print(&format!("{line}{}", " ".repeat(padding)), style)?;
+        }
+        backend.move_to(first + shown, prompt.len().min(cols - 1))?;
+        backend.show_cursor()?;
+        backend.flush()
+    }
+
+    /// The float by the cursor, for use in `draw`
+    fn print_float(&self) -> Result<()> {
+        match &self.float {
+            Some(lines) => self.print_menu(lines, None, self.active_window().cursor_on_screen()),
+            None => Ok(()),
+        }
+    }
+
+    /// The insert mode completion menu, its text lined up with the word's, for use in `draw`
+    fn print_popup(&self) -> Result<()> {
+        let Some(popup) = self.popup.as_ref().filter(|popup| popup.menu) else {
+            return Ok(());
+        };
+        let (row, col) = self.active_window().cursor_on_screen();
+        let col = col.saturating_sub(popup.text().chars().count() + 1);
+        self.print_menu(&popup.candidates, popup.selected, (row, col))
+    }
+
+    /// `lines` in a box of `Pmenu` below screen position `at`, or above it if there's more
+    /// room there, with the `selected` one in `PmenuSel` and scrolled to
+    fn print_menu(
+        &self,
+        lines: &[String],
+        selected: Option<usize>,
+        at: (usize, usize),
+    ) -> Result<()> {
+        let (row, col) = at;
+        let (bottom, cols) = (self.messageline_row(), self.cols());
+        let (above, below) = (row, bottom.saturating_sub(row + 1));
+        let height = lines.len().min(above.max(below));
+        let top = if below >= height {
+            row + 1
+        } else {
+            row - height
+        };
+        let first = selected.map_or(0, |i| (i + 1).saturating_sub(height));
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count() + 2)
+            .max()
+            .unwrap_or(0)
+            .min(cols);
+        let left = col.min(cols - width);
+        let highlights = self.highlights.borrow();
+        let mut backend = self.backend.borrow_mut();
+        for (i, line) in lines.iter().enumerate().skip(first).take(height) {
+            let text: String = format!(" {line}").chars().take(width).collect();
+            let padding = width - text.chars().count();
+            let group = if selected == Some(i) {
+                "PmenuSel"
+            } else {
+                "Pmenu"
+            };
+            backend.move_to(top + i - first, left)?;
+            backend.print(
+                &format!("{text}{}", " ".repeat(padding)),
+                highlights.style(group),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Takes the float away, if there is one
+    pub fn close_float(&mut self) -> Result<()> {
+        if self.float.take().is_some() {
+            self.draw()?;
+        }
+        Ok(())
+    }
+
+    /// Usable on its own
+    fn reprint_messageline(&self) -> Result<()> {
+        self.print_messageline()?;
+        self.reprint_cursor()
+    }
+
+    fn cols(&self) -> usize {
+        self.backend.borrow().size().unwrap().1
+    }
+
+    fn messageline_row(&self) -> usize {
+        self.backend.borrow().size().unwrap().0 - 1
+    }
+
+    /// Returns whether the file was written
+    pub fn write(&mut self, force: bool) -> Result<bool> {
+        let result = self.active_window_mut().write(force);
+        self.report_write(result)
+    }
+
+    /// `:w`, `:w file`, `:w !cmd` and `:w -`
+    pub fn write_command(&mut self, arg: Option<String>, force: bool) -> Result<()> {
+        match arg {
+            None => self.write_in_background(force).map(|_| ()),
+            Some(arg) if arg == "-" => self.write_to_stdout(force),
+            Some(arg) => match arg.strip_prefix('!') {
+                Some(cmd) => self.write_to_command(cmd),
+                None => self.write_to_filename(arg, force).map(|_| ()),
+            },
+        }
+    }
+
+    /// `:w -`: the buffer goes to stdout on the way out, to whatever rim is piped into
+    fn write_to_stdout(&mut self, force: bool) -> Result<()> {
+        match self.active_window_mut().write_to_stdout(force) {
+            Ok(bytes) => {
+                let lines = self.active_window().buffer().lines().len();
+                let message = format!("{lines}L, {}B to stdout on exit", bytes.len());
+                self.stdout = Some(bytes);
+                self.set_message(message)
+            }
+            Err(e) => self.set_error_message(e),
+        }
+    }
+
+    /// `:w !cmd`: the buffer is piped into `cmd`, and its output shown
+    fn write_to_command(&mut self, cmd: &str) -> Result<()> {
+        let cmd = match self.expand(cmd) {
+            Ok(cmd) => cmd,
+            Err(e) => return self.set_error_message(e),
+        };
+        let input = self.active_window().buffer().bytes(&self.options.borrow());
+        self.start_job(&cmd, Some(input), JobKind::Show { interactive: false })
+    }
+
+    /// `:r file`, `:r !cmd` or `:r` for the file itself again: puts the text below the cursor
+    pub fn read(&mut self, arg: Option<String>) -> Result<()> {
+        let read_file = |path: &std::path::Path| {
+            fs::read(path).map_err(|e| format!("\"{}\" {e}", path.display()))
+        };
+        let result = match &arg {
+            Some(arg) => match arg.strip_prefix('!') {
+                Some(cmd) => {
+                    let cmd = match self.expand(cmd) {
+                        Ok(cmd) => cmd,
+                        Err(e) => return self.set_error_message(e),
+                    };
+                    let buffer = self.active_window().buffer().id();
+                    return self.start_job(&cmd, None, JobKind::Read { buffer });
+                }
+                None => read_file(arg.as_ref()),
+            },
+            None => match self.active_window().buffer().path() {
+                Some(path) => read_file(path),
+                None => Err("No file name".to_string()),
+            },
+        };
+        let bytes = match result {
+            Ok(bytes) => bytes,
+            Err(e) => return self.set_error_message(e),
+        };
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let lines = self.decode_lines(&bytes);
+        self.active_window_mut().insert_lines_below(lines)
+    }
+
+    /// Text from a file or a command, as lines
+    fn decode_lines(&self, bytes: &[u8]) -> Vec<String> {
+        let decoded = encoding::decode(bytes, &self.options.borrow().fileencodings);
+        buffer::split_lines(&decoded.text, FileFormat::detect(&decoded.text)).0
+    }
+
+    /// `%` in a shell command, for the active window's file
+    fn expand(&self, cmd: &str) -> std::result::Result<String, String> {
+        let buffer = self.active_window().buffer();
+        shell::expand(cmd, buffer.path().map(|_| buffer.filename()))
+    }
+
+    /// `:!cmd`: the terminal is handed over to it until it's done, then what it printed is
+    /// shown again
+    pub fn shell_command(&mut self, cmd: &str) -> Result<()> {
+        let cmd = match self.expand(cmd) {
+            Ok(cmd) => cmd,
+            Err(e) => return self.set_error_message(e),
+        };
+        self.start_job(&cmd, None, JobKind::Show { interactive: true })
+    }
+
+    /// `:{range}!cmd`: lines `range` go through `cmd` and its output takes their place
+    pub fn filter(&mut self, range: Range, cmd: &str) -> Result<()> {
+        let cmd = match self.expand(cmd) {
+            Ok(cmd) => cmd,
+            Err(e) => return self.set_error_message(e),
+        };
+        let buffer = self.active_window().buffer();
+        let mut input = buffer.lines()[range.first..=range.last].join("\n");
+        input.push('\n');
+        let kind = JobKind::Filter {
+            buffer: buffer.id(),
+            range,
+        };
+        self.start_job(&cmd, Some(input.into_bytes()), kind)
+    }
+
+    fn start_job(&mut self, cmd: &str, input: Option<Vec<u8>>, kind: JobKind) -> Result<()> {
+        if self.job.is_some() {
+            return self.set_error_message("A command is already running");
+        }
+        let interactive = matches!(kind, JobKind::Show { interactive: true });
+        let shell = self.options.borrow().shell.clone();
+        let job = match job::spawn(&shell, cmd, input, interactive, &self.jobs) {
+            Ok(job) => job,
+            Err(e) => return self.set_error_message(e),
+        };
+        self.job = Some(RunningJob {
+            job,
+            cmd: cmd.to_owned(),
+            kind,
+            output: Vec::new(),
+            errors: Vec::new(),
+        });
+        if interactive {
+            let mut backend = self.backend.borrow_mut();
+            backend.suspend()?;
+            backend.echo(format!(":!{cmd}\n").as_bytes())
+        } else {
+            self.set_message(format!("Running `{cmd}`, <C-c> to stop it"))
+        }
+    }
+
+    /// Where running commands report back, for the event loop
+    pub fn take_job_events(&mut self) -> job::Receiver {
+        self.job_receiver.take().expect("only taken once")
+    }
+
+    /// Whether a command is running, which only `<C-c>` can interrupt
+    pub fn job_running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Whether the terminal is handed over to a `:!` command
+    pub fn suspended(&self) -> bool {
+        matches!(
+            self.job,
+            Some(RunningJob {
+                kind: JobKind::Show { interactive: true },
+                ..
+            })
+        )
+    }
+
+    /// Output from the running command, or the news that it's done
+    pub fn job_event(&mut self, event: job::Event) -> Result<()> {
+        let terminal = self.windows.iter().position(|window| {
+            window
+                .terminal()
+                .is_some_and(|terminal| terminal.id() == event.id())
+        });
+        if let Some(i) = terminal {
+            return self.terminal_event(i, event);
+        }
+        if let Some(c) = self.lsp.iter().position(|client| client.id() == event.id()) {
+            return self.lsp_event(c, event);
+        }
+        let suspended = self.suspended();
+        let Some(running) = self
+            .job
+            .as_mut()
+            .filter(|running| running.job.id == event.id())
+        else {
+            return Ok(());
+        };
+        let status = match event {
+            job::Event::Output { bytes, stderr, .. } => {
+                if suspended {
+                    self.backend.borrow_mut().echo(&bytes)?;
+                }
+                // Compilers say the interesting things on stderr
+                if stderr && !matches!(running.kind, JobKind::Show { .. } | JobKind::List(_)) {
+                    running.errors.extend(bytes);
+                } else {
+                    running.output.extend(bytes);
+                }
+                return Ok(());
+            }
+            job::Event::Exited { result, .. } => result,
+        };
+        let running = self.job.take().expect("just matched");
+        if suspended {
+            self.backend.borrow_mut().resume()?;
+        }
+        let code = match status {
+            Ok(code) => code,
+            Err(e) => return self.set_error_message(e),
+        };
+        let failure = match code {
+            Some(0) => None,
+            Some(code) => Some(format!("shell returned {code}")),
+            None => Some("Interrupted".to_string()),
+        };
+        // Nothing printed is no lines, rather than one empty one
+        let mut lines = if running.output.is_empty() {
+            Vec::new()
+        } else {
+            self.decode_lines(&running.output)
+        };
+        if let JobKind::Show { .. } = running.kind {
+            lines.extend(failure);
+            return self.show_output(lines);
+        }
+        if let JobKind::List(job) = running.kind {
+            return self.finish_list(job, lines, failure);
+        }
+        if let Some(failure) = failure {
+            let errors = String::from_utf8_lossy(&running.errors);
+            return self.set_error_message(match errors.lines().next() {
+                Some(line) => format!("{failure}: {line}"),
+                None => failure,
+            });
+        }
+        match running.kind {
+            JobKind::Filter { buffer, range } => {
+                let Some(window) = self.window_with_buffer(buffer) else {
+                    return Ok(());
+                };
+                self.windows[window].replace_lines(range.first, range.last, lines)?;
+                let filtered = range.last - range.first + 1;
+                self.set_message(format!(
+                    "{filtered} lines filtered through `{}`",
+                    running.cmd
+                ))
+            }
+            JobKind::Read { buffer } => {
+                let Some(window) = self.window_with_buffer(buffer) else {
+                    return Ok(());
+                };
+                self.set_message("")?;
+                if lines.is_empty() {
+                    return Ok(());
+                }
+                self.windows[window].insert_lines_below(lines)?;
+                self.reprint_cursor()
+            }
+            JobKind::Show { .. } | JobKind::List(_) => unreachable!("handled above"),
+        }
+    }
+
+    /// Whatever the output of `:make` or `:grep` has entries for is the new list. A program
+    /// that failed without giving any is an error.
+    fn finish_list(
+        &mut self,
+        job: ListJob,
+        lines: Vec<String>,
+        failure: Option<String>,
+    ) -> Result<()> {
+        let list = List::new(job.title, job.format.parse(&lines.join("\n")));
+        let found = !list.entries().is_empty();
+        if !found {
+            match failure {
+                Some(failure) => self.set_error_message(match lines.first() {
+                    Some(line) => format!("{failure}: {line}"),
+                    None => failure,
+                })?,
+                None => self.set_message("No Errors")?,
+            }
+        }
+        self.finished_list = Some(FinishedList {
+            list,
+            location: job.location,
+            jump: job.jump && found,
+        });
+        Ok(())
+    }
+
+    /// A list `:make` or `:grep` came up with, for `set_list`
+    pub fn take_finished_list(&mut self) -> Option<FinishedList> {
+        self.finished_list.take()
+    }
+
+    /// `:make` and `:grep`, or with `location` `:lmake` and `:lgrep`: `makeprg` or `grepprg`
+    /// runs in the background with `args` in place of `$*`, and its output becomes the list.
+    /// It jumps to the first entry with `jump`, which the `!` versions don't.
+    pub fn make(
+        &mut self,
+        args: Option<String>,
+        grep: bool,
+        location: bool,
+        jump: bool,
+    ) -> Result<()> {
+        let (program, errorformat) = {
+            let options = self.options.borrow();
+            if grep {
+                (options.grepprg.clone(), options.grepformat.clone())
+            } else {
+                (options.makeprg.clone(), options.errorformat.clone())
+            }
+        };
+        if grep && args.is_none() {
+            return self.set_error_message("Argument required");
+        }
+        let args = match args.map(|args| self.expand(&args)).transpose() {
+            Ok(args) => args.unwrap_or_default(),
+            Err(e) => return self.set_error_message(e),
+        };
+        let format = match Format::new(&errorformat) {
+            Ok(format) => format,
+            Err(e) => return self.set_error_message(e),
+        };
+        let cmd = if program.contains("$*") {
+            program.replace("$*", &args)
+        } else {
+            format!("{program} {args}").trim_end().to_owned()
+        };
+        let job = ListJob {
+            title: format!(":{cmd}"),
+            format,
+            location,
+            jump,
+        };
+        self.start_job(&cmd, None, JobKind::List(job))
+    }
+
+    /// Whose location list `:lnext` and the like are about: the active window's, or in a
+    /// `:lopen` window the one it shows
+    fn loclist_owner(&self) -> usize {
+        match &self.list_window {
+            Some(list) if list.location && list.window == self.cur_window => list.origin,
+            _ => self.cur_window,
+        }
+    }
+
+    /// The quickfix list `State` keeps, or with `location` the location list
+    pub fn list<'a>(&'a self, quickfix: &'a List, location: bool) -> &'a List {
+        if location {
+            self.windows[self.loclist_owner()].loclist()
+        } else {
+            quickfix
+        }
+    }
+
+    fn list_mut<'a>(&'a mut self, quickfix: &'a mut List, location: bool) -> &'a mut List {
+        if location {
+            let owner = self.loclist_owner();
+            self.windows[owner].loclist_mut()
+        } else {
+            quickfix
+        }
+    }
+
+    /// Puts a list from `take_finished_list` in its place
+    pub fn set_list(&mut self, quickfix: &mut List, finished: FinishedList) -> Result<()> {
+        let count = finished.list.entries().len();
+        let title = finished.list.title.clone();
+        *self.list_mut(quickfix, finished.location) = finished.list;
+        if finished.jump {
+            return self.pick_entry(quickfix, finished.location, Pick::Nth(0));
+        }
+        self.refresh_list_window(quickfix)?;
+        if count > 0 {
+            self.set_message(format!("{title}: {count} found"))?;
+        }
+        Ok(())
+    }
+
+    /// `:cc`, `:cnext` and the like: goes to an entry of the quickfix or location list
+    pub fn pick_entry(&mut self, quickfix: &mut List, location: bool, pick: Pick) -> Result<()> {
+        let list = self.list_mut(quickfix, location);
+        let entry = match list.pick(pick) {
+            Ok(entry) => entry.clone(),
+            Err(e) => return self.set_error_message(e),
+        };
+        let position = list.position();
+        if let Some(list) = &self.list_window {
+            if list.window == self.cur_window {
+                self.focus(list.origin)?;
+            }
+        }
+        self.refresh_list_window(quickfix)?;
+        if self.jump_to(&entry)? {
+            self.set_message(position)?;
+        }
+        Ok(())
+    }
+
+    /// Puts the active window at `entry`, loading its file if need be. Returns whether it got
+    /// there.
+    fn jump_to(&mut self, entry: &Entry) -> Result<bool> {
+        self.active_window_mut().push_jump();
+        if !self.open_file(&entry.file)? {
+            return Ok(false);
+        }
+        let (row, col) = (entry.line.saturating_sub(1), entry.col.saturating_sub(1));
+        self.active_window_mut().goto(row, col)?;
+        Ok(true)
+    }
+
+    /// Shows `file` in the active window, unless it already is. Returns whether it is now.
+    fn open_file(&mut self, file: &str) -> Result<bool> {
+        let same_file = |path: &Path| same_file(path, Path::new(file));
+        if !self.active_window().buffer().path().is_some_and(same_file) {
+            if self.active_window().unsaved_changes() {
+                self.set_error_message("No write since last change (add ! to override)")?;
+                return Ok(false);
+            }
+            self.load_into(self.cur_window, file.to_owned())?;
+            if self.message_is_error || self.prompting() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// To `place`: in the active window if it's the active buffer or a file, or else in the
+    /// window that has its buffer. Returns whether it got there.
+    fn open_place(&mut self, place: &Place) -> Result<bool> {
+        if self.active_window().buffer().id() != place.buffer {
+            let opened = match (&place.file, self.window_with_buffer(place.buffer)) {
+                (Some(file), _) => self.open_file(file)?,
+                (None, Some(i)) => {
+                    self.focus(i)?;
+                    true
+                }
+                (None, None) => false,
+            };
+            if !opened {
+                return Ok(false);
+            }
+        }
+        self.active_window_mut().goto(place.pos.0, place.pos.1)?;
+        Ok(true)
+    }
+
+    /// `u`, or `<C-r>` with `redo`
+    pub fn undo(&mut self, redo: bool) -> Result<()> {
+        if self.active_window_mut().undo(redo)? {
+            self.set_message("")
+        } else if redo {
+            self.set_error_message("Already at newest change")
+        } else {
+            self.set_error_message("Already at oldest change")
+        }
+    }
+
+    /// Before a normal mode command, so each one is undone by itself
+    pub fn seal_undo(&mut self) {
+        for window in &mut self.windows {
+            window.seal_undo();
+        }
+    }
+
+    /// `m`, `'` and `` ` ``: the next key names the mark
+    pub fn start_mark(&mut self, kind: char) {
+        self.mark_pending = Some(kind);
+    }
+
+    pub fn mark_pending(&self) -> bool {
+        self.mark_pending.is_some()
+    }
+
+    /// The rest of `m`, `'` or `` ` ``. `None` is a key that can't name a mark, which just
+    /// ends it.
+    pub fn mark_key(&mut self, name: Option<char>) -> Result<()> {
+        match (self.mark_pending.take(), name) {
+            (Some('m'), Some(name)) => self.set_mark(name),
+            (Some(kind), Some(name)) => self.jump_to_mark(name, kind == '`'),
+            _ => Ok(()),
+        }
+    }
+
+    /// `m{name}`: `a`-`z` and the special marks that can be set are the buffer's own, `A`-`Z`
+    /// are in one buffer at a time
+    fn set_mark(&mut self, name: char) -> Result<()> {
+        let place = self.active_window().place();
+        match name {
+            'a'..='z' | '\'' | '`' | '[' | ']' | '<' | '>' => {
+                self.active_window_mut().marks_mut().set(name, place.pos);
+            }
+            'A'..='Z' => {
+                for window in &mut self.windows {
+                    window.marks_mut().remove(name);
+                }
+                self.active_window_mut().marks_mut().set(name, place.pos);
+                self.global_marks.insert(name, place);
+            }
+            _ => {
+                return self
+                    .set_error_message("Argument must be a letter or forward/backward quote")
+            }
+        }
+        Ok(())
+    }
+
+    /// Where mark `name` is, if it's set
+    fn mark(&self, name: char) -> Option<Place> {
+        if !name.is_ascii_uppercase() {
+            let mut place = self.active_window().place();
+            place.pos = self.active_window().buffer().marks().get(name)?;
+            return Some(place);
+        }
+        let mut place = self.global_marks.get(&name)?.clone();
+        let open = self.windows.iter().map(Window::buffer).find(|buffer| {
+            let file = place.file.as_deref().map(Path::new);
+            buffer.id() == place.buffer
+                || buffer
+                    .path()
+                    .zip(file)
+                    .is_some_and(|(path, file)| same_file(path, file))
+        });
+        if let Some(buffer) = open {
+            place.buffer = buffer.id();
+            place.pos = buffer.marks().get(name).unwrap_or(place.pos);
+        }
+        Some(place)
+    }
+
+    /// `'{name}` goes to the first non-blank of the mark's line, `` `{name} `` (`exact`) to
+    /// the mark itself
+    fn jump_to_mark(&mut self, name: char, exact: bool) -> Result<()> {
+        let Some(place) = self.mark(name) else {
+            return self.set_error_message("Mark not set");
+        };
+        self.active_window_mut().push_jump();
+        if self.open_place(&place)? && !exact {
+            let row = self.active_window().adjusetd_cursor().0;
+            let line = self.active_window().buffer().nth_line(row);
+            let indent = line.len() - line.trim_start().len();
+            self.active_window_mut().goto(row, indent)?;
+        }
+        Ok(())
+    }
+
+    /// `<C-o>` (`step` -1) and `<C-i>` (1): back and forth through the active window's jump
+    /// list. Places whose buffer is gone without a file to open again are skipped.
+    pub fn step_jump(&mut self, step: isize) -> Result<()> {
+        loop {
+            let here = self.active_window().place();
+            let jumps = self.active_window_mut().jumps_mut();
+            let jump = if step < 0 {
+                jumps.back(here)
+            } else {
+                jumps.forward()
+            };
+            let Some(jump) = jump else {
+                return Ok(());
+            };
+            let gone = jump.file.is_none() && self.window_with_buffer(jump.buffer).is_none();
+            if !gone {
+                self.open_place(&jump)?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// `g;` (`step` -1) and `g,` (1): back and forth through where the buffer was changed
+    pub fn step_change(&mut self, step: isize) -> Result<()> {
+        match self.active_window_mut().marks_mut().step_change(step) {
+            Ok((row, col)) => self.active_window_mut().goto(row, col),
+            Err(e) => self.set_error_message(e),
+        }
+    }
+
+    /// `:marks [names]`: every mark, or the ones named, with where they are and the text
+    /// there, or the file for a global mark in another one
+    pub fn show_marks(&mut self, names: Option<String>) -> Result<()> {
+        let buffer = self.active_window().buffer();
+        let local = buffer
+            .marks()
+            .iter()
+            .filter(|(name, _)| !name.is_ascii_uppercase());
+        let global = self.global_marks.keys().copied();
+        let mut all: Vec<(char, Place)> = local
+            .map(|(name, _)| name)
+            .chain(global)
+            .filter(|name| names.as_ref().is_none_or(|names| names.contains(*name)))
+            .filter_map(|name| Some((name, self.mark(name)?)))
+            .collect();
+        // Like Vim: `'` first, then the letters, then the rest
+        all.sort_by_key(|&(name, _)| (name != '\'', !name.is_ascii_alphabetic(), name));
+        if all.is_empty() {
+            return self.set_error_message("No marks set");
+        }
+        let mut lines = vec!["mark line  col file/text".to_string()];
+        for (name, place) in all {
+            let text = if place.buffer == buffer.id() {
+                let line = buffer.lines().get(place.pos.0).map_or("", String::as_str);
+                line.trim_start().to_owned()
+            } else {
+                place.file.unwrap_or_default()
+            };
+            let (row, col) = place.pos;
+            lines.push(format!(" {name} {:>6} {col:>4} {text}", row + 1));
+        }
+        self.show_output(lines)
+    }
+
+    /// `:delmarks {names}`, where `a-d` is a range of them, or `:delmarks!` for every `a`-`z`
+    pub fn delete_marks(&mut self, names: Option<String>, all: bool) -> Result<()> {
+        let mut chars = Vec::new();
+        if all {
+            chars.extend('a'..='z');
+        }
+        let names = names.unwrap_or_default();
+        let names: Vec<char> = names.chars().filter(|c| !c.is_whitespace()).collect();
+        if names.is_empty() && !all {
+            return self.set_error_message("Argument required");
+        }
+        let mut i = 0;
+        while i < names.len() {
+            match names.get(i + 1..i + 3) {
+                Some(&['-', last]) if names[i] <= last => {
+                    chars.extend(names[i]..=last);
+                    i += 3;
+                }
+                _ => {
+                    chars.push(names[i]);
+                    i += 1;
+                }
+            }
+        }
+        if let Some(bad) = chars
+            .iter()
+            .find(|c| !c.is_ascii_alphabetic() && !"'`\"[]<>^.".contains(**c))
+        {
+            return self.set_error_message(format!("Invalid argument: `{bad}`"));
+        }
+        for name in chars {
+            if name.is_ascii_uppercase() {
+                self.global_marks.remove(&name);
+                for window in &mut self.windows {
+                    window.marks_mut().remove(name);
+                }
+            } else {
+                let name = if name == '`' { '\'' } else { name };
+                self.active_window_mut().marks_mut().remove(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// `:copen` and `:lopen`: the list in a window below, where `<CR>` goes to the entry under
+    /// the cursor
+    pub fn open_list_window(&mut self, quickfix: &List, location: bool) -> Result<()> {
+        if let Some(list) = &self.list_window {
+            let (window, origin) = (list.window, list.origin);
+            let back = if self.cur_window == window {
+                origin
+            } else {
+                self.cur_window
+            };
+            self.close_window(window)?;
+            self.focus(if back > window { back - 1 } else { back })?;
+        }
+        let height = self.active_window().height();
+        if height < 3 {
+            return self.set_error_message("Not enough room");
+        }
+        let origin = self.cur_window;
+        self.split_below(11.min(height / 2 + 1))?;
+        self.list_window = Some(ListWindow {
+            window: self.cur_window,
+            origin,
+            location,
+        });
+        self.refresh_list_window(quickfix)
+    }
+
+    /// `:cclose` and `:lclose`
+    pub fn close_list_window(&mut self) -> Result<()> {
+        match &self.list_window {
+            Some(list) => self.close_window(list.window),
+            None => Ok(()),
+        }
+    }
+
+    /// In the `:copen` window, the entry under the cursor and whether it's a location list
+    pub fn list_window_entry(&self) -> Option<(usize, bool)> {
+        let list = self.list_window.as_ref()?;
+        (list.window == self.cur_window)
+            .then(|| (self.active_window().adjusetd_cursor().0, list.location))
+    }
+
+    /// Brings the `:copen` window up to date with its list
+    fn refresh_list_window(&mut self, quickfix: &List) -> Result<()> {
+        let Some(window) = &self.list_window else {
+            return Ok(());
+        };
+        let (i, location) = (window.window, window.location);
+        let list = self.list(quickfix, location);
+        let mut lines: Vec<String> = list.entries().iter().map(Entry::describe).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        let current = list.current();
+        let name = if location {
+            "[Location List]"
+        } else {
+            "[Quickfix List]"
+        };
+        self.windows[i].set_buffer(Buffer::scratch(name, lines))?;
+        self.windows[i].goto(current, 0)?;
+        self.reprint_cursor()
+    }
+
+    /// Whether the last thing done ended in an error, for `:cdo` to stop at
+    pub fn failed(&self) -> bool {
+        self.message_is_error
+    }
+
+    /// Tells language servers about buffers they haven't seen and edits they haven't heard
+    /// about, starting the ones that are needed. Called by the event loop, on the runtime.
+    pub fn lsp_sync(&mut self) -> Result<()> {
+        let lspservers = self.options.borrow().lspservers.clone();
+        if lspservers.is_empty() && self.lsp.is_empty() {
+            return Ok(());
+        }
+        let open: Vec<usize> = self.windows.iter().map(|w| w.buffer().id()).collect();
+        for client in &mut self.lsp {
+            client.close_all_but(&open);
+        }
+        for i in 0..self.windows.len() {
+            let buffer = self.windows[i].buffer();
+            let (Some(path), Some(filetype)) = (buffer.path(), buffer.filetype()) else {
+                continue;
+            };
+            if buffer.loading() {
+                continue;
+            }
+            let (path, id) = (path.to_path_buf(), buffer.id());
+            let c = match self.lsp.iter().position(|c| c.filetype == filetype) {
+                Some(c) => c,
+                None => {
+                    let Some(cmd) = lsp::server(&lspservers, filetype) else {
+                        continue;
+                    };
+                    if self.lsp_failed.iter().any(|failed| failed == filetype) {
+                        continue;
+                    }
+                    let shell = self.options.borrow().shell.clone();
+                    match Client::spawn(&shell, &cmd, filetype, &self.jobs) {
+                        Ok(client) => self.lsp.push(client),
+                        Err(e) => {
+                            self.lsp_failed.push(filetype.to_owned());
+                            self.set_error_message(e)?;
+                            continue;
+                        }
+                    }
+                    self.lsp.len() - 1
+                }
+            };
+            if self.lsp[c].has_document(id) {
+                let changes = self.windows[i].take_changes();
+                self.lsp[c].change(id, changes, self.windows[i].buffer().lines());
+            } else if self.lsp[c].open(id, &path, self.windows[i].buffer().lines()) {
+                self.windows[i].track_changes();
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks every language server to shut down and exit, for when rim is exiting
+    pub fn lsp_shutdown(&mut self) {
+        for client in &mut self.lsp {
+            client.shutdown();
+        }
+    }
+
+    /// Whether any language server hasn't exited yet
+    pub fn lsp_running(&self) -> bool {
+        !self.lsp.is_empty()
+    }
+
+    /// What comes from a language server after `lsp_shutdown`, when all that matters is the
+    /// answer to `shutdown` and when it exits. The screen is gone by then.
+    pub fn lsp_exit_event(&mut self, event: job::Event) {
+        let Some(c) = self.lsp.iter().position(|client| client.id() == event.id()) else {
+            return;
+        };
+        match event {
+            job::Event::Output { bytes, .. } => {
+                self.lsp[c].output(&bytes);
+            }
+            job::Event::Exited { .. } => {
+                self.lsp.remove(c);
+            }
+        }
+    }
+
+    fn lsp_event(&mut self, c: usize, event: job::Event) -> Result<()> {
+        let bytes = match event {
+            job::Event::Output { bytes, .. } => bytes,
+            job::Event::Exited { .. } => {
+                let client = self.lsp.remove(c);
+                let message = format!("Language server for {} exited", client.filetype);
+                self.lsp_failed.push(client.filetype);
+                return self.set_error_message(message);
+            }
+        };
+        for message in self.lsp[c].output(&bytes) {
+            self.lsp_message(c, message)?;
+        }
+        Ok(())
+    }
+
+    fn lsp_message(&mut self, c: usize, message: Message) -> Result<()> {
+        let (request, result) = match message {
+            Message::Response { request, result } => (request, result),
+            Message::Diagnostics { uri, diagnostics } => {
+                let Some(path) = lsp::path(&uri) else {
+                    return Ok(());
+                };
+                for i in 0..self.windows.len() {
+                    let buffer = self.windows[i].buffer();
+                    if !buffer.path().is_some_and(|p| same_file(p, &path)) {
+                        continue;
+                    }
+                    let diagnostics = diagnostics
+                        .iter()
+                        .filter_map(|d| self.lsp[c].diagnostic(buffer.lines(), d))
+                        .collect();
+                    self.windows[i].set_diagnostics(diagnostics)?;
+                }
+                return self.reprint_cursor();
+            }
+            Message::ApplyEdit(edit) => return self.apply_workspace_edit(c, &edit),
+            Message::Show(text, true) => return self.set_error_message(text),
+            Message::Show(text, false) => return self.set_message(text),
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => return self.set_error_message(e),
+        };
+        match request {
+            Request::Initialize | Request::Shutdown | Request::ExecuteCommand => Ok(()),
+            Request::Definition => match lsp::locations(&result).first() {
+                Some((uri, range)) => self.lsp_jump(c, uri, range),
+                None => self.set_error_message("No definition found"),
+            },
+            Request::References => self.show_references(c, &result),
+            Request::Hover => {
+                let text = lsp::hover_text(result.get("contents").unwrap_or(&Value::Null));
+                let lines: Vec<String> = text.trim().lines().map(String::from).collect();
+                if lines.is_empty() {
+                    return self.set_message("No information available");
+                }
+                self.float = Some(lines);
+                self.draw()
+            }
+            Request::Rename if result.is_null() => self.set_error_message("Nothing to rename"),
+            Request::Rename => self.apply_workspace_edit(c, &result),
+            Request::CodeActions { apply } => self.code_actions(c, &result, apply),
+            Request::Formatting { buffer } => {
+                let Some(i) = self.window_with_buffer(buffer) else {
+                    return Ok(());
+                };
+                let edits = result.as_array().map(Vec::as_slice).unwrap_or_default();
+                self.apply_text_edits(c, i, edits)?;
+                self.reprint_cursor()
+            }
+            Request::Completion { buffer, row, start } => {
+                let items = result.get("items").unwrap_or(&result).as_array();
+                let words: Vec<String> = items
+                    .into_iter()
+                    .flatten()
+                    .filter_map(lsp::completion_word)
+                    .map(|word| word.lines().next().unwrap_or_default().to_owned())
+                    .collect();
+                self.complete_words(buffer, (row, start), words)
+            }
+        }
+    }
+
+    /// To the start of `range` in the file at `uri`
+    fn lsp_jump(&mut self, c: usize, uri: &str, range: &Value) -> Result<()> {
+        let (Some(file), Some(start)) = (lsp_file(uri), range.get("start")) else {
+            return Ok(());
+        };
+        let row = start.get("line").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let entry = Entry {
+            file,
+            line: row + 1,
+            col: 0,
+            kind: None,
+            text: String::new(),
+        };
+        if !self.jump_to(&entry)? {
+            return Ok(());
+        }
+        let lines = self.active_window().buffer().lines();
+        if let Some((row, col)) = self.lsp[c].location(lines, start) {
+            self.active_window_mut().goto(row, col)?;
+        }
+        Ok(())
+    }
+
+    /// The `Location`s in `result` as a new quickfix list, for `State` to pick up
+    fn show_references(&mut self, c: usize, result: &Value) -> Result<()> {
+        let mut files: HashMap<String, Vec<String>> = HashMap::new();
+        let mut entries = Vec::new();
+        for (uri, range) in lsp::locations(result) {
+            let (Some(file), Some(start)) = (lsp_file(&uri), range.get("start")) else {
+                continue;
+            };
+            if !files.contains_key(&file) {
+                files.insert(file.clone(), self.lines_of(Path::new(&file)));
+            }
+            let lines = &files[&file];
+            let Some((row, col)) = self.lsp[c].location(lines, start) else {
+                continue;
+            };
+            let text = lines.get(row).map_or("", |line| line.trim());
+            entries.push(Entry {
+                text: text.to_owned(),
+                file,
+                line: row + 1,
+                col: col + 1,
+                kind: None,
+            });
+        }
+        if entries.is_empty() {
+            return self.set_error_message("No references found");
+        }
+        self.finished_list = Some(FinishedList {
+            list: List::new(":references".to_string(), entries),
+            location: false,
+            jump: true,
+        });
+        Ok(())
+    }
+
+    /// A file's lines, from a window that has it or else from disk
+    fn lines_of(&self, path: &Path) -> Vec<String> {
+        let window = self.windows.iter().find(|window| {
+            window
+                .buffer()
+                .path()
+                .is_some_and(|other| same_file(other, path))
+        });
+        match window {
+            Some(window) => window.buffer().lines().to_vec(),
+            None => fs::read_to_string(path)
+                .map(|contents| buffer::split_lines(&contents, FileFormat::detect(&contents)).0)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `:LspCodeAction`'s list of what can be done at the cursor, or with `apply` the one to do
+    fn code_actions(&mut self, c: usize, result: &Value, apply: Option<usize>) -> Result<()> {
+        let actions = result.as_array().map(Vec::as_slice).unwrap_or_default();
+        if actions.is_empty() {
+            return self.set_message("No code actions available");
+        }
+        let Some(i) = apply else {
+            let lines = actions
+                .iter()
+                .enumerate()
+                .map(|(i, action)| {
+                    let title = action.get("title").and_then(Value::as_str);
+                    format!("{}: {}", i + 1, title.unwrap_or_default())
+                })
+                .collect();
+            return self.show_output(lines);
+        };
+        let Some(action) = actions.get(i) else {
+            return self.set_error_message(format!("No code action {}", i + 1));
+        };
+        if let Some(edit) = action.get("edit") {
+            self.apply_workspace_edit(c, edit)?;
+        }
+        // A `CodeAction` has its `Command`, or is a `Command` itself
+        let command = match action.get("command") {
+            Some(Value::String(_)) => Some(action),
+            command => command,
+        };
+        if let Some(command) = command {
+            let params = json!({
+                "command": command.get("command").cloned().unwrap_or(Value::Null),
+                "arguments": command.get("arguments").cloned().unwrap_or(json!([])),
+            });
+            let request = Request::ExecuteCommand;
+            self.lsp[c].request("workspace/executeCommand", params, request);
+        }
+        Ok(())
+    }
+
+    /// Makes the changes of a `WorkspaceEdit`: in the window that has a file, or else in the file
+    /// on disk
+    fn apply_workspace_edit(&mut self, c: usize, edit: &Value) -> Result<()> {
+        let mut files = 0;
+        for (uri, edits) in lsp::workspace_edits(edit) {
+            let Some(path) = lsp::path(&uri) else {
+                continue;
+            };
+            let window = self.windows.iter().position(|window| {
+                window
+                    .buffer()
+                    .path()
+                    .is_some_and(|other| same_file(other, &path))
+            });
+            let result = match window {
+                Some(i) => {
+                    self.apply_text_edits(c, i, &edits)?;
+                    Ok(())
+                }
+                None => self.edit_file(c, &path, &edits),
+            };
+            if let Err(e) = result {
+                return self.set_error_message(e);
+            }
+            files += 1;
+        }
+        if files > 1 {
+            self.set_message(format!("{files} files changed"))?;
+        }
+        self.reprint_cursor()
+    }
+
+    /// `TextEdit`s to the buffer of window `i`
+    fn apply_text_edits(&mut self, c: usize, i: usize, edits: &[Value]) -> Result<()> {
+        let lines = self.windows[i].buffer().lines();
+        let edits = edits
+            .iter()
+            .filter_map(|edit| self.lsp[c].edit(lines, edit))
+            .collect();
+        match lsp::apply(lines, edits) {
+            Some((first, last, lines)) => self.windows[i].edit_lines(first, last, lines),
+            None => Ok(()),
+        }
+    }
+
+    /// `TextEdit`s to a file no window has
+    fn edit_file(&self, c: usize, path: &Path, edits: &[Value]) -> std::result::Result<(), String> {
+        let err = |e: std::io::Error| format!("\"{}\" {e}", path.display());
+        let contents = fs::read_to_string(path).map_err(err)?;
+        let fileformat = FileFormat::detect(&contents);
+        let (mut lines, endofline) = buffer::split_lines(&contents, fileformat);
+        let edits = edits
+            .iter()
+            .filter_map(|edit| self.lsp[c].edit(&lines, edit))
+            .collect();
+        let Some((first, last, new)) = lsp::apply(&lines, edits) else {
+            return Ok(());
+        };
+        lines.splice(first..=last, new);
+        let mut text = lines.join(fileformat.ending());
+        if endofline {
+            text.push_str(fileformat.ending());
+        }
+        fs::write(path, text).map_err(err)
+    }
+
+    /// The language server that has the active window's buffer, after telling it the latest.
+    /// Without one it's an error on the message line.
+    fn lsp_client(&mut self) -> Result<Option<usize>> {
+        self.lsp_sync()?;
+        let id = self.active_window().buffer().id();
+        let client = self.lsp.iter().position(|client| client.has_document(id));
+        if client.is_none() {
+            self.set_error_message("No language server for this buffer")?;
+        }
+        Ok(client)
+    }
+
+    /// Asks the active window's server `method` about the cursor, with `more` params than
+    /// where it is
+    fn lsp_at_cursor(
+        &mut self,
+        method: &str,
+        more: Vec<(&str, Value)>,
+        request: Request,
+    ) -> Result<()> {
+        let Some(c) = self.lsp_client()? else {
+            return Ok(());
+        };
+        let window = self.active_window();
+        let (id, lines) = (window.buffer().id(), window.buffer().lines());
+        let params = self.lsp[c].position_params(id, lines, window.adjusetd_cursor());
+        let Some(Value::Object(mut params)) = params else {
+            return Ok(());
+        };
+        params.extend(more.into_iter().map(|(key, value)| (key.to_owned(), value)));
+        self.lsp[c].request(method, Value::Object(params), request);
+        Ok(())
+    }
+
+    /// `gd`
+    pub fn lsp_definition(&mut self) -> Result<()> {
+        self.lsp_at_cursor("textDocument/definition", Vec::new(), Request::Definition)
+    }
+
+    /// `gr`, into the quickfix list
+    pub fn lsp_references(&mut self) -> Result<()> {
+        let context = json!({"includeDeclaration": true});
+        self.lsp_at_cursor(
+            "textDocument/references",
+            vec![("context", context)],
+            Request::References,
+        )
+    }
+
+    /// `K`, in a float
+    pub fn lsp_hover(&mut self) -> Result<()> {
+        self.lsp_at_cursor("textDocument/hover", Vec::new(), Request::Hover)
+    }
+
+    /// `:LspRename {name}`: the symbol under the cursor, everywhere it's used
+    pub fn lsp_rename(&mut self, name: Option<String>) -> Result<()> {
+        let Some(name) = name else {
+            return self.set_error_message("Argument required");
+        };
+        let more = vec![("newName", name.into())];
+        self.lsp_at_cursor("textDocument/rename", more, Request::Rename)
+    }
+
+    /// `:LspCodeAction` lists what can be done at the cursor, `:LspCodeAction N` does the Nth
+    pub fn lsp_code_action(&mut self, n: Option<String>) -> Result<()> {
+        let apply = match n.map(|n| n.trim().parse::<usize>()) {
+            None => None,
+            Some(Ok(n @ 1..)) => Some(n - 1),
+            Some(_) => return self.set_error_message("Invalid argument"),
+        };
+        let Some(c) = self.lsp_client()? else {
+            return Ok(());
+        };
+        let window = self.active_window();
+        let (id, lines) = (window.buffer().id(), window.buffer().lines());
+        let cursor = window.adjusetd_cursor();
+        let position = self.lsp[c].position(lines, cursor);
+        let diagnostics: Vec<Value> = window
+            .buffer()
+            .diagnostics()
+            .iter()
+            .filter(|diagnostic| diagnostic.row == cursor.0)
+            .map(|diagnostic| self.lsp[c].lsp_diagnostic(lines, diagnostic))
+            .collect();
+        let Some(document) = self.lsp[c].text_document(id) else {
+            return Ok(());
+        };
+        let params = json!({
+            "textDocument": document,
+            "range": {"start": position, "end": position},
+            "context": {"diagnostics": diagnostics},
+        });
+        let request = Request::CodeActions { apply };
+        self.lsp[c].request("textDocument/codeAction", params, request);
+        Ok(())
+    }
+
+    /// `:LspFormat`: the whole buffer, as the server would have it
+    pub fn lsp_format(&mut self) -> Result<()> {
+        let Some(c) = self.lsp_client()? else {
+            return Ok(());
+        };
+        let id = self.active_window().buffer().id();
+        let Some(document) = self.lsp[c].text_document(id) else {
+            return Ok(());
+        };
+        let options = json!({"tabSize": 4, "insertSpaces": true});
+        let params = json!({"textDocument": document, "options": options});
+        let request = Request::Formatting { buffer: id };
+        self.lsp[c].request("textDocument/formatting", params, request);
+        Ok(())
+    }
+
+    /// `<C-x><C-o>` in insert mode, the omni completion: what the server would put in place of
+    /// the word before the cursor
+    pub fn lsp_complete(&mut self) -> Result<()> {
+        self.end_popup()?;
+        let row = self.active_window().adjusetd_cursor().0;
+        let start = self.completion_start(complete::is_keyword);
+        let buffer = self.active_window().buffer().id();
+        let request = Request::Completion { buffer, row, start };
+        self.lsp_at_cursor("textDocument/completion", Vec::new(), request)
+    }
+
+    /// Completion candidates for what's from byte `start` of line `row` up to the cursor, if
+    /// the cursor's still there in insert mode
+    fn complete_words(
+        &mut self,
+        buffer: usize,
+        (row, start): (usize, usize),
+        words: Vec<String>,
+    ) -> Result<()> {
+        let window = self.active_window();
+        let (cursor_row, col) = window.adjusetd_cursor();
+        if self.mode.get() != Mode::Insert
+            || window.buffer().id() != buffer
+            || cursor_row != row
+            || col < start
+        {
+            return Ok(());
+        }
+        self.start_popup(start, words)
+    }
+
+    /// Where the text before the cursor made of `part` characters starts in its line
+    fn completion_start(&self, part: impl Fn(char) -> bool) -> usize {
+        let (row, col) = self.active_window().adjusetd_cursor();
+        let line = self.active_window().buffer().nth_line(row);
+        let col = col.min(line.len());
+        line[..col]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| part(c))
+            .last()
+            .map_or(col, |(i, _)| i)
+    }
+
+    /// `<C-n>` (`step` 1) and `<C-p>` (-1) in insert mode: the next or previous candidate in
+    /// the menu, or else a menu of the keywords the one before the cursor could be, from this
+    /// buffer first and then the others
+    pub fn complete_keyword(&mut self, step: isize) -> Result<()> {
+        if let Some(popup) = &mut self.popup {
+            popup.cycle(step);
+            let (start, text) = (popup.start, popup.text().to_owned());
+            self.active_window_mut().complete_text(start, &text)?;
+            return self.draw();
+        }
+        let start = self.completion_start(complete::is_keyword);
+        let window = self.active_window();
+        let row = window.adjusetd_cursor().0;
+        let mut words = complete::keywords(window.buffer().lines(), Some((row, start)), step > 0);
+        for buffer in self.other_buffers() {
+            words.extend(complete::keywords(buffer.lines(), None, true));
+        }
+        self.start_popup(start, words)
+    }
+
+    /// `<C-x><C-f>`: the paths the one before the cursor could be the start of
+    pub fn complete_file(&mut self) -> Result<()> {
+        self.end_popup()?;
+        let start = self.completion_start(|c| !c.is_whitespace());
+        let (row, col) = self.active_window().adjusetd_cursor();
+        let word = &self.active_window().buffer().nth_line(row)[start..col];
+        let files = complete::files(word, false);
+        self.start_popup(start, files)
+    }
+
+    /// `<C-x><C-l>`: whole lines starting like the cursor's does, nearest above it first and
+    /// then the other buffers'. Indent doesn't count.
+    pub fn complete_line(&mut self) -> Result<()> {
+        self.end_popup()?;
+        let start = self.completion_start(|_| true);
+        let window = self.active_window();
+        let line = window.buffer().nth_line(window.adjusetd_cursor().0);
+        let start = start + line[start..].len() - line[start..].trim_start().len();
+        let row = window.adjusetd_cursor().0;
+        let lines = window.buffer().lines();
+        let others = self.other_buffers().into_iter().flat_map(Buffer::lines);
+        let candidates = lines[..row]
+            .iter()
+            .rev()
+            .chain(lines[row + 1..].iter().rev())
+            .chain(others)
+            .map(|line| line.trim_start().to_owned())
+            .filter(|line| !line.is_empty())
+            .collect();
+        self.start_popup(start, candidates)
+    }
+
+    /// Every buffer shown in a window but the active one's, each once
+    fn other_buffers(&self) -> Vec<&Buffer> {
+        let mut buffers: Vec<&Buffer> = vec![self.active_window().buffer()];
+        for window in &self.windows {
+            if !buffers.iter().any(|b| b.id() == window.buffer().id()) {
+                buffers.push(window.buffer());
+            }
+        }
+        buffers.split_off(1)
+    }
+
+    /// Insert mode completion of what's from byte `start` of the cursor's line up to the
+    /// cursor with those of `candidates` that start with it, as `completeopt` says
+    fn start_popup(&mut self, start: usize, candidates: Vec<String>) -> Result<()> {
+        self.popup = None;
+        let (row, col) = self.active_window().adjusetd_cursor();
+        let typed = self.active_window().buffer().nth_line(row)[start..col].to_owned();
+        let candidates: Vec<String> = complete::filter(candidates, &typed, false)
+            .into_iter()
+            .filter(|candidate| *candidate != typed)
+            .collect();
+        if candidates.is_empty() {
+            return self.set_error_message("Pattern not found");
+        }
+        let opt = CompleteOpt::parse(&self.options.borrow().completeopt);
+        let mut popup = Popup {
+            start,
+            menu: opt.menu && (candidates.len() > 1 || opt.menuone),
+            typed,
+            candidates,
+            selected: None,
+            insert: !opt.noinsert,
+        };
+        if opt.longest {
+            let longest = complete::longest_common_prefix(&popup.candidates);
+            if longest.len() > popup.typed.len() {
+                popup.typed = longest;
+            }
+        } else if !opt.noselect {
+            popup.selected = Some(0);
+        }
+        let text = popup.text().to_owned();
+        self.popup = Some(popup);
+        self.active_window_mut().complete_text(start, &text)?;
+        self.draw()
+    }
+
+    /// `<C-y>`: the selected candidate stays, or goes in if `noinsert` kept it out, and the
+    /// menu goes
+    pub fn accept_completion(&mut self) -> Result<()> {
+        let Some(popup) = self.popup.take() else {
+            return Ok(());
+        };
+        if let (false, Some(i)) = (popup.insert, popup.selected) {
+            self.active_window_mut()
+                .complete_text(popup.start, &popup.candidates[i])?;
+        }
+        self.draw()
+    }
+
+    /// `<C-e>`: back to what was there before the menu
+    pub fn cancel_completion(&mut self) -> Result<()> {
+        let Some(popup) = self.popup.take() else {
+            return Ok(());
+        };
+        self.active_window_mut()
+            .complete_text(popup.start, &popup.typed)?;
+        self.draw()
+    }
+
+    /// Any key that isn't for the menu leaves the text as it is, and the menu goes
+    pub fn end_popup(&mut self) -> Result<()> {
+        match self.popup.take() {
+            Some(popup) if popup.menu => self.draw(),
+            _ => Ok(()),
+        }
+    }
+
+    fn terminal_event(&mut self, i: usize, event: job::Event) -> Result<()> {
+        let result = match event {
+            job::Event::Output { bytes, .. } => {
+                self.windows[i].terminal_output(&bytes)?;
+                return self.reprint_cursor();
+            }
+            job::Event::Exited { result, .. } => result,
+        };
+        let window = &mut self.windows[i];
+        let Some(terminal) = window.terminal_mut() else {
+            return Ok(());
+        };
+        terminal.exited();
+        let name = format!("\"!{}\"", terminal.cmd);
+        window.terminal_changed()?;
+        match result {
+            Ok(Some(code)) => self.set_message(format!("{name} [Process exited {code}]")),
+            Ok(None) => self.set_message(format!("{name} [Process killed]")),
+            Err(e) => self.set_error_message(e),
+        }
+    }
+
+    fn window_with_buffer(&self, id: usize) -> Option<usize> {
+        self.windows
+            .iter()
+            .position(|window| window.buffer().id() == id)
+    }
+
+    /// `<C-c>`: stops the running command, or else loading the active window's file
+    pub fn interrupt(&mut self) -> Result<()> {
+        let Some(running) = self.job.take() else {
+            return self.cancel_loading();
+        };
+        if let JobKind::Show { interactive: true } = running.kind {
+            self.backend.borrow_mut().resume()?;
+        }
+        // Dropping it is what kills it
+        drop(running);
+        self.set_error_message("Interrupted")
+    }
+
+    /// Output from a command: a line fits on the message line, more than that goes in the
+    /// pager
+    fn show_output(&mut self, lines: Vec<String>) -> Result<()> {
+        if lines.len() > 1 {
+            self.pager = Some(Pager { lines, top: 0 });
+            return self.draw();
+        }
+        self.set_message(lines.into_iter().next().unwrap_or_default())
+    }
+
+    /// Whether keys go to `page`
+    pub fn paging(&self) -> bool {
+        self.pager.is_some()
+    }
+
+    /// A key while the pager is up: `<Space>` or `f` is a page further, `d` half a page, `j`
+    /// or `<CR>` a line. Any other key, or any key at the end, closes it. Returns whether the
+    /// key was `:`, which goes on to start a command line.
+    pub fn page(&mut self, key: char) -> Result<bool> {
+        let page = self.messageline_row();
+        let Some(pager) = &mut self.pager else {
+            return Ok(false);
+        };
+        let end = pager.lines.len().saturating_sub(page);
+        let step = match key {
+            ' ' | 'f' => page,
+            'd' => page / 2,
+            'j' | '\r' => 1,
+            _ => 0,
+        };
+        if pager.top >= end || step == 0 {
+            self.pager = None;
+            self.message.clear();
+            self.draw()?;
+            return Ok(key == ':');
+        }
+        pager.top = (pager.top + step).min(end);
+        self.draw().map(|_| false)
+    }
+
+    /// What `:w -` left for stdout
+    pub fn take_stdout(&mut self) -> Option<Vec<u8>> {
+        self.stdout.take()
+    }
+
+    /// `:w`, which leaves big buffers to the background. Returns whether that got going.
+    pub fn write_in_background(&mut self, force: bool) -> Result<bool> {
+        let events = self.events.clone();
+        let result = self.active_window_mut().write_in_background(force, &events);
+        self.report_write(result)
+    }
+
+    pub fn write_to_filename(&mut self, filename: String, force: bool) -> Result<bool> {
+        let result = self.active_window_mut().write_to_filename(filename, force);
+        self.report_write(result)
+    }
+
+    fn report_write(&mut self, result: std::result::Result<String, String>) -> Result<bool> {
+        match result {
+            Ok(msg) => {
+                let id = self.active_window().buffer().id();
+                for client in &mut self.lsp {
+                    client.save(id);
+                }
+                self.set_message(msg)?
+            }
+            Err(e) => {
+                self.set_error_message(e)?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn set_message(&mut self, message: impl ToString) -> Result<()> {
+        self.message = message.to_string();
+        self.message_is_error = false;
+        self.draw()
+    }
+
+    pub fn set_error_message(&mut self, message: impl ToString) -> Result<()> {
+        self.message = message.to_string();
+        self.message_is_error = true;
+        self.draw()
+    }
+
+    /// `kind` is `:` for a command or `/` for a search
+    pub fn enter_command_mode(&mut self, kind: char) -> Result<()> {
+        self.command_mode_cursor = Some(1);
+        self.browsing = None;
+        self.register_pending = false;
+        self.completion = None;
+        self.message = kind.to_string();
+        self.message_is_error = false;
+        self.draw()
+    }
+
+    pub fn leave_command_mode(&mut self) -> Result<()> {
+        if self.command_mode_cursor.take().is_some() {
+            self.message.clear();
+        }
+        self.completion = None;
+        self.draw()
+    }
+
+    /// `:` or `/`
+    pub fn command_kind(&self) -> char {
+        self.message.chars().next().unwrap_or(':')
+    }
+
+    fn command_cursor(&self) -> usize {
+        self.command_mode_cursor.expect("is in command mode")
+    }
+
+    /// `rl` characters to the right, negative goes left
+    pub fn command_move_cursor(&mut self, rl: isize) -> Result<()> {
+        let mut cursor = self.command_cursor();
+        for _ in 0..rl.unsigned_abs() {
+            let c = if rl < 0 {
+                self.message[1..cursor].chars().next_back()
+            } else {
+                self.message[cursor..].chars().next()
+            };
+            let Some(c) = c else {
+                break;
+            };
+            if rl < 0 {
+                cursor -= c.len_utf8();
+            } else {
+                cursor += c.len_utf8();
+            }
+        }
+        self.command_mode_cursor = Some(cursor);
+        self.end_completion()?;
+        self.reprint_cursor()
+    }
+
+    /// `<Home>` and `<End>`
+    pub fn command_move_cursor_to_end(&mut self, end: bool) -> Result<()> {
+        self.command_mode_cursor = Some(if end { self.message.len() } else { 1 });
+        self.end_completion()?;
+        self.reprint_cursor()
+    }
+
+    pub fn command_type_char(&mut self, c: char) -> Result<()> {
+        self.command_insert(c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Puts `text` in at the cursor, which ends up after it
+    pub fn command_insert(&mut self, text: &str) -> Result<()> {
+        let cursor = self.command_cursor();
+        self.message.insert_str(cursor, text);
+        self.command_mode_cursor = Some(cursor + text.len());
+        self.command_edited()
+    }
+
+    /// Takes out the text from `start` to the cursor
+    fn command_delete_to(&mut self, start: usize) -> Result<()> {
+        let cursor = self.command_cursor();
+        self.message.replace_range(start..cursor, "");
+        self.command_mode_cursor = Some(start);
+        self.command_edited()
+    }
+
+    fn command_edited(&mut self) -> Result<()> {
+        // What's there now is what the next `<Up>` or `<Tab>` looks for
+        self.browsing = None;
+        self.end_completion()?;
+        self.reprint_messageline()
+    }
+
+    /// `<BS>`, the character before the cursor
+    pub fn command_delete_char(&mut self) -> Result<()> {
+        let cursor = self.command_cursor();
+        match self.message[1..cursor].chars().next_back() {
+            Some(c) => self.command_delete_to(cursor - c.len_utf8()),
+            None => Ok(()),
+        }
+    }
+
+    /// `<C-w>`, the word before the cursor and any spaces after it
+    pub fn command_delete_word(&mut self) -> Result<()> {
+        let cursor = self.command_cursor();
+        let before = &self.message[1..cursor];
+        let trimmed = before.trim_end();
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let word = match trimmed.chars().next_back() {
+            Some(last) if is_word(last) => trimmed.trim_end_matches(is_word),
+            Some(_) => trimmed.trim_end_matches(|c: char| !is_word(c) && !c.is_whitespace()),
+            None => trimmed,
+        };
+        self.command_delete_to(1 + word.len())
+    }
+
+    /// `<C-u>`, everything before the cursor
+    pub fn command_delete_to_start(&mut self) -> Result<()> {
+        self.command_delete_to(1)
+    }
+
+    /// `<Up>` (negative `step`) and `<Down>`: the next history entry that starts with what was
+    /// typed, and past the newest one what was typed again
+    pub fn command_history(&mut self, step: isize) -> Result<()> {
+        let kind = self.command_kind();
+        let list = self.history.list(kind);
+        let (from, typed) = self
+            .browsing
+            .take()
+            .unwrap_or_else(|| (list.len(), self.get_curr_command().to_owned()));
+        let found = if step < 0 {
+            self.history.older(kind, &typed, from)
+        } else {
+            self.history.newer(kind, &typed, from)
+        };
+        let (index, line) = match found {
+            Some(i) => (i, list[i].clone()),
+            None if step > 0 => (list.len(), typed.clone()),
+            None => {
+                self.browsing = Some((from, typed));
+                return Ok(());
+            }
+        };
+        self.message = format!("{kind}{line}");
+        self.command_mode_cursor = Some(self.message.len());
+        self.browsing = Some((index, typed));
+        self.end_completion()?;
+        self.reprint_messageline()
+    }
+
+    /// `<Tab>` (`step` 1) and `<S-Tab>` (-1) on a `:` command line, `commands` being every
+    /// command name. What each `<Tab>` does is up to `wildmode`.
+    pub fn complete(&mut self, step: isize, commands: Vec<String>) -> Result<()> {
+        let mut completion = match self.completion.take() {
+            Some(mut completion) => {
+                completion.tabs += 1;
+                completion
+            }
+            None => match self.start_completion(commands) {
+                Some(completion) => completion,
+                None => return Ok(()),
+            },
+        };
+        let was_shown = completion.menu;
+        let replaced = completion.text().len();
+        let options = self.options.borrow().clone();
+        let stage = complete::stage(&options.wildmode, options.wildmenu, completion.tabs);
+        if stage.longest {
+            let longest = complete::longest_common_prefix(&completion.candidates);
+            if longest.len() > completion.typed.len() && longest.starts_with(&completion.typed) {
+                completion.typed = longest;
+                completion.selected = None;
+            }
+        } else if stage.full {
+            completion.cycle(step);
+        }
+        completion.menu = stage.menu;
+
+        let start = 1 + completion.start;
+        self.message
+            .replace_range(start..start + replaced, completion.text());
+        self.command_mode_cursor = Some(start + completion.text().len());
+        let redraw = was_shown || completion.menu;
+        self.completion = Some(completion);
+        if redraw {
+            self.draw()
+        } else {
+            self.reprint_messageline()
+        }
+    }
+
+    /// The word before the cursor and what it could be, `None` when nothing fits
+    fn start_completion(&self, commands: Vec<String>) -> Option<Completion> {
+        if self.command_kind() != ':' {
+            return None;
+        }
+        let line = &self.message[1..self.command_cursor()];
+        let (context, start) = complete::context(line);
+        let word = &line[start..];
+        let fuzzy = self
+            .options
+            .borrow()
+            .wildoptions
+            .split(',')
+            .any(|option| option == "fuzzy");
+        let candidates = match context {
+            Context::File => complete::files(word, fuzzy),
+            context => complete::filter(self.completion_source(context, commands), word, fuzzy),
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(Completion {
+            start,
+            typed: word.to_owned(),
+            candidates,
+            selected: None,
+            tabs: 0,
+            menu: false,
+        })
+    }
+
+    fn completion_source(&self, context: Context, commands: Vec<String>) -> Vec<String> {
+        match context {
+            Context::Command => commands,
+            Context::Buffer => self
+                .windows
+                .iter()
+                .filter(|window| window.buffer().path().is_some())
+                .map(|window| window.buffer().filename().to_owned())
+                .chain(self.arglist.iter().cloned())
+                .collect(),
+            Context::Option => {
+                let mut names: Vec<String> = Options::NAMES
+                    .iter()
+                    .chain(LocalOptions::NAMES)
+                    .map(|name| name.to_string())
+                    .collect();
+                names.sort();
+                names
+            }
+            Context::OptionValue(name) => {
+                let local = self.active_window().buffer().local_options();
+                self.options
+                    .borrow()
+                    .value(&name)
+                    .or_else(|| local.value(&name))
+                    .into_iter()
+                    .collect()
+            }
+            Context::Highlight => {
+                let mut names = self.highlights.borrow().group_names();
+                names.extend(["clear".to_owned(), "link".to_owned()]);
+                names
+            }
+            Context::ColorScheme => Highlights::scheme_names(),
+            Context::File | Context::Nothing => Vec::new(),
+        }
+    }
+
+    /// Any key but `<Tab>` leaves the line as it is, and the wildmenu goes
+    fn end_completion(&mut self) -> Result<()> {
+        match self.completion.take() {
+            Some(completion) if completion.menu => self.draw(),
+            _ => Ok(()),
+        }
+    }
+
+    /// `<C-r>`, the next key is a register name
+    pub fn command_start_register(&mut self) {
+        self.register_pending = true;
+    }
+
+    pub fn register_pending(&self) -> bool {
+        self.register_pending
+    }
+
+    /// The rest of `<C-r>`: any register, lines joined with spaces since the command line is
+    /// one, and `None` is `<C-w>` for the word under the cursor. Unset registers insert
+    /// nothing.
+    pub fn command_insert_register(&mut self, register: Option<char>) -> Result<()> {
+        self.register_pending = false;
+        let text = match register {
+            None => self.active_window().word_under_cursor(),
+            Some(name) => self.register(name).map(|register| register.lines.join(" ")),
+        };
+        match text {
+            Some(text) => self.command_insert(&text),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    #[cfg(test)]
+    pub fn message_is_error(&self) -> bool {
+        self.message_is_error
+    }
+
+    #[cfg(test)]
+    pub fn style(&self, group: &str) -> crossterm::style::ContentStyle {
+        self.highlights.borrow().style(group)
+    }
+
+    pub fn get_curr_command(&self) -> &str {
+        &self.message[1..]
+    }
+}
+
+/// Whether `a` and `b` are the same file, even by different paths
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// The file at a language server's `uri`, relative to the current directory if it's in there
+fn lsp_file(uri: &str) -> Option<String> {
+    let path = lsp::path(uri)?;
+    let relative = env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf));
+    Some(relative.unwrap_or(path).display().to_string())
 }
@@ -0,0 +1,467 @@
+//! What's kept between runs. The state file is written on exit, merged with what other runs
+//! wrote to it meanwhile, and read on startup: the command line history, the registers, the
+//! global marks, the jump list and where the cursor was in each file. Session files from
+//! `:mksession` are ex commands that open the same windows on the same files again.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    history::History,
+    marks::{Place, Pos, LIST_LEN},
+    registers::{Register, Registers},
+};
+
+/// Where the cursor was in each file when it was left, newest last
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Positions(Vec<(String, Pos)>);
+
+impl Positions {
+    pub fn get(&self, file: &str) -> Option<Pos> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(other, _)| other == file)
+            .map(|&(_, pos)| pos)
+    }
+
+    pub fn set(&mut self, file: String, pos: Pos) {
+        self.0.retain(|(other, _)| *other != file);
+        self.0.push((file, pos));
+        let extra = self.0.len().saturating_sub(LIST_LEN);
+        self.0.drain(..extra);
+    }
+}
+
+/// The state file. File names in it are absolute, so it means the same from any directory.
+///
+/// One thing per line, told apart by the first character:
+/// - `:command` and `/pattern`, the history, which is also what the `:` and `/` registers are
+/// - `'A 12 4 file`, global mark `A` on line 12, counting from 1, at byte 4
+/// - `-12 4 file`, a jump
+/// - `"12 4 file`, where the cursor was in `file`
+/// - `@a l`, register `a`, `l` for lines or `c` for text, followed by its lines, each after a
+///   `|`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StateFile {
+    pub history: History,
+    /// `"`, `0`-`9` and `a`-`z`
+    pub registers: Registers,
+    /// `A`-`Z`
+    pub marks: BTreeMap<char, Place>,
+    /// The active window's, oldest first
+    pub jumps: Vec<Place>,
+    pub positions: Positions,
+}
+
+impl StateFile {
+    /// `$XDG_STATE_HOME/rim/state`, or `~/.local/state/rim/state`
+    pub fn default_path() -> Option<PathBuf> {
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state"))
+            })?;
+        Some(state.join("rim/state"))
+    }
+
+    /// A missing file is just nothing kept yet. Lines that don't make sense are skipped.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut state = Self::default();
+        // The register that `|` lines go in, with what's been read of it
+        let mut register: Option<(char, Register)> = None;
+        // Only `\n` ends lines, since register contents can have a `\r` at the end. The empty
+        // one after the last sets the register before it.
+        for line in contents.split('\n').chain([""]) {
+            if let Some(text) = line.strip_prefix('|') {
+                if let Some((_, register)) = &mut register {
+                    register.lines.push(text.to_owned());
+                }
+                continue;
+            }
+            if let Some((name, register)) = register.take() {
+                if !register.lines.is_empty() {
+                    state.registers.set(name, register);
+                }
+            }
+            if state.history.read_line(line) {
+                continue;
+            }
+            let mut chars = line.chars();
+            match chars.next() {
+                Some('@') => {
+                    register = match (chars.next(), chars.as_str()) {
+                        (Some(name), " l") if Registers::writable(name) => {
+                            Some((name, Register::new(Vec::new(), true)))
+                        }
+                        (Some(name), " c") if Registers::writable(name) => {
+                            Some((name, Register::new(Vec::new(), false)))
+                        }
+                        _ => None,
+                    }
+                }
+                Some('\'') => {
+                    let Some(name @ 'A'..='Z') = chars.next() else {
+                        continue;
+                    };
+                    if let Some(place) = parse_place(chars.as_str().trim_start()) {
+                        state.marks.insert(name, place);
+                    }
+                }
+                Some('-') => state.jumps.extend(parse_place(chars.as_str())),
+                Some('"') => {
+                    if let Some(Place {
+                        file: Some(file),
+                        pos,
+                        ..
+                    }) = parse_place(chars.as_str())
+                    {
+                        state.positions.0.push((file, pos));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = String::new();
+        self.history.write_lines(&mut contents);
+        for (name, register) in self.registers.iter() {
+            let kind = if register.linewise { 'l' } else { 'c' };
+            contents.push_str(&format!("@{name} {kind}\n"));
+            for line in &register.lines {
+                contents.push_str(&format!("|{line}\n"));
+            }
+        }
+        let mut line = |prefix: &str, file: &str, (row, col): Pos| {
+            contents.push_str(&format!("{prefix}{} {col} {file}\n", row + 1));
+        };
+        for (name, place) in &self.marks {
+            if let Some(file) = &place.file {
+                line(&format!("'{name} "), file, place.pos);
+            }
+        }
+        for jump in &self.jumps {
+            if let Some(file) = &jump.file {
+                line("-", file, jump.pos);
+            }
+        }
+        for (file, pos) in &self.positions.0 {
+            line("\"", file, *pos);
+        }
+        fs::write(path, contents)
+    }
+
+    /// Brings in what another run wrote since this one started, which is older than anything
+    /// here. Marks aren't merged: this run started out with the file's, so one that's missing
+    /// was deleted. Registers only come from the other run when they're empty here.
+    pub fn merge(&mut self, older: StateFile, max_history: usize) {
+        self.history.merge(&older.history, max_history);
+        self.registers.fill(older.registers);
+
+        let mut jumps: Vec<Place> = older
+            .jumps
+            .into_iter()
+            .filter(|old| !self.jumps.iter().any(|jump| jump.same_line(old)))
+            .collect();
+        jumps.append(&mut self.jumps);
+        let extra = jumps.len().saturating_sub(LIST_LEN);
+        jumps.drain(..extra);
+        self.jumps = jumps;
+
+        let mut positions: Vec<(String, Pos)> = older
+            .positions
+            .0
+            .into_iter()
+            .filter(|(file, _)| self.positions.get(file).is_none())
+            .collect();
+        positions.append(&mut self.positions.0);
+        let extra = positions.len().saturating_sub(LIST_LEN);
+        positions.drain(..extra);
+        self.positions.0 = positions;
+    }
+}
+
+/// `12 4 file` from the state file
+fn parse_place(s: &str) -> Option<Place> {
+    let mut parts = s.splitn(3, ' ');
+    let row: usize = parts.next()?.parse().ok()?;
+    let col = parts.next()?.parse().ok()?;
+    let file = parts.next().filter(|file| !file.is_empty())?;
+    Some(Place::in_file(file.to_owned(), (row.checked_sub(1)?, col)))
+}
+
+/// `file` the way the state file has it
+pub fn absolute(file: &str) -> String {
+    fs::canonicalize(file)
+        .or_else(|_| std::path::absolute(file))
+        .map_or_else(|_| file.to_owned(), |path| path.display().to_string())
+}
+
+/// A file name from the state file the way it would have been typed: relative when it's under
+/// the working directory
+pub fn relative(file: &str) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| {
+            Some(
+                Path::new(file)
+                    .strip_prefix(cwd)
+                    .ok()?
+                    .display()
+                    .to_string(),
+            )
+        })
+        .filter(|file| !file.is_empty())
+        .unwrap_or_else(|| file.to_owned())
+}
+
+/// The rows and columns a window covers, its statusline and divider included. `bottom` and
+/// `right` are just past it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub top: usize,
+    pub left: usize,
+    pub bottom: usize,
+    pub right: usize,
+}
+
+/// A window, for `:mksession`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionWindow {
+    pub rect: Rect,
+    pub file: Option<String>,
+    /// The cursor, as `:goto` counts
+    pub byte: usize,
+}
+
+/// Which earlier window each window after the first can be split off from to get `rects`, and
+/// whether to the right (`:vne`) or below (`:new`). Going from the last window back, each one
+/// is given back to a neighbour it makes a rectangle with, like closing it would. `None` if
+/// the windows don't tile that way.
+fn splits(rects: &[Rect]) -> Option<Vec<(usize, bool)>> {
+    let mut rects = rects.to_vec();
+    let mut splits = vec![(0, false); rects.len().saturating_sub(1)];
+    for k in (1..rects.len()).rev() {
+        let new = rects[k];
+        let (j, vertical) = (0..k).find_map(|j| {
+            let old = rects[j];
+            if old.right == new.left && old.top == new.top && old.bottom == new.bottom {
+                Some((j, true))
+            } else if old.bottom == new.top && old.left == new.left && old.right == new.right {
+                Some((j, false))
+            } else {
+                None
+            }
+        })?;
+        rects[j].right = new.right;
+        rects[j].bottom = new.bottom;
+        splits[k - 1] = (j, vertical);
+    }
+    Some(splits)
+}
+
+/// The session file for `windows`, with `active` the one to end up in. Windows without a file
+/// come back empty. If the layout can't be worked out they're just stacked.
+pub fn script(cwd: &Path, windows: &[SessionWindow], active: usize) -> String {
+    let rects: Vec<Rect> = windows.iter().map(|window| window.rect).collect();
+    let splits = splits(&rects).unwrap_or_else(|| {
+        (0..windows.len().saturating_sub(1))
+            .map(|k| (k, false))
+            .collect()
+    });
+    let mut lines = vec![
+        "\" rim session, :source it or start with rim -S".to_string(),
+        format!("cd {}", cwd.display()),
+    ];
+    let mut current = 0;
+    for (k, window) in windows.iter().enumerate() {
+        let cmd = match k.checked_sub(1).map(|k| splits[k]) {
+            None => "e",
+            Some((parent, vertical)) => {
+                focus(&mut lines, &mut current, parent);
+                // The new window is the active one
+                current = k;
+                if vertical {
+                    "vne"
+                } else {
+                    "new"
+                }
+            }
+        };
+        match &window.file {
+            Some(file) => {
+                lines.push(format!("{cmd} {file}"));
+                lines.push(format!("goto {}", window.byte));
+            }
+            None if k > 0 => lines.push(cmd.to_string()),
+            None => {}
+        }
+    }
+    focus(&mut lines, &mut current, active);
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// `:wincmd t` to the first window, then `:wincmd w` on to window `i`
+fn focus(lines: &mut Vec<String>, current: &mut usize, i: usize) {
+    if *current != i {
+        lines.push("wincmd t".to_string());
+        lines.extend((0..i).map(|_| "wincmd w".to_string()));
+        *current = i;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+
+    #[test]
+    fn state_file_round_trips() {
+        let path = temp_dir("state-file").join("state/state");
+        assert_eq!(StateFile::read(&path).unwrap(), StateFile::default());
+        let mut state = StateFile::default();
+        state.history.add(':', "set wrap", 50);
+        state
+            .registers
+            .yank(None, Register::new(vec!["a\r".into(), "|b".into()], true));
+        state.registers.yank(
+            Some('x'),
+            Register::new(vec!["@x l".into(), "".into()], false),
+        );
+        state
+            .marks
+            .insert('A', Place::in_file("/a b/c".to_string(), (2, 3)));
+        state.jumps.push(Place::in_file("/a".to_string(), (0, 0)));
+        state.positions.set("/a".to_string(), (4, 1));
+        state.positions.set("/b".to_string(), (0, 0));
+        state.write(&path).unwrap();
+        assert_eq!(StateFile::read(&path).unwrap(), state);
+
+        let mut newer = StateFile::default();
+        newer.jumps.push(Place::in_file("/b".to_string(), (1, 0)));
+        newer.jumps.push(Place::in_file("/a".to_string(), (0, 5)));
+        newer.positions.set("/a".to_string(), (9, 0));
+        newer
+            .registers
+            .yank(Some('x'), Register::new(vec!["y".into()], true));
+        newer.merge(state, 50);
+        assert_eq!(newer.history.list(':'), ["set wrap"]);
+        assert_eq!(newer.registers.get('x').unwrap().lines, ["y"]);
+        assert_eq!(newer.registers.get('0').unwrap().lines, ["a\r", "|b"]);
+        assert!(newer.marks.is_empty());
+        assert_eq!(newer.jumps.len(), 2);
+        assert_eq!(newer.positions.get("/a"), Some((9, 0)));
+        assert_eq!(newer.positions.get("/b"), Some((0, 0)));
+    }
+
+    #[test]
+    fn session_layout() {
+        let window = |top, left, bottom, right, file: Option<&str>| SessionWindow {
+            rect: Rect {
+                top,
+                left,
+                bottom,
+                right,
+            },
+            file: file.map(str::to_owned),
+            byte: 1,
+        };
+        // a | c
+        // --+--
+        // b | d
+        let windows = [
+            window(0, 0, 11, 40, Some("a")),
+            window(0, 40, 11, 80, Some("c")),
+            window(11, 0, 23, 40, None),
+            window(11, 40, 23, 80, Some("d")),
+        ];
+        assert_eq!(
+            script(Path::new("/src"), &windows, 2),
+            "\" rim session, :source it or start with rim -S\n\
+             cd /src\n\
+             e a\n\
+             goto 1\n\
+             vne c\n\
+             goto 1\n\
+             wincmd t\n\
+             new\n\
+             wincmd t\n\
+             wincmd w\n\
+             new d\n\
+             goto 1\n\
+             wincmd t\n\
+             wincmd w\n\
+             wincmd w\n"
+        );
+        assert_eq!(splits(&[windows[0].rect, windows[3].rect]), None);
+    }
+
+    #[test]
+    fn state_file_and_sessions() {
+        let dir = temp_dir("state_file_and_sessions");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "alpha\nbeta\n  gamma\n").unwrap();
+        fs::write(&b, "one\ntwo\n").unwrap();
+        let (a, b) = (a.display().to_string(), b.display().to_string());
+        let state = dir.join("state/state");
+
+        let mut h = Harness::new("");
+        h.state().screen_mut().load_state(state.clone()).unwrap();
+        h.keys(&format!(":e {a}<CR>jjllmB\"wyy:e {b}<CR>j"));
+        h.state().screen_mut().finish().unwrap();
+        let contents = fs::read_to_string(&state).unwrap();
+        fs::write(&state, format!("{contents}@t c\n|x\n|y")).unwrap();
+
+        let mut h = Harness::new("");
+        h.state().screen_mut().load_state(state.clone()).unwrap();
+        h.keys(":<Up>");
+        assert_eq!(h.message(), format!(":e {b}"));
+        h.keys("<Esc>'B");
+        assert_eq!(
+            (h.text(), h.cursor()),
+            ("alpha\nbeta\n  gamma".to_string(), (2, 2))
+        );
+        assert_eq!(h.register('w'), Some("  gamma\n".to_string()));
+        assert_eq!(h.register('t'), Some("x\ny".to_string()));
+        h.keys("0\"tp");
+        assert_eq!(h.text(), "alpha\nbeta\n x\ny gamma");
+        assert_eq!(h.cursor(), (3, 0));
+        h.keys("u'B");
+        h.keys(&format!(":e {b}<CR>"));
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys(&format!("gg:vne {a}<CR>"));
+        assert_eq!(h.cursor(), (2, 2));
+
+        let session = dir.join("session.rim").display().to_string();
+        h.keys(&format!("gg0jl:mksession {session}<CR>"));
+        assert_eq!(h.message(), format!("\"{session}\" written"));
+        h.keys(&format!(":mks {session}<CR>"));
+        assert_eq!(
+            h.message(),
+            format!("\"{session}\" exists (add ! to override)")
+        );
+
+        let mut h = Harness::new("");
+        h.keys(&format!(":source {session}<CR>"));
+        assert!(h.screen()[0].contains("1 one") && h.screen()[0].contains("1 alpha"));
+        assert_eq!(h.cursor(), (1, 1));
+        assert_eq!(h.text(), "alpha\nbeta\n  gamma");
+        h.keys(":wincmd t<CR>");
+        assert_eq!((h.text(), h.cursor()), ("one\ntwo".to_string(), (0, 0)));
+    }
+}
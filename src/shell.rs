@@ -0,0 +1,81 @@
+//! Being one step of a shell pipeline: `rim -` edits whatever was piped in, and commands run
+//! from the editor get `%` as the current file.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Read},
+    os::fd::AsRawFd,
+};
+
+/// All of stdin, for `rim -`. Stdin is the terminal afterwards, so keys come from there, like
+/// they would have without the pipe.
+pub fn read_stdin() -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    // SAFETY: both are open file descriptors, and replacing stdin is the point
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bytes)
+}
+
+/// `cmd` with `%` replaced by `filename`, and `\%` by a `%`
+pub fn expand(cmd: &str, filename: Option<&str>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'%') => expanded.push(chars.next().unwrap()),
+            '%' => expanded.push_str(filename.ok_or("Empty file name for '%'")?),
+            c => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[test]
+    fn expands_filename() {
+        assert_eq!(
+            expand("wc -l % \\% %", Some("a.rs")).unwrap(),
+            "wc -l a.rs % a.rs"
+        );
+        assert_eq!(expand("ls", None).unwrap(), "ls");
+        assert_eq!(expand("cat %", None), Err("Empty file name for '%'".into()));
+    }
+
+    #[test]
+    fn pipelines() {
+        let mut h = Harness::new("");
+        h.state().screen_mut().read_stdin(b"b\na\n").unwrap();
+        assert_eq!(h.text(), "b\na");
+        assert_eq!(h.message(), "\"-\" 2L, 4B");
+
+        h.keys(":w !sort<CR>").finish_job();
+        assert_eq!(
+            h.screen()[21..],
+            ["a", "b", "Press ENTER or type command to continue"]
+        );
+        h.keys("<CR>:r !echo one; echo two<CR>").finish_job();
+        assert_eq!(h.text(), "b\none\ntwo\na");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys(":r !exit 2<CR>").finish_job();
+        assert_eq!(h.message(), "shell returned 2");
+        h.keys(":r<CR>");
+        assert_eq!(h.message(), "No file name");
+
+        h.keys(":w -<CR>");
+        assert_eq!(h.message(), "4L, 12B to stdout on exit");
+        h.keys(":q<CR>");
+        assert!(h.state().should_quit());
+        assert_eq!(
+            h.state().screen_mut().take_stdout().unwrap(),
+            b"b\none\ntwo\na\n"
+        );
+    }
+}
@@ -1,24 +1,45 @@
-use std::{collections::HashMap, process::exit, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use crossterm::{cursor::SetCursorStyle, Result};
-use futures::stream::FuturesUnordered;
-use tokio::{
-    spawn,
-    task::JoinHandle,
-    time::{sleep, Duration},
-};
 
 use crate::{
+    args::Args,
+    backend::{BackendRef, TerminalBackend},
+    clock::{Clock, SystemClock},
     command::Commands,
+    job,
     keys::keyhandler::{new_keymap_trie, Key, KeymapTrie},
+    quickfix::{List, Pick},
+    range,
     screen::Screen,
 };
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Mode {
     Normal,
     Insert,
     Command,
+    /// Keys go to the program in a `:terminal` window
+    Terminal,
+}
+
+impl Mode {
+    /// For `%{mode}` in the statusline
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Command => "COMMAND",
+            Mode::Terminal => "TERMINAL",
+        }
+    }
 }
 
 pub enum Command {
@@ -31,7 +52,12 @@ pub struct State {
     commands: Rc<Commands>,
     current_key_event: Vec<Key>,
     mode: Mode,
-    queue: FuturesUnordered<JoinHandle<Command>>,
+    clock: Rc<dyn Clock>,
+    /// Commands waiting for their deadline, see `push_queue`
+    queue: Vec<(Instant, Command)>,
+    quit: bool,
+    /// What `:make` or `:grep` found last. Location lists are the windows' own.
+    quickfix: List,
 }
 
 macro_rules! keymaps {
@@ -52,11 +78,21 @@ macro_rules! commands {
 
 impl State {
     pub fn init() -> Result<Self> {
+        Self::new(
+            Rc::new(RefCell::new(TerminalBackend::new())),
+            Rc::new(SystemClock),
+        )
+    }
+
+    pub fn new(backend: BackendRef, clock: Rc<dyn Clock>) -> Result<Self> {
         Ok(Self {
-            screen: Screen::new()?,
+            screen: Screen::new(backend)?,
             mode: Mode::Normal,
             current_key_event: Vec::new(),
-            queue: FuturesUnordered::new(),
+            clock,
+            queue: Vec::new(),
+            quit: false,
+            quickfix: List::default(),
             keymaps: Rc::new(HashMap::from([
                 (
                     Mode::Normal,
@@ -65,10 +101,19 @@ impl State {
                         "j" => |state| state.screen_mut().active_window_mut().move_cursor_row(1),
                         "k" => |state| state.screen_mut().active_window_mut().move_cursor_row(-1),
                         "l" => |state| state.screen_mut().active_window_mut().move_cursor_col(1),
-                        "ZQ" => |_| State::finish(),
+                        "ZQ" => |state| state.finish(),
                         "ZZ" => |state| {
-                            state.screen_mut().write()?;
-                            State::finish()
+                            if state.screen_mut().write(false)? {
+                                state.finish()?;
+                            }
+                            Ok(())
+                        },
+                        "<CR>" => |state| match state.screen_mut().take_command_window_line()? {
+                            Some((kind, line)) => state.run_typed(kind, &line),
+                            None => match state.screen().list_window_entry() {
+                                Some((i, location)) => state.pick_entry(location, Pick::Nth(i)),
+                                None => state.screen_mut().open_or_move_down(),
+                            },
                         },
                         "i" => |state| state.enter_insert_mode(),
                         "I" => |state| {
@@ -98,15 +143,84 @@ impl State {
                         //      - allow for numbers at the start of a command and strip them out
                         //      and then repeat (but special case for this one)
                         // - r
-                        // - u
-                        "gg" => |state| state.screen_mut().active_window_mut().zero_cursor_row(),
-                        "G" => |state| state.screen_mut().active_window_mut().maximize_cursor_row(),
-                        ":" => |state| state.enter_command_mode(),
-                        "dd" => |state| state.screen_mut().active_window_mut().delete_line(),
+                        "u" => |state| state.screen_mut().undo(false),
+                        "<C-r>" => |state| state.screen_mut().undo(true),
+                        "gg" => |state| {
+                            let window = state.screen_mut().active_window_mut();
+                            window.push_jump();
+                            window.zero_cursor_row()
+                        },
+                        "gj" => |state| state.screen_mut().active_window_mut().move_cursor_display_row(1),
+                        "gk" => |state| state.screen_mut().active_window_mut().move_cursor_display_row(-1),
+                        "g0" => |state| state.screen_mut().active_window_mut().zero_cursor_display_col(),
+                        "g$" => |state| state.screen_mut().active_window_mut().move_cursor_end_of_display_line(),
+                        "G" => |state| {
+                            let window = state.screen_mut().active_window_mut();
+                            window.push_jump();
+                            window.maximize_cursor_row()
+                        },
+                        "m" => |state| {
+                            state.screen_mut().start_mark('m');
+                            Ok(())
+                        },
+                        "'" => |state| {
+                            state.screen_mut().start_mark('\'');
+                            Ok(())
+                        },
+                        "`" => |state| {
+                            state.screen_mut().start_mark('`');
+                            Ok(())
+                        },
+                        "<C-o>" => |state| state.screen_mut().step_jump(-1),
+                        "<C-i>" => |state| state.screen_mut().step_jump(1),
+                        // What terminals send for `<C-i>`
+                        "<Tab>" => |state| state.screen_mut().step_jump(1),
+                        "g;" => |state| state.screen_mut().step_change(-1),
+                        "g," => |state| state.screen_mut().step_change(1),
+                        "]c" => |state| state.screen_mut().step_diff(1),
+                        "[c" => |state| state.screen_mut().step_diff(-1),
+                        "gt" => |state| state.screen_mut().step_tab(1),
+                        "gT" => |state| state.screen_mut().step_tab(-1),
+                        "gd" => |state| state.screen_mut().lsp_definition(),
+                        "gr" => |state| state.screen_mut().lsp_references(),
+                        "K" => |state| state.screen_mut().lsp_hover(),
+                        ":" => |state| state.enter_command_mode(':'),
+                        "/" => |state| state.enter_command_mode('/'),
+                        "!!" => |state| state.enter_filter_command("."),
+                        "!j" => |state| state.enter_filter_command(".,.+1"),
+                        "!k" => |state| state.enter_filter_command(".-1,."),
+                        "!G" => |state| state.enter_filter_command(".,$"),
+                        "!gg" => |state| state.enter_filter_command("1,."),
+                        "q:" => |state| state.screen_mut().open_command_window(':'),
+                        "q/" => |state| state.screen_mut().open_command_window('/'),
+                        "dd" => |state| state.screen_mut().delete_line(false),
                         "cc" => |state| {
-                            state.screen_mut().active_window_mut().change_line()?;
+                            state.screen_mut().delete_line(true)?;
                             state.enter_insert_mode()
                         },
+                        "\"" => |state| {
+                            state.screen_mut().start_register_name();
+                            Ok(())
+                        },
+                        "yy" => |state| state.screen_mut().yank_line(),
+                        "Y" => |state| state.screen_mut().yank_line(),
+                        "p" => |state| state.screen_mut().put(true),
+                        "P" => |state| state.screen_mut().put(false),
+                        "<C-e>" => |state| state.screen_mut().active_window_mut().scroll_view(1),
+                        "<C-y>" => |state| state.screen_mut().active_window_mut().scroll_view(-1),
+                        "<C-d>" => |state| state.screen_mut().active_window_mut().scroll_half_page(1),
+                        "<C-u>" => |state| state.screen_mut().active_window_mut().scroll_half_page(-1),
+                        "<C-f>" => |state| state.screen_mut().active_window_mut().scroll_page(1),
+                        "<C-b>" => |state| state.screen_mut().active_window_mut().scroll_page(-1),
+                        "<C-c>" => |state| state.screen_mut().interrupt(),
+                        "zt" => |state| state.screen_mut().active_window_mut().scroll_cursor_top(),
+                        "zz" => |state| state.screen_mut().active_window_mut().scroll_cursor_center(),
+                        "zb" => |state| state.screen_mut().active_window_mut().scroll_cursor_bottom(),
+                        "z<CR>" => |state| state.screen_mut().active_window_mut().scroll_cursor_top_first_char(),
+                        "zh" => |state| state.screen_mut().active_window_mut().scroll_view_cols(-1),
+                        "zl" => |state| state.screen_mut().active_window_mut().scroll_view_cols(1),
+                        "zs" => |state| state.screen_mut().active_window_mut().scroll_cursor_start(),
+                        "ze" => |state| state.screen_mut().active_window_mut().scroll_cursor_end(),
                         "<space>h" => |state| state.screen_mut().move_to_left_window(),
                         "<space>l" => |state| state.screen_mut().move_to_right_window(),
                         "<space>j" => |state| state.screen_mut().move_to_down_window(),
@@ -117,59 +231,189 @@ impl State {
                     Mode::Insert,
                     keymaps! {
                         "jk" => |state| state.enter_normal_mode(),
-                        "<Esc>" => |state| state.enter_normal_mode()
+                        "<Esc>" => |state| state.enter_normal_mode(),
+                        "<C-n>" => |state| state.screen_mut().complete_keyword(1),
+                        "<C-p>" => |state| state.screen_mut().complete_keyword(-1),
+                        "<C-x><C-f>" => |state| state.screen_mut().complete_file(),
+                        "<C-x><C-l>" => |state| state.screen_mut().complete_line(),
+                        "<C-x><C-o>" => |state| state.screen_mut().lsp_complete(),
+                        "<C-y>" => |state| state.screen_mut().accept_completion(),
+                        "<C-e>" => |state| state.screen_mut().cancel_completion(),
+                    },
+                ),
+                (
+                    Mode::Terminal,
+                    keymaps! {
+                        "<C-\\><C-n>" => |state| state.leave_terminal_mode(),
                     },
                 ),
                 (
                     Mode::Command,
                     keymaps! {
-                        "<Esc>" => |state| state.leave_command_mode()
+                        "<Esc>" => |state| state.leave_command_mode(),
+                        "<C-w>" => |state| state.screen_mut().command_delete_word(),
+                        "<C-u>" => |state| state.screen_mut().command_delete_to_start(),
+                        "<C-r>" => |state| {
+                            state.screen_mut().command_start_register();
+                            Ok(())
+                        },
                     },
                 ),
             ])),
             commands: Rc::new(commands! {
-                "w" => |state, arg| {
-                    if let Some(arg) = arg {
-                        state.screen_mut().write_to_filename(arg)
-                    } else {
-                        state.screen_mut().write()
-                    }
-                },
+                "w" => |state, arg| state.screen_mut().write_command(arg, false),
+                "w!" => |state, arg| state.screen_mut().write_command(arg, true),
                 "q" => |state, arg| {
                     if let Some(arg) = arg {
                         state.screen_mut().set_error_message(format!("unexpeted chars: `{}`", arg))
-                    } else if state.screen_mut().active_window().unsaved_changes() {
+                    } else if state.screen_mut().close_command_window()? {
+                        Ok(())
+                    } else if state.screen().active_window().unsaved_changes() {
                         state.screen_mut().set_error_message("no write since last change")
+                    } else if state.screen().active_window().terminal_running() {
+                        state.screen_mut().set_error_message("Job still running (add ! to end the job)")
                     } else {
-                        State::finish()
+                        state.finish()
                     }
                 },
                 "q!" => |state, arg| {
                     if let Some(arg) = arg {
                         state.screen_mut().set_error_message(format!("unexpeted chars: `{}`", arg))
+                    } else if state.screen_mut().close_command_window()? {
+                        Ok(())
                     } else {
-                        State::finish()
+                        state.finish()
                     }
                 },
                 "wq" => |state, arg| {
                     if let Some(arg) = arg {
                         state.screen_mut().set_error_message(format!("unexpeted chars: `{}`", arg))
                     } else {
-                        state.screen_mut().write()?;
-                        State::finish()
+                        if state.screen_mut().write(false)? {
+                            state.finish()?;
+                        }
+                        Ok(())
                     }
                 },
                 // TODO: qa (the others should only quit one window)
                 "vne" => |state, filename| state.screen_mut().new_vertical_split(filename),
+                "tabnew" => |state, filename| state.screen_mut().new_tab(filename),
+                "tabe" => |state, filename| state.screen_mut().new_tab(filename),
+                "tabedit" => |state, filename| state.screen_mut().new_tab(filename),
+                "tabn" => |state, n| state.screen_mut().tab_next(n),
+                "tabnext" => |state, n| state.screen_mut().tab_next(n),
+                "tabp" => |state, _| state.screen_mut().step_tab(-1),
+                "tabprevious" => |state, _| state.screen_mut().step_tab(-1),
+                "tabN" => |state, _| state.screen_mut().step_tab(-1),
+                "tabNext" => |state, _| state.screen_mut().step_tab(-1),
+                "diffthis" => |state, _| state.screen_mut().diff_this(false),
+                "diffoff" => |state, _| state.screen_mut().diff_off(),
+                "tabc" => |state, _| state.screen_mut().close_tab(false),
+                "tabclose" => |state, _| state.screen_mut().close_tab(false),
+                "tabc!" => |state, _| state.screen_mut().close_tab(true),
+                "tabclose!" => |state, _| state.screen_mut().close_tab(true),
                 "new" => |state, filename| state.screen_mut().new_horizontal_split(filename),
-                "e" => |state, filename| state.screen_mut().load_file(filename),
+                "e" => |state, filename| state.screen_mut().edit(filename, false),
+                "e!" => |state, filename| state.screen_mut().edit(filename, true),
+                "r" => |state, arg| state.screen_mut().read(arg),
+                "read" => |state, arg| state.screen_mut().read(arg),
+                "n" => |state, _| state.screen_mut().step_arglist(1, false),
+                "next" => |state, _| state.screen_mut().step_arglist(1, false),
+                "n!" => |state, _| state.screen_mut().step_arglist(1, true),
+                "next!" => |state, _| state.screen_mut().step_arglist(1, true),
+                "N" => |state, _| state.screen_mut().step_arglist(-1, false),
+                "prev" => |state, _| state.screen_mut().step_arglist(-1, false),
+                "previous" => |state, _| state.screen_mut().step_arglist(-1, false),
+                "N!" => |state, _| state.screen_mut().step_arglist(-1, true),
+                "prev!" => |state, _| state.screen_mut().step_arglist(-1, true),
+                "previous!" => |state, _| state.screen_mut().step_arglist(-1, true),
+                "args" => |state, _| state.screen_mut().show_arglist(),
+                "b" => |state, name| state.screen_mut().buffer(name),
+                "buffer" => |state, name| state.screen_mut().buffer(name),
+                "so" => |state, path| match path {
+                    Some(path) => state.source(Path::new(&path)),
+                    None => state.screen_mut().set_error_message("Argument required"),
+                },
+                "source" => |state, path| match path {
+                    Some(path) => state.source(Path::new(&path)),
+                    None => state.screen_mut().set_error_message("Argument required"),
+                },
+                "checktime" => |state, _| state.screen_mut().check_time(),
+                "set" => |state, args| state.screen_mut().set_options(args),
+                "se" => |state, args| state.screen_mut().set_options(args),
+                "highlight" => |state, args| state.screen_mut().highlight(args),
+                "hi" => |state, args| state.screen_mut().highlight(args),
+                "colorscheme" => |state, name| state.screen_mut().colorscheme(name),
+                "colo" => |state, name| state.screen_mut().colorscheme(name),
+                "terminal" => |state, cmd| state.terminal(cmd),
+                "term" => |state, cmd| state.terminal(cmd),
+                "make" => |state, args| state.screen_mut().make(args, false, false, true),
+                "make!" => |state, args| state.screen_mut().make(args, false, false, false),
+                "grep" => |state, args| state.screen_mut().make(args, true, false, true),
+                "grep!" => |state, args| state.screen_mut().make(args, true, false, false),
+                "lmake" => |state, args| state.screen_mut().make(args, false, true, true),
+                "lmake!" => |state, args| state.screen_mut().make(args, false, true, false),
+                "lgrep" => |state, args| state.screen_mut().make(args, true, true, true),
+                "lgrep!" => |state, args| state.screen_mut().make(args, true, true, false),
+                "cc" => |state, n| state.pick_nth(false, n),
+                "cn" => |state, _| state.pick_entry(false, Pick::Step(1)),
+                "cnext" => |state, _| state.pick_entry(false, Pick::Step(1)),
+                "cp" => |state, _| state.pick_entry(false, Pick::Step(-1)),
+                "cN" => |state, _| state.pick_entry(false, Pick::Step(-1)),
+                "cprev" => |state, _| state.pick_entry(false, Pick::Step(-1)),
+                "cprevious" => |state, _| state.pick_entry(false, Pick::Step(-1)),
+                "cfirst" => |state, _| state.pick_entry(false, Pick::Nth(0)),
+                "clast" => |state, _| state.pick_entry(false, Pick::Nth(usize::MAX)),
+                "ll" => |state, n| state.pick_nth(true, n),
+                "lne" => |state, _| state.pick_entry(true, Pick::Step(1)),
+                "lnext" => |state, _| state.pick_entry(true, Pick::Step(1)),
+                "lp" => |state, _| state.pick_entry(true, Pick::Step(-1)),
+                "lN" => |state, _| state.pick_entry(true, Pick::Step(-1)),
+                "lprev" => |state, _| state.pick_entry(true, Pick::Step(-1)),
+                "lprevious" => |state, _| state.pick_entry(true, Pick::Step(-1)),
+                "lfirst" => |state, _| state.pick_entry(true, Pick::Nth(0)),
+                "llast" => |state, _| state.pick_entry(true, Pick::Nth(usize::MAX)),
+                "copen" => |state, _| state.screen.open_list_window(&state.quickfix, false),
+                "lopen" => |state, _| state.screen.open_list_window(&state.quickfix, true),
+                "cclose" => |state, _| state.screen_mut().close_list_window(),
+                "lclose" => |state, _| state.screen_mut().close_list_window(),
+                "cdo" => |state, cmd| state.list_do(false, false, cmd),
+                "cfdo" => |state, cmd| state.list_do(false, true, cmd),
+                "ldo" => |state, cmd| state.list_do(true, false, cmd),
+                "lfdo" => |state, cmd| state.list_do(true, true, cmd),
+                "marks" => |state, names| state.screen_mut().show_marks(names),
+                "delm" => |state, names| state.screen_mut().delete_marks(names, false),
+                "delmarks" => |state, names| state.screen_mut().delete_marks(names, false),
+                "delm!" => |state, names| state.screen_mut().delete_marks(names, true),
+                "delmarks!" => |state, names| state.screen_mut().delete_marks(names, true),
+                "mks" => |state, file| state.screen_mut().make_session(file, false),
+                "mksession" => |state, file| state.screen_mut().make_session(file, false),
+                "mks!" => |state, file| state.screen_mut().make_session(file, true),
+                "mksession!" => |state, file| state.screen_mut().make_session(file, true),
+                "cd" => |state, dir| state.screen_mut().change_dir(dir),
+                "go" => |state, n| state.screen_mut().goto_byte(n),
+                "goto" => |state, n| state.screen_mut().goto_byte(n),
+                "winc" => |state, c| state.screen_mut().wincmd(c),
+                "wincmd" => |state, c| state.screen_mut().wincmd(c),
+                "LspRename" => |state, name| state.screen_mut().lsp_rename(name),
+                "LspCodeAction" => |state, n| state.screen_mut().lsp_code_action(n),
+                "LspFormat" => |state, _| state.screen_mut().lsp_format(),
             }),
         })
     }
 
-    pub fn finish() -> Result<()> {
-        Screen::finish()?;
-        exit(0);
+    /// Stops `keyhandler::watch` after the current key
+    pub fn finish(&mut self) -> Result<()> {
+        self.quit = true;
+        Ok(())
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
     }
 
     pub fn screen_mut(&mut self) -> &mut Screen {
@@ -194,6 +438,9 @@ impl State {
 
     pub fn append_current_key_event(&mut self, c: Key) {
         if self.current_key_event.is_empty() {
+            // A timeout left over from an earlier key event would cut this one short
+            self.queue
+                .retain(|(_, cmd)| !matches!(cmd, Command::ClearCurrentKeyEvent));
             self.push_queue(Duration::from_secs(1), Command::ClearCurrentKeyEvent);
         }
         self.current_key_event.push(c);
@@ -203,51 +450,262 @@ impl State {
         self.current_key_event = key;
     }
 
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.screen.set_mode(mode);
+    }
+
     pub fn enter_insert_mode(&mut self) -> Result<()> {
-        self.mode = Mode::Insert;
+        if self.screen.active_window().terminal_running() {
+            // There's nothing to insert into, only a program to type to
+            return self.enter_terminal_mode();
+        }
+        self.set_mode(Mode::Insert);
         self.screen_mut().set_message("-- INSERT --")?;
         self.screen.set_cursor_shape(SetCursorStyle::SteadyBar)
     }
 
     pub fn enter_normal_mode(&mut self) -> Result<()> {
-        self.mode = Mode::Normal;
+        let window = self.screen.active_window_mut();
+        let cursor = window.adjusetd_cursor();
+        window.marks_mut().set('^', cursor);
+        self.set_mode(Mode::Normal);
         self.screen_mut().set_message("")?;
         self.screen.set_cursor_shape(SetCursorStyle::SteadyBlock)?;
         self.screen.active_window_mut().move_cursor_col(-1)
     }
 
-    pub fn enter_command_mode(&mut self) -> Result<()> {
-        self.mode = Mode::Command;
-        self.screen_mut().enter_command_mode()
+    fn enter_terminal_mode(&mut self) -> Result<()> {
+        self.set_mode(Mode::Terminal);
+        self.screen_mut().set_message("-- TERMINAL --")?;
+        self.screen.follow_terminal()
+    }
+
+    /// `<C-\><C-n>`: normal mode, to move around and copy from the scrollback
+    fn leave_terminal_mode(&mut self) -> Result<()> {
+        self.set_mode(Mode::Normal);
+        self.screen_mut().set_message("")
+    }
+
+    /// `:terminal [cmd]`, typing to it straight away
+    fn terminal(&mut self, cmd: Option<String>) -> Result<()> {
+        self.screen.terminal(cmd)?;
+        if self.screen.active_window().terminal_running() {
+            self.enter_terminal_mode()?;
+        }
+        Ok(())
+    }
+
+    /// Output from a command or `:terminal` program. Terminal-job mode ends with the program.
+    pub fn job_event(&mut self, event: job::Event) -> Result<()> {
+        self.screen.job_event(event)?;
+        if let Some(finished) = self.screen.take_finished_list() {
+            self.screen.set_list(&mut self.quickfix, finished)?;
+        }
+        if self.mode == Mode::Terminal && !self.screen.active_window().terminal_running() {
+            self.set_mode(Mode::Normal);
+        }
+        Ok(())
+    }
+
+    /// `:cnext` and the like, or with `location` `:lnext` and friends
+    fn pick_entry(&mut self, location: bool, pick: Pick) -> Result<()> {
+        self.screen.pick_entry(&mut self.quickfix, location, pick)
+    }
+
+    /// `:cc [N]` and `:ll [N]`: entry `N`, counting from 1, or the current one again
+    fn pick_nth(&mut self, location: bool, n: Option<String>) -> Result<()> {
+        let i = match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => n.saturating_sub(1),
+            Some(Err(_)) => return self.screen.set_error_message("Number required"),
+            None => self.screen.list(&self.quickfix, location).current(),
+        };
+        self.pick_entry(location, Pick::Nth(i))
+    }
+
+    /// `:cdo` and `:cfdo` (`per_file`): `cmd` at every entry of the list, or at the first one
+    /// in every file. `|` separates commands to run one after the other, `\|` is a plain `|`.
+    /// Stops at the first error.
+    fn list_do(&mut self, location: bool, per_file: bool, cmd: Option<String>) -> Result<()> {
+        let Some(cmd) = cmd else {
+            return self.screen.set_error_message("Argument required");
+        };
+        let list = self.screen.list(&self.quickfix, location);
+        let targets: Vec<usize> = if per_file {
+            list.first_per_file()
+        } else {
+            (0..list.entries().len()).collect()
+        };
+        if targets.is_empty() {
+            return self.screen.set_error_message("No Errors");
+        }
+        let cmds = split_bar(&cmd);
+        for i in targets {
+            self.pick_entry(location, Pick::Nth(i))?;
+            for cmd in &cmds {
+                if self.screen.failed() {
+                    return Ok(());
+                }
+                self.run_command(cmd)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `kind` is `:` for a command or `/` for a search
+    pub fn enter_command_mode(&mut self, kind: char) -> Result<()> {
+        self.set_mode(Mode::Command);
+        self.screen_mut().enter_command_mode(kind)
+    }
+
+    /// `!{motion}`: a command line ready for the command to filter the lines through
+    fn enter_filter_command(&mut self, range: &str) -> Result<()> {
+        self.enter_command_mode(':')?;
+        self.screen_mut().command_insert(&format!("{range}!"))
     }
 
     pub fn leave_command_mode(&mut self) -> Result<()> {
-        self.mode = Mode::Normal;
+        self.set_mode(Mode::Normal);
         self.screen_mut().leave_command_mode()
     }
 
     pub fn enter_command(&mut self) -> Result<()> {
-        if let Some((f, arg)) = self
-            .commands
-            .clone()
-            .get(self.screen_mut().get_curr_command())
-        {
-            f(self, arg)?;
+        let kind = self.screen.command_kind();
+        let line = self.screen.get_curr_command().to_owned();
+        // First, so whatever the command has to say stays on the message line
+        self.leave_command_mode()?;
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        self.run_typed(kind, &line)
+    }
+
+    /// `<Tab>` and `<S-Tab>` on the command line
+    pub fn complete(&mut self, step: isize) -> Result<()> {
+        let names = self.commands.names();
+        self.screen.complete(step, names)
+    }
+
+    /// A line from the command line or the `q:` window, which goes in the history
+    pub fn run_typed(&mut self, kind: char, line: &str) -> Result<()> {
+        self.screen.add_history(kind, line);
+        match kind {
+            '/' => self.screen.search(line),
+            _ => self.run_command(line),
+        }
+    }
+
+    /// An ex command, as typed after the `:`. Besides the named ones there's `:N` and `:$` to
+    /// go to a line and `:/pattern` to go to a match.
+    pub fn run_command(&mut self, cmd: &str) -> Result<()> {
+        if let Some((f, arg)) = self.commands.clone().get(cmd) {
+            return f(self, arg);
+        }
+        let cmd = cmd.trim();
+        let window = self.screen.active_window();
+        let last = window.buffer().lines().len() - 1;
+        let (range, rest) = range::parse(cmd, window.adjusetd_cursor().0, last);
+        if let Some(shell_cmd) = rest.strip_prefix('!') {
+            match range {
+                Some(range) => self.screen.filter(range, shell_cmd),
+                None => self.screen.shell_command(shell_cmd),
+            }
+        } else if let (Some(range), "") = (range, rest) {
+            let window = self.screen.active_window_mut();
+            window.push_jump();
+            window.goto_line(range.last)
+        } else if let Some(pattern) = cmd.strip_prefix('/') {
+            self.screen.search(pattern)
         } else {
-            let error_msg = format!("Unknown command `{}`", self.screen_mut().get_curr_command());
-            self.screen_mut().set_error_message(error_msg)?;
+            self.screen
+                .set_error_message(format!("Unknown command `{cmd}`"))
+        }
+    }
+
+    /// `:source`: every line of `path` is an ex command, except blank ones and `"` comments
+    pub fn source(&mut self, path: &Path) -> Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return self
+                    .screen
+                    .set_error_message(format!("Cannot read \"{}\": {e}", path.display()))
+            }
+        };
+        for line in contents.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('"') {
+                self.run_command(line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything the command line asks for before the first key: the rcfile, the state file,
+    /// the files, then the `+cmd` and `-c` commands
+    pub fn start(
+        &mut self,
+        args: Args,
+        stdin: Option<Vec<u8>>,
+        rcfile: Option<PathBuf>,
+        state_file: Option<PathBuf>,
+    ) -> Result<()> {
+        if let Some(rcfile) = rcfile {
+            self.source(&rcfile)?;
+        }
+        if let Some(path) = state_file {
+            self.screen.load_state(path)?;
+        }
+        if args.noswapfile {
+            self.run_command("set noswapfile")?;
         }
-        self.leave_command_mode()
+        match stdin {
+            Some(bytes) => self.screen.read_stdin(&bytes)?,
+            None => self
+                .screen
+                .open_files(args.files, args.layout, args.recover)?,
+        }
+        if args.readonly {
+            self.screen.set_read_only()?;
+        }
+        if args.diff {
+            self.screen.diff_this(true)?;
+        }
+        for cmd in &args.commands {
+            self.run_command(cmd)?;
+        }
+        Ok(())
     }
 
+    /// Run `cmd` once `duration` has passed on the state's clock
     pub fn push_queue(&mut self, duration: Duration, cmd: Command) {
-        self.queue.push(spawn(async move {
-            sleep(duration).await;
-            cmd
-        }));
+        self.queue.push((self.clock.now() + duration, cmd));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.iter().map(|(deadline, _)| *deadline).min()
     }
 
-    pub fn queue(&mut self) -> &mut FuturesUnordered<JoinHandle<Command>> {
-        &mut self.queue
+    /// Removes and returns every queued command whose deadline has passed
+    pub fn take_due_commands(&mut self) -> Vec<Command> {
+        let now = self.clock.now();
+        let (due, pending) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        self.queue = pending;
+        due.into_iter().map(|(_, cmd)| cmd).collect()
+    }
+}
+
+/// Commands separated by `|`, where `\|` is one that's part of a command
+fn split_bar(cmd: &str) -> Vec<String> {
+    let mut cmds = vec![String::new()];
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => cmds.last_mut().unwrap().push('|'),
+            '|' => cmds.push(String::new()),
+            c => cmds.last_mut().unwrap().push(c),
+        }
     }
+    cmds.iter().map(|cmd| cmd.trim().to_owned()).collect()
 }
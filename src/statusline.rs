@@ -0,0 +1,291 @@
+//! The `statusline` option: a format string in the style of vim's.
+//!
+//! | item          | shows                                                  |
+//! |---------------|--------------------------------------------------------|
+//! | `%f` `%F` `%t`| file name as given, full path, last component          |
+//! | `%m` `%M`     | `[+]` / `+` when modified                              |
+//! | `%r` `%R`     | `[RO]` / `RO` when read-only                           |
+//! | `%y` `%Y`     | `[rust]` / `rust`, the filetype                        |
+//! | `%l` `%L`     | current line, number of lines                          |
+//! | `%c`          | column                                                 |
+//! | `%p` `%P`     | percentage through the file, `Top`/`Bot`/`All`/`NN%`   |
+//! | `%{name}`     | `mode`, `fileformat`/`ff`, `fileencoding`/`fenc`, `filetype`/`ft`, `progress` |
+//! | `%=`          | alignment point: free space is shared between these    |
+//! | `%<`          | truncate here (with a `<`) when the line is too long   |
+//! | `%#Group#`    | switch highlight group, `%*` switches back             |
+//! | `%%`          | a `%`                                                  |
+//!
+//! Items take an optional width like `%-10.20f`: at least 10 columns, left aligned, at most 20
+//! (cut from the left).
+
+use std::{iter::Peekable, path::Path, str::Chars};
+
+/// Used when `statusline` is empty
+pub const DEFAULT_FORMAT: &str = "%f %m%r%{progress}%=%l:%c";
+
+/// Everything a statusline can show about a window
+pub struct Info<'a> {
+    pub filename: &'a str,
+    pub modified: bool,
+    pub readonly: bool,
+    pub filetype: Option<&'a str>,
+    /// 1-based
+    pub line: usize,
+    pub lines: usize,
+    /// 1-based
+    pub col: usize,
+    pub mode: &'a str,
+    pub fileformat: &'a str,
+    pub fileencoding: &'a str,
+    /// Like `[loading 42%]` while the file is read or written in the background, else empty
+    pub progress: &'a str,
+}
+
+impl Info<'_> {
+    fn item(&self, c: char) -> Option<String> {
+        let flag = |on: bool, s: &str| if on { s.to_owned() } else { String::new() };
+        Some(match c {
+            'f' => self.filename.to_owned(),
+            'F' => Path::new(self.filename)
+                .canonicalize()
+                .map_or_else(|_| self.filename.to_owned(), |p| p.display().to_string()),
+            't' => Path::new(self.filename).file_name().map_or_else(
+                || self.filename.to_owned(),
+                |name| name.to_string_lossy().into(),
+            ),
+            'm' => flag(self.modified, "[+]"),
+            'M' => flag(self.modified, "+"),
+            'r' => flag(self.readonly, "[RO]"),
+            'R' => flag(self.readonly, "RO"),
+            'y' => self
+                .filetype
+                .map_or_else(String::new, |ft| format!("[{ft}]")),
+            'Y' => self.filetype.unwrap_or_default().to_owned(),
+            'l' => self.line.to_string(),
+            'L' => self.lines.to_string(),
+            'c' => self.col.to_string(),
+            'p' => (self.line * 100 / self.lines.max(1)).to_string(),
+            'P' => match (self.line, self.lines) {
+                (_, 0 | 1) => "All".to_owned(),
+                (1, _) => "Top".to_owned(),
+                (line, lines) if line == lines => "Bot".to_owned(),
+                (line, lines) => format!("{}%", line * 100 / lines),
+            },
+            _ => return None,
+        })
+    }
+
+    fn expression(&self, name: &str) -> String {
+        match name {
+            "mode" => self.mode.to_owned(),
+            "fileformat" | "ff" => self.fileformat.to_owned(),
+            "fileencoding" | "fenc" | "encoding" | "enc" => self.fileencoding.to_owned(),
+            "filetype" | "ft" => self.filetype.unwrap_or_default().to_owned(),
+            "progress" => self.progress.to_owned(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// One screen column of the rendered line, with the highlight group it's drawn in (`None` is the
+/// window's own statusline group)
+type Cell = (char, Option<String>);
+
+/// Applies `%-10.20x` style widths
+fn sized(text: String, left_align: bool, min: Option<usize>, max: Option<usize>) -> String {
+    let len = text.chars().count();
+    let text = match max {
+        Some(max) if len > max && max > 0 => {
+            let kept: String = text.chars().skip(len - max + 1).collect();
+            format!("<{kept}")
+        }
+        _ => text,
+    };
+    let padding = " ".repeat(min.unwrap_or(0).saturating_sub(text.chars().count()));
+    if left_align {
+        text + &padding
+    } else {
+        padding + &text
+    }
+}
+
+fn number(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+        digits.push(d);
+    }
+    digits.parse().ok()
+}
+
+/// Renders `format` into exactly `width` cells
+pub fn render(format: &str, info: &Info, width: usize) -> Vec<Cell> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let format = if format.is_empty() {
+        DEFAULT_FORMAT
+    } else {
+        format
+    };
+    let mut cells: Vec<Cell> = Vec::new();
+    let mut group: Option<String> = None;
+    // Indices into `cells`
+    let mut separators = Vec::new();
+    let mut truncate_at = None;
+
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        let push = |cells: &mut Vec<Cell>, text: &str, group: &Option<String>| {
+            cells.extend(text.chars().map(|c| (c, group.clone())));
+        };
+        if c != '%' {
+            cells.push((c, group.clone()));
+            continue;
+        }
+        let left_align = chars.next_if_eq(&'-').is_some();
+        let min = number(&mut chars);
+        let max = chars.next_if_eq(&'.').and_then(|_| number(&mut chars));
+        let Some(item) = chars.next() else {
+            break;
+        };
+        match item {
+            '%' => cells.push(('%', group.clone())),
+            '=' => separators.push(cells.len()),
+            '<' => truncate_at = Some(cells.len()),
+            '*' => group = None,
+            '#' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '#').collect();
+                group = Some(name).filter(|name| !name.is_empty());
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let text = sized(info.expression(name.trim()), left_align, min, max);
+                push(&mut cells, &text, &group);
+            }
+            _ => {
+                if let Some(text) = info.item(item) {
+                    push(&mut cells, &sized(text, left_align, min, max), &group);
+                }
+            }
+        }
+    }
+
+    if cells.len() > width {
+        // Cut out whatever doesn't fit right after the truncation point, marking it with `<`
+        let at = truncate_at.unwrap_or(0).min(width - 1);
+        let excess = cells.len() - width;
+        let group = cells[at].1.clone();
+        cells.splice(at..at + excess + 1, [('<', group)]);
+        for separator in &mut separators {
+            if *separator > at {
+                *separator = separator.saturating_sub(excess).max(at + 1);
+            }
+        }
+    }
+
+    let extra = width - cells.len();
+    if separators.is_empty() {
+        cells.extend((0..extra).map(|_| (' ', group.clone())));
+    } else {
+        let n = separators.len();
+        // Back to front so earlier indices stay valid
+        for (i, &at) in separators.iter().enumerate().rev() {
+            let share = extra / n + usize::from(i < extra % n);
+            let group = at
+                .checked_sub(1)
+                .and_then(|prev| cells.get(prev))
+                .and_then(|c| c.1.clone());
+            cells.splice(at..at, (0..share).map(|_| (' ', group.clone())));
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    fn info() -> Info<'static> {
+        Info {
+            filename: "src/main.rs",
+            modified: true,
+            readonly: false,
+            filetype: Some("rust"),
+            line: 5,
+            lines: 20,
+            col: 3,
+            mode: "NORMAL",
+            fileformat: "unix",
+            fileencoding: "utf-8",
+            progress: "",
+        }
+    }
+
+    fn text(format: &str, width: usize) -> String {
+        render(format, &info(), width)
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect()
+    }
+
+    #[test]
+    fn items() {
+        assert_eq!(text("%t%m%r %y", 20), "main.rs[+] [rust]   ");
+        assert_eq!(text("%l/%L:%c %p%% %P", 20), "5/20:3 25% 25%      ");
+        assert_eq!(
+            text("%{mode} %{ff} %{fenc} %{ft}", 30),
+            "NORMAL unix utf-8 rust        "
+        );
+        assert_eq!(text("", 20), "src/main.rs [+]  5:3");
+    }
+
+    #[test]
+    fn alignment_and_widths() {
+        assert_eq!(text("a%=b%=c", 9), "a   b   c");
+        assert_eq!(text("a%=b%=c", 8), "a   b  c");
+        assert_eq!(text("[%5l][%-5l]", 14), "[    5][5    ]");
+        assert_eq!(text("%.6f", 6), "<in.rs");
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(text("%f%=%l:%c", 8), "<n.rs5:3");
+        assert_eq!(text("%f %<%{mode}%=%l", 16), "src/main.rs <AL5");
+    }
+
+    #[test]
+    fn groups() {
+        let cells = render("a%#Search#b%*c", &info(), 3);
+        let groups: Vec<Option<&str>> = cells.iter().map(|(_, g)| g.as_deref()).collect();
+        assert_eq!(groups, [None, Some("Search"), None]);
+    }
+
+    #[test]
+    fn statusline() {
+        let line: String = ('a'..='z').collect();
+        let mut h = Harness::with_size(&format!("{line}\nx"), 6, 20);
+        h.keys("$");
+        // The column used to come out wrong once the view scrolled sideways
+        assert_eq!(h.screen()[4], "[No Name]       1:27");
+
+        h.keys(":set stl=%{mode}%=%l/%L<CR>i");
+        assert_eq!(h.screen()[4], "INSERT           1/2");
+        h.keys("<Esc>:set stl=%#ErrorMsg#%m%*%=%P<CR>");
+        let style = |h: &Harness, col| h.backend().borrow().cell(4, col).style;
+        assert_eq!(h.screen()[4], "                 Top");
+        assert_eq!(style(&h, 19), h.state().screen().style("StatusLine"));
+        h.keys("i!<Esc>");
+        assert_eq!(style(&h, 0), h.state().screen().style("ErrorMsg"));
+
+        h.keys(":set ls=0<CR>");
+        assert_eq!(h.screen()[4], "~");
+        h.keys(":set ls=1<CR>");
+        assert_eq!(h.screen()[4], "~");
+        h.keys(":vne<CR>");
+        assert_eq!(style(&h, 9), h.state().screen().style("StatusLineNC"));
+        assert_eq!(style(&h, 19), h.state().screen().style("StatusLine"));
+        h.keys(":set ls=0<CR>");
+        assert_eq!(h.screen()[4].trim_end(), "~         ~");
+    }
+}
@@ -0,0 +1,399 @@
+//! Swap files: copies of modified buffers, kept up to date while editing, so that a crash or a
+//! lost connection doesn't lose everything since the last write.
+//!
+//! A buffer only has a swap file while it has unsaved changes. Writing the file or quitting
+//! removes it, so one left lying around means the rim that made it is still running or died.
+//! The file is a short header followed by the buffer's contents:
+//!
+//! ```text
+//! rim swap file
+//! pid: 1234
+//! file name: /home/me/notes.txt
+//!
+//! ...
+//! ```
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{options::Options, save};
+
+const MAGIC: &str = "rim swap file";
+
+/// Tried in order when a swap file is already taken, like vim does
+const EXTENSIONS: [&str; 3] = ["swp", "swo", "swn"];
+
+/// What a swap file says about the rim that wrote it
+pub struct Swapped {
+    pub pid: u32,
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+impl Swapped {
+    /// Whether whoever wrote this is still around
+    pub fn running(&self) -> bool {
+        self.pid == std::process::id() || Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+}
+
+/// The directories in `directory` that exist, and whether each is the file's own (`.`)
+fn swap_dirs(path: &Path, directory: &str) -> Vec<(PathBuf, bool)> {
+    directory
+        .split(',')
+        .filter_map(|dir| {
+            let (dir, beside) = match dir {
+                "." => (
+                    path.parent()
+                        .filter(|dir| !dir.as_os_str().is_empty())
+                        .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+                    true,
+                ),
+                _ => match dir.strip_prefix("~/") {
+                    Some(rest) => (PathBuf::from(std::env::var_os("HOME")?).join(rest), false),
+                    None => (PathBuf::from(dir), false),
+                },
+            };
+            dir.is_dir().then_some((dir, beside))
+        })
+        .collect()
+}
+
+/// The name files about `path` have in `dir` before their extension. Next to the file it's
+/// `.name`, elsewhere the whole path with `%` for `/` so files with the same name don't collide.
+fn name_in(path: &Path, (dir, beside): (PathBuf, bool)) -> (PathBuf, String) {
+    let name = if beside {
+        format!(
+            ".{}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        )
+    } else {
+        std::path::absolute(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .to_string_lossy()
+            .replace('/', "%")
+    };
+    (dir, name)
+}
+
+/// The first of `directory` that exists, and the name files about `path` have there
+fn base_name(path: &Path, directory: &str) -> Option<(PathBuf, String)> {
+    let dir = swap_dirs(path, directory).into_iter().next()?;
+    Some(name_in(path, dir))
+}
+
+/// Every name a swap file for `path` could have, in the order they're tried
+fn candidates(path: &Path, directory: &str) -> Vec<PathBuf> {
+    swap_dirs(path, directory)
+        .into_iter()
+        .flat_map(|dir| {
+            let (dir, name) = name_in(path, dir);
+            EXTENSIONS.map(|ext| dir.join(format!("{name}.{ext}")))
+        })
+        .collect()
+}
+
+/// A file about `path` named like its swap file but ending in `ext`, like undo files
+pub fn named_like(path: &Path, directory: &str, ext: &str) -> Option<PathBuf> {
+    let (dir, name) = base_name(path, directory)?;
+    Some(dir.join(format!("{name}.{ext}")))
+}
+
+/// An existing swap file for `path`
+pub fn existing(path: &Path, directory: &str) -> Option<PathBuf> {
+    candidates(path, directory)
+        .into_iter()
+        .find(|swap| swap.exists())
+}
+
+/// Where a new swap file for `path` should go, `None` with `noswapfile` or when there's nowhere
+/// to put it
+pub fn location(path: &Path, options: &Options) -> Option<PathBuf> {
+    if !options.swapfile {
+        return None;
+    }
+    candidates(path, &options.directory)
+        .into_iter()
+        .find(|swap| !swap.exists())
+}
+
+/// Where the swap file for `path` goes after `failed` couldn't be written: in one of the
+/// directories after its own
+pub fn fallback(path: &Path, options: &Options, failed: &Path) -> Option<PathBuf> {
+    if !options.swapfile {
+        return None;
+    }
+    let mut candidates = candidates(path, &options.directory).into_iter();
+    candidates.find(|swap| swap.parent() == failed.parent())?;
+    candidates
+        .filter(|swap| swap.parent() != failed.parent())
+        .find(|swap| !swap.exists())
+}
+
+/// Contents of a swap file for `path`
+pub fn encode(path: &Path, contents: &str) -> Vec<u8> {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    format!(
+        "{MAGIC}\npid: {}\nfile name: {}\n\n{contents}",
+        std::process::id(),
+        path.display()
+    )
+    .into_bytes()
+}
+
+pub fn read(swap: &Path) -> io::Result<Swapped> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a rim swap file");
+    let text = fs::read_to_string(swap)?;
+    let (header, contents) = text.split_once("\n\n").ok_or_else(invalid)?;
+    let mut lines = header.lines();
+    if lines.next() != Some(MAGIC) {
+        return Err(invalid());
+    }
+    let mut field = |name: &str| {
+        lines
+            .next()
+            .and_then(|line| line.strip_prefix(name))
+            .map(str::to_owned)
+            .ok_or_else(invalid)
+    };
+    let pid = field("pid: ")?.parse().map_err(|_| invalid())?;
+    let path = PathBuf::from(field("file name: ")?);
+    Ok(Swapped {
+        pid,
+        path,
+        contents: contents.to_owned(),
+    })
+}
+
+/// A swap file to bring up to date
+pub struct Job {
+    pub swap: PathBuf,
+    /// The file being edited
+    pub file: PathBuf,
+    pub contents: Vec<u8>,
+}
+
+impl Job {
+    pub fn run(&self) -> io::Result<()> {
+        write(&self.swap, &self.contents, &self.file)
+    }
+}
+
+/// Replaces the swap file for `file` in one go, so a crash halfway leaves the previous one
+pub fn write(swap: &Path, contents: &[u8], file: &Path) -> io::Result<()> {
+    let mut temp = swap.as_os_str().to_owned();
+    temp.push(".tmp");
+    save::write_copy(Path::new(&temp), contents, file)?;
+    fs::rename(&temp, swap)
+}
+
+/// What `rim -r` prints: every swap file in `directory`
+pub fn list(directory: &str) -> String {
+    let mut out = String::from("Swap files found:\n");
+    let mut found = 0;
+    for dir in directory.split(',') {
+        let dir = match dir.strip_prefix("~/") {
+            Some(rest) => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(rest),
+                None => continue,
+            },
+            None => PathBuf::from(dir),
+        };
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut swaps: Vec<(PathBuf, Swapped)> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext == *e))
+            })
+            .filter_map(|path| Some((path.clone(), read(&path).ok()?)))
+            .collect();
+        if swaps.is_empty() {
+            continue;
+        }
+        swaps.sort_by(|a, b| a.0.cmp(&b.0));
+        out.push_str(&format!("   In directory {}:\n", dir.display()));
+        for (swap, swapped) in swaps {
+            found += 1;
+            let name = swap.file_name().unwrap_or_default().to_string_lossy();
+            let running = if swapped.running() {
+                " (STILL RUNNING)"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "{found}.    {name}\n          file name: {}\n         process ID: {}{running}\n",
+                swapped.path.display(),
+                swapped.pid,
+            ));
+        }
+    }
+    if found == 0 {
+        out.push_str("   -- none --\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use crate::swap;
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("swap-round-trip");
+        let path = dir.join("notes.txt");
+        let swap = location(&path, &Options::default()).unwrap();
+        assert_eq!(swap, dir.join(".notes.txt.swp"));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write(&swap, &encode(&path, "one\n\ntwo\n"), &path).unwrap();
+        // It's the same text, so no more people get to read it
+        assert_eq!(fs::metadata(&swap).unwrap().mode() & 0o777, 0o640);
+
+        let swapped = read(&swap).unwrap();
+        assert_eq!(swapped.contents, "one\n\ntwo\n");
+        assert_eq!(swapped.path, path);
+        assert!(swapped.running());
+        assert_eq!(existing(&path, ".").as_ref(), Some(&swap));
+        // Taken, so the next one gets the next name
+        assert_eq!(
+            location(&path, &Options::default()),
+            Some(dir.join(".notes.txt.swo"))
+        );
+
+        let listed = list(&dir.display().to_string());
+        assert!(listed.contains("1.    .notes.txt.swp\n"));
+        assert!(listed.contains(&format!("file name: {}\n", path.display())));
+        assert!(listed.contains("(STILL RUNNING)"));
+    }
+
+    #[test]
+    fn other_directories() {
+        let dir = temp_dir("swap-dirs");
+        let options = Options {
+            directory: format!("/nonexistent,{}", dir.display()),
+            ..Options::default()
+        };
+        let swap = location(Path::new("/some/file.rs"), &options).unwrap();
+        assert_eq!(swap, dir.join("%some%file.rs.swp"));
+
+        let off = Options {
+            swapfile: false,
+            ..Options::default()
+        };
+        assert_eq!(location(Path::new("/some/file.rs"), &off), None);
+        assert!(read(Path::new("/nonexistent.swp")).is_err());
+    }
+
+    #[test]
+    fn swap_files() {
+        let dir = temp_dir("swap");
+        let file = dir.join("f.txt");
+        let swap = dir.join(".f.txt.swp");
+        fs::write(&file, "saved\n").unwrap();
+
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>", file.display())).write_swaps();
+        assert!(!swap.exists());
+        h.keys("iedited <Esc>").write_swaps();
+        assert_eq!(swap::read(&swap).unwrap().contents, "edited saved\n");
+        h.keys(":w<CR>");
+        assert!(!swap.exists());
+        // Written just as the swap file is, which mustn't come back once it's gone. The :w
+        // doesn't wait for the write, the removal comes after it in the background.
+        h.keys("x").start_swaps().keys(":w<CR>").write_swaps();
+        assert!(!swap.exists());
+
+        // Left behind by a rim that's gone
+        let header = |pid| {
+            format!(
+                "rim swap file\npid: {pid}\nfile name: {}\n\n",
+                file.display()
+            )
+        };
+        fs::write(&swap, header(999_999_999) + "lost work\n").unwrap();
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>", file.display()));
+        assert!(h
+            .message()
+            .starts_with(&format!("Swap file \"{}\" already exists!", swap.display())));
+        h.keys("x:");
+        assert_eq!(h.text(), "");
+        h.keys("r");
+        assert_eq!(h.text(), "lost work");
+        assert!(h.message().starts_with("Recovered"));
+        assert_eq!(swap::read(&swap).unwrap().pid, std::process::id());
+        h.keys(":w<CR>");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "lost work\n");
+        assert!(!swap.exists());
+
+        // Another rim still has it open
+        fs::write(&swap, header(1) + "theirs\n").unwrap();
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>", file.display()));
+        assert!(h.message().contains("process 1 is still editing it"));
+        h.keys("o");
+        assert_eq!(h.text(), "lost work");
+        h.keys("ix<Esc>:w<CR>").write_swaps();
+        assert!(h.state().screen().message_is_error());
+        assert_eq!(swap::read(&swap).unwrap().contents, "theirs\n");
+
+        h.keys(&format!(":e {}<CR>q", file.display()));
+        assert!(h.state().should_quit());
+    }
+
+    #[test]
+    fn line_edits_write_swaps() {
+        let dir = temp_dir("swap-lines");
+        let file = dir.join("f.txt");
+        let swap = dir.join(".f.txt.swp");
+        fs::write(&file, "one\ntwo\n").unwrap();
+
+        let mut h = Harness::new("");
+        h.keys(&format!(":e {}<CR>dd", file.display()))
+            .write_swaps();
+        assert_eq!(swap::read(&swap).unwrap().contents, "two\n");
+        assert!(h.screen()[22].contains("[+]"));
+        h.keys(":q<CR>");
+        assert!(h.state().screen().message_is_error());
+        assert!(!h.state().should_quit());
+    }
+
+    #[test]
+    fn unwritable_directories() {
+        let dir = temp_dir("swap-unwritable");
+        let file = dir.join("f.txt");
+        fs::write(&file, "saved\n").unwrap();
+        // A directory nobody can create files in, not even root
+        let mut h = Harness::new("");
+        h.keys(&format!(
+            ":set dir=/proc,.<CR>:e {}<CR>A!<Esc>",
+            file.display()
+        ));
+        h.write_swaps();
+        let failed = format!("/proc/{}.swp", file.display().to_string().replace('/', "%"));
+        assert!(h.state().screen().message_is_error());
+        assert!(h
+            .message()
+            .starts_with(&format!("Unable to write swap file \"{failed}\": ")));
+        assert!(!h.message().ends_with("recovery impossible"));
+        assert_eq!(read(&dir.join(".f.txt.swp")).unwrap().contents, "saved!\n");
+
+        let mut h = Harness::new("");
+        h.keys(&format!(
+            ":set dir=/proc<CR>:e {}<CR>A!<Esc>",
+            file.display()
+        ));
+        h.write_swaps();
+        assert!(h.message().ends_with(", recovery impossible"));
+    }
+}
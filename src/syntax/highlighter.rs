@@ -0,0 +1,268 @@
+//! Every line remembers the context stack it starts in, so after an edit only the changed lines
+//! are re-highlighted, plus however many after them until the stack comes out the same as before.
+
+use super::{Action, Syntax};
+
+/// Byte range of a line that belongs to a scope (`Comment`, `String`, ...)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub scope: String,
+}
+
+struct LineState {
+    /// Context stack at the start of the line
+    start: Vec<usize>,
+    spans: Vec<Span>,
+    /// Contents changed since `spans` was computed
+    dirty: bool,
+}
+
+impl LineState {
+    fn dirty(start: Vec<usize>) -> Self {
+        Self {
+            start,
+            spans: Vec::new(),
+            dirty: true,
+        }
+    }
+}
+
+pub struct Highlighter {
+    syntax: &'static Syntax,
+    lines: Vec<LineState>,
+}
+
+impl Highlighter {
+    pub fn new(syntax: &'static Syntax, line_count: usize) -> Self {
+        Self {
+            syntax,
+            lines: (0..line_count).map(|_| LineState::dirty(vec![0])).collect(),
+        }
+    }
+
+    pub fn syntax(&self) -> &'static Syntax {
+        self.syntax
+    }
+
+    /// `removed` lines starting at `row` were replaced by `inserted` new ones
+    pub fn edit(&mut self, row: usize, removed: usize, inserted: usize) {
+        let row = row.min(self.lines.len());
+        let end = (row + removed).min(self.lines.len());
+        // Whatever is at `row` now still starts where the old line did
+        let start = self
+            .lines
+            .get(row)
+            .map_or_else(|| vec![0], |line| line.start.clone());
+        self.lines.splice(
+            row..end,
+            (0..inserted).map(|_| LineState::dirty(start.clone())),
+        );
+        if inserted == 0 {
+            if let Some(line) = self.lines.get_mut(row) {
+                *line = LineState::dirty(start);
+            }
+        }
+    }
+
+    /// Spans for line `row`, highlighting everything before it that's out of date first
+    pub fn spans(&mut self, lines: &[String], row: usize) -> &[Span] {
+        if self.lines.len() != lines.len() {
+            // Shouldn't happen, but better to start over than to index out of bounds
+            *self = Self::new(self.syntax, lines.len());
+        }
+        self.update(lines, row);
+        &self.lines[row].spans
+    }
+
+    fn update(&mut self, lines: &[String], upto: usize) {
+        let Some(mut i) = self.lines[..=upto].iter().position(|line| line.dirty) else {
+            return;
+        };
+        let mut stack = self.lines[i].start.clone();
+        while i <= upto {
+            let line = &mut self.lines[i];
+            if !line.dirty && line.start == stack {
+                // Converged: everything up to the next changed line is still right
+                match self.lines[i..=upto].iter().position(|line| line.dirty) {
+                    Some(next) => {
+                        i += next;
+                        stack = self.lines[i].start.clone();
+                        continue;
+                    }
+                    None => return,
+                }
+            }
+            line.start = stack.clone();
+            line.spans = tokenize(self.syntax, &lines[i], &mut stack);
+            line.dirty = false;
+            i += 1;
+        }
+        if let Some(next) = self.lines.get_mut(i) {
+            if next.start != stack {
+                *next = LineState::dirty(stack);
+            }
+        }
+    }
+}
+
+fn push_span(spans: &mut Vec<Span>, start: usize, end: usize, scope: &Option<String>) {
+    let Some(scope) = scope else {
+        return;
+    };
+    if start >= end {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.end == start && &last.scope == scope => last.end = end,
+        _ => spans.push(Span {
+            start,
+            end,
+            scope: scope.clone(),
+        }),
+    }
+}
+
+/// Highlights one line starting in context `stack`, leaving `stack` as it is at the end of the
+/// line
+fn tokenize(syntax: &Syntax, line: &str, stack: &mut Vec<usize>) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    // Empty matches that only switch contexts could otherwise go on forever
+    let mut empty_matches = 0;
+    while pos <= line.len() {
+        let context = &syntax.contexts[*stack.last().unwrap()];
+        let Some((rule, captures)) = context
+            .rules
+            .iter()
+            .filter_map(|rule| rule.regex.captures_at(line, pos).map(|c| (rule, c)))
+            .min_by_key(|(_, captures)| captures.get(0).unwrap().start())
+        else {
+            break;
+        };
+        let whole = captures.get(0).unwrap();
+        push_span(&mut spans, pos, whole.start(), &context.scope);
+
+        if whole.is_empty() && (rule.action == Action::None || empty_matches > 16) {
+            // Step over a character so we get anywhere at all
+            let next = line[whole.start()..]
+                .chars()
+                .next()
+                .map_or(line.len() + 1, |c| whole.start() + c.len_utf8());
+            push_span(
+                &mut spans,
+                whole.start(),
+                next.min(line.len()),
+                &context.scope,
+            );
+            pos = next;
+            continue;
+        }
+        if whole.is_empty() {
+            empty_matches += 1;
+        }
+
+        if rule.scopes.len() == 1 {
+            push_span(&mut spans, whole.start(), whole.end(), &rule.scopes[0]);
+        } else {
+            for (group, scope) in rule.scopes.iter().enumerate() {
+                if let Some(group) = captures.get(group + 1) {
+                    push_span(&mut spans, group.start(), group.end(), scope);
+                }
+            }
+        }
+        match rule.action {
+            Action::None => {}
+            Action::Push(context) => stack.push(context),
+            Action::Set(context) => *stack.last_mut().unwrap() = context,
+            Action::Pop => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+            }
+        }
+        pos = whole.end();
+    }
+    if pos < line.len() {
+        let context = &syntax.contexts[*stack.last().unwrap()];
+        push_span(&mut spans, pos, line.len(), &context.scope);
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+    use crate::harness::Harness;
+    use crate::syntax::by_name;
+    use crossterm::style::Color;
+
+    fn scopes(highlighter: &mut Highlighter, lines: &[String], row: usize) -> Vec<String> {
+        let line = &lines[row];
+        highlighter
+            .spans(lines, row)
+            .iter()
+            .map(|span| format!("{}:{}", span.scope, &line[span.start..span.end]))
+            .collect()
+    }
+
+    #[test]
+    fn block_comment_state_carries_over() {
+        let mut lines: Vec<String> = ["let a = 1; /* start", "still comment", "end */ fn f() {}"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut h = Highlighter::new(by_name("rust").unwrap(), lines.len());
+        assert_eq!(scopes(&mut h, &lines, 1), ["Comment:still comment"]);
+        assert_eq!(
+            scopes(&mut h, &lines, 2),
+            ["Comment:end */", "Keyword:fn", "Function:f"]
+        );
+
+        // Closing the comment early changes how the following lines start
+        lines[0].push_str(" */");
+        h.edit(0, 1, 1);
+        assert_eq!(scopes(&mut h, &lines, 1), Vec::<String>::new());
+        assert_eq!(scopes(&mut h, &lines, 2), ["Keyword:fn", "Function:f"]);
+
+        lines.remove(0);
+        h.edit(0, 1, 0);
+        assert_eq!(scopes(&mut h, &lines, 0), Vec::<String>::new());
+        lines.insert(0, "/*".to_string());
+        h.edit(0, 0, 1);
+        assert_eq!(scopes(&mut h, &lines, 1), ["Comment:still comment"]);
+    }
+
+    #[test]
+    fn raw_strings_end_at_as_many_hashes() {
+        let lines: Vec<String> = [r##"r#"a "b" c"# x"##, r#"r"a" x"#, r###"br##"a"# "##.y"###]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut h = Highlighter::new(by_name("rust").unwrap(), lines.len());
+        assert_eq!(scopes(&mut h, &lines, 0), [r##"String:r#"a "b" c"#"##]);
+        assert_eq!(scopes(&mut h, &lines, 1), [r#"String:r"a""#]);
+        assert_eq!(scopes(&mut h, &lines, 2), [r###"String:br##"a"# "##"###]);
+    }
+
+    #[test]
+    fn syntax_highlighting() {
+        let mut h = Harness::with_size("", 5, 20);
+        let buffer = Buffer::from_string("/* a\nb */ fn x".to_owned()).with_syntax("rust");
+        h.state()
+            .screen_mut()
+            .active_window_mut()
+            .set_buffer(buffer)
+            .unwrap();
+        h.keys(":hi Normal fg=NONE<CR>:hi Comment fg=DarkGrey<CR>:hi Keyword fg=Yellow<CR>");
+        let color =
+            |h: &Harness, row, col| h.backend().borrow().cell(row, col).style.foreground_color;
+        assert_eq!(color(&h, 1, 5), Some(Color::DarkGrey));
+        assert_eq!(color(&h, 1, 10), Some(Color::Yellow));
+        h.keys("A */<Esc>");
+        assert_eq!(color(&h, 1, 5), None);
+        assert_eq!(color(&h, 1, 10), Some(Color::Yellow));
+    }
+}
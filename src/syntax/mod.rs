@@ -0,0 +1,294 @@
+//! Syntax definitions, loaded from `.syntax` files rather than written in Rust.
+//!
+//! The format is loosely modeled on sublime-syntax: a file is a list of contexts, each with rules
+//! tried in order at every position. The earliest match wins (ties go to the first rule).
+//!
+//! ```text
+//! # comment
+//! name rust
+//! extensions rs
+//! shebangs               (interpreters, for `#!` lines)
+//! filenames Makefile     (exact file names)
+//!
+//! context main           (the first context is where every file starts)
+//!     match Comment //.*
+//!     match Keyword,Function \b(fn)\s+(\w+)   (one scope per capture group)
+//!     push Comment block_comment /\*
+//! context block_comment Comment              (unmatched text in here is a Comment)
+//!     pop Comment \*/
+//! ```
+//!
+//! Actions are `match SCOPE REGEX`, `push SCOPE CONTEXT REGEX`, `set SCOPE CONTEXT REGEX` and
+//! `pop SCOPE REGEX`. The regex is the rest of the line. A scope of `-` means no highlighting.
+
+use std::{fs, path::Path, sync::OnceLock};
+
+use regex::Regex;
+
+mod highlighter;
+
+pub use highlighter::{Highlighter, Span};
+
+const BUNDLED: &[(&str, &str)] = &[
+    ("c", include_str!("../../runtime/syntax/c.syntax")),
+    ("json", include_str!("../../runtime/syntax/json.syntax")),
+    (
+        "markdown",
+        include_str!("../../runtime/syntax/markdown.syntax"),
+    ),
+    ("python", include_str!("../../runtime/syntax/python.syntax")),
+    ("rust", include_str!("../../runtime/syntax/rust.syntax")),
+    ("sh", include_str!("../../runtime/syntax/sh.syntax")),
+    ("toml", include_str!("../../runtime/syntax/toml.syntax")),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    None,
+    Push(usize),
+    Set(usize),
+    Pop,
+}
+
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    /// One per capture group, or just the whole match
+    scopes: Vec<Option<String>>,
+    action: Action,
+}
+
+#[derive(Debug)]
+struct Context {
+    name: String,
+    /// For text in this context that no rule matched
+    scope: Option<String>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+pub struct Syntax {
+    name: String,
+    extensions: Vec<String>,
+    filenames: Vec<String>,
+    shebangs: Vec<String>,
+    contexts: Vec<Context>,
+}
+
+fn parse_scope(scope: &str) -> Vec<Option<String>> {
+    scope
+        .split(',')
+        .map(|s| if s == "-" { None } else { Some(s.to_owned()) })
+        .collect()
+}
+
+impl Syntax {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut syntax = Syntax {
+            name: String::new(),
+            extensions: Vec::new(),
+            filenames: Vec::new(),
+            shebangs: Vec::new(),
+            contexts: Vec::new(),
+        };
+        // Targets can be defined after they're used, so resolve them at the end
+        // (context, rule, target, is `set`)
+        let mut targets: Vec<(usize, usize, String, bool)> = Vec::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let err = |msg: &str| format!("line {}: {msg}", i + 1);
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            let words = || rest.split_whitespace().map(String::from).collect();
+            match keyword {
+                "name" => syntax.name = rest.trim().to_owned(),
+                "extensions" => syntax.extensions = words(),
+                "filenames" => syntax.filenames = words(),
+                "shebangs" => syntax.shebangs = words(),
+                "context" => {
+                    let mut words = rest.split_whitespace();
+                    let name = words.next().ok_or_else(|| err("context needs a name"))?;
+                    syntax.contexts.push(Context {
+                        name: name.to_owned(),
+                        scope: words.next().map(String::from),
+                        rules: Vec::new(),
+                    });
+                }
+                "match" | "push" | "set" | "pop" => {
+                    let context = syntax
+                        .contexts
+                        .len()
+                        .checked_sub(1)
+                        .ok_or_else(|| err("rule outside of a context"))?;
+                    let (scope, rest) = rest
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| err("expected a scope and a regex"))?;
+                    let (target, pattern) = if let "push" | "set" = keyword {
+                        let (target, pattern) =
+                            rest.trim_start()
+                                .split_once(char::is_whitespace)
+                                .ok_or_else(|| err("expected a context and a regex"))?;
+                        (Some(target), pattern)
+                    } else {
+                        (None, rest)
+                    };
+                    let regex = Regex::new(pattern.trim_start())
+                        .map_err(|e| err(&format!("bad regex: {e}")))?;
+                    let rules = &mut syntax.contexts[context].rules;
+                    if let Some(target) = target {
+                        targets.push((context, rules.len(), target.to_owned(), keyword == "set"));
+                    }
+                    rules.push(Rule {
+                        regex,
+                        scopes: parse_scope(scope),
+                        action: match keyword {
+                            "pop" => Action::Pop,
+                            _ => Action::None,
+                        },
+                    });
+                }
+                _ => return Err(err(&format!("unknown keyword `{keyword}`"))),
+            }
+        }
+
+        for (context, rule, target, is_set) in targets {
+            let index = syntax
+                .contexts
+                .iter()
+                .position(|c| c.name == target)
+                .ok_or_else(|| format!("unknown context `{target}`"))?;
+            syntax.contexts[context].rules[rule].action = if is_set {
+                Action::Set(index)
+            } else {
+                Action::Push(index)
+            };
+        }
+        if syntax.name.is_empty() {
+            return Err("missing `name`".to_string());
+        }
+        if syntax.contexts.is_empty() {
+            return Err("no contexts".to_string());
+        }
+        Ok(syntax)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn load_all() -> Vec<Syntax> {
+    let mut syntaxes: Vec<Syntax> = BUNDLED
+        .iter()
+        .map(|(name, source)| {
+            Syntax::parse(source).unwrap_or_else(|e| panic!("bundled syntax `{name}`: {e}"))
+        })
+        .collect();
+    // User definitions replace bundled ones with the same name. Broken files are skipped.
+    if let Some(dir) =
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".config/rim/syntax"))
+    {
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let Ok(source) = fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            if let Ok(syntax) = Syntax::parse(&source) {
+                syntaxes.retain(|s| s.name != syntax.name);
+                syntaxes.push(syntax);
+            }
+        }
+    }
+    syntaxes
+}
+
+pub fn syntaxes() -> &'static [Syntax] {
+    static SYNTAXES: OnceLock<Vec<Syntax>> = OnceLock::new();
+    SYNTAXES.get_or_init(load_all)
+}
+
+pub fn by_name(name: &str) -> Option<&'static Syntax> {
+    syntaxes().iter().find(|s| s.name == name)
+}
+
+/// `vim: set ft=rust:` or `rim: ft=rust` in the first or last few lines
+fn from_modeline(lines: &[String]) -> Option<&'static Syntax> {
+    static MODELINE: OnceLock<Regex> = OnceLock::new();
+    let modeline = MODELINE.get_or_init(|| {
+        Regex::new(r"(?:^|\s)(?:vi|vim|ex|rim):.*\b(?:ft|filetype|syn|syntax)=([\w-]+)").unwrap()
+    });
+    let tail = lines.len().saturating_sub(5).max(5);
+    lines
+        .iter()
+        .take(5)
+        .chain(lines.iter().skip(tail))
+        .find_map(|line| modeline.captures(line))
+        .and_then(|captures| by_name(&captures[1]))
+}
+
+/// `#!/usr/bin/env python3` and `#!/bin/sh`
+fn from_shebang(first_line: &str) -> Option<&'static Syntax> {
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    syntaxes().iter().find(|s| {
+        s.shebangs.iter().any(|shebang| {
+            interpreter == shebang
+                || interpreter
+                    .strip_prefix(shebang.as_str())
+                    .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        })
+    })
+}
+
+/// Picks a syntax by modeline, then file name, then shebang
+pub fn detect(path: &str, lines: &[String]) -> Option<&'static Syntax> {
+    if let Some(syntax) = from_modeline(lines) {
+        return Some(syntax);
+    }
+    let file_name = Path::new(path).file_name()?.to_str()?;
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    syntaxes()
+        .iter()
+        .find(|s| {
+            s.filenames.iter().any(|f| f == file_name)
+                || extension.is_some_and(|ext| s.extensions.iter().any(|e| e == ext))
+        })
+        .or_else(|| lines.first().and_then(|line| from_shebang(line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn bundled_syntaxes_parse() {
+        // Not through `syntaxes()`, which has the user's too
+        for (name, source) in BUNDLED {
+            assert_eq!(Syntax::parse(source).unwrap().name, *name);
+        }
+    }
+
+    #[test]
+    fn detection() {
+        let name = |path, text| detect(path, &lines(text)).map(Syntax::name);
+        assert_eq!(name("src/main.rs", ""), Some("rust"));
+        assert_eq!(name("Cargo.toml", ""), Some("toml"));
+        assert_eq!(name("script", "#!/usr/bin/env python3\n"), Some("python"));
+        assert_eq!(name("script", "#!/bin/bash -e\n"), Some("sh"));
+        assert_eq!(
+            name("notes.txt", "hi\n# vim: set ft=markdown:"),
+            Some("markdown")
+        );
+        assert_eq!(name("notes.txt", "hi"), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! `:terminal`: a program on a pseudo-terminal, so it thinks it has a terminal of its own. What
+//! it prints goes through `vt` onto a screen of cells, and keys typed in terminal-job mode are
+//! written back to it.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    process::Stdio,
+};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tokio::process::Command;
+
+use crate::{
+    job::{self, Job},
+    loader,
+    vt::Vt,
+};
+
+pub struct Terminal {
+    pub vt: Vt,
+    /// What `:terminal` ran, for the buffer name and messages
+    pub cmd: String,
+    id: usize,
+    /// Our end of the pty. Both go once the program exits.
+    master: Option<File>,
+    job: Option<Job>,
+}
+
+impl Terminal {
+    /// Starts `cmd` with `shell -c`, or `shell` itself without one, on a pty of `rows` by
+    /// `cols`. Has to be called on the runtime.
+    pub fn spawn(
+        shell: &str,
+        cmd: Option<&str>,
+        (rows, cols): (usize, usize),
+        scrollback: usize,
+        events: &job::Sender,
+    ) -> Result<Self, String> {
+        let (master, slave) = openpty(rows, cols).map_err(|e| format!("Cannot open a pty: {e}"))?;
+        let stdio = || slave.try_clone().map(Stdio::from);
+        let mut command = Command::new(shell);
+        if let Some(cmd) = cmd {
+            command.arg("-c").arg(cmd);
+        }
+        command
+            .env("TERM", "xterm-256color")
+            .stdin(stdio().map_err(|e| e.to_string())?)
+            .stdout(stdio().map_err(|e| e.to_string())?)
+            .stderr(stdio().map_err(|e| e.to_string())?)
+            .kill_on_drop(true);
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                // A session of its own with the pty as its terminal, so `<C-c>` and job control
+                // work in there
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command
+            .spawn()
+            .map_err(|e| format!("Cannot execute {shell}: {e}"))?;
+        // The program's copies are all it needs, ours would keep the pty open after it exits
+        drop(command);
+        drop(slave);
+
+        let id = loader::next_id();
+        let reader = File::from(master.try_clone().map_err(|e| e.to_string())?);
+        let output = job::forward(
+            id,
+            Some(tokio::fs::File::from_std(reader)),
+            false,
+            events.clone(),
+        );
+        Ok(Self {
+            vt: Vt::new(rows, cols, scrollback),
+            cmd: cmd.unwrap_or(shell).to_owned(),
+            id,
+            master: Some(File::from(master)),
+            job: Some(job::watch(id, child, vec![output], false, events)),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Output from the program. Answers to anything it asked go straight back.
+    pub fn output(&mut self, bytes: &[u8]) {
+        self.vt.feed(bytes);
+        let responses = self.vt.take_responses();
+        if !responses.is_empty() {
+            self.write(&responses);
+        }
+    }
+
+    /// After the program's exited: there's nothing left to write to
+    pub fn exited(&mut self) {
+        self.job = None;
+        self.master = None;
+    }
+
+    /// A key typed in terminal-job mode
+    pub fn key(&mut self, key: KeyEvent) {
+        let bytes = key_bytes(key, self.vt.app_cursor());
+        self.write(&bytes);
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        if let Some(master) = &mut self.master {
+            // Gone is as good as exited, which is about to be heard of
+            let _ = master.write_all(bytes);
+        }
+    }
+
+    /// The window it's in changed size: the screen does, and the program is told
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        self.vt.resize(rows, cols);
+        if let Some(master) = &self.master {
+            let size = winsize(rows, cols);
+            // SAFETY: a valid fd and a valid winsize
+            unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        }
+    }
+}
+
+fn winsize(rows: usize, cols: usize) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows as u16,
+        ws_col: cols as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// (master, slave)
+fn openpty(rows: usize, cols: usize) -> io::Result<(OwnedFd, OwnedFd)> {
+    let (mut master, mut slave) = (0, 0);
+    let size = winsize(rows, cols);
+    // SAFETY: the out pointers are valid, and null is allowed for the name and termios
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both were just opened and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) })
+}
+
+/// What a terminal sends for `key`. `app_cursor` is the program asking for `ESC O` arrows.
+pub fn key_bytes(key: KeyEvent, app_cursor: bool) -> Vec<u8> {
+    let arrow = |c: char| {
+        let prefix = if app_cursor { "\x1bO" } else { "\x1b[" };
+        format!("{prefix}{c}").into_bytes()
+    };
+    let mut bytes = match key.code {
+        KeyCode::Char(c) if key.modifiers.intersects(KeyModifiers::CONTROL) => {
+            match c.to_ascii_lowercase() {
+                c @ ('@'..='_' | 'a'..='z') => vec![c as u8 & 0x1f],
+                ' ' | '2' => vec![0],
+                '4' => vec![0x1c],
+                _ => c.to_string().into_bytes(),
+            }
+        }
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        KeyCode::Enter => b"\r".to_vec(),
+        KeyCode::Backspace => b"\x7f".to_vec(),
+        KeyCode::Tab => b"\t".to_vec(),
+        KeyCode::BackTab => b"\x1b[Z".to_vec(),
+        KeyCode::Esc => b"\x1b".to_vec(),
+        KeyCode::Up => arrow('A'),
+        KeyCode::Down => arrow('B'),
+        KeyCode::Right => arrow('C'),
+        KeyCode::Left => arrow('D'),
+        KeyCode::Home => arrow('H'),
+        KeyCode::End => arrow('F'),
+        KeyCode::Insert => b"\x1b[2~".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        KeyCode::PageUp => b"\x1b[5~".to_vec(),
+        KeyCode::PageDown => b"\x1b[6~".to_vec(),
+        _ => Vec::new(),
+    };
+    if key.modifiers.intersects(KeyModifiers::ALT) && !bytes.is_empty() {
+        bytes.insert(0, 0x1b);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+    use crate::state::Mode;
+    use crossterm::style::Color;
+
+    #[test]
+    fn keys() {
+        let key = |code, modifiers| key_bytes(KeyEvent::new(code, modifiers), false);
+        assert_eq!(key(KeyCode::Char('c'), KeyModifiers::CONTROL), [3]);
+        assert_eq!(key(KeyCode::Char('\\'), KeyModifiers::CONTROL), [0x1c]);
+        assert_eq!(key(KeyCode::Char('é'), KeyModifiers::NONE), "é".as_bytes());
+        assert_eq!(key(KeyCode::Char('b'), KeyModifiers::ALT), b"\x1bb");
+        assert_eq!(key(KeyCode::Up, KeyModifiers::NONE), b"\x1b[A");
+        let up = key_bytes(KeyEvent::new(KeyCode::Up, KeyModifiers::NONE), true);
+        assert_eq!(up, b"\x1bOA");
+    }
+
+    #[tokio::test]
+    async fn runs_on_a_pty() {
+        let (events, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut terminal = Terminal::spawn(
+            "sh",
+            Some("stty size; test -t 0 && echo tty; read line; echo got $line"),
+            (5, 30),
+            100,
+            &events,
+        )
+        .unwrap();
+        let mut typed = false;
+        loop {
+            match receiver.recv().await.unwrap() {
+                job::Event::Output { bytes, .. } => {
+                    terminal.output(&bytes);
+                    // Once it's waiting, or the echo could come before the rest
+                    if !typed && terminal.vt.lines().iter().any(|line| line == "tty") {
+                        terminal.write(b"it\r");
+                        typed = true;
+                    }
+                }
+                job::Event::Exited { result, .. } => {
+                    assert_eq!(result, Ok(Some(0)));
+                    break;
+                }
+            }
+        }
+        terminal.exited();
+        assert!(!terminal.running());
+        assert_eq!(terminal.vt.lines()[..4], ["5 30", "tty", "it", "got it"]);
+    }
+
+    #[test]
+    fn terminal() {
+        let mut h = Harness::new("text");
+        h.keys(":set shell=sh<CR>:terminal<CR>");
+        assert_eq!(h.mode(), &Mode::Terminal);
+        assert_eq!(h.buffer().filename(), "!sh");
+        h.keys("stty size; printf '\\033[31mred\\033[0m\\n'<CR>");
+        h.wait_for(|h| h.text().lines().any(|line| line == "red"));
+        // The bottom half of the editor, less the number column
+        assert!(h.text().contains("10 74\n"));
+        let row = h
+            .screen()
+            .iter()
+            .position(|row| row.ends_with(" red"))
+            .unwrap();
+        let cell = h.backend().borrow().cell(row, 5).clone();
+        assert_eq!(
+            (cell.c, cell.style.foreground_color),
+            ('r', Some(Color::DarkRed))
+        );
+
+        h.keys("<C-\\><C-n>");
+        assert_eq!(h.mode(), &Mode::Normal);
+        let (row, _) = h.cursor();
+        h.keys("k");
+        assert_eq!(h.cursor().0, row - 1);
+        h.keys(":new<CR><space>ki");
+        assert_eq!(h.mode(), &Mode::Terminal);
+        h.keys("stty size<CR>");
+        h.wait_for(|h| h.text().contains("5 74"));
+
+        h.keys("<C-\\><C-n>:q<CR>");
+        assert_eq!(h.message(), "Job still running (add ! to end the job)");
+        h.keys("iexit 3<CR>");
+        h.wait_for(|h| h.mode() == &Mode::Normal);
+        assert_eq!(h.message(), "\"!sh\" [Process exited 3]");
+        h.keys("i");
+        assert_eq!(h.mode(), &Mode::Insert);
+    }
+}
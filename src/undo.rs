@@ -0,0 +1,432 @@
+//! Undo: every change to a buffer's text is kept as the lines it replaced and the lines that
+//! replaced them, so it can be taken back and done again. Changes are grouped into steps, one
+//! per normal mode command or stretch of insert mode, which is what `u` and `<C-r>` go by.
+//!
+//! With `undofile` the steps are written next to the file, or in `undodir`, whenever the file
+//! is, and read back when it's opened again. They're only used if the text is still what it
+//! was then, which the hash says:
+//!
+//! ```text
+//! rim undo file
+//! hash: 5d7e2f0c4b1a3e96
+//! done: 2
+//! step 3 4
+//! edit 3 1 2
+//! -the line before
+//! +the lines
+//! +after
+//! ```
+//!
+//! `done` is how many steps are done, the rest were undone. Each `step` has the cursor to go
+//! back to and its edits follow it: the row, how many lines were there before and after, then
+//! those lines, with `\\`, `\n` and `\r` for backslashes and line break characters in them.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{marks::Pos, options::Options, save, swap};
+
+const MAGIC: &str = "rim undo file";
+
+/// How many steps are kept, like Vim's `undolevels`
+const LEVELS: usize = 1000;
+
+/// `before`, the lines at `row`, were replaced by `after`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub row: usize,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Step {
+    pub edits: Vec<Edit>,
+    /// Where the first edit was made, where the cursor goes when it's undone or redone
+    pub cursor: Pos,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoList {
+    steps: Vec<Step>,
+    /// How many of `steps` are done, the rest were undone and are kept for redoing
+    done: usize,
+    /// Whether the last done step still takes edits
+    open: bool,
+    /// `done` as of reading or writing the file, `None` once there's no getting back to that
+    saved: Option<usize>,
+}
+
+impl Default for UndoList {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            done: 0,
+            open: false,
+            saved: Some(0),
+        }
+    }
+}
+
+impl UndoList {
+    /// Goes in the open step, or a new one made at `cursor`, which is the end of whatever was
+    /// undone
+    pub fn record(&mut self, edit: Edit, cursor: Pos) {
+        if !self.open {
+            self.steps.truncate(self.done);
+            if self.saved.is_some_and(|saved| saved > self.done) {
+                self.saved = None;
+            }
+            self.steps.push(Step {
+                edits: Vec::new(),
+                cursor,
+            });
+            self.done += 1;
+            self.open = true;
+            if self.steps.len() > LEVELS {
+                self.steps.remove(0);
+                self.done -= 1;
+                self.saved = self.saved.and_then(|saved| saved.checked_sub(1));
+            }
+        }
+        let edits = &mut self.steps[self.done - 1].edits;
+        // Typing along a line is one edit rather than one per character
+        match edits.last_mut() {
+            Some(last) if last.row == edit.row && last.after == edit.before => {
+                last.after = edit.after;
+            }
+            _ => edits.push(edit),
+        }
+    }
+
+    /// The next edit starts a new step
+    pub fn seal(&mut self) {
+        self.open = false;
+    }
+
+    /// The step to undo, which is then not done anymore
+    pub fn undo(&mut self) -> Option<&Step> {
+        self.seal();
+        self.done = self.done.checked_sub(1)?;
+        Some(&self.steps[self.done])
+    }
+
+    /// The step to redo
+    pub fn redo(&mut self) -> Option<&Step> {
+        self.seal();
+        if self.done == self.steps.len() {
+            return None;
+        }
+        self.done += 1;
+        Some(&self.steps[self.done - 1])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The text is what's in the file now
+    pub fn set_saved(&mut self) {
+        self.seal();
+        self.saved = Some(self.done);
+    }
+
+    /// Whether undoing or redoing got back to what's in the file
+    pub fn at_saved(&self) -> bool {
+        self.saved == Some(self.done)
+    }
+
+    /// Saves the steps for when `lines`, the text of `file` as written, is read again. Only
+    /// those who can read `file` can read them.
+    pub fn write(&self, path: &Path, lines: &[String], file: &Path) -> io::Result<()> {
+        let mut contents = format!("{MAGIC}\nhash: {:016x}\ndone: {}\n", hash(lines), self.done);
+        for step in &self.steps {
+            contents.push_str(&format!("step {} {}\n", step.cursor.0, step.cursor.1));
+            for edit in &step.edits {
+                contents.push_str(&format!(
+                    "edit {} {} {}\n",
+                    edit.row,
+                    edit.before.len(),
+                    edit.after.len()
+                ));
+                for line in &edit.before {
+                    contents.push_str(&format!("-{}\n", escape(line)));
+                }
+                for line in &edit.after {
+                    contents.push_str(&format!("+{}\n", escape(line)));
+                }
+            }
+        }
+        save::write_copy(path, contents.as_bytes(), file)
+    }
+
+    /// The steps saved for `lines`. `None` when there's no undo file, an error when there's
+    /// one that's not for `lines` or makes no sense.
+    pub fn read(path: &Path, lines: &[String]) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidData, why.to_string());
+        let corrupt = || invalid("Corrupt undo file");
+        // Not `lines`, which would take a `\r` that's part of one as its end
+        let mut text = text.split_terminator('\n');
+        if text.next() != Some(MAGIC) {
+            return Err(invalid("Not a rim undo file"));
+        }
+        let mut field = |name: &str| {
+            text.next()
+                .and_then(|line| line.strip_prefix(name))
+                .map(str::to_owned)
+                .ok_or_else(corrupt)
+        };
+        let saved_hash = u64::from_str_radix(&field("hash: ")?, 16).map_err(|_| corrupt())?;
+        if saved_hash != hash(lines) {
+            return Err(invalid("File contents changed"));
+        }
+        let done: usize = field("done: ")?.parse().map_err(|_| corrupt())?;
+        let numbers = |line: &str, name: &str| -> Option<Vec<usize>> {
+            let rest = line.strip_prefix(name)?;
+            rest.split(' ').map(|n| n.parse().ok()).collect()
+        };
+        let mut steps: Vec<Step> = Vec::new();
+        while let Some(line) = text.next() {
+            if let Some(&[row, col]) = numbers(line, "step ").as_deref() {
+                steps.push(Step {
+                    edits: Vec::new(),
+                    cursor: (row, col),
+                });
+                continue;
+            }
+            let (Some(&[row, before, after]), Some(step)) =
+                (numbers(line, "edit ").as_deref(), steps.last_mut())
+            else {
+                return Err(corrupt());
+            };
+            let mut take = |n: usize, prefix: char| -> io::Result<Vec<String>> {
+                (0..n)
+                    .map(|_| {
+                        text.next()
+                            .and_then(|line| line.strip_prefix(prefix))
+                            .and_then(unescape)
+                            .ok_or_else(corrupt)
+                    })
+                    .collect()
+            };
+            let edit = Edit {
+                row,
+                before: take(before, '-')?,
+                after: take(after, '+')?,
+            };
+            step.edits.push(edit);
+        }
+        if done > steps.len() {
+            return Err(corrupt());
+        }
+        Ok(Some(Self {
+            steps,
+            done,
+            open: false,
+            saved: Some(done),
+        }))
+    }
+}
+
+/// Where the undo file for `path` goes, `None` with `noundofile` or when there's nowhere to put
+/// it
+pub fn location(path: &Path, options: &Options) -> Option<PathBuf> {
+    if !options.undofile {
+        return None;
+    }
+    swap::named_like(path, &options.undodir, "un~")
+}
+
+/// A line as the undo file has it, which can't have line breaks in it
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// The line `escape` made into `line`, `None` if it isn't one
+fn unescape(line: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next()? {
+            '\\' => '\\',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
+
+/// FNV-1a over the lines, each ending in `\n`, which is the same from one build to the next
+/// unlike the standard library's hashers
+fn hash(lines: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for line in lines {
+        for &byte in line.as_bytes().iter().chain(b"\n") {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{temp_dir, Harness};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn strings(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn edit(row: usize, before: &[&str], after: &[&str]) -> Edit {
+        Edit {
+            row,
+            before: strings(before),
+            after: strings(after),
+        }
+    }
+
+    #[test]
+    fn steps_and_files() {
+        let mut undo = UndoList::default();
+        undo.record(edit(0, &["a"], &["ab"]), (0, 1));
+        undo.record(edit(0, &["ab"], &["abc"]), (0, 2));
+        undo.record(edit(1, &[], &[""]), (1, 0));
+        undo.seal();
+        undo.record(edit(1, &[""], &[]), (1, 0));
+        assert_eq!(undo.steps.len(), 2);
+        assert_eq!(
+            undo.steps[0].edits,
+            [edit(0, &["a"], &["abc"]), edit(1, &[], &[""])]
+        );
+        assert_eq!(undo.steps[0].cursor, (0, 1));
+        assert!(!undo.at_saved());
+        assert_eq!(undo.undo().unwrap().cursor, (1, 0));
+        assert!(undo.undo().is_some() && undo.undo().is_none());
+        assert!(undo.at_saved());
+        assert!(undo.redo().is_some());
+
+        let path = crate::harness::temp_dir("undo").join("a.un~");
+        let lines = strings(&["abc", "-x", "+y"]);
+        assert!(UndoList::read(&path, &lines).unwrap().is_none());
+        undo.write(&path, &lines, Path::new("/nonexistent"))
+            .unwrap();
+        let mut read = UndoList::read(&path, &lines).unwrap().unwrap();
+        undo.set_saved();
+        assert_eq!(read, undo);
+        assert_eq!(read.redo().unwrap().edits, [edit(1, &[""], &[])]);
+
+        let e = UndoList::read(&path, &strings(&["abc"])).unwrap_err();
+        assert_eq!(e.to_string(), "File contents changed");
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("edit 1 1 0", "edit 1 2 0")).unwrap();
+        let e = UndoList::read(&path, &lines).unwrap_err();
+        assert_eq!(e.to_string(), "Corrupt undo file");
+
+        // Backslashes and line break characters in lines, even a `\r` at the end, come back
+        let mut undo = UndoList::default();
+        undo.record(edit(0, &["y\r"], &["a\\nb\r", "c\nd\\", "\r"]), (0, 0));
+        undo.seal();
+        undo.set_saved();
+        undo.write(&path, &lines, Path::new("/nonexistent"))
+            .unwrap();
+        assert_eq!(UndoList::read(&path, &lines).unwrap().unwrap(), undo);
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap().replace("\\n", "\\x"),
+        )
+        .unwrap();
+        let e = UndoList::read(&path, &lines).unwrap_err();
+        assert_eq!(e.to_string(), "Corrupt undo file");
+    }
+
+    #[test]
+    fn undo_and_undofile() {
+        let mut h = Harness::new("one\ntwo");
+        h.keys("Afoo<Esc>jdd");
+        assert_eq!(h.text(), "onefoo");
+        h.keys("u");
+        assert_eq!((h.text(), h.cursor()), ("onefoo\ntwo".to_string(), (1, 0)));
+        h.keys("u");
+        assert_eq!((h.text(), h.cursor()), ("one\ntwo".to_string(), (0, 3)));
+        h.keys("u");
+        assert_eq!(h.message(), "Already at oldest change");
+        h.keys("<C-r><C-r>");
+        assert_eq!(h.text(), "onefoo");
+        h.keys("<C-r>");
+        assert_eq!(h.message(), "Already at newest change");
+        // A new change leaves nothing to redo
+        h.keys("uox<Esc><C-r>");
+        assert_eq!(h.text(), "onefoo\ntwo\nx");
+        assert_eq!(h.message(), "Already at newest change");
+        h.keys("u");
+        assert_eq!(h.text(), "onefoo\ntwo");
+
+        let dir = temp_dir("undo_and_undofile");
+        let a = dir.join("a");
+        fs::write(&a, "alpha\n").unwrap();
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o640)).unwrap();
+        let open = |h: &mut Harness| {
+            h.keys(&format!(":set undofile<CR>:e {}<CR>", a.display()));
+        };
+        let mut h = Harness::new("");
+        open(&mut h);
+        h.keys("Ax<Esc>:w<CR>");
+        let mode = fs::metadata(dir.join(".a.un~"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+        // Under another name, the undo file goes with it and that's what's saved
+        h.keys(&format!("Ay<Esc>:w {}<CR>", dir.join("b").display()));
+        assert!(dir.join(".b.un~").exists());
+        assert!(!h.screen()[22].contains("[+]"));
+        h.keys("u");
+        assert!(h.screen()[22].contains("[+]"));
+        h.keys("<C-r>");
+        assert!(!h.screen()[22].contains("[+]"));
+
+        let mut h = Harness::new("");
+        open(&mut h);
+        assert_eq!(h.message(), format!("\"{}\" 1L, 7B", a.display()));
+        h.keys("u");
+        assert_eq!(h.text(), "alpha");
+        assert!(h.screen()[22].contains("[+]"));
+        h.keys("<C-r>");
+        assert!(!h.screen()[22].contains("[+]"));
+
+        fs::write(&a, "beta\n").unwrap();
+        let mut h = Harness::new("");
+        open(&mut h);
+        assert_eq!(
+            h.message(),
+            format!(
+                "\"{}\" [undo file ignored: File contents changed] 1L, 5B",
+                a.display()
+            )
+        );
+        h.keys("u");
+        assert_eq!(h.message(), "Already at oldest change");
+        fs::write(dir.join(".a.un~"), "junk\n").unwrap();
+        let mut h = Harness::new("");
+        open(&mut h);
+        assert!(h
+            .message()
+            .contains("[undo file ignored: Not a rim undo file]"));
+    }
+}
@@ -0,0 +1,637 @@
+//! The screen of a program running in `:terminal`: what it prints, VT100 and the xterm extensions
+//! programs commonly use, turned into a grid of styled cells. Lines scrolled off the top are kept
+//! as scrollback.
+
+use crossterm::style::{Attribute, Color, ContentStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub style: ContentStyle,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            style: ContentStyle::new(),
+        }
+    }
+}
+
+type Grid = Vec<Vec<Cell>>;
+
+/// Where in an escape sequence the parser is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// `ESC [`, collecting parameters until the final byte
+    Csi,
+    /// `ESC ]`, a title or such, skipped until `BEL` or `ESC \`
+    Osc,
+    OscEscape,
+    /// `ESC (` and friends pick a character set, which is always ASCII here
+    Charset,
+}
+
+pub struct Vt {
+    rows: usize,
+    cols: usize,
+    grid: Grid,
+    scrollback: Grid,
+    max_scrollback: usize,
+    cursor: (usize, usize),
+    /// A character went in the last column: the next one goes on a new line
+    wrap_pending: bool,
+    style: ContentStyle,
+    saved: ((usize, usize), ContentStyle),
+    /// Top and bottom rows of the part that scrolls, both included
+    region: (usize, usize),
+    cursor_visible: bool,
+    /// `ESC [ ? 1 h`: arrow keys send `ESC O A` rather than `ESC [ A`
+    app_cursor: bool,
+    /// The main screen and cursor, put away while a full screen program has the alternate one
+    alternate: Option<(Grid, (usize, usize))>,
+    state: State,
+    params: String,
+    /// The first bytes of a character that isn't ASCII
+    utf8: Vec<u8>,
+    /// Answers to the program's questions, like where the cursor is, for the pty
+    responses: Vec<u8>,
+}
+
+impl Vt {
+    pub fn new(rows: usize, cols: usize, max_scrollback: usize) -> Self {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        Self {
+            rows,
+            cols,
+            grid: vec![vec![Cell::default(); cols]; rows],
+            scrollback: Vec::new(),
+            max_scrollback,
+            cursor: (0, 0),
+            wrap_pending: false,
+            style: ContentStyle::new(),
+            saved: ((0, 0), ContentStyle::new()),
+            region: (0, rows - 1),
+            cursor_visible: true,
+            app_cursor: false,
+            alternate: None,
+            state: State::Ground,
+            params: String::new(),
+            utf8: Vec::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// The scrollback then the screen, trailing blanks left off. The cursor's line goes at
+    /// least as far as the cursor.
+    pub fn lines(&self) -> Vec<String> {
+        let cursor = self.cursor();
+        self.rows()
+            .enumerate()
+            .map(|(i, row)| {
+                let keep = if i == cursor.0 { cursor.1 } else { 0 };
+                let len = row
+                    .iter()
+                    .rposition(|cell| cell.c != ' ')
+                    .map_or(0, |i| i + 1)
+                    .max(keep);
+                row[..len.min(row.len())]
+                    .iter()
+                    .map(|cell| cell.c)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Line `n`, counting the scrollback like `lines` does
+    pub fn row(&self, n: usize) -> &[Cell] {
+        let row = match n.checked_sub(self.scrollback.len()) {
+            Some(n) => self.grid.get(n),
+            None => self.scrollback.get(n),
+        };
+        row.map_or(&[], Vec::as_slice)
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &Vec<Cell>> {
+        self.scrollback.iter().chain(&self.grid)
+    }
+
+    /// (line, column), counting the scrollback like `lines` does
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.scrollback.len() + self.cursor.0, self.cursor.1)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn app_cursor(&self) -> bool {
+        self.app_cursor
+    }
+
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
+    }
+
+    /// Fits the screen to `rows` by `cols`. Rows that no longer fit above the cursor go to the
+    /// scrollback.
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        let resize = |grid: &mut Grid| {
+            for row in grid.iter_mut() {
+                row.resize(cols, Cell::default());
+            }
+        };
+        resize(&mut self.grid);
+        while self.grid.len() > rows && self.cursor.0 > 0 {
+            let row = self.grid.remove(0);
+            self.cursor.0 -= 1;
+            if self.alternate.is_none() {
+                self.push_scrollback(row);
+            }
+        }
+        self.grid.resize(rows, vec![Cell::default(); cols]);
+        if let Some((grid, cursor)) = &mut self.alternate {
+            resize(grid);
+            grid.resize(rows, vec![Cell::default(); cols]);
+            *cursor = (cursor.0.min(rows - 1), cursor.1.min(cols - 1));
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.region = (0, rows - 1);
+        self.cursor = (self.cursor.0.min(rows - 1), self.cursor.1.min(cols - 1));
+        self.wrap_pending = false;
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            match self.state {
+                State::Ground => self.ground(b),
+                State::Escape => self.escape(b),
+                State::Csi => match b {
+                    0x20..=0x3f => self.params.push(b as char),
+                    0x40..=0x7e => {
+                        self.state = State::Ground;
+                        self.csi(b as char);
+                    }
+                    0x1b => self.state = State::Escape,
+                    // Control characters still count in the middle of a sequence
+                    _ => self.control(b),
+                },
+                State::Osc => match b {
+                    0x07 => self.state = State::Ground,
+                    0x1b => self.state = State::OscEscape,
+                    _ => {}
+                },
+                State::OscEscape | State::Charset => self.state = State::Ground,
+            }
+        }
+    }
+
+    fn ground(&mut self, b: u8) {
+        if b < 0x80 && self.utf8.is_empty() {
+            match b {
+                0x1b => self.state = State::Escape,
+                0x20..=0x7e => self.put(b as char),
+                _ => self.control(b),
+            }
+            return;
+        }
+        self.utf8.push(b);
+        let len = match self.utf8[0] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        if self.utf8.len() < len {
+            return;
+        }
+        let bytes = std::mem::take(&mut self.utf8);
+        let c = std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        self.put(c);
+    }
+
+    fn control(&mut self, b: u8) {
+        match b {
+            b'\r' => self.set_cursor(self.cursor.0, 0),
+            b'\n' | 0x0b | 0x0c => self.linefeed(),
+            0x08 => self.set_cursor(self.cursor.0, self.cursor.1.saturating_sub(1)),
+            b'\t' => {
+                let next = (self.cursor.1 / 8 + 1) * 8;
+                self.set_cursor(self.cursor.0, next.min(self.cols - 1));
+            }
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, b: u8) {
+        self.state = State::Ground;
+        match b {
+            b'[' => {
+                self.params.clear();
+                self.state = State::Csi;
+            }
+            b']' => self.state = State::Osc,
+            b'(' | b')' | b'*' | b'+' => self.state = State::Charset,
+            b'7' => self.saved = (self.cursor, self.style),
+            b'8' => {
+                let ((row, col), style) = self.saved;
+                self.style = style;
+                self.set_cursor(row, col);
+            }
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.linefeed();
+                self.set_cursor(self.cursor.0, 0);
+            }
+            b'M' => {
+                if self.cursor.0 == self.region.0 {
+                    self.scroll_down(1);
+                } else {
+                    self.set_cursor(self.cursor.0.saturating_sub(1), self.cursor.1);
+                }
+            }
+            b'c' => {
+                let max_scrollback = self.max_scrollback;
+                let scrollback = std::mem::take(&mut self.scrollback);
+                *self = Self::new(self.rows, self.cols, max_scrollback);
+                self.scrollback = scrollback;
+            }
+            _ => {}
+        }
+    }
+
+    fn put(&mut self, c: char) {
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.cursor.1 = 0;
+            self.linefeed();
+        }
+        let (row, col) = self.cursor;
+        self.grid[row][col] = Cell {
+            c,
+            style: self.style,
+        };
+        if col + 1 == self.cols {
+            self.wrap_pending = true;
+        } else {
+            self.cursor.1 += 1;
+        }
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.cursor = (row.min(self.rows - 1), col.min(self.cols - 1));
+        self.wrap_pending = false;
+    }
+
+    fn linefeed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.0 == self.region.1 {
+            self.scroll_up(1);
+        } else if self.cursor.0 + 1 < self.rows {
+            self.cursor.0 += 1;
+        }
+    }
+
+    /// What erased cells look like: the background color stays
+    fn blank(&self) -> Cell {
+        Cell {
+            c: ' ',
+            style: ContentStyle {
+                background_color: self.style.background_color,
+                ..ContentStyle::new()
+            },
+        }
+    }
+
+    fn blank_row(&self) -> Vec<Cell> {
+        vec![self.blank(); self.cols]
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        let (top, bottom) = self.region;
+        for _ in 0..n.min(bottom - top + 1) {
+            let row = self.grid.remove(top);
+            self.grid.insert(bottom, self.blank_row());
+            if top == 0 && self.alternate.is_none() {
+                self.push_scrollback(row);
+            }
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let (top, bottom) = self.region;
+        for _ in 0..n.min(bottom - top + 1) {
+            self.grid.remove(bottom);
+            self.grid.insert(top, self.blank_row());
+        }
+    }
+
+    fn push_scrollback(&mut self, row: Vec<Cell>) {
+        self.scrollback.push(row);
+        let extra = self.scrollback.len().saturating_sub(self.max_scrollback);
+        self.scrollback.drain(..extra);
+    }
+
+    fn csi(&mut self, action: char) {
+        let private = self.params.starts_with('?');
+        let params: Vec<usize> = self
+            .params
+            .trim_start_matches(['?', '>', '='])
+            .split([';', ':'])
+            .map(|n| n.parse().unwrap_or(0))
+            .collect();
+        // The first parameter, where leaving it out or 0 means `default`
+        let n = |i: usize, default: usize| match params.get(i) {
+            Some(0) | None => default,
+            Some(&n) => n,
+        };
+        let (row, col) = self.cursor;
+        match action {
+            'A' => self.set_cursor(row.saturating_sub(n(0, 1)), col),
+            'B' | 'e' => self.set_cursor(row.saturating_add(n(0, 1)), col),
+            'C' | 'a' => self.set_cursor(row, col.saturating_add(n(0, 1))),
+            'D' => self.set_cursor(row, col.saturating_sub(n(0, 1))),
+            'E' => self.set_cursor(row.saturating_add(n(0, 1)), 0),
+            'F' => self.set_cursor(row.saturating_sub(n(0, 1)), 0),
+            'G' | '`' => self.set_cursor(row, n(0, 1) - 1),
+            'd' => self.set_cursor(n(0, 1) - 1, col),
+            'H' | 'f' => self.set_cursor(n(0, 1) - 1, n(1, 1) - 1),
+            'J' => {
+                let blank = self.blank();
+                let (from, to) = match params[0] {
+                    0 => ((row, col), (self.rows - 1, self.cols)),
+                    1 => ((0, 0), (row, col + 1)),
+                    _ => ((0, 0), (self.rows - 1, self.cols)),
+                };
+                for r in from.0..=to.0 {
+                    let start = if r == from.0 { from.1 } else { 0 };
+                    let end = if r == to.0 { to.1 } else { self.cols };
+                    self.grid[r][start..end.min(self.cols)].fill(blank);
+                }
+                if params[0] == 3 {
+                    self.scrollback.clear();
+                }
+            }
+            'K' => {
+                let blank = self.blank();
+                let (start, end) = match params[0] {
+                    0 => (col, self.cols),
+                    1 => (0, col + 1),
+                    _ => (0, self.cols),
+                };
+                self.grid[row][start..end.min(self.cols)].fill(blank);
+            }
+            'L' | 'M' if (self.region.0..=self.region.1).contains(&row) => {
+                let bottom = self.region.1;
+                for _ in 0..n(0, 1).min(bottom - row + 1) {
+                    if action == 'L' {
+                        self.grid.remove(bottom);
+                        self.grid.insert(row, self.blank_row());
+                    } else {
+                        self.grid.remove(row);
+                        self.grid.insert(bottom, self.blank_row());
+                    }
+                }
+                self.set_cursor(row, 0);
+            }
+            '@' => {
+                let blank = self.blank();
+                let line = &mut self.grid[row];
+                for _ in 0..n(0, 1).min(self.cols - col) {
+                    line.insert(col, blank);
+                }
+                line.truncate(self.cols);
+            }
+            'P' => {
+                let blank = self.blank();
+                let line = &mut self.grid[row];
+                for _ in 0..n(0, 1).min(self.cols - col) {
+                    line.remove(col);
+                    line.push(blank);
+                }
+            }
+            'X' => {
+                let blank = self.blank();
+                let end = col.saturating_add(n(0, 1)).min(self.cols);
+                self.grid[row][col..end].fill(blank);
+            }
+            'S' => self.scroll_up(n(0, 1)),
+            'T' if !private => self.scroll_down(n(0, 1)),
+            'm' => self.sgr(&params),
+            'r' if !private => {
+                let (top, bottom) = (n(0, 1) - 1, n(1, self.rows) - 1);
+                if top < bottom && bottom < self.rows {
+                    self.region = (top, bottom);
+                    self.set_cursor(0, 0);
+                }
+            }
+            's' => self.saved = (self.cursor, self.style),
+            'u' => {
+                let ((row, col), style) = self.saved;
+                self.style = style;
+                self.set_cursor(row, col);
+            }
+            'h' | 'l' if private => {
+                for &mode in &params {
+                    self.set_mode(mode, action == 'h');
+                }
+            }
+            'n' => match params[0] {
+                5 => self.responses.extend(b"\x1b[0n"),
+                6 => self
+                    .responses
+                    .extend(format!("\x1b[{};{}R", row + 1, col + 1).as_bytes()),
+                _ => {}
+            },
+            'c' if !self.params.starts_with(['>', '=']) => {
+                self.responses.extend(b"\x1b[?1;2c");
+            }
+            _ => {}
+        }
+    }
+
+    fn set_mode(&mut self, mode: usize, on: bool) {
+        match mode {
+            1 => self.app_cursor = on,
+            25 => self.cursor_visible = on,
+            47 | 1047 | 1049 => {
+                if on && self.alternate.is_none() {
+                    let grid = std::mem::replace(
+                        &mut self.grid,
+                        vec![vec![Cell::default(); self.cols]; self.rows],
+                    );
+                    self.alternate = Some((grid, self.cursor));
+                } else if let (false, Some((grid, cursor))) = (on, self.alternate.take()) {
+                    self.grid = grid;
+                    self.set_cursor(cursor.0, cursor.1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Select graphic rendition, `ESC [ ... m`
+    fn sgr(&mut self, params: &[usize]) {
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            let style = &mut self.style;
+            match param {
+                0 => *style = ContentStyle::new(),
+                1 => style.attributes.set(Attribute::Bold),
+                2 => style.attributes.set(Attribute::Dim),
+                3 => style.attributes.set(Attribute::Italic),
+                4 => style.attributes.set(Attribute::Underlined),
+                5 => style.attributes.set(Attribute::SlowBlink),
+                7 => style.attributes.set(Attribute::Reverse),
+                8 => style.attributes.set(Attribute::Hidden),
+                9 => style.attributes.set(Attribute::CrossedOut),
+                22 => {
+                    style.attributes.unset(Attribute::Bold);
+                    style.attributes.unset(Attribute::Dim);
+                }
+                23 => style.attributes.unset(Attribute::Italic),
+                24 => style.attributes.unset(Attribute::Underlined),
+                25 => style.attributes.unset(Attribute::SlowBlink),
+                27 => style.attributes.unset(Attribute::Reverse),
+                28 => style.attributes.unset(Attribute::Hidden),
+                29 => style.attributes.unset(Attribute::CrossedOut),
+                30..=37 => style.foreground_color = Some(ansi_color(param - 30)),
+                38 => style.foreground_color = extended_color(&mut params),
+                39 => style.foreground_color = None,
+                40..=47 => style.background_color = Some(ansi_color(param - 40)),
+                48 => style.background_color = extended_color(&mut params),
+                49 => style.background_color = None,
+                90..=97 => style.foreground_color = Some(ansi_color(param - 90 + 8)),
+                100..=107 => style.background_color = Some(ansi_color(param - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// One of the 16 colors every terminal has
+fn ansi_color(n: usize) -> Color {
+    [
+        Color::Black,
+        Color::DarkRed,
+        Color::DarkGreen,
+        Color::DarkYellow,
+        Color::DarkBlue,
+        Color::DarkMagenta,
+        Color::DarkCyan,
+        Color::Grey,
+        Color::DarkGrey,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::White,
+    ][n]
+}
+
+/// The rest of `38;5;n` or `38;2;r;g;b`
+fn extended_color(params: &mut impl Iterator<Item = usize>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::AnsiValue(params.next()? as u8)),
+        2 => {
+            let (r, g, b) = (params.next()?, params.next()?, params.next()?);
+            Some(Color::Rgb {
+                r: r as u8,
+                g: g as u8,
+                b: b as u8,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vt(rows: usize, cols: usize, output: &str) -> Vt {
+        let mut vt = Vt::new(rows, cols, 100);
+        vt.feed(output.as_bytes());
+        vt
+    }
+
+    #[test]
+    fn prints_and_wraps() {
+        let vt = vt(3, 5, "hello world");
+        assert_eq!(vt.lines(), ["hello", " worl", "d"]);
+        let vt = self::vt(3, 5, "one\r\ntwo\r\nthree\r\nfour");
+        assert_eq!(vt.lines(), ["one", "two", "three", "four"]);
+        assert_eq!(vt.cursor(), (3, 4));
+        let vt = self::vt(1, 10, "ab\tcé\x08x");
+        assert_eq!(vt.lines(), ["ab      xé"]);
+    }
+
+    #[test]
+    fn cursor_and_erasing() {
+        let vt = vt(
+            3,
+            10,
+            "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[K\x1b[1;2H\x1b[1P",
+        );
+        assert_eq!(vt.lines(), ["aaaa", "bb", "ccccc"]);
+        assert_eq!(vt.cursor(), (0, 1));
+        let vt = self::vt(3, 10, "abc\x1b[2J\x1b[Hx\x1b[3;1Hz\x1b[A\x1b[2Cy");
+        assert_eq!(vt.lines(), ["x", "   y", "z"]);
+        let vt = self::vt(3, 10, "1\r\n2\r\n3\x1b[1;1H\x1b[L");
+        assert_eq!(vt.lines(), ["", "1", "2"]);
+        // Counts as big as they come stop at the edge
+        let vt = self::vt(
+            3,
+            10,
+            "ab\x1b[18446744073709551615C\x1b[18446744073709551615B\x1b[1;1H\x1b[18446744073709551615X",
+        );
+        assert_eq!(vt.cursor(), (0, 0));
+        assert_eq!(vt.lines(), ["", "", ""]);
+        let vt = self::vt(
+            3,
+            10,
+            "\x1b[18446744073709551615Cx\x1b[18446744073709551615Ey",
+        );
+        assert_eq!(vt.lines(), ["         x", "", "y"]);
+    }
+
+    #[test]
+    fn colors() {
+        let vt = vt(1, 10, "\x1b[1;31mA\x1b[38;5;200;48;2;1;2;3mB\x1b[0mC");
+        let a = vt.row(0)[0].style;
+        assert_eq!(a.foreground_color, Some(Color::DarkRed));
+        assert!(a.attributes.has(Attribute::Bold));
+        let b = vt.row(0)[1].style;
+        assert_eq!(b.foreground_color, Some(Color::AnsiValue(200)));
+        assert_eq!(b.background_color, Some(Color::Rgb { r: 1, g: 2, b: 3 }));
+        assert_eq!(vt.row(0)[2].style, ContentStyle::new());
+    }
+
+    #[test]
+    fn scrolling_and_modes() {
+        let mut vt = vt(2, 4, "a\r\nb\r\nc\r\nd");
+        assert_eq!(vt.lines(), ["a", "b", "c", "d"]);
+        vt.feed(b"\x1b[?1049h\x1b[Hfull\x1b]0;title\x07");
+        assert_eq!(vt.lines(), ["a", "b", "full", ""]);
+        vt.feed(b"\x1b[?1049l\x1b[?25l\x1b[6n");
+        assert_eq!(vt.lines(), ["a", "b", "c", "d"]);
+        assert!(!vt.cursor_visible());
+        assert_eq!(vt.take_responses(), b"\x1b[2;2R");
+
+        vt.resize(1, 2);
+        assert_eq!(vt.lines(), ["a", "b", "c", "d"]);
+        assert_eq!(vt.cursor(), (3, 1));
+        vt.resize(3, 4);
+        assert_eq!(vt.lines(), ["a", "b", "c", "d", "", ""]);
+    }
+}
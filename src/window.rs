@@ -1,21 +1,119 @@
-use std::{cmp::min, io::stdout};
+use std::{cell::Cell, cmp::min, path::PathBuf, rc::Rc};
 
-use crossterm::{
-    cursor, execute,
-    style::{self, Color},
-    Result as CResult,
-};
+use crossterm::{style::ContentStyle, Result as CResult};
+use regex::Regex;
 
-use crate::buffer::Buffer;
+use crate::{
+    backend::BackendRef,
+    buffer::{Buffer, Change, DiskChange},
+    diff::{self, Diff},
+    highlight::{Highlights, HighlightsRef},
+    loader::{self, Event},
+    lsp::Diagnostic,
+    marks::{JumpList, Marks, Place},
+    options::OptionsRef,
+    quickfix::List,
+    registers::Register,
+    state::Mode,
+    statusline::{self, Info},
+    syntax::Span,
+    term::Terminal,
+    vt,
+    wrap::{self, segment_of, Segment},
+};
 
 const SIDEBAR_LEN: usize = 4;
+/// Left of the line numbers while the buffer has diagnostics
+const SIGN_LEN: usize = 2;
+
+/// One row of text on the screen: chars `start..end` of buffer line `line`
+struct ScreenRow {
+    line: usize,
+    /// Only the first row of a wrapped line gets a line number
+    first: bool,
+    prefix: String,
+    start: usize,
+    end: usize,
+}
+
+/// Chars `start..end` of `line`, split into runs that are drawn the same way
+fn highlighted_runs(
+    line: &str,
+    spans: &[Span],
+    start: usize,
+    end: usize,
+    highlights: &Highlights,
+) -> Vec<(String, ContentStyle)> {
+    let mut runs: Vec<(String, Option<&str>)> = Vec::new();
+    let mut spans = spans.iter().peekable();
+    for (byte, c) in line.char_indices().skip(start).take(end - start) {
+        while spans.next_if(|span| span.end <= byte).is_some() {}
+        let scope = spans
+            .peek()
+            .filter(|span| span.start <= byte)
+            .map(|span| span.scope.as_str());
+        match runs.last_mut() {
+            Some((text, run_scope)) if *run_scope == scope => text.push(c),
+            _ => runs.push((c.to_string(), scope)),
+        }
+    }
+    runs.into_iter()
+        .map(|(text, scope)| (text, highlights.style(scope.unwrap_or("Normal"))))
+        .collect()
+}
+
+/// Like `highlighted_runs`, for a `:terminal`'s cells. Cells without a color of their own get
+/// `Normal`'s.
+fn terminal_runs(
+    cells: &[vt::Cell],
+    start: usize,
+    end: usize,
+    highlights: &Highlights,
+) -> Vec<(String, ContentStyle)> {
+    let normal = highlights.style("Normal");
+    let mut runs: Vec<(String, ContentStyle)> = Vec::new();
+    for cell in &cells[start.min(cells.len())..end.min(cells.len())] {
+        let style = ContentStyle {
+            foreground_color: cell.style.foreground_color.or(normal.foreground_color),
+            background_color: cell.style.background_color.or(normal.background_color),
+            ..cell.style
+        };
+        match runs.last_mut() {
+            Some((text, run_style)) if *run_style == style => text.push(cell.c),
+            _ => runs.push((cell.c.to_string(), style)),
+        }
+    }
+    runs
+}
 
 pub struct Window {
     buffer: Buffer,
+    /// What `:terminal` started in it, which `buffer` shows the screen and scrollback of
+    terminal: Option<Terminal>,
+    /// `:lmake` and `:lgrep` results, this window's own
+    loclist: List,
+    /// Where `G`, searches and the like went from, for `<C-o>`
+    jumps: JumpList,
+    backend: BackendRef,
+    options: OptionsRef,
+    highlights: HighlightsRef,
+    mode: Rc<Cell<Mode>>,
+    /// The window the cursor is in, which gets the brighter statusline
+    active: bool,
+    /// Whether the row below the text is a statusline; `height` doesn't include it
+    statusline: bool,
+    /// The tab page it's on
+    tab: usize,
+    /// On a tab page that isn't the one shown, so it draws nothing
+    hidden: bool,
+    /// In diff mode: how its text differs from the window it's compared with
+    diff: Option<Diff>,
 
     /// (row, col) relative to screen
     cursor: (usize, usize),
     offset: (usize, usize),
+    /// With `wrap`, how many rows of the top line are scrolled out of view
+    wrap_skip: usize,
 
     /// top left corner
     loc: (usize, usize),
@@ -24,12 +122,33 @@ pub struct Window {
 }
 
 impl Window {
-    pub fn new(height: usize, width: usize, loc: (usize, usize)) -> Self {
+    pub fn new(
+        height: usize,
+        width: usize,
+        loc: (usize, usize),
+        backend: BackendRef,
+        options: OptionsRef,
+        highlights: HighlightsRef,
+        mode: Rc<Cell<Mode>>,
+    ) -> Self {
         Self {
             // TODO: centered info screen
             buffer: Buffer::from_string(String::new()),
+            terminal: None,
+            loclist: List::default(),
+            jumps: JumpList::default(),
+            backend,
+            options,
+            highlights,
+            mode,
+            active: true,
+            statusline: true,
+            tab: 0,
+            hidden: false,
+            diff: None,
             cursor: (0, 0),
             offset: (0, 0),
+            wrap_skip: 0,
             height,
             width,
             loc,
@@ -52,101 +171,450 @@ impl Window {
         self.offset.1
     }
 
-    fn adjusetd_cursor(&self) -> (usize, usize) {
+    /// (row, col) in the buffer
+    pub fn adjusetd_cursor(&self) -> (usize, usize) {
         (
             self.cursor_row() + self.offset_row(),
             self.cursor_col() + self.offset_col(),
         )
     }
 
+    /// Columns left of the text: line numbers, and signs when there are any
+    fn gutter(&self) -> usize {
+        if self.buffer.diagnostics().is_empty() {
+            SIDEBAR_LEN
+        } else {
+            SIDEBAR_LEN + SIGN_LEN
+        }
+    }
+
     fn usable_cols(&self) -> usize {
-        self.width - SIDEBAR_LEN - 1
+        self.width - self.gutter() - 1
     }
 
-    pub fn reprint_cursor(&self) -> CResult<()> {
-        let row = self.cursor_row() + self.loc.0;
-        let col = self.cursor_col() + self.loc.1 + SIDEBAR_LEN + 1;
-        execute!(
-            stdout(),
-            cursor::MoveTo(col as u16, row as u16),
-            cursor::Show
+    /// Columns actually used for text
+    fn text_width(&self) -> usize {
+        self.usable_cols() - 1
+    }
+
+    fn wrapping(&self) -> bool {
+        self.options.borrow().wrap
+    }
+
+    fn line_segments(&self, row: usize) -> Vec<Segment> {
+        wrap::segments(
+            self.buffer.nth_line(row),
+            self.text_width(),
+            &self.options.borrow(),
         )
     }
 
+    /// What ends up on each row of the window, top to bottom
+    fn screen_rows(&self) -> Vec<ScreenRow> {
+        let lines = self.buffer.lines();
+        if !self.wrapping() {
+            return (self.offset_row()..lines.len())
+                .take(self.height)
+                .map(|line| ScreenRow {
+                    line,
+                    first: true,
+                    prefix: String::new(),
+                    start: self.offset_col(),
+                    end: min(
+                        lines[line].chars().count(),
+                        self.offset_col() + self.text_width(),
+                    )
+                    .max(self.offset_col()),
+                })
+                .collect();
+        }
+
+        let mut rows = Vec::new();
+        for (line, text) in lines.iter().enumerate().skip(self.offset_row()) {
+            let prefix = wrap::continuation_prefix(text, self.text_width(), &self.options.borrow());
+            let skip = if line == self.offset_row() {
+                self.wrap_skip
+            } else {
+                0
+            };
+            for (i, segment) in self.line_segments(line).into_iter().enumerate().skip(skip) {
+                if rows.len() == self.height {
+                    return rows;
+                }
+                rows.push(ScreenRow {
+                    line,
+                    first: i == 0,
+                    prefix: if i == 0 {
+                        String::new()
+                    } else {
+                        prefix.clone()
+                    },
+                    start: segment.start,
+                    end: segment.end,
+                });
+            }
+        }
+        rows
+    }
+
+    /// Where the cursor is drawn, relative to the start of the text area
+    fn screen_cursor(&self) -> (usize, usize) {
+        if !self.wrapping() {
+            return self.cursor;
+        }
+        let (row, col) = self.adjusetd_cursor();
+        self.screen_rows()
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, screen_row)| screen_row.line == row && screen_row.start <= col)
+            .map(|(i, screen_row)| {
+                (
+                    i,
+                    screen_row.prefix.chars().count() + col - screen_row.start,
+                )
+            })
+            .unwrap_or((0, 0))
+    }
+
+    /// Where on the whole screen the cursor is drawn
+    pub fn cursor_on_screen(&self) -> (usize, usize) {
+        let (row, col) = self.screen_cursor();
+        (row + self.loc.0, col + self.loc.1 + self.gutter() + 1)
+    }
+
+    pub fn reprint_cursor(&self) -> CResult<()> {
+        if self.hidden {
+            return Ok(());
+        }
+        let (row, col) = self.cursor_on_screen();
+        // A program that hides its cursor doesn't want ours in its way either
+        let hidden = self.mode.get() == Mode::Terminal
+            && self
+                .terminal
+                .as_ref()
+                .is_some_and(|terminal| !terminal.vt.cursor_visible());
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(row, col)?;
+        if hidden {
+            backend.hide_cursor()?;
+        } else {
+            backend.show_cursor()?;
+        }
+        backend.flush()
+    }
+
     /// Moves cursor `du` down (negative goes up) if allowed
     pub fn move_cursor_row(&mut self, du: isize) -> CResult<()> {
-        let old_row = self.cursor_row();
-        let old_offset = self.offset_row();
-        let mut new_row = if self.buffer.lines().is_empty() {
-            0
+        let (row, col) = self.adjusetd_cursor();
+        let last = self.buffer.lines().len().saturating_sub(1);
+        let new_row = (row as isize + du).clamp(0, last as isize) as usize;
+        self.set_cursor_position((new_row, min(col, self.line_len(new_row))))
+    }
+
+    /// Moves cursor `rl` to the right (negative goes left)
+    pub fn move_cursor_col(&mut self, rl: isize) -> CResult<()> {
+        let (row, col) = self.adjusetd_cursor();
+        // TODO: subtract 1 from n if we're in normal mode but we are allowed to go one further
+        // if we are in insert mode
+        let new_col = (col as isize + rl).clamp(0, self.line_len(row) as isize) as usize;
+        self.set_cursor_position((row, new_col))
+    }
+
+    fn line_len(&self, row: usize) -> usize {
+        self.buffer.lines().get(row).map_or(0, String::len)
+    }
+
+    /// How a line is split over screen rows: always a single row without `wrap`
+    fn display_segments(&self, row: usize) -> Vec<Segment> {
+        if self.wrapping() && row < self.buffer.lines().len() {
+            self.line_segments(row)
         } else {
-            min(
-                self.buffer.lines().len() as isize - 1,
-                self.cursor_row() as isize + du,
-            )
-        };
-        let term_height = self.height - 1;
-        if new_row < 0 {
-            if self.offset_row() > 0 {
-                let amt_under = (-new_row) as usize;
-                if self.offset_row() < amt_under {
-                    self.offset.0 = 0;
+            vec![Segment {
+                start: 0,
+                end: self.line_len(row),
+            }]
+        }
+    }
+
+    /// Screen row positions are (line, row within that line). This is the top of the window.
+    fn top(&self) -> (usize, usize) {
+        (self.offset_row(), self.wrap_skip)
+    }
+
+    fn set_top(&mut self, (line, skip): (usize, usize)) {
+        self.offset.0 = line;
+        self.wrap_skip = skip;
+    }
+
+    /// The screen row `n` rows after `pos` (before if negative), stopping at the ends of the buffer
+    fn step_rows(&self, (mut line, mut skip): (usize, usize), n: isize) -> (usize, usize) {
+        for _ in 0..n.unsigned_abs() {
+            if n > 0 {
+                if skip + 1 < self.display_segments(line).len() {
+                    skip += 1;
+                } else if line + 1 < self.buffer.lines().len() {
+                    line += 1;
+                    skip = 0;
                 } else {
-                    self.offset.0 -= amt_under;
+                    break;
                 }
+            } else if skip > 0 {
+                skip -= 1;
+            } else if line > 0 {
+                line -= 1;
+                skip = self.display_segments(line).len() - 1;
+            } else {
+                break;
             }
-            new_row = 0;
-        } else if new_row as usize > term_height {
-            let amt_over = new_row as usize - term_height;
-            self.offset.0 += amt_over;
-            new_row -= amt_over as isize;
-        }
-        if new_row as usize != old_row || self.offset_row() != old_offset {
-            self.set_cursor_row(new_row as usize)?;
-            self.redraw()?;
         }
-        self.move_cursor_col(0)
+        (line, skip)
     }
 
-    /// Moves cursor `rl` to the right (negative goes left)
-    pub fn move_cursor_col(&mut self, rl: isize) -> CResult<()> {
-        let old_col = self.cursor_col();
-        let old_offset = self.offset_col();
-        let mut new_col = if self.buffer.lines().is_empty() {
-            0
+    /// Number of screen rows from `from` through `to`
+    fn rows_between(&self, from: (usize, usize), to: (usize, usize)) -> usize {
+        if from.0 == to.0 {
+            return to.1 + 1 - from.1;
+        }
+        self.display_segments(from.0).len() - from.1
+            + (from.0 + 1..to.0)
+                .map(|line| self.display_segments(line).len())
+                .sum::<usize>()
+            + to.1
+            + 1
+    }
+
+    fn last_screen_row(&self) -> (usize, usize) {
+        let line = self.buffer.lines().len().saturating_sub(1);
+        (line, self.display_segments(line).len() - 1)
+    }
+
+    fn cursor_screen_row(&self) -> (usize, usize) {
+        let (row, col) = self.adjusetd_cursor();
+        (row, segment_of(&self.display_segments(row), col))
+    }
+
+    /// Column in screen row `pos` that's `within` columns from the start of that row
+    fn col_in_screen_row(&self, (line, skip): (usize, usize), within: usize) -> usize {
+        let segments = self.display_segments(line);
+        let segment = segments[skip];
+        // The column past the end of a segment belongs to the next one
+        let last_col = if skip + 1 == segments.len() {
+            segment.end
         } else {
-            let line_len = self
-                .buffer
-                .nth_line(self.cursor_row() + self.offset_row())
-                .len() as isize
-                - self.offset_col() as isize;
-            // TODO: subtract 1 from n if we're in normal mode but we are allowed to go one further
-            // if we are in insert mode
-            min(line_len, self.cursor_col() as isize + rl)
+            segment.end - 1
         };
-        let term_width = self.usable_cols() - 1;
-        if new_col < 0 {
-            if self.offset_col() > 0 {
-                let amt_under = (-new_col) as usize;
-                if self.offset_col() < amt_under {
-                    self.offset.1 = 0;
-                } else {
-                    self.offset.1 -= amt_under;
-                }
+        min(segment.start.saturating_add(within), last_col)
+    }
+
+    fn scrolloff(&self) -> usize {
+        min(
+            self.options.borrow().scrolloff,
+            self.height.saturating_sub(1) / 2,
+        )
+    }
+
+    fn sidescrolloff(&self) -> usize {
+        min(
+            self.options.borrow().sidescrolloff,
+            self.text_width().saturating_sub(1) / 2,
+        )
+    }
+
+    /// Puts the cursor at buffer position `(row, col)` and scrolls just enough to keep it
+    /// `scrolloff` rows and `sidescrolloff` columns away from the edges
+    fn set_cursor_position(&mut self, (row, col): (usize, usize)) -> CResult<()> {
+        let old = (self.cursor, self.offset, self.wrap_skip);
+        let pos = (row, segment_of(&self.display_segments(row), col));
+        let so = self.scrolloff() as isize;
+        let earliest_top = self.step_rows(pos, -so);
+        if self.top() > earliest_top {
+            self.set_top(earliest_top);
+        }
+        let mut rows = self.rows_between(self.top(), self.step_rows(pos, so));
+        while rows > self.height {
+            self.set_top(self.step_rows(self.top(), 1));
+            rows -= 1;
+        }
+
+        if self.wrapping() {
+            self.offset.1 = 0;
+        } else {
+            let sso = self.sidescrolloff();
+            if self.offset_col() + sso > col {
+                self.offset.1 = col.saturating_sub(sso);
+            } else if col + sso > self.offset_col() + self.text_width() {
+                self.offset.1 = col + sso - self.text_width();
             }
-            new_col = 0;
-        } else if new_col as usize > term_width {
-            let amt_over = new_col as usize - term_width;
-            self.offset.1 += amt_over;
-            new_col -= amt_over as isize;
-        }
-        if new_col as usize != old_col || self.offset_col() != old_offset {
-            self.set_cursor_col(new_col as usize)?;
+        }
+
+        self.cursor = (row - self.offset_row(), col - self.offset_col());
+        if (self.cursor, self.offset, self.wrap_skip) != old {
             self.redraw()?;
         }
         Ok(())
     }
 
+    /// `gj` and `gk`: like `j` and `k` but by screen row when lines wrap
+    pub fn move_cursor_display_row(&mut self, du: isize) -> CResult<()> {
+        let (row, col) = self.adjusetd_cursor();
+        let from = self.cursor_screen_row();
+        let within = col - self.display_segments(row)[from.1].start;
+        let to = self.step_rows(from, du);
+        self.set_cursor_position((to.0, self.col_in_screen_row(to, within)))
+    }
+
+    /// `g0`: first column on the screen
+    pub fn zero_cursor_display_col(&mut self) -> CResult<()> {
+        if !self.wrapping() {
+            return self.move_cursor_col(-(self.cursor_col() as isize));
+        }
+        let pos = self.cursor_screen_row();
+        self.set_cursor_position((pos.0, self.col_in_screen_row(pos, 0)))
+    }
+
+    /// `g$`: last column on the screen
+    pub fn move_cursor_end_of_display_line(&mut self) -> CResult<()> {
+        if !self.wrapping() {
+            return self
+                .move_cursor_col(self.text_width() as isize - 1 - self.cursor_col() as isize);
+        }
+        let pos = self.cursor_screen_row();
+        self.set_cursor_position((pos.0, self.col_in_screen_row(pos, usize::MAX)))
+    }
+
+    /// `<C-e>` and `<C-y>`: moves the view `n` screen rows down (up if negative). The cursor only
+    /// moves if it would otherwise get closer than `scrolloff` to the edge.
+    pub fn scroll_view(&mut self, n: isize) -> CResult<()> {
+        let (row, col) = self.adjusetd_cursor();
+        let pos = self.cursor_screen_row();
+        let within = col - self.display_segments(row)[pos.1].start;
+        self.set_top(self.step_rows(self.top(), n));
+        let so = self.scrolloff() as isize;
+        let first = if self.top() == (0, 0) {
+            self.top()
+        } else {
+            self.step_rows(self.top(), so)
+        };
+        let bottom = self.step_rows(self.top(), self.height as isize - 1);
+        let last = if bottom == self.last_screen_row() {
+            bottom
+        } else {
+            self.step_rows(bottom, -so)
+        };
+        let new_pos = pos.clamp(first, last);
+        self.set_cursor_position((new_pos.0, self.col_in_screen_row(new_pos, within)))?;
+        self.redraw()
+    }
+
+    /// `<C-d>` and `<C-u>`: scrolls the view and the cursor by `scroll` rows, half the window
+    /// if that's 0
+    pub fn scroll_half_page(&mut self, direction: isize) -> CResult<()> {
+        let amount = match self.options.borrow().scroll {
+            0 => self.height / 2,
+            n => n,
+        } as isize
+            * direction;
+        let (row, col) = self.adjusetd_cursor();
+        let pos = self.cursor_screen_row();
+        let within = col - self.display_segments(row)[pos.1].start;
+        let new_pos = self.step_rows(pos, amount);
+        self.set_top(self.step_rows(self.top(), amount));
+        self.set_cursor_position((new_pos.0, self.col_in_screen_row(new_pos, within)))?;
+        self.redraw()
+    }
+
+    /// `<C-f>` and `<C-b>`
+    pub fn scroll_page(&mut self, direction: isize) -> CResult<()> {
+        self.scroll_view(self.height.saturating_sub(2).max(1) as isize * direction)
+    }
+
+    /// `zt`, `zz` and `zb`: scrolls so the cursor ends up `rows_above` rows from the top of the
+    /// window
+    fn scroll_cursor_to(&mut self, rows_above: usize) -> CResult<()> {
+        let cursor = self.adjusetd_cursor();
+        self.set_top(self.step_rows(self.cursor_screen_row(), -(rows_above as isize)));
+        self.set_cursor_position(cursor)?;
+        self.redraw()
+    }
+
+    pub fn scroll_cursor_top(&mut self) -> CResult<()> {
+        self.scroll_cursor_to(self.scrolloff())
+    }
+
+    pub fn scroll_cursor_center(&mut self) -> CResult<()> {
+        self.scroll_cursor_to(self.height.saturating_sub(1) / 2)
+    }
+
+    pub fn scroll_cursor_bottom(&mut self) -> CResult<()> {
+        self.scroll_cursor_to(self.height.saturating_sub(1) - self.scrolloff())
+    }
+
+    /// `z<CR>`: `zt` and then to the first non-blank
+    pub fn scroll_cursor_top_first_char(&mut self) -> CResult<()> {
+        self.scroll_cursor_top()?;
+        let row = self.adjusetd_cursor().0;
+        let indent = self
+            .buffer
+            .lines()
+            .get(row)
+            .map_or(0, |line| line.len() - line.trim_start().len());
+        self.set_cursor_position((row, indent))
+    }
+
+    /// `zl` and `zh`: moves the view `n` columns right (left if negative) without `wrap`. Stops
+    /// at the end of the cursor line so the cursor stays visible.
+    pub fn scroll_view_cols(&mut self, n: isize) -> CResult<()> {
+        if self.wrapping() {
+            return Ok(());
+        }
+        let (row, col) = self.adjusetd_cursor();
+        let len = self.line_len(row);
+        self.offset.1 = (self.offset_col() as isize + n).clamp(0, len as isize) as usize;
+        let sso = self.sidescrolloff();
+        let first = if self.offset_col() == 0 {
+            0
+        } else {
+            self.offset_col() + sso
+        };
+        let last = (self.offset_col() + self.text_width()).saturating_sub(sso);
+        let new_col = col.clamp(min(first, len), min(last.max(first), len));
+        self.set_cursor_position((row, new_col))?;
+        self.redraw()
+    }
+
+    /// `zs`: scrolls horizontally so the cursor is at the start of the screen
+    pub fn scroll_cursor_start(&mut self) -> CResult<()> {
+        if self.wrapping() {
+            return Ok(());
+        }
+        let cursor = self.adjusetd_cursor();
+        self.offset.1 = cursor.1.saturating_sub(self.sidescrolloff());
+        self.set_cursor_position(cursor)?;
+        self.redraw()
+    }
+
+    /// `ze`: scrolls horizontally so the cursor is at the end of the screen
+    pub fn scroll_cursor_end(&mut self) -> CResult<()> {
+        if self.wrapping() {
+            return Ok(());
+        }
+        let cursor = self.adjusetd_cursor();
+        self.offset.1 = (cursor.1 + 1 + self.sidescrolloff()).saturating_sub(self.text_width());
+        self.set_cursor_position(cursor)?;
+        self.redraw()
+    }
+
+    /// Called after `:set` in case something the layout depends on changed
+    pub fn options_changed(&mut self) -> CResult<()> {
+        if !self.wrapping() {
+            self.wrap_skip = 0;
+        }
+        let cursor = self.adjusetd_cursor();
+        self.set_cursor_position(cursor)?;
+        self.redraw()
+    }
+
     fn validate_cursor(&mut self) -> CResult<()> {
         self.move_cursor_row(0)
     }
@@ -157,6 +625,107 @@ impl Window {
         self.redraw()
     }
 
+    /// `:N`, as close as the buffer allows
+    pub fn goto_line(&mut self, row: usize) -> CResult<()> {
+        let row = row.min(self.buffer.lines().len() - 1);
+        self.set_cursor_position((row, 0))?;
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
+    /// To a place from a quickfix list: `col` is a byte offset into the line, both as close as
+    /// the buffer allows
+    pub fn goto(&mut self, row: usize, col: usize) -> CResult<()> {
+        let row = row.min(self.buffer.lines().len() - 1);
+        let line = self.buffer.nth_line(row);
+        let mut col = col.min(line.len());
+        while !line.is_char_boundary(col) {
+            col -= 1;
+        }
+        self.set_cursor_position((row, col))?;
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
+    /// The cursor as `:goto` counts: bytes from the start of the buffer, the first being 1 and
+    /// each line break 1 more
+    pub fn byte_offset(&self) -> usize {
+        let (row, col) = self.adjusetd_cursor();
+        let before: usize = self.buffer.lines()[..row]
+            .iter()
+            .map(|line| line.len() + 1)
+            .sum();
+        before + col + 1
+    }
+
+    /// `:goto n`, as close as the buffer allows
+    pub fn goto_byte(&mut self, n: usize) -> CResult<()> {
+        let mut left = n.saturating_sub(1);
+        let lines = self.buffer.lines();
+        let mut row = 0;
+        while row + 1 < lines.len() && left > lines[row].len() {
+            left -= lines[row].len() + 1;
+            row += 1;
+        }
+        self.goto(row, left)
+    }
+
+    /// Moves to the next match of `regex` after the cursor, wrapping around the end. Returns
+    /// whether it wrapped, `None` if nothing matches anywhere.
+    pub fn search_forward(&mut self, regex: &Regex) -> CResult<Option<bool>> {
+        let (row, col) = self.adjusetd_cursor();
+        let lines = self.buffer.lines();
+        let line = &lines[row];
+        // The cursor can be partway into a character after moving from a line with narrower
+        // ones
+        let mut col = col.min(line.len());
+        while !line.is_char_boundary(col) {
+            col -= 1;
+        }
+        let after = line[col..]
+            .chars()
+            .next()
+            .map_or(line.len(), |c| col + c.len_utf8());
+        let found = regex
+            .find_at(line, after)
+            .map(|m| (row, m.start(), false))
+            .or_else(|| {
+                // The cursor's own line comes around again last, for matches before the cursor
+                (1..=lines.len()).find_map(|i| {
+                    let r = (row + i) % lines.len();
+                    regex
+                        .find(&lines[r])
+                        .map(|m| (r, m.start(), row + i >= lines.len()))
+                })
+            });
+        let Some((row, col, wrapped)) = found else {
+            return Ok(None);
+        };
+        self.set_cursor_position((row, col))?;
+        self.validate_cursor()?;
+        self.redraw()?;
+        Ok(Some(wrapped))
+    }
+
+    /// The word the cursor is on, or else the next one on its line
+    pub fn word_under_cursor(&self) -> Option<String> {
+        let (row, col) = self.adjusetd_cursor();
+        let line = self.buffer.nth_line(row);
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let col = col.min(line.len());
+        let found = col + line.get(col..)?.find(is_word)?;
+        let start = line[..found]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_word(c))
+            .last()
+            .map_or(found, |(i, _)| i);
+        let end = line[start..]
+            .find(|c| !is_word(c))
+            .map_or(line.len(), |i| start + i);
+        Some(line[start..end].to_owned())
+    }
+
     pub fn zero_cursor_col(&mut self) -> CResult<()> {
         self.move_cursor_col(0 - self.offset_col() as isize - self.cursor_col() as isize)
     }
@@ -194,6 +763,65 @@ impl Window {
         self.move_cursor_row(-1)
     }
 
+    /// `:r`: puts `lines` below the cursor's, and the cursor on the first of them
+    pub fn insert_lines_below(&mut self, lines: Vec<String>) -> CResult<()> {
+        let row = self.adjusetd_cursor().0 + 1;
+        self.buffer.insert_lines(row, lines);
+        self.set_cursor_position((row, 0))?;
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
+    /// A filter's output in place of lines `first` to `last`, with the cursor on the first
+    pub fn replace_lines(&mut self, first: usize, last: usize, lines: Vec<String>) -> CResult<()> {
+        let first = first.min(self.buffer.lines().len() - 1);
+        self.buffer.replace_lines(first, last, lines);
+        let row = first.min(self.buffer.lines().len() - 1);
+        self.set_cursor_position((row, 0))?;
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
+    /// `yy`, `dd` and `cc` take the cursor line
+    pub fn cursor_line(&self) -> Register {
+        let row = self.adjusetd_cursor().0;
+        Register::new(vec![self.buffer.nth_line(row).to_owned()], true)
+    }
+
+    /// `p` (`after`) and `P`. Lines go below or above the cursor's, which goes to the first
+    /// non-blank of the first; text goes after or at the cursor, which goes to its last char.
+    pub fn put(&mut self, register: &Register, after: bool) -> CResult<()> {
+        if register.lines.is_empty() {
+            return Ok(());
+        }
+        let (row, col) = self.adjusetd_cursor();
+        if register.linewise {
+            let row = row + after as usize;
+            self.buffer.insert_lines(row, register.lines.clone());
+            let line = self.buffer.nth_line(row);
+            let indent = line.len() - line.trim_start().len();
+            self.set_cursor_position((row, indent))?;
+        } else {
+            let line = self.buffer.nth_line(row);
+            let col = col.min(line.len());
+            let col = match line[col..].chars().next() {
+                Some(c) if after => col + c.len_utf8(),
+                _ => col,
+            };
+            self.buffer.insert_text((row, col), &register.lines);
+            let last = register.lines.len() - 1;
+            let end = match last {
+                0 => col + register.lines[0].len(),
+                _ => register.lines[last].len(),
+            };
+            let line = self.buffer.nth_line(row + last);
+            let end = line[..end].char_indices().last().map_or(0, |(i, _)| i);
+            self.set_cursor_position((row + last, end))?;
+        }
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
     pub fn delete_line(&mut self) -> CResult<()> {
         self.buffer.delete_line(self.adjusetd_cursor());
         self.validate_cursor()?;
@@ -207,59 +835,97 @@ impl Window {
     }
 
     pub fn draw(&self) -> CResult<()> {
-        execute!(
-            stdout(),
-            cursor::Hide,
-            cursor::MoveTo(self.loc.1 as u16, self.loc.0 as u16),
-            style::ResetColor,
-        )?;
+        if self.hidden {
+            return Ok(());
+        }
+        let rows = self.screen_rows();
+        let highlights = self.highlights.borrow();
+        let mut backend = self.backend.borrow_mut();
+        backend.hide_cursor()?;
         let cur_line = self.offset_row() + self.cursor_row();
-        let mut num_lines = 0;
-        for line in self
-            .buffer
-            .lines()
-            .iter()
-            .skip(self.offset_row())
-            .take(self.height)
-        {
-            num_lines += 1;
-            let formatted_line = if line.len() < self.offset_col() {
-                " ".repeat(self.usable_cols() - 1)
-            } else if line[self.offset_col()..].len() > self.usable_cols() {
-                line[self.offset_col()..self.usable_cols() - 1].to_owned()
-            } else {
-                let padding = " ".repeat(self.usable_cols() - 1 - line[self.offset_col()..].len());
-                format!("{}{padding}", &line[self.offset_col()..])
-            };
-            let absolute_linenum = self.offset_row() + num_lines - 1;
-            let (linenum, color) = if absolute_linenum == cur_line {
-                (format!("{}", cur_line + 1), Color::White)
+        for (i, row) in rows.iter().enumerate() {
+            let (linenum, group) = if !row.first {
+                (String::new(), "LineNr")
+            } else if row.line == cur_line {
+                (format!("{}", cur_line + 1), "CursorLineNr")
             } else {
-                (
-                    format!("{}", cur_line.abs_diff(absolute_linenum)),
-                    Color::DarkGrey,
-                )
+                (format!("{}", cur_line.abs_diff(row.line)), "LineNr")
             };
             let linenum_padding = " ".repeat(SIDEBAR_LEN - linenum.len());
-            execute!(
-                stdout(),
-                style::SetForegroundColor(color),
-                style::Print(format!("{linenum_padding}{linenum} ")),
-                style::ResetColor,
-                style::Print(formatted_line),
-                cursor::MoveToColumn(self.loc.1 as u16),
-                cursor::MoveDown(1)
+            // The worst on the line, which sorting put first
+            let diagnostic = self
+                .buffer
+                .diagnostics()
+                .iter()
+                .find(|diagnostic| diagnostic.row == row.line);
+            let runs = match &self.terminal {
+                Some(terminal) => {
+                    terminal_runs(terminal.vt.row(row.line), row.start, row.end, &highlights)
+                }
+                None => highlighted_runs(
+                    &self.buffer.lines()[row.line],
+                    &self.buffer.highlights(row.line),
+                    row.start,
+                    row.end,
+                    &highlights,
+                ),
+            };
+            // Differences only change the background, so the syntax still shows
+            let changed = match self.diff.as_ref().and_then(|diff| diff.lines.get(row.line)) {
+                Some(diff::Line::Added) => Some(highlights.style("DiffAdd")),
+                Some(diff::Line::Changed) => Some(highlights.style("DiffChange")),
+                _ => None,
+            };
+            let background = |mut style: ContentStyle| {
+                if let Some(changed) = changed {
+                    style.background_color = changed.background_color;
+                }
+                style
+            };
+            let padding = self.text_width() - row.prefix.chars().count() - (row.end - row.start);
+            backend.move_to(self.loc.0 + i, self.loc.1)?;
+            if self.gutter() > SIDEBAR_LEN {
+                match diagnostic.filter(|_| row.first) {
+                    Some(diagnostic) => backend.print(
+                        diagnostic.severity.sign(),
+                        highlights.style(diagnostic.severity.group()),
+                    )?,
+                    None => backend.print(&" ".repeat(SIGN_LEN), highlights.style("SignColumn"))?,
+                }
+            }
+            backend.print(
+                &format!("{linenum_padding}{linenum} "),
+                highlights.style(group),
+            )?;
+            backend.print(&row.prefix, highlights.style("NonText"))?;
+            for (text, style) in runs {
+                backend.print(&text, background(style))?;
+            }
+            // The message goes after the end of the line, where there's room
+            let last = rows.get(i + 1).is_none_or(|next| next.line != row.line);
+            let message = match diagnostic.filter(|_| last && padding > 3) {
+                Some(diagnostic) => {
+                    let first_line = diagnostic.message.lines().next().unwrap_or_default();
+                    let message: String = format!("  {first_line}").chars().take(padding).collect();
+                    backend.print(&message, highlights.style(diagnostic.severity.group()))?;
+                    message.chars().count()
+                }
+                None => 0,
+            };
+            backend.print(
+                &" ".repeat(padding - message),
+                background(highlights.style("Normal")),
             )?;
         }
-        for _ in 0..(self.height - num_lines) {
-            execute!(
-                stdout(),
-                style::SetForegroundColor(Color::DarkGrey),
-                style::Print(format!("~{}", " ".repeat(self.width - 2))),
-                cursor::MoveToColumn(self.loc.1 as u16),
-                cursor::MoveDown(1)
+        for row in rows.len()..self.height {
+            backend.move_to(self.loc.0 + row, self.loc.1)?;
+            backend.print(
+                &format!("~{}", " ".repeat(self.width - 2)),
+                highlights.style("NonText"),
             )?;
         }
+        drop(backend);
+        drop(highlights);
         self.print_statusline()
     }
 
@@ -269,59 +935,182 @@ impl Window {
     }
 
     fn print_statusline(&self) -> CResult<()> {
-        let left_side = format!(
-            "{name}{save_marker}",
-            name = self.buffer.filename(),
-            save_marker = if self.buffer.unsaved_changes() {
-                " [+]"
-            } else {
-                ""
-            }
-        );
-        let right_side = format!(
-            "{cursor_loc}",
-            cursor_loc = format!(
-                "{}:{}",
-                self.cursor_row() + self.offset_row() + 1,
-                self.cursor_col() + self.offset_row() + 1
-            )
-        );
+        if !self.statusline || self.hidden {
+            return Ok(());
+        }
+        let (row, col) = self.adjusetd_cursor();
+        let progress = self.buffer.progress();
+        let info = Info {
+            filename: self.buffer.filename(),
+            modified: self.buffer.unsaved_changes(),
+            readonly: self.buffer.readonly(),
+            filetype: self.buffer.filetype(),
+            line: row + 1,
+            lines: self.buffer.lines().len(),
+            col: col + 1,
+            mode: self.mode.get().name(),
+            fileformat: self.buffer.fileformat(),
+            fileencoding: self.buffer.fileencoding(),
+            progress: &progress,
+        };
+        let cells = statusline::render(&self.options.borrow().statusline, &info, self.width);
 
-        let padding = " ".repeat(self.width - (left_side.len() + right_side.len()));
-        execute!(
-            stdout(),
-            style::ResetColor,
-            style::SetBackgroundColor(Color::DarkGrey),
-            style::Print(format!("{left_side}{padding}{right_side}"))
+        let highlights = self.highlights.borrow();
+        let base = if self.active {
+            "StatusLine"
+        } else {
+            "StatusLineNC"
+        };
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(self.loc.0 + self.height, self.loc.1)?;
+        for run in cells.chunk_by(|a, b| a.1 == b.1) {
+            let text: String = run.iter().map(|(c, _)| c).collect();
+            backend.print(&text, highlights.style(run[0].1.as_deref().unwrap_or(base)))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_active(&mut self, active: bool) -> CResult<()> {
+        self.active = active;
+        self.print_statusline()
+    }
+
+    /// Gives the statusline row to the text or takes it back, for `laststatus`
+    pub fn set_statusline(&mut self, shown: bool) -> CResult<()> {
+        if shown == self.statusline {
+            return Ok(());
+        }
+        self.statusline = shown;
+        if shown {
+            self.set_height(self.height - 1);
+            return Ok(());
+        }
+        if self.hidden {
+            self.set_height(self.height + 1);
+            return Ok(());
+        }
+        // Text rows stop short of the last column, which would keep part of the old statusline
+        let row = self.loc.0 + self.height;
+        self.set_height(self.height + 1);
+        let mut backend = self.backend.borrow_mut();
+        backend.move_to(row, self.loc.1)?;
+        backend.print(
+            &" ".repeat(self.width),
+            self.highlights.borrow().style("Normal"),
         )
     }
 
+    /// The row just below the window, statusline included
+    pub fn bottom(&self) -> usize {
+        self.loc.0 + self.height + usize::from(self.statusline)
+    }
+
     pub fn print_divider(&self) -> CResult<()> {
-        execute!(
-            stdout(),
-            cursor::MoveTo((self.loc.1 + self.width - 1) as u16, self.loc.0 as u16),
-            style::SetForegroundColor(Color::Black),
-            style::SetBackgroundColor(Color::DarkGrey)
-        )?;
-        for _ in 0..self.height {
-            execute!(
-                stdout(),
-                style::Print("|"),
-                cursor::MoveDown(1),
-                cursor::MoveLeft(1)
-            )?;
+        if self.hidden {
+            return Ok(());
         }
-        Ok(())
+        let style = self.highlights.borrow().style("VertSplit");
+        let mut backend = self.backend.borrow_mut();
+        for row in 0..self.height {
+            backend.move_to(self.loc.0 + row, self.loc.1 + self.width - 1)?;
+            backend.print("|", style)?;
+        }
+        backend.flush()
     }
 
-    pub fn load_file(&mut self, filename: String) -> CResult<()> {
-        self.buffer = Buffer::from_filepath(filename);
+    pub fn set_buffer(&mut self, buffer: Buffer) -> CResult<()> {
+        self.buffer.discard_swap();
+        self.buffer = buffer;
         self.redraw()?;
         self.cursor = (0, 0);
         self.offset = (0, 0);
+        self.wrap_skip = 0;
         self.reprint_cursor()
     }
 
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn marks_mut(&mut self) -> &mut Marks {
+        self.buffer.marks_mut()
+    }
+
+    /// The cursor's place, for the jump list or a global mark
+    pub fn place(&self) -> Place {
+        Place {
+            buffer: self.buffer.id(),
+            file: self
+                .buffer
+                .path()
+                .map(|_| self.buffer.filename().to_owned()),
+            pos: self.adjusetd_cursor(),
+        }
+    }
+
+    /// Before the cursor goes somewhere else: where it is goes in the jump list, and is `''`
+    pub fn push_jump(&mut self) {
+        self.remember_jump(self.place());
+    }
+
+    /// Like `push_jump`, from where the cursor was at `from`
+    pub fn remember_jump(&mut self, from: Place) {
+        if from.buffer == self.buffer.id() {
+            self.buffer.marks_mut().set('\'', from.pos);
+        }
+        self.jumps.push(from);
+    }
+
+    pub fn jumps(&self) -> &JumpList {
+        &self.jumps
+    }
+
+    pub fn jumps_mut(&mut self) -> &mut JumpList {
+        &mut self.jumps
+    }
+
+    pub fn track_changes(&mut self) {
+        self.buffer.track_changes();
+    }
+
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.buffer.take_changes()
+    }
+
+    /// New diagnostics from the language server, which can bring the sign column or take it
+    /// away
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) -> CResult<()> {
+        self.buffer.set_diagnostics(diagnostics);
+        let cursor = self.adjusetd_cursor();
+        self.set_cursor_position(cursor)?;
+        self.redraw()
+    }
+
+    /// Lines `first` to `last` replaced from elsewhere, like a language server's edit, with
+    /// the cursor staying about where it was
+    pub fn edit_lines(&mut self, first: usize, last: usize, lines: Vec<String>) -> CResult<()> {
+        let cursor = self.adjusetd_cursor();
+        self.buffer.replace_lines(first, last, lines);
+        self.put_cursor_near(cursor)?;
+        self.redraw()
+    }
+
+    /// Insert mode completion: `text` in place of what's from byte `start` of the cursor's line
+    /// up to the cursor, with the cursor after it
+    pub fn complete_text(&mut self, start: usize, text: &str) -> CResult<()> {
+        let (row, col) = self.adjusetd_cursor();
+        let mut line = self.buffer.nth_line(row).to_owned();
+        let start = start.min(col);
+        if line[start..col] == *text {
+            return Ok(());
+        }
+        line.replace_range(start..col, text);
+        self.buffer.replace_lines(row, row, vec![line]);
+        self.set_cursor_position((row, start + text.len()))?;
+        self.validate_cursor()?;
+        self.redraw()
+    }
+
     pub fn type_char(&mut self, c: char) -> CResult<()> {
         if c == '\n' {
             self.buffer.add_line_break(self.adjusetd_cursor());
@@ -356,15 +1145,128 @@ impl Window {
         self.redraw()
     }
 
-    pub fn write(&mut self) -> Result<String, String> {
-        match self.buffer.write() {
+    /// The file or directory under the cursor, if this is a directory listing
+    pub fn directory_entry(&self) -> Option<PathBuf> {
+        self.buffer.directory_entry(self.adjusetd_cursor().0)
+    }
+
+    /// `u`, or `<C-r>` with `redo`. Returns whether there was anything to undo or redo.
+    pub fn undo(&mut self, redo: bool) -> CResult<bool> {
+        let Some(cursor) = self.buffer.undo(redo) else {
+            return Ok(false);
+        };
+        self.put_cursor_near(cursor)?;
+        self.redraw()?;
+        Ok(true)
+    }
+
+    pub fn seal_undo(&mut self) {
+        self.buffer.seal_undo();
+    }
+
+    /// Swaps in a fresh copy of the same file, keeping the cursor about where it was and the
+    /// marks where they were
+    pub fn reload(&mut self, mut buffer: Buffer) -> CResult<()> {
+        let cursor = self.adjusetd_cursor();
+        *buffer.marks_mut() = std::mem::take(self.buffer.marks_mut());
+        self.set_buffer(buffer)?;
+        self.put_cursor_near(cursor)
+    }
+
+    /// Puts the cursor at `(row, col)`, or as close as the buffer allows
+    fn put_cursor_near(&mut self, (row, col): (usize, usize)) -> CResult<()> {
+        let row = row.min(self.buffer.lines().len() - 1);
+        let col = col.min(self.buffer.nth_line(row).len());
+        self.set_cursor_position((row, col))?;
+        self.validate_cursor()?;
+        self.reprint_cursor()
+    }
+
+    /// Hands the buffer what a background load or save sent, see `Buffer::background_event`
+    pub fn background_event(&mut self, event: Event) -> CResult<Option<Result<String, String>>> {
+        let cursor = self.adjusetd_cursor();
+        let result = self.buffer.background_event(event);
+        // A file that had to be decoded as a whole replaces the lines wholesale
+        self.put_cursor_near(cursor)?;
+        self.redraw()?;
+        Ok(result)
+    }
+
+    /// Returns whether there was a load to cancel
+    pub fn cancel_loading(&mut self) -> CResult<bool> {
+        let cancelled = self.buffer.cancel_loading();
+        self.print_statusline()?;
+        self.reprint_cursor()?;
+        Ok(cancelled)
+    }
+
+    pub fn open_read_only(&mut self) -> CResult<()> {
+        self.buffer.open_read_only();
+        self.print_statusline()
+    }
+
+    pub fn set_local_options(&mut self, args: &str) -> Result<String, String> {
+        self.buffer.set_local_options(args)
+    }
+
+    pub fn unnoticed_disk_change(&mut self) -> Option<DiskChange> {
+        self.buffer.unnoticed_disk_change()
+    }
+
+    pub fn write_swap(&mut self) {
+        self.buffer.write_swap();
+    }
+
+    #[cfg(test)]
+    pub fn swap_busy(&self) -> bool {
+        self.buffer.swap_busy()
+    }
+
+    pub fn swap_written(&mut self) -> Result<(), String> {
+        self.buffer.swap_written(&self.options.borrow())
+    }
+
+    pub fn discard_swap(&mut self) {
+        self.buffer.discard_swap();
+    }
+
+    pub fn discard_swap_now(&mut self) {
+        self.buffer.discard_swap_now();
+    }
+
+    pub fn write(&mut self, force: bool) -> Result<String, String> {
+        match self.buffer.write(force, &self.options.borrow()) {
             Ok(()) => Ok(format!("\"{}\" written", self.buffer.filename())),
             Err(e) => Err(e),
         }
     }
 
-    pub fn write_to_filename(&mut self, filename: String) -> Result<String, String> {
-        match self.buffer.write_to_filename(filename) {
+    /// Like `write`, but big buffers are written in the background
+    pub fn write_in_background(
+        &mut self,
+        force: bool,
+        events: &loader::Sender,
+    ) -> Result<String, String> {
+        let written = self
+            .buffer
+            .write_in_background(force, &self.options.borrow(), events)?;
+        let filename = self.buffer.filename();
+        Ok(if written {
+            format!("\"{filename}\" written")
+        } else {
+            format!("\"{filename}\" writing...")
+        })
+    }
+
+    pub fn write_to_stdout(&mut self, force: bool) -> Result<Vec<u8>, String> {
+        self.buffer.write_to_stdout(force, &self.options.borrow())
+    }
+
+    pub fn write_to_filename(&mut self, filename: String, force: bool) -> Result<String, String> {
+        match self
+            .buffer
+            .write_to_filename(filename, force, &self.options.borrow())
+        {
             Ok(()) => Ok(format!("\"{}\" written", self.buffer.filename())),
             Err(e) => Err(e),
         }
@@ -380,17 +1282,330 @@ impl Window {
 
     pub fn set_height(&mut self, height: usize) {
         self.height = height;
+        self.resize_terminal();
     }
 
     pub fn set_width(&mut self, width: usize) {
         self.width = width;
+        self.resize_terminal();
+    }
+
+    /// `:terminal`: the window shows what `terminal` runs from now on
+    pub fn start_terminal(&mut self, terminal: Terminal) -> CResult<()> {
+        let name = format!("!{}", terminal.cmd);
+        self.set_buffer(Buffer::scratch(&name, vec![String::new()]))?;
+        self.terminal = Some(terminal);
+        self.terminal_changed()
+    }
+
+    /// The screen size a program in the window gets: all of the text area
+    pub fn terminal_size(&self) -> (usize, usize) {
+        (self.height, self.text_width())
+    }
+
+    pub fn terminal(&self) -> Option<&Terminal> {
+        self.terminal.as_ref()
+    }
+
+    pub fn terminal_mut(&mut self) -> Option<&mut Terminal> {
+        self.terminal.as_mut()
+    }
+
+    /// Whether there's a `:terminal` in the window that's still running
+    pub fn terminal_running(&self) -> bool {
+        self.terminal.as_ref().is_some_and(Terminal::running)
+    }
+
+    pub fn terminal_output(&mut self, bytes: &[u8]) -> CResult<()> {
+        if let Some(terminal) = &mut self.terminal {
+            terminal.output(bytes);
+        }
+        self.terminal_changed()
+    }
+
+    /// Brings the buffer up to date with the terminal's screen. The cursor goes where the
+    /// program's is, except in the active window outside of terminal-job mode, where it's
+    /// being moved around the scrollback.
+    pub fn terminal_changed(&mut self) -> CResult<()> {
+        let Some(terminal) = &self.terminal else {
+            return Ok(());
+        };
+        let (row, col) = terminal.vt.cursor();
+        self.buffer.set_lines(terminal.vt.lines());
+        if self.active && self.mode.get() != Mode::Terminal {
+            self.validate_cursor()?;
+            return self.draw();
+        }
+        let line = self.buffer.nth_line(row);
+        let col = line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+        self.set_cursor_position((row, col))?;
+        self.draw()
+    }
+
+    fn resize_terminal(&mut self) {
+        let (rows, cols) = self.terminal_size();
+        if let Some(terminal) = &mut self.terminal {
+            terminal.resize(rows, cols);
+            self.buffer.set_lines(terminal.vt.lines());
+        }
+    }
+
+    pub fn loclist(&self) -> &List {
+        &self.loclist
+    }
+
+    pub fn loclist_mut(&mut self) -> &mut List {
+        &mut self.loclist
     }
 
     pub fn loc(&self) -> (usize, usize) {
         self.loc
     }
 
+    /// Moves the top left corner, for when the tabline comes or goes
+    pub fn set_loc(&mut self, loc: (usize, usize)) {
+        self.loc = loc;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn tab(&self) -> usize {
+        self.tab
+    }
+
+    pub fn set_tab(&mut self, tab: usize) {
+        self.tab = tab;
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    pub fn diff(&self) -> Option<&Diff> {
+        self.diff.as_ref()
+    }
+
+    /// Into diff mode, out of it with `None`, or what it found out about the text now
+    pub fn set_diff(&mut self, diff: Option<Diff>) -> CResult<()> {
+        self.diff = diff;
+        self.draw()
+    }
+
+    /// The line on the top row
+    pub fn top_line(&self) -> usize {
+        self.offset_row()
+    }
+
+    /// For diff mode: line `top` on the top row and the cursor on line `row`, as near as the
+    /// window allows
+    pub fn scroll_to(&mut self, top: usize, row: usize) -> CResult<()> {
+        let last = self.buffer.lines().len() - 1;
+        let (top, row) = (top.min(last), row.min(last));
+        let (old_row, col) = self.adjusetd_cursor();
+        if (self.top_line(), old_row) == (top, row) {
+            return Ok(());
+        }
+        self.set_top((top, 0));
+        self.goto(row, col)
+    }
+
     pub fn unsaved_changes(&self) -> bool {
         self.buffer.unsaved_changes()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::harness::{temp_dir, Harness};
+
+    fn numbered_lines(n: usize) -> String {
+        (0..n)
+            .map(|i| format!("l{i}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Buffer line shown on the first row of the window
+    fn top_line(h: &Harness) -> String {
+        h.screen()[0].split_whitespace().last().unwrap().to_owned()
+    }
+
+    #[test]
+    fn scrolloff() {
+        let mut h = Harness::with_size(&numbered_lines(30), 10, 20);
+        h.keys(":set so=2<CR>jjjjj");
+        assert_eq!(top_line(&h), "l0");
+        h.keys("j");
+        assert_eq!(top_line(&h), "l1");
+        assert_eq!(h.backend().borrow().cursor().0, 5);
+        h.keys("G");
+        assert_eq!(top_line(&h), "l22");
+        h.keys("kkkkk");
+        assert_eq!(h.cursor(), (24, 0));
+        assert_eq!(top_line(&h), "l22");
+        h.keys("k");
+        assert_eq!(top_line(&h), "l21");
+    }
+
+    #[test]
+    fn scroll_commands() {
+        let mut h = Harness::with_size(&numbered_lines(30), 10, 20);
+        h.keys("<C-e>");
+        assert_eq!(top_line(&h), "l1");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys("<C-y><C-y>");
+        assert_eq!(top_line(&h), "l0");
+        assert_eq!(h.cursor(), (1, 0));
+
+        h.keys("<C-d>");
+        assert_eq!((top_line(&h), h.cursor()), ("l4".to_string(), (5, 0)));
+        h.keys(":set scroll=1<CR><C-u>");
+        assert_eq!((top_line(&h), h.cursor()), ("l3".to_string(), (4, 0)));
+
+        h.keys("<C-f>");
+        assert_eq!((top_line(&h), h.cursor()), ("l9".to_string(), (9, 0)));
+        h.keys("<C-b>");
+        assert_eq!((top_line(&h), h.cursor()), ("l3".to_string(), (9, 0)));
+
+        h.keys("zt");
+        assert_eq!(top_line(&h), "l9");
+        h.keys("zb");
+        assert_eq!(top_line(&h), "l2");
+        h.keys("zz");
+        assert_eq!(top_line(&h), "l6");
+        assert_eq!(h.cursor(), (9, 0));
+    }
+
+    #[test]
+    fn horizontal_scroll() {
+        let line: String = ('a'..='z').collect();
+        let mut h = Harness::with_size(&line, 5, 20);
+        h.keys("zl");
+        assert_eq!(h.screen()[0], "   1 bcdefghijklmno");
+        assert_eq!(h.cursor(), (0, 1));
+        h.keys("$");
+        assert_eq!(h.screen()[0], "   1 mnopqrstuvwxyz");
+        h.keys("zh");
+        assert_eq!(h.screen()[0], "   1 lmnopqrstuvwxy");
+        assert_eq!(h.cursor(), (0, 25));
+        h.keys("0lllzs");
+        assert_eq!(h.screen()[0], "   1 defghijklmnopq");
+        h.keys("ze");
+        assert_eq!(h.screen()[0], "   1 abcdefghijklmn");
+        h.keys(":set siso=3<CR>llllllllllll");
+        assert_eq!(h.cursor(), (0, 15));
+        assert_eq!(h.screen()[0], "   1 efghijklmnopqr");
+    }
+
+    #[test]
+    fn search_from_inside_a_character() {
+        let mut h = Harness::new("ab\néxé");
+        h.keys("lj/x<CR>");
+        assert_eq!(h.cursor(), (1, 2));
+    }
+
+    #[test]
+    fn tab_pages() {
+        let mut h = Harness::with_size("one\ntwo", 8, 30);
+        h.keys(":tabnew<CR>ihi<Esc>");
+        assert_eq!(h.screen()[0], " [No Name]  [No Name]");
+        assert_eq!(h.screen()[1], "   1 hi");
+        let style = |h: &Harness, col| h.backend().borrow().cell(0, col).style;
+        assert_eq!(style(&h, 1), h.state().screen().style("TabLine"));
+        assert_eq!(style(&h, 12), h.state().screen().style("TabLineSel"));
+        assert_eq!(style(&h, 25), h.state().screen().style("TabLineFill"));
+
+        // Each tab page keeps its own windows, and which of them was active
+        h.keys(":vne<CR>gt");
+        assert_eq!(h.screen()[0], " [No Name]  2 [No Name]");
+        assert_eq!(h.screen()[1], "   1 one");
+        assert_eq!(h.text(), "one\ntwo");
+        h.keys("gT");
+        assert_eq!(h.screen()[1], "   1 hi       |   1");
+        assert_eq!(h.text(), "");
+        h.keys(":wincmd w<CR>");
+        assert_eq!(h.text(), "hi");
+        h.keys(":wincmd w<CR>");
+        assert_eq!(h.text(), "");
+
+        h.keys(":tabc<CR>");
+        assert_eq!(
+            h.message(),
+            "No write since last change (add ! to override)"
+        );
+        h.keys(":tabc!<CR>");
+        assert_eq!(h.screen()[0], "   1 one");
+        assert_eq!(h.text(), "one\ntwo");
+        h.keys(":tabclose<CR>");
+        assert_eq!(h.message(), "Cannot close last tab page");
+
+        h.keys(":tabe<CR>:tabe<CR>:tabn 1<CR>");
+        assert_eq!(h.text(), "one\ntwo");
+        h.keys(":tabp<CR>");
+        // Cut off at the edge
+        assert_eq!(h.screen()[0], " [No Name]  [No Name]  [No Nam");
+        assert_eq!(style(&h, 23), h.state().screen().style("TabLineSel"));
+        h.keys(":tabn 4<CR>");
+        assert_eq!(h.message(), "Invalid argument");
+        // Closing one shows the one after it
+        h.keys(":tabn 2<CR>:tabc<CR>");
+        assert_eq!(style(&h, 12), h.state().screen().style("TabLineSel"));
+        h.keys(":tabc<CR>");
+        assert_eq!(h.text(), "one\ntwo");
+        assert_eq!(h.screen()[0], "   1 one");
+    }
+
+    #[test]
+    fn diff_mode() {
+        let dir = temp_dir("diff");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let mut lines: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+        fs::write(&a, lines.join("\n")).unwrap();
+        lines.insert(3, "new".to_string());
+        lines[15] = "fifteen".to_string();
+        fs::write(&b, lines.join("\n")).unwrap();
+        let mut h = Harness::with_size("", 8, 30);
+        h.keys(&format!(":e {}<CR>:diffthis<CR>", a.display()));
+        h.keys(&format!(":vne {}<CR>", b.display()));
+        h.finish_background();
+        h.keys(":diffthis<CR>");
+        let background =
+            |h: &Harness, row, col| h.backend().borrow().cell(row, col).style.background_color;
+        let add = h.state().screen().style("DiffAdd").background_color;
+        let change = h.state().screen().style("DiffChange").background_color;
+        assert_eq!(h.screen()[3], "   3 4        |   3 new");
+        assert_eq!(background(&h, 3, 6), None);
+        assert_eq!(background(&h, 3, 21), add);
+        // To the end of the window
+        assert_eq!(background(&h, 3, 28), add);
+
+        // The other window keeps to the same place in its own text
+        h.keys("]c]c");
+        assert_eq!(h.cursor(), (15, 0));
+        assert_eq!(h.screen()[0], "   5 10       |   5 10");
+        assert_eq!(h.screen()[5], "  15 15       |  16 fifteen");
+        assert_eq!(background(&h, 5, 6), change);
+        assert_eq!(background(&h, 5, 21), change);
+        h.keys("]c");
+        assert_eq!(h.cursor(), (15, 0));
+
+        // Worked out again after an edit
+        h.keys("[cdd");
+        assert_eq!(h.cursor(), (3, 0));
+        assert_eq!(h.screen()[0], "   4 4        |   4 4");
+        assert_eq!(background(&h, 0, 21), None);
+        h.keys("]c");
+        assert_eq!(h.cursor(), (14, 0));
+        assert_eq!(background(&h, 5, 21), change);
+
+        h.keys(":diffoff<CR>");
+        assert_eq!(background(&h, 5, 21), None);
+        // No longer scrolls along
+        h.keys(":1<CR>");
+        assert_eq!(h.screen()[5], "  15 15       |   5 6");
+    }
+}
@@ -0,0 +1,167 @@
+//! Splitting long lines over several screen rows for `:set wrap`
+
+use crate::options::Options;
+
+/// Characters `linebreak` is allowed to break after
+const BREAKAT: &str = " \t!@*-+;:,./?";
+
+/// The part of a line shown on one screen row, as char indices `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Printed before the text on every row of a line except the first
+pub fn continuation_prefix(line: &str, width: usize, options: &Options) -> String {
+    let mut prefix = String::new();
+    if options.breakindent {
+        prefix.extend(line.chars().take_while(|c| c.is_whitespace()));
+    }
+    prefix.push_str(&options.showbreak);
+    // Always leave room for at least some text
+    if prefix.chars().count() >= width {
+        String::new()
+    } else {
+        prefix
+    }
+}
+
+/// How `line` is laid out in a window `width` columns wide. Never empty.
+pub fn segments(line: &str, width: usize, options: &Options) -> Vec<Segment> {
+    let chars: Vec<char> = line.chars().collect();
+    let width = width.max(1);
+    let prefix_len = continuation_prefix(line, width, options).chars().count();
+    let mut segments = Vec::new();
+    let mut start = 0;
+    loop {
+        let avail = if segments.is_empty() {
+            width
+        } else {
+            width - prefix_len
+        };
+        if chars.len() - start <= avail {
+            segments.push(Segment {
+                start,
+                end: chars.len(),
+            });
+            return segments;
+        }
+        let mut end = start + avail;
+        if options.linebreak {
+            if let Some(i) = (start + 1..=end)
+                .rev()
+                .find(|&i| BREAKAT.contains(chars[i - 1]))
+            {
+                end = i;
+            }
+        }
+        segments.push(Segment { start, end });
+        start = end;
+    }
+}
+
+/// Index of the segment `col` is displayed in. The column just past the end of the line belongs
+/// to the last segment.
+pub fn segment_of(segments: &[Segment], col: usize) -> usize {
+    segments
+        .iter()
+        .rposition(|segment| segment.start <= col)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    fn texts(line: &str, width: usize, options: &Options) -> Vec<String> {
+        segments(line, width, options)
+            .iter()
+            .map(|s| line[s.start..s.end].to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn hard_wrap() {
+        let options = Options::default();
+        assert_eq!(texts("", 4, &options), vec![""]);
+        assert_eq!(texts("abcdefghij", 4, &options), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(texts("abcdefgh", 4, &options), vec!["abcd", "efgh"]);
+    }
+
+    #[test]
+    fn linebreak_and_showbreak() {
+        let options = Options {
+            linebreak: true,
+            showbreak: "> ".to_string(),
+            ..Options::default()
+        };
+        assert_eq!(
+            texts("the quick brown fox", 10, &options),
+            vec!["the quick ", "brown ", "fox"]
+        );
+        assert_eq!(
+            texts("abcdefghijkl", 6, &options),
+            vec!["abcdef", "ghij", "kl"]
+        );
+    }
+
+    #[test]
+    fn breakindent() {
+        let options = Options {
+            breakindent: true,
+            ..Options::default()
+        };
+        assert_eq!(continuation_prefix("  abc", 10, &options), "  ");
+        assert_eq!(texts("  abcdefgh", 6, &options), vec!["  abcd", "efgh"]);
+        let segs = segments("  abcdefgh", 6, &options);
+        assert_eq!(segment_of(&segs, 5), 0);
+        assert_eq!(segment_of(&segs, 6), 1);
+        assert_eq!(segment_of(&segs, 10), 1);
+    }
+
+    #[test]
+    fn soft_wrap() {
+        let mut h = Harness::with_size("aaaaaaaaaa bbbbbbbbbb cccccccccc\nsecond", 10, 20);
+        h.keys(":set wrap<CR>");
+        assert_eq!(
+            &h.screen()[..4],
+            [
+                "   1 aaaaaaaaaa bbb",
+                "     bbbbbbb cccccc",
+                "     cccc",
+                "   1 second"
+            ]
+        );
+        h.keys("gj");
+        assert_eq!(h.cursor(), (0, 14));
+        assert_eq!(h.backend().borrow().cursor(), (1, 5));
+        h.keys("gjgj");
+        assert_eq!(h.cursor(), (1, 0));
+        h.keys("gkg$");
+        assert_eq!(h.cursor(), (0, 32));
+        h.keys("gkg$");
+        assert_eq!(h.cursor(), (0, 27));
+        h.keys("g0");
+        assert_eq!(h.cursor(), (0, 14));
+
+        h.keys(":set lbr sbr=+<CR>");
+        assert_eq!(
+            &h.screen()[..3],
+            ["   1 aaaaaaaaaa", "     +bbbbbbbbbb", "     +cccccccccc"]
+        );
+    }
+
+    #[test]
+    fn wrapped_line_taller_than_window() {
+        let mut h = Harness::with_size(&"x".repeat(100), 5, 20);
+        h.keys(":set wrap<CR>$");
+        assert_eq!(h.cursor(), (0, 100));
+        assert_eq!(h.screen()[0], format!("     {}", "x".repeat(14)));
+        assert_eq!(h.screen()[2], format!("     {}", "x".repeat(2)));
+        assert_eq!(h.backend().borrow().cursor(), (2, 7));
+        h.keys("0");
+        assert_eq!(h.screen()[0], format!("   1 {}", "x".repeat(14)));
+    }
+}